@@ -38,323 +38,14056 @@
 //!   Postgres is allowed)
 //! - Public API already written for this task must not be changed (any change to
 //!   the public API items must be considered as breaking change).
+//!
+//! ### Optional features
+//!
+//! - `serde`: `Serialize`/`Deserialize` for the domain types and events, JSON
+//!   export/import of the event log and the file-backed event store.
+//! - `http`: [axum](https://docs.rs/axum) router exposing the service over
+//!   HTTP, described by an OpenAPI document generated with
+//!   [utoipa](https://docs.rs/utoipa) (implies `serde`).
+//! - `grpc`: [tonic](https://docs.rs/tonic) service exposing the command
+//!   and query handlers over gRPC.
+//! - `graphql`: [async-graphql](https://docs.rs/async-graphql) schema over
+//!   the queries and commands, served on `/graphql` by the `http` router too
+//!   (implies `serde`).
+//! - `cli`: turns the binary into the `url-shortener` command line tool
+//!   operating on a file-backed event store (implies `serde`).
+//! - `webhooks`: [`EventListener`] POSTing JSON notifications about link
+//!   events to configured endpoints (implies `serde`).
+//! - `kafka`: [`publishing::EventPublisher`] forwarding the event log to
+//!   Kafka topics with [rdkafka](https://docs.rs/rdkafka) (implies `serde`).
+//! - `nats`: [`publishing::EventPublisher`] forwarding the event log to NATS
+//!   JetStream subjects with [async-nats](https://docs.rs/async-nats)
+//!   (implies `serde`).
+//! - `redis`: [`Projection`] mirroring the links and their redirect counts
+//!   into Redis with [redis-rs](https://docs.rs/redis), for stateless
+//!   redirect frontends.
+//! - `metrics`: [Prometheus](https://docs.rs/prometheus) metrics of the
+//!   service, served on `/metrics` by the `http` router too.
+//! - `tracing`: [tracing](https://docs.rs/tracing) spans around commands
+//!   and queries, with the slug, outcome and duration recorded.
+//! - `metadata`: fetching titles, descriptions and icons of destination
+//!   pages with [reqwest](https://docs.rs/reqwest).
+//! - `health`: background checker requesting destination URLs with
+//!   [reqwest](https://docs.rs/reqwest) on [tokio](https://docs.rs/tokio)
+//!   to find broken links.
+//! - `sled`: event store persisted in an embedded [sled](https://docs.rs/sled)
+//!   database (implies `serde`).
+//! - `postgres`: event store persisted in PostgreSQL with
+//!   [sqlx](https://docs.rs/sqlx), for deployments not bound to the
+//!   playground (implies `serde`).
+//! - `sqlite`: event store persisted in an SQLite database with
+//!   [rusqlite](https://docs.rs/rusqlite) (implies `serde`).
+//! - `bincode`: compact binary encoding of events with
+//!   [bincode](https://docs.rs/bincode) (implies `serde`).
+//! - `rkyv`: binary encoding of events with [rkyv](https://docs.rs/rkyv),
+//!   validated when decoded.
+//! - `qr`: QR codes of short links rendered as PNG or SVG with
+//!   [qrcode](https://docs.rs/qrcode).
+//! - `parallel`: replaying large event logs partitioned by link on the
+//!   [rayon](https://docs.rs/rayon) thread pool, to speed up cold starts and
+//!   rebuilds of projections.
+//! - `exact-visitors`: counts unique visitors of links exactly instead of
+//!   estimating them with HyperLogLog, at the cost of memory growing with the
+//!   number of visitors.
+//!
+//! The playground builds the service without any of them. Elsewhere, the
+//! features and the dependencies they enable are declared in the manifest
+//! as follows, including the ones a feature implies:
+//!
+//! ```toml
+//! [dependencies]
+//! rand = "0.8"
+//! sha2 = "0.10"
+//! url = "2"
+//! uuid = "1"
+//! serde = { version = "1", features = ["derive"], optional = true }
+//! serde_json = { version = "1", optional = true }
+//! axum = { version = "0.8", optional = true }
+//! utoipa = { version = "5", optional = true }
+//! tonic = { version = "0.13", optional = true }
+//! prost = { version = "0.13", optional = true }
+//! http-body = { version = "1", optional = true }
+//! async-graphql = { version = "7", optional = true, default-features = false }
+//! clap = { version = "4", features = ["derive"], optional = true }
+//! ureq = { version = "2", optional = true }
+//! rdkafka = { version = "0.36", optional = true }
+//! async-nats = { version = "0.42", optional = true }
+//! redis = { version = "0.27", optional = true, default-features = false }
+//! prometheus = { version = "0.14", optional = true, default-features = false }
+//! tracing = { version = "0.1", optional = true }
+//! tokio = { version = "1", optional = true, features = ["rt-multi-thread", "time"] }
+//! sled = { version = "0.34", optional = true }
+//! rusqlite = { version = "0.32", optional = true }
+//! bincode = { version = "1.3", optional = true }
+//! rkyv = { version = "0.8", optional = true, features = ["uuid-1"] }
+//! image = { version = "0.25", optional = true, default-features = false, features = ["png"] }
+//! rayon = { version = "1", optional = true }
+//!
+//! [dependencies.reqwest]
+//! version = "0.12"
+//! optional = true
+//! default-features = false
+//! features = ["rustls-tls"]
+//!
+//! [dependencies.sqlx]
+//! version = "0.8"
+//! optional = true
+//! default-features = false
+//! features = ["runtime-tokio", "postgres", "json"]
+//!
+//! [dependencies.qrcode]
+//! version = "0.14"
+//! optional = true
+//! default-features = false
+//! features = ["svg", "image"]
+//!
+//! [dev-dependencies]
+//! tower = { version = "0.5", features = ["util"] }
+//! tokio = { version = "1", features = ["rt", "net"] }
+//!
+//! [features]
+//! serde = ["dep:serde", "dep:serde_json", "uuid/serde"]
+//! http = ["serde", "dep:axum", "dep:utoipa"]
+//! grpc = ["dep:tonic", "dep:prost", "dep:http-body"]
+//! graphql = ["serde", "dep:async-graphql"]
+//! cli = ["serde", "dep:clap"]
+//! webhooks = ["serde", "dep:ureq"]
+//! kafka = ["serde", "dep:rdkafka"]
+//! nats = ["serde", "dep:async-nats", "dep:tokio"]
+//! redis = ["dep:redis"]
+//! metrics = ["dep:prometheus"]
+//! tracing = ["dep:tracing"]
+//! metadata = ["dep:reqwest"]
+//! health = ["dep:reqwest", "dep:tokio"]
+//! sled = ["serde", "dep:sled"]
+//! postgres = ["serde", "dep:sqlx", "dep:tokio"]
+//! sqlite = ["serde", "dep:rusqlite"]
+//! bincode = ["serde", "dep:bincode"]
+//! rkyv = ["dep:rkyv"]
+//! qr = ["dep:qrcode", "dep:image"]
+//! parallel = ["dep:rayon"]
+//! exact-visitors = []
+//! ```
 
 #![allow(unused_variables, dead_code)]
 
 //crates must have
+use std::cmp::Reverse;
+use std::fmt;
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{
+    Arc, Condvar, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    Weak,
+};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 use rand::{thread_rng, Rng};
-use rand::distributions::Alphanumeric;
+use sha2::{Digest, Sha256};
 use commands::CommandHandler;
-use queries::QueryHandler;
+use queries::{HistoryQueryHandler, QueryHandler};
+use store::{EventStore, InMemoryEventStore};
+use outbox::OutboxMessage;
+use uuid::Uuid;
+use validation::{DefaultUrlValidator, Rejection, UrlValidator, Validator, ValidatorChain};
+use normalization::{DefaultUrlNormalizer, UrlNormalizer};
+use generation::{RandomAlphanumeric, SlugGenerator};
+use clock::{Clock, SystemClock};
+use filtering::{ClickFilter, DefaultClickFilter};
+use audit::{AuditEntry, AuditFilter, AuditLog, AuditOutcome, InMemoryAuditLog};
+use safety::{SafetyChecker, SafetyVerdict};
 //event sourcing event enumerate
 #[derive(Debug, PartialEq,Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "rkyv", derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize))]
 pub enum Event {
     LinkCreated {
         slug: Slug,
         url: Url,
+        #[cfg_attr(feature = "serde", serde(default))]
+        raw_url: Option<Url>,
+        #[cfg_attr(feature = "serde", serde(default))]
+        max_clicks: Option<u64>,
+        #[cfg_attr(feature = "serde", serde(default))]
+        owner: Option<OwnerId>,
+        #[cfg_attr(feature = "serde", serde(default))]
+        #[cfg_attr(feature = "rkyv", rkyv(with = rkyv::with::Map<rkyv::with::AsUnixTime>))]
+        active_from: Option<SystemTime>,
+        #[cfg_attr(feature = "serde", serde(default))]
+        #[cfg_attr(feature = "rkyv", rkyv(with = rkyv::with::Map<rkyv::with::AsUnixTime>))]
+        active_until: Option<SystemTime>,
+        #[cfg_attr(feature = "serde", serde(default))]
+        fallback_url: Option<Url>,
     },
     LinkAccessed {
         slug: Slug,
     },
-    
+
+    LinkAccessedV2 {
+        slug: Slug,
+        context: ClickContext,
+    },
+
     UrlChanged{
         slug: Slug,
         new_url: Url,
     },
-}
 
-/// All possible errors of the [`UrlShortenerService`].
-#[derive(Debug, PartialEq)]
-pub enum ShortenerError {
-    /// This error occurs when an invalid [`Url`] is provided for shortening.
-    InvalidUrl,
+    LinkDeleted {
+        slug: Slug,
+    },
 
-    /// This error occurs when an attempt is made to use a slug (custom alias)
-    /// that already exists.
-    SlugAlreadyInUse,
+    LinkExhausted {
+        slug: Slug,
+    },
 
-    /// This error occurs when the provided [`Slug`] does not map to any existing
-    /// short link.
-    SlugNotFound,
-}
+    LinkDisabled {
+        slug: Slug,
+    },
 
-/// A unique string (or alias) that represents the shortened version of the
-/// URL.
-#[derive(Clone, Debug, PartialEq)]
-pub struct Slug(pub String);
+    LinkEnabled {
+        slug: Slug,
+    },
 
-/// The original URL that the short link points to.
-#[derive(Clone, Debug, PartialEq)]
-pub struct Url(pub String);
+    LinkActivated {
+        slug: Slug,
+    },
 
-/// Shortened URL representation.
-#[derive(Debug, Clone, PartialEq)]
-pub struct ShortLink {
-    /// A unique string (or alias) that represents the shortened version of the
-    /// URL.
-    pub slug: Slug,
+    LinkDeactivated {
+        slug: Slug,
+    },
 
-    /// The original URL that the short link points to.
-    pub url: Url,
-}
+    SlugRenamed {
+        slug: Slug,
+        new_slug: Slug,
+        keep_alias: bool,
+    },
 
-/// Statistics of the [`ShortLink`].
-#[derive(Debug, Clone, PartialEq)]
-pub struct Stats {
-    /// [`ShortLink`] to which this [`Stats`] are related.
-    pub link: ShortLink,
+    LinkMetadataFetched {
+        slug: Slug,
+        metadata: LinkMetadata,
+    },
 
-    /// Count of redirects of the [`ShortLink`].
-    pub redirects: u64,
-}
+    DestinationsSet {
+        slug: Slug,
+        destinations: Vec<(Url, u32)>,
+    },
 
-/// Commands for CQRS.
-pub mod commands {
-    use super::{ShortLink, ShortenerError, Slug, Url};
+    VariantServed {
+        slug: Slug,
+        url: Url,
+    },
 
-    /// Trait for command handlers.
-    pub trait CommandHandler {
-        /// Creates a new short link. It accepts the original url and an
-        /// optional [`Slug`]. If a [`Slug`] is not provided, the service will generate
-        /// one. Returns the newly created [`ShortLink`].
-        ///
-        /// ## Errors
-        ///
-        /// See [`ShortenerError`].
-        fn handle_create_short_link(
-            &mut self,
-            url: Url,
-            slug: Option<Slug>,
-        ) -> Result<ShortLink, ShortenerError>;
+    GeoRulesSet {
+        slug: Slug,
+        rules: Vec<(String, Url)>,
+    },
 
-        /// Processes a redirection by [`Slug`], returning the associated
-        /// [`ShortLink`] or a [`ShortenerError`].
-        fn handle_redirect(
-            &mut self,
-            slug: Slug,
-        ) -> Result<ShortLink, ShortenerError>;
-        
-        fn handle_change_short_link(
-        &mut self,
+    RedirectRuleSet {
         slug: Slug,
-        new_url: Url
-    ) -> Result<ShortLink, ShortenerError>;
-    }
-}
+        device: Device,
+        url: Option<Url>,
+    },
 
-/// Queries for CQRS
-pub mod queries {
-    use super::{ShortenerError, Slug, Stats};
+    RedirectPolicySet {
+        slug: Slug,
+        policy: RedirectPolicy,
+    },
 
-    /// Trait for query handlers.
-    pub trait QueryHandler {
-        /// Returns the [`Stats`] for a specific [`ShortLink`], such as the
-        /// number of redirects (clicks).
-        ///
-        /// [`ShortLink`]: super::ShortLink
-        fn get_stats(&self, slug: Slug) -> Result<Stats, ShortenerError>;
-    }
-}
+    ClicksAggregated {
+        slug: Slug,
+        count: u64,
+        up_to_sequence: u64,
+        //events folded into it by compaction, besides itself
+        #[cfg_attr(feature = "serde", serde(default))]
+        folded: u64,
+    },
 
-/// CQRS and Event Sourcing-based service implementation
-pub struct UrlShortenerService {
-    // TODO: add needed fields
-    events: Vec<Event>,
+    MilestoneReached {
+        slug: Slug,
+        clicks: u64,
+    },
+
+    CampaignCreated {
+        campaign: Slug,
+        name: String,
+    },
+
+    LinkAddedToCampaign {
+        slug: Slug,
+        campaign: Slug,
+    },
+
+    LinkRemovedFromCampaign {
+        slug: Slug,
+        campaign: Slug,
+    },
+
+    LinkTagged {
+        slug: Slug,
+        tag: String,
+    },
+
+    BotAccess {
+        slug: Slug,
+        context: ClickContext,
+    },
+
+    DestinationUnhealthy {
+        slug: Slug,
+        url: Url,
+        reason: String,
+    },
+
+    DestinationHealthy {
+        slug: Slug,
+        url: Url,
+    },
+
+    LinkUntagged {
+        slug: Slug,
+        tag: String,
+    },
+
+    DestinationBlocked {
+        slug: Option<Slug>,
+        url: Url,
+        domain: String,
+    },
+
+    LinkFlagged {
+        slug: Slug,
+        url: Url,
+        reason: String,
+    },
+
+    AliasAdded {
+        slug: Slug,
+        alias: Slug,
+    },
 }
 
-impl UrlShortenerService {
-    /// Creates a new instance of the service
-    pub fn new() -> Self {
-        Self {
-            events: Vec::new(),
+impl Event {
+    //approximate number of bytes allocated on the heap by the event
+    fn heap_size(&self) -> usize {
+        fn url(url: &Url) -> usize {
+            url.0.len()
         }
-    }
-    
-    //my functions
-    
-    //record event
-    fn record_event(&mut self, event: Event) {
-        self.events.push(event);
-    }
-    //replay events
-    fn replay(&self) -> (Vec<ShortLink>, Vec<Stats>) {
-        let mut links = Vec::new();
-        let mut stats = Vec::new();
-    
-        for event in &self.events {
-            if let Event::LinkCreated { slug, url } = event {
-                let short_link = ShortLink {
-                    slug: slug.clone(),
-                    url: url.clone(),
-                };
-                links.push(short_link.clone());
-                stats.push(Stats {
-                    link: short_link,
-                    redirects: 0, 
-                });
-            }
-        
-        
-            // match event {
-            //     Event::LinkCreated { slug, url } => {
-            //         links.push(ShortLink {
-            //             slug: slug.clone(),
-            //             url: url.clone
-            //         })
-            //     }
-            // }
+        fn optional(url: &Option<Url>) -> usize {
+            url.as_ref().map_or(0, |url| url.0.len())
         }
-    
-        for event in &self.events {
-            if let Event::LinkAccessed { slug } = event {
-                if let Some(stat) = stats.iter_mut().find(|stat| stat.link.slug == *slug) {
-                    stat.redirects += 1; 
-                }
-            }
+        fn context(context: &ClickContext) -> usize {
+            [&context.referrer, &context.user_agent, &context.country]
+                .into_iter()
+                .flatten()
+                .map(String::len)
+                .sum()
         }
-        
-        for event in &self.events {
-            if let Event::UrlChanged {slug, new_url} = event {
-                if let Some(link) = links.iter_mut().find(|link| link.slug == *slug) {
-                    link.url = new_url.clone();
+        self.slug().0.len()
+            + match self {
+                Event::LinkCreated { url: created, raw_url, fallback_url, owner, .. } => {
+                    url(created)
+                        + optional(raw_url)
+                        + optional(fallback_url)
+                        + owner.as_ref().map_or(0, |owner| owner.0.len())
+                }
+                Event::LinkAccessedV2 { context: accessed, .. }
+                | Event::BotAccess { context: accessed, .. } => context(accessed),
+                Event::UrlChanged { new_url: changed, .. }
+                | Event::VariantServed { url: changed, .. }
+                | Event::DestinationHealthy { url: changed, .. } => url(changed),
+                Event::DestinationUnhealthy { url: unhealthy, reason, .. }
+                | Event::LinkFlagged { url: unhealthy, reason, .. } => {
+                    url(unhealthy) + reason.len()
                 }
+                Event::DestinationBlocked { url: blocked, domain, .. } => {
+                    url(blocked) + domain.len()
+                }
+                Event::SlugRenamed { new_slug: other, .. }
+                | Event::AliasAdded { alias: other, .. } => other.0.len(),
+                Event::LinkMetadataFetched { metadata, .. } => {
+                    metadata.title.as_ref().map_or(0, String::len)
+                        + metadata.description.as_ref().map_or(0, String::len)
+                        + optional(&metadata.favicon)
+                }
+                Event::DestinationsSet { destinations, .. } => {
+                    destinations.iter().map(|(destination, _)| url(destination)).sum()
+                }
+                Event::GeoRulesSet { rules, .. } => {
+                    rules.iter().map(|(country, rule)| country.len() + url(rule)).sum()
+                }
+                Event::RedirectRuleSet { url: rule, .. } => optional(rule),
+                Event::CampaignCreated { name, .. } => name.len(),
+                Event::LinkAddedToCampaign { campaign, .. }
+                | Event::LinkRemovedFromCampaign { campaign, .. } => campaign.0.len(),
+                Event::LinkTagged { tag, .. } | Event::LinkUntagged { tag, .. } => tag.len(),
+                _ => 0,
             }
-        }
-    
-        (links, stats)
     }
-}
 
-impl commands::CommandHandler for UrlShortenerService {
-    fn handle_create_short_link(
-        &mut self,
-        url: Url,
-        slug: Option<Slug>,
-    ) -> Result<ShortLink, ShortenerError> {
-        // todo!("Implement the logic for creating a short link")
-        if !url.0.starts_with("http") || url.0.is_empty() {
-            return Err(ShortenerError::InvalidUrl);
-        }
-        let slug = slug.unwrap_or_else(|| {
-            let random_slug: String = thread_rng()
-                .sample_iter(&Alphanumeric)
-                .take(6)
-                .map(char::from)
-                .collect();
-            Slug(random_slug)
-        });
-        //check if slug is unique
-        let (links, _) = self.replay();
-        if links.iter().any(|link| link.slug == slug) {
-            return Err(ShortenerError::SlugAlreadyInUse);
+    /// Returns the [`StreamId`] of the link this event belongs to. Events of
+    /// a [`Campaign`] belong to its own stream, prefixed with `campaign:` so
+    /// it can't collide with the stream of a link. Likewise blocked attempts
+    /// belong to the stream of the domain, prefixed with `blocked:`.
+    ///
+    /// [`StreamId`]: store::StreamId
+    pub fn stream_id(&self) -> store::StreamId {
+        match self {
+            Event::CampaignCreated { campaign, .. } => {
+                store::StreamId(Slug(format!("campaign:{}", campaign.0)))
+            }
+            Event::DestinationBlocked { domain, .. } => {
+                store::StreamId(Slug(format!("blocked:{domain}")))
+            }
+            event => store::StreamId(event.slug().clone()),
         }
-        //record event
-        self.record_event(Event::LinkCreated { slug: slug.clone(), url: url.clone() });
+    }
 
-        Ok(ShortLink { slug, url })
+    /// Returns the [`Slug`] of the link this event belongs to, or the
+    /// identifier of the [`Campaign`] for [`Event::CampaignCreated`]. For
+    /// [`Event::DestinationBlocked`] it is the requested [`Slug`], empty if
+    /// none was requested, as no link was created or changed.
+    pub fn slug(&self) -> &Slug {
+        static UNASSIGNED: Slug = Slug(String::new());
+        match self {
+            Event::LinkCreated { slug, .. }
+            | Event::LinkAccessed { slug }
+            | Event::LinkAccessedV2 { slug, .. }
+            | Event::UrlChanged { slug, .. }
+            | Event::LinkDeleted { slug }
+            | Event::LinkExhausted { slug }
+            | Event::LinkDisabled { slug }
+            | Event::LinkEnabled { slug }
+            | Event::LinkActivated { slug }
+            | Event::LinkDeactivated { slug }
+            | Event::SlugRenamed { slug, .. }
+            | Event::LinkMetadataFetched { slug, .. }
+            | Event::DestinationsSet { slug, .. }
+            | Event::VariantServed { slug, .. }
+            | Event::GeoRulesSet { slug, .. }
+            | Event::RedirectRuleSet { slug, .. }
+            | Event::RedirectPolicySet { slug, .. }
+            | Event::ClicksAggregated { slug, .. }
+            | Event::MilestoneReached { slug, .. }
+            | Event::LinkAddedToCampaign { slug, .. }
+            | Event::LinkRemovedFromCampaign { slug, .. }
+            | Event::LinkTagged { slug, .. }
+            | Event::LinkUntagged { slug, .. }
+            | Event::BotAccess { slug, .. }
+            | Event::DestinationUnhealthy { slug, .. }
+            | Event::DestinationHealthy { slug, .. }
+            | Event::LinkFlagged { slug, .. }
+            | Event::AliasAdded { slug, .. } => slug,
+            Event::CampaignCreated { campaign, .. } => campaign,
+            Event::DestinationBlocked { slug, .. } => slug.as_ref().unwrap_or(&UNASSIGNED),
+        }
     }
+}
 
-    fn handle_redirect(
-        &mut self,
-        slug: Slug,
-    ) -> Result<ShortLink, ShortenerError> {
-        //todo!("Implement the logic for redirection and incrementing the click counter")
-        let (links, _) = self.replay();
-        let link = links.into_iter().find(|link| link.slug == slug).ok_or(ShortenerError::SlugNotFound)?;
-        self.record_event(Event::LinkAccessed { slug: slug.clone() });
-        Ok(link)
+/// Event identified by a stable type name and payload version rather than
+/// by the layout of the Rust type, so stored and transmitted events can be
+/// told apart and upgraded independently of how the enum evolves.
+pub trait DomainEvent {
+    /// Name of the kind of event in snake case, e.g. `link_created`. Once
+    /// released, it never changes, even if the Rust variant is renamed.
+    fn event_type(&self) -> &'static str;
+
+    /// Version of the payload of the [`DomainEvent::event_type()`], starting
+    /// from `1`. It is bumped by changes old readers can't handle, while
+    /// optional fields are added without bumping it.
+    fn event_version(&self) -> u32;
+}
+
+impl DomainEvent for Event {
+    fn event_type(&self) -> &'static str {
+        match self {
+            Event::LinkCreated { .. } => "link_created",
+            Event::LinkAccessed { .. } | Event::LinkAccessedV2 { .. } => "link_accessed",
+            Event::UrlChanged { .. } => "url_changed",
+            Event::LinkDeleted { .. } => "link_deleted",
+            Event::LinkExhausted { .. } => "link_exhausted",
+            Event::LinkDisabled { .. } => "link_disabled",
+            Event::LinkEnabled { .. } => "link_enabled",
+            Event::LinkActivated { .. } => "link_activated",
+            Event::LinkDeactivated { .. } => "link_deactivated",
+            Event::SlugRenamed { .. } => "slug_renamed",
+            Event::LinkMetadataFetched { .. } => "link_metadata_fetched",
+            Event::DestinationsSet { .. } => "destinations_set",
+            Event::VariantServed { .. } => "variant_served",
+            Event::GeoRulesSet { .. } => "geo_rules_set",
+            Event::RedirectRuleSet { .. } => "redirect_rule_set",
+            Event::RedirectPolicySet { .. } => "redirect_policy_set",
+            Event::ClicksAggregated { .. } => "clicks_aggregated",
+            Event::MilestoneReached { .. } => "milestone_reached",
+            Event::CampaignCreated { .. } => "campaign_created",
+            Event::LinkAddedToCampaign { .. } => "link_added_to_campaign",
+            Event::LinkRemovedFromCampaign { .. } => "link_removed_from_campaign",
+            Event::LinkTagged { .. } => "link_tagged",
+            Event::BotAccess { .. } => "bot_access",
+            Event::DestinationUnhealthy { .. } => "destination_unhealthy",
+            Event::DestinationHealthy { .. } => "destination_healthy",
+            Event::LinkUntagged { .. } => "link_untagged",
+            Event::DestinationBlocked { .. } => "destination_blocked",
+            Event::LinkFlagged { .. } => "link_flagged",
+            Event::AliasAdded { .. } => "alias_added",
+        }
     }
-    
-    fn handle_change_short_link(
-        &mut self,
-        slug: Slug,
-        new_url: Url
-    ) -> Result<ShortLink, ShortenerError> {
-        let (links, _) = self.replay();
-        let mut link = links.into_iter().find(|link| link.slug == slug).ok_or(ShortenerError::SlugNotFound)?;
-        link.url = new_url.clone();
-        self.record_event(Event::UrlChanged {slug: slug.clone(), new_url: new_url.clone()});
-        Ok(link)
+
+    fn event_version(&self) -> u32 {
+        match self {
+            //the click context was added as a new variant
+            Event::LinkAccessedV2 { .. } => 2,
+            _ => 1,
+        }
     }
-        
 }
 
+/// [`Event`] together with the metadata recorded when it was stored.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "rkyv", derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize))]
+pub struct EventEnvelope {
+    /// Unique identifier of the event.
+    pub id: Uuid,
 
-impl queries::QueryHandler for UrlShortenerService {
-    fn get_stats(&self, slug: Slug) -> Result<Stats, ShortenerError> {
-        //todo!("Implement the logic for retrieving link statistics")
-        let (_, stats) = self.replay();
+    /// Position of the event in the whole event log, starting from `0`.
+    pub sequence: u64,
+
+    /// Version of the event stream of the link after this event, i.e. the
+    /// position of the event in that stream starting from `1`.
+    pub version: u64,
 
-        let stat = stats.into_iter().find(|stat| stat.link.slug == slug).ok_or(ShortenerError::SlugNotFound)?;
+    /// Moment the event was recorded at.
+    #[cfg_attr(feature = "rkyv", rkyv(with = rkyv::with::AsUnixTime))]
+    pub occurred_at: SystemTime,
 
-        Ok(stat)
+    /// The recorded [`Event`] itself.
+    pub event: Event,
+
+    /// Identifier shared by all the events of a multi-step workflow, e.g. the
+    /// id of the command or event which started it. [`None`] if the event
+    /// was not recorded as a part of one.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub correlation_id: Option<Uuid>,
+
+    /// Identifier of the command or event which directly caused this event,
+    /// if known.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub causation_id: Option<Uuid>,
+}
+
+impl EventEnvelope {
+    /// Wraps the given [`Event`] into a new envelope with a random id,
+    /// recorded now.
+    pub fn new(sequence: u64, version: u64, event: Event) -> Self {
+        Self {
+            id: random_uuid(),
+            sequence,
+            version,
+            occurred_at: SystemTime::now(),
+            event,
+            correlation_id: None,
+            causation_id: None,
+        }
+    }
+
+    /// Same as [`EventEnvelope::new()`], recorded at the given moment.
+    pub fn new_at(sequence: u64, version: u64, event: Event, occurred_at: SystemTime) -> Self {
+        Self {
+            occurred_at,
+            ..Self::new(sequence, version, event)
+        }
     }
 }
-//my tests
-// #[cfg(test)]
 
-// mod tests {
-//     use super::*;
+/// Observer notified about every [`Event`] recorded by the
+/// [`UrlShortenerService`], e.g. for logging, metrics or notifications.
+///
+/// Implemented for closures taking an [`EventEnvelope`] as well.
+pub trait EventListener {
+    /// Called synchronously after the event was stored and applied to the
+    /// read model.
+    fn on_event(&mut self, envelope: &EventEnvelope);
+}
 
-//     #[test]
-//     fn test_create_short_link() {
-//         let mut service = UrlShortenerService::new();
-//         let url = Url("https://example.com".to_string());
-//         let slug = Slug("example".to_string());
+impl<F: FnMut(&EventEnvelope)> EventListener for F {
+    fn on_event(&mut self, envelope: &EventEnvelope) {
+        self(envelope)
+    }
+}
 
-//         let result = service.handle_create_short_link(url.clone(), Some(slug.clone()));
-//         assert_eq!(result, Ok(ShortLink { slug: slug.clone(), url: url.clone() }));
+/// User-defined read model, e.g. redirects per country, kept up to date by
+/// the [`UrlShortenerService`] it is registered with using
+/// [`UrlShortenerService::register_projection()`].
+///
+/// Unlike an [`EventListener`], a projection is built from the whole event
+/// log: it catches up with the events recorded before it was registered and
+/// is rebuilt by [`UrlShortenerService::rebuild_projections()`].
+pub trait Projection {
+    /// Applies the next [`Event`] of the log to the projection.
+    fn apply(&mut self, envelope: &EventEnvelope);
 
-//         let result = service.handle_create_short_link(url.clone(), Some(slug.clone()));
-//         assert_eq!(result, Err(ShortenerError::SlugAlreadyInUse));
-//     }
+    /// Clears the projection before it is rebuilt from the start of the log.
+    /// Does nothing by default, which suits projections built from scratch
+    /// only once.
+    fn reset(&mut self) {}
+}
 
-//     #[test]
-//     fn test_redirect() {
-//         let mut service = UrlShortenerService::new();
-//         let url = Url("https://example.com".to_string());
-//         let slug = Slug("example".to_string());
+impl<P: Projection> Projection for Arc<Mutex<P>> {
+    fn apply(&mut self, envelope: &EventEnvelope) {
+        self.lock().unwrap_or_else(PoisonError::into_inner).apply(envelope);
+    }
 
-//         let _ = service.handle_create_short_link(url.clone(), Some(slug.clone()));
+    fn reset(&mut self) {
+        self.lock().unwrap_or_else(PoisonError::into_inner).reset();
+    }
+}
 
-//         let result = service.handle_redirect(slug.clone());
-//         assert_eq!(result, Ok(ShortLink { slug: slug.clone(), url: url.clone() }));
-//     }
+/// Catch-up subscription returned by
+/// [`UrlShortenerService::subscribe_from()`], yielding the stored events from
+/// a checkpoint followed by the events recorded afterwards, each exactly
+/// once and in log order.
+///
+/// Iterating blocks until the next event is recorded and ends once the
+/// service is dropped. Async consumers poll it with
+/// [`Subscription::next_async()`] or [`Subscription::poll_next()`] instead.
+#[derive(Debug)]
+pub struct Subscription {
+    feed: Arc<SubscriptionFeed>,
+    checkpoint: u64,
+}
 
-//     #[test]
-//     fn test_get_stats() {
-//         let mut service = UrlShortenerService::new();
-//         let url = Url("https://example.com".to_string());
-//         let slug = Slug("example".to_string());
+impl Subscription {
+    /// Returns the sequence to resume from with
+    /// [`UrlShortenerService::subscribe_from()`] after a restart, which is the
+    /// one following the last yielded event.
+    pub fn checkpoint(&self) -> u64 {
+        self.checkpoint
+    }
 
-//         let _ = service.handle_create_short_link(url.clone(), Some(slug.clone()));
-//         let _ = service.handle_redirect(slug.clone());
+    /// Returns the next event if it is already available, without waiting.
+    pub fn try_next(&mut self) -> Option<EventEnvelope> {
+        let envelope = self.feed.lock().events.pop_front()?;
+        Some(self.yielded(envelope))
+    }
 
-//         let result = service.get_stats(slug.clone());
-//         assert_eq!(result, Ok(Stats { link: ShortLink { slug: slug.clone(), url: url.clone() }, redirects: 1 }));
-//     }
+    /// Polls for the next event like `futures::Stream::poll_next()`, so the
+    /// subscription can be adapted to a stream with `futures::stream::poll_fn`.
+    /// Returns `None` once the service is dropped.
+    pub fn poll_next(&mut self, cx: &mut Context<'_>) -> Poll<Option<EventEnvelope>> {
+        let mut state = self.feed.lock();
+        if let Some(envelope) = state.events.pop_front() {
+            drop(state);
+            return Poll::Ready(Some(self.yielded(envelope)));
+        }
+        if state.closed {
+            return Poll::Ready(None);
+        }
+        state.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
 
-//     #[test]
-//     fn test_redirect_with_multiple_redirects() {
-//         let mut service = UrlShortenerService::new();
-//         let url = Url("https://example.com".to_string());
-//         let slug = Slug("example".to_string());
+    /// Waits for the next event without blocking the thread. Returns `None`
+    /// once the service is dropped.
+    pub async fn next_async(&mut self) -> Option<EventEnvelope> {
+        std::future::poll_fn(|cx| self.poll_next(cx)).await
+    }
 
-//         let _ = service.handle_create_short_link(url.clone(), Some(slug.clone()));
-//         let _ = service.handle_redirect(slug.clone());
-//         let _ = service.handle_redirect(slug.clone());
-//         let _ = service.handle_redirect(slug.clone());
+    fn yielded(&mut self, envelope: EventEnvelope) -> EventEnvelope {
+        self.checkpoint = envelope.sequence + 1;
+        envelope
+    }
+}
 
-//         let result = service.get_stats(slug.clone());
-//         assert_eq!(result, Ok(Stats { link: ShortLink { slug: slug.clone(), url: url.clone() }, redirects: 3 }));
-//     }
-// }
+impl Iterator for Subscription {
+    type Item = EventEnvelope;
 
-fn main() {
-    // example of usage
-    let mut service = UrlShortenerService::new();
-    let url = Url("https://example-of/verylong-link.com".to_string());
-    let new_url = Url("https://example-of/verylong-link/asasasdsad.com".to_string());
+    fn next(&mut self) -> Option<EventEnvelope> {
+        let mut state = self.feed.lock();
+        loop {
+            if let Some(envelope) = state.events.pop_front() {
+                drop(state);
+                return Some(self.yielded(envelope));
+            }
+            if state.closed {
+                return None;
+            }
+            state = self.feed.ready.wait(state).unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+}
+
+//events of a subscription not consumed yet, shared with the service
+#[derive(Debug, Default)]
+struct SubscriptionFeed {
+    state: Mutex<FeedState>,
+    ready: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct FeedState {
+    events: VecDeque<EventEnvelope>,
+    waker: Option<Waker>,
+    //whether the service was dropped
+    closed: bool,
+}
+
+impl SubscriptionFeed {
+    fn lock(&self) -> MutexGuard<'_, FeedState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    //wakes up the subscription waiting for the next event
+    fn notify(&self, state: &mut FeedState) {
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+        self.ready.notify_all();
+    }
+}
+
+//feeds of the subscriptions of a service, closed when it is dropped
+#[derive(Default)]
+struct Subscriptions(Vec<Weak<SubscriptionFeed>>);
+
+impl Subscriptions {
+    fn publish(&mut self, envelope: &EventEnvelope) {
+        //feeds of dropped subscriptions can't be upgraded anymore
+        self.0.retain(|feed| {
+            let Some(feed) = feed.upgrade() else {
+                return false;
+            };
+            let mut state = feed.lock();
+            state.events.push_back(envelope.clone());
+            feed.notify(&mut state);
+            true
+        });
+    }
+}
+
+impl Drop for Subscriptions {
+    fn drop(&mut self) {
+        for feed in self.0.iter().filter_map(Weak::upgrade) {
+            let mut state = feed.lock();
+            state.closed = true;
+            feed.notify(&mut state);
+        }
+    }
+}
+
+/// All possible errors of the [`UrlShortenerService`].
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "http", derive(utoipa::ToSchema))]
+pub enum ShortenerError {
+    /// This error occurs when an invalid [`Url`] is provided for shortening.
+    InvalidUrl,
+
+    /// This error occurs when an attempt is made to use a slug (custom alias)
+    /// that already exists.
+    SlugAlreadyInUse,
+
+    /// This error occurs when the provided [`Slug`] does not map to any existing
+    /// short link.
+    SlugNotFound,
+
+    /// This error occurs when the [`Event`] describing the change could not be
+    /// persisted in the [`EventStore`].
+    StorageFailure,
+
+    /// This error occurs when the link was modified concurrently, so its
+    /// version differs from the one the command expected.
+    VersionConflict,
+
+    /// This error occurs when the link reached its maximum number of
+    /// redirects and no longer redirects.
+    LinkExhausted,
+
+    /// This error occurs when the link was disabled and does not redirect
+    /// until it is enabled again.
+    LinkDisabled,
+
+    /// This error occurs when the link is accessed outside of its activation
+    /// window and has no fallback URL.
+    LinkNotActive,
+
+    /// This error occurs when there is no [`Campaign`] with the given
+    /// identifier.
+    CampaignNotFound,
+
+    /// This error occurs when creating a [`Campaign`] with an identifier
+    /// which is already used.
+    CampaignAlreadyExists,
+
+    /// This error occurs when an attempt is made to use a slug which is
+    /// reserved by the service configuration.
+    SlugReserved,
+
+    /// This error occurs when a custom [`Slug`] breaks the [`SlugPolicy`].
+    ///
+    /// [`SlugPolicy`]: validation::SlugPolicy
+    InvalidSlug(validation::SlugViolation),
+
+    /// This error occurs when reverting a URL change of a link whose URL was
+    /// never changed.
+    NothingToRevert,
+
+    /// This error occurs when the caller created too many links in a short
+    /// time, see [`RateLimitPolicy`].
+    RateLimited,
+
+    /// This error occurs when an owner attempts to modify a link it does not
+    /// own.
+    NotOwner,
+
+    /// This error occurs when the provided API key is unknown or revoked.
+    Unauthorized,
+
+    /// This error occurs when the role of an authenticated caller does not
+    /// permit the command.
+    Forbidden,
+
+    /// This error occurs when the metadata of the destination page can't be
+    /// fetched.
+    MetadataUnavailable,
+
+    /// This error occurs when none of the destinations of a link has a
+    /// positive weight.
+    InvalidWeights,
+
+    /// This error occurs when a country code is not a two-letter ISO 3166-1
+    /// code.
+    InvalidCountry,
+
+    /// This error occurs when a tag is blank or longer than 64 characters.
+    InvalidTag,
+
+    /// This error occurs when the destination of a link is on a domain
+    /// rejected by the [`DomainPolicy`].
+    ///
+    /// [`DomainPolicy`]: validation::DomainPolicy
+    DestinationBlocked,
+
+    /// This error occurs when the destination of a link points back at the
+    /// service, or chains through too many other URL shorteners, see
+    /// [`LoopPolicy`].
+    ///
+    /// [`LoopPolicy`]: validation::LoopPolicy
+    RedirectLoop,
+
+    /// This error occurs when none of the [`Slug`]s generated for a link of a
+    /// [`ShardedUrlShortenerService`] hashed into the shard the link is
+    /// created in.
+    ///
+    /// [`ShardedUrlShortenerService`]: sharding::ShardedUrlShortenerService
+    NoSlugInShard,
+
+    /// This error occurs when a command executed with
+    /// [`UrlShortenerService::execute()`] is rejected by one of the
+    /// [`Validator`]s of the service, or its events are vetoed by a hook
+    /// registered with [`UrlShortenerService::on_before_commit()`].
+    ///
+    /// [`Validator`]: validation::Validator
+    Rejected(validation::Rejection),
+}
+
+impl ShortenerError {
+    /// Machine-readable kind of the error in snake case, e.g.
+    /// `slug_not_found`, stable across releases.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ShortenerError::InvalidUrl => "invalid_url",
+            ShortenerError::SlugAlreadyInUse => "slug_already_in_use",
+            ShortenerError::SlugNotFound => "slug_not_found",
+            ShortenerError::StorageFailure => "storage_failure",
+            ShortenerError::VersionConflict => "version_conflict",
+            ShortenerError::LinkExhausted => "link_exhausted",
+            ShortenerError::LinkDisabled => "link_disabled",
+            ShortenerError::LinkNotActive => "link_not_active",
+            ShortenerError::CampaignNotFound => "campaign_not_found",
+            ShortenerError::CampaignAlreadyExists => "campaign_already_exists",
+            ShortenerError::SlugReserved => "slug_reserved",
+            ShortenerError::InvalidSlug(_) => "invalid_slug",
+            ShortenerError::NothingToRevert => "nothing_to_revert",
+            ShortenerError::RateLimited => "rate_limited",
+            ShortenerError::NotOwner => "not_owner",
+            ShortenerError::Unauthorized => "unauthorized",
+            ShortenerError::Forbidden => "forbidden",
+            ShortenerError::MetadataUnavailable => "metadata_unavailable",
+            ShortenerError::InvalidWeights => "invalid_weights",
+            ShortenerError::InvalidCountry => "invalid_country",
+            ShortenerError::InvalidTag => "invalid_tag",
+            ShortenerError::DestinationBlocked => "destination_blocked",
+            ShortenerError::RedirectLoop => "redirect_loop",
+            ShortenerError::NoSlugInShard => "no_slug_in_shard",
+            ShortenerError::Rejected(_) => "rejected",
+        }
+    }
+}
+
+impl fmt::Display for ShortenerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShortenerError::InvalidUrl => f.write_str("invalid URL"),
+            ShortenerError::SlugAlreadyInUse => f.write_str("slug is already in use"),
+            ShortenerError::SlugNotFound => f.write_str("slug not found"),
+            ShortenerError::StorageFailure => f.write_str("event could not be stored"),
+            ShortenerError::VersionConflict => f.write_str("link was modified concurrently"),
+            ShortenerError::LinkExhausted => {
+                f.write_str("link reached its maximum number of redirects")
+            }
+            ShortenerError::LinkDisabled => f.write_str("link is disabled"),
+            ShortenerError::LinkNotActive => f.write_str("link is not active"),
+            ShortenerError::CampaignNotFound => f.write_str("campaign not found"),
+            ShortenerError::CampaignAlreadyExists => f.write_str("campaign already exists"),
+            ShortenerError::SlugReserved => f.write_str("slug is reserved"),
+            ShortenerError::InvalidSlug(violation) => write!(f, "invalid slug: {violation}"),
+            ShortenerError::NothingToRevert => f.write_str("URL of the link was never changed"),
+            ShortenerError::RateLimited => f.write_str("too many links created, try again later"),
+            ShortenerError::NotOwner => f.write_str("link is owned by someone else"),
+            ShortenerError::Unauthorized => f.write_str("unknown or revoked API key"),
+            ShortenerError::Forbidden => f.write_str("role does not permit the command"),
+            ShortenerError::MetadataUnavailable => {
+                f.write_str("destination page could not be fetched")
+            }
+            ShortenerError::InvalidWeights => f.write_str("no destination has a positive weight"),
+            ShortenerError::InvalidCountry => f.write_str("invalid country code"),
+            ShortenerError::InvalidTag => f.write_str("invalid tag"),
+            ShortenerError::DestinationBlocked => f.write_str("destination domain is blocked"),
+            ShortenerError::RedirectLoop => f.write_str("destination would make redirects loop"),
+            ShortenerError::NoSlugInShard => f.write_str("no slug generated for the shard"),
+            ShortenerError::Rejected(rejection) => write!(f, "rejected by {rejection}"),
+        }
+    }
+}
+
+impl std::error::Error for ShortenerError {}
+
+/// [`ShortenerError`] together with the [`Slug`] and [`Url`] it occurred
+/// for, if known. Attached to results with [`ErrorContext`], e.g. to be
+/// reported by applications through `?`.
+#[derive(Debug, PartialEq)]
+pub struct Error {
+    kind: ShortenerError,
+    slug: Option<Slug>,
+    url: Option<Url>,
+    suggestions: Vec<Slug>,
+}
+
+impl Error {
+    /// Returns the [`ShortenerError`] which occurred.
+    pub fn kind(&self) -> &ShortenerError {
+        &self.kind
+    }
+
+    /// Returns the [`Slug`] the error occurred for, if known.
+    pub fn slug(&self) -> Option<&Slug> {
+        self.slug.as_ref()
+    }
+
+    /// Returns the [`Url`] the error occurred for, if known.
+    pub fn url(&self) -> Option<&Url> {
+        self.url.as_ref()
+    }
+
+    /// Returns available alternatives to the requested [`Slug`], see
+    /// [`UrlShortenerService::create_with_suggestions()`]. Empty for other
+    /// errors.
+    pub fn suggestions(&self) -> &[Slug] {
+        &self.suggestions
+    }
+
+    /// Returns the [`ShortenerError::code()`] of the error.
+    pub fn code(&self) -> &'static str {
+        self.kind.code()
+    }
+
+    /// Unwraps the [`ShortenerError`].
+    pub fn into_kind(self) -> ShortenerError {
+        self.kind
+    }
+}
+
+impl From<ShortenerError> for Error {
+    fn from(kind: ShortenerError) -> Self {
+        Self {
+            kind,
+            slug: None,
+            url: None,
+            suggestions: Vec::new(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.kind)?;
+        if let Some(slug) = &self.slug {
+            write!(f, " (slug `{}`)", slug.0)?;
+        }
+        if let Some(url) = &self.url {
+            write!(f, " (URL `{}`)", url.0)?;
+        }
+        if !self.suggestions.is_empty() {
+            let suggestions: Vec<&str> =
+                self.suggestions.iter().map(|slug| slug.0.as_str()).collect();
+            write!(f, " (available: `{}`)", suggestions.join("`, `"))?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Attaches context to results of the service, turning their
+/// [`ShortenerError`] into an [`Error`].
+pub trait ErrorContext<T> {
+    /// Records the [`Slug`] the error occurred for.
+    fn with_slug(self, slug: &Slug) -> Result<T, Error>;
+
+    /// Records the [`Url`] the error occurred for.
+    fn with_url(self, url: &Url) -> Result<T, Error>;
+}
+
+impl<T, E: Into<Error>> ErrorContext<T> for Result<T, E> {
+    fn with_slug(self, slug: &Slug) -> Result<T, Error> {
+        self.map_err(|e| Error {
+            slug: Some(slug.clone()),
+            ..e.into()
+        })
+    }
+
+    fn with_url(self, url: &Url) -> Result<T, Error> {
+        self.map_err(|e| Error {
+            url: Some(url.clone()),
+            ..e.into()
+        })
+    }
+}
+
+/// A unique string (or alias) that represents the shortened version of the
+/// URL.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "http", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "rkyv", derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize))]
+pub struct Slug(pub String);
+
+/// Identifier of the owner (user or tenant) of short links.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "rkyv", derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize))]
+pub struct OwnerId(pub String);
+
+/// The original URL that the short link points to.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "http", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "rkyv", derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize))]
+pub struct Url(pub String);
+
+/// Shortened URL representation.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "http", derive(utoipa::ToSchema))]
+pub struct ShortLink {
+    /// A unique string (or alias) that represents the shortened version of the
+    /// URL.
+    pub slug: Slug,
+
+    /// The original URL that the short link points to.
+    pub url: Url,
+}
+
+/// Optional settings of a newly created [`ShortLink`].
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LinkOptions {
+    /// Maximum number of redirects after which the link deactivates itself.
+    pub max_clicks: Option<u64>,
+
+    /// Key of the caller the creation is rate limited by, see
+    /// [`RateLimitPolicy`]. Callers without a key share a single limit.
+    pub rate_limit_key: Option<String>,
+
+    /// Owner of the link, see [`OwnedLinkHandler`].
+    ///
+    /// [`OwnedLinkHandler`]: commands::OwnedLinkHandler
+    pub owner: Option<OwnerId>,
+
+    /// Moment the link starts redirecting at, immediately if [`None`].
+    pub active_from: Option<SystemTime>,
+
+    /// Moment the link stops redirecting at, never if [`None`].
+    pub active_until: Option<SystemTime>,
+
+    /// URL redirected to outside of the activation window instead of failing
+    /// with [`ShortenerError::LinkNotActive`].
+    pub fallback_url: Option<Url>,
+}
+
+/// Details of the request a redirect was made for.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "rkyv", derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize))]
+pub struct ClickContext {
+    /// Page the visitor came from, i.e. the `Referer` header.
+    pub referrer: Option<String>,
+
+    /// Client of the visitor, i.e. the `User-Agent` header.
+    pub user_agent: Option<String>,
+
+    /// IP address of the visitor.
+    pub ip: Option<IpAddr>,
+
+    /// ISO 3166-1 alpha-2 code of the country of the visitor, e.g. `DE`,
+    /// as resolved by the caller.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub country: Option<String>,
+}
+
+/// How redirects of a [`ShortLink`] are issued.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "rkyv", derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize))]
+pub struct RedirectPolicy {
+    /// Whether redirects are permanent (`301 Moved Permanently`) instead of
+    /// temporary (`302 Found`). Browsers cache permanent redirects, so
+    /// repeated visits may not reach the service and are not counted.
+    pub permanent: bool,
+
+    /// Whether the query string of the incoming request is appended to the
+    /// query of the destination.
+    pub preserve_query: bool,
+
+    /// Whether the fragment of the incoming request replaces the fragment of
+    /// the destination.
+    pub preserve_fragment: bool,
+}
+
+/// Outcome of [`RedirectHandler::resolve_redirect()`], telling an HTTP layer
+/// where and how to redirect.
+///
+/// [`RedirectHandler::resolve_redirect()`]: commands::RedirectHandler::resolve_redirect
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RedirectDecision {
+    /// Current [`Slug`] of the redirected link.
+    pub slug: Slug,
+
+    /// URL to redirect to, i.e. the `Location` header.
+    pub location: Url,
+
+    /// Whether the redirect is permanent.
+    pub permanent: bool,
+
+    /// Why the destination was flagged by the [`SafetyChecker`], if it was.
+    /// Visitors should then be shown an interstitial warning page linking
+    /// to the `location` instead of being redirected.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub warning: Option<String>,
+}
+
+impl RedirectDecision {
+    /// HTTP status code of the redirect, `301` if permanent and `302`
+    /// otherwise.
+    pub fn status_code(&self) -> u16 {
+        if self.permanent {
+            301
+        } else {
+            302
+        }
+    }
+}
+
+/// Kind of device of a visitor, detected from its user agent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "rkyv", derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize))]
+pub enum Device {
+    /// Phone or tablet.
+    Mobile,
+
+    /// Any other device.
+    Desktop,
+}
+
+impl Device {
+    /// Detects the device from the `User-Agent` header.
+    pub fn detect(user_agent: &str) -> Self {
+        const MOBILE_MARKERS: &[&str] =
+            &["mobi", "android", "iphone", "ipad", "ipod", "windows phone"];
+        let user_agent = user_agent.to_ascii_lowercase();
+        if MOBILE_MARKERS.iter().any(|marker| user_agent.contains(marker)) {
+            Device::Mobile
+        } else {
+            Device::Desktop
+        }
+    }
+}
+
+impl ClickContext {
+    //stable hash identifying the visitor by ip and user agent, if any is known
+    fn visitor_hash(&self) -> Option<u64> {
+        if self.ip.is_none() && self.user_agent.is_none() {
+            return None;
+        }
+        let mut hasher = Sha256::new();
+        if let Some(ip) = &self.ip {
+            hasher.update(ip.to_string());
+        }
+        hasher.update([0]);
+        if let Some(user_agent) = &self.user_agent {
+            hasher.update(user_agent);
+        }
+        let hash = hasher.finalize();
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&hash[..8]);
+        Some(u64::from_be_bytes(bytes))
+    }
+}
+
+/// [`Stats`] of the [`ShortLink`] extended with analytics.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DetailedStats {
+    /// Basic [`Stats`] of the [`ShortLink`].
+    pub stats: Stats,
+
+    /// Count of distinct visitors of the [`ShortLink`], identified by the IP
+    /// address and user agent of their [`ClickContext`]. Estimated with
+    /// HyperLogLog (about 3% error) unless the `exact-visitors` feature is
+    /// enabled.
+    pub unique_visitors: u64,
+
+    /// Count of redirects of crawlers and other bots, recognized by the
+    /// [`ClickFilter`] of the service. They are not included in the
+    /// redirects of the [`Stats`].
+    ///
+    /// [`ClickFilter`]: filtering::ClickFilter
+    pub bot_redirects: u64,
+
+    /// Moment the [`ShortLink`] was created at, if known.
+    pub created_at: Option<SystemTime>,
+
+    /// Moment of the last redirect of the [`ShortLink`], if any.
+    pub last_accessed_at: Option<SystemTime>,
+
+    /// Count of changes of the original URL of the [`ShortLink`],
+    /// reverts included.
+    pub url_change_count: u64,
+}
+
+/// Metadata of the destination page of a [`ShortLink`], e.g. for link
+/// previews.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "rkyv", derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize))]
+pub struct LinkMetadata {
+    /// Title of the page.
+    pub title: Option<String>,
+
+    /// Description of the page.
+    pub description: Option<String>,
+
+    /// Absolute URL of the icon of the page.
+    pub favicon: Option<Url>,
+}
+
+/// Details of a [`ShortLink`] for preview UIs.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LinkDetails {
+    /// Basic [`Stats`] of the [`ShortLink`].
+    pub stats: Stats,
+
+    /// Full short URL of the [`ShortLink`], see
+    /// [`UrlShortenerService::short_url()`].
+    pub short_url: Url,
+
+    /// Full short URLs of the [`ShortLink`] under the alternate base URLs.
+    pub alternate_short_urls: Vec<Url>,
+
+    /// Moment the [`ShortLink`] was created at, if known.
+    pub created_at: Option<SystemTime>,
+
+    /// Metadata of the destination page, if fetched since the destination
+    /// was last changed.
+    pub metadata: Option<LinkMetadata>,
+
+    /// Why the destination was flagged by the [`SafetyChecker`], if it was
+    /// since it was last changed.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub flagged: Option<String>,
+}
+
+/// Unique visitors of a [`ShortLink`] captured in a [`LinkSnapshot`].
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum VisitorsSnapshot {
+    /// HyperLogLog registers, empty if there were no visitors.
+    Estimated(Vec<u8>),
+
+    /// Hashes of all the visitors.
+    Exact(Vec<u64>),
+}
+
+impl Default for VisitorsSnapshot {
+    fn default() -> Self {
+        VisitorsSnapshot::Estimated(Vec::new())
+    }
+}
+
+/// Statistics of the [`ShortLink`].
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "http", derive(utoipa::ToSchema))]
+pub struct Stats {
+    /// [`ShortLink`] to which this [`Stats`] are related.
+    pub link: ShortLink,
+
+    /// Count of redirects of the [`ShortLink`].
+    pub redirects: u64,
+}
+
+/// [`ShortLink`] whose destination was found unhealthy, e.g. responding with
+/// `404 Not Found`, by the last check.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BrokenLink {
+    /// The broken [`ShortLink`].
+    pub link: ShortLink,
+
+    /// Why the destination is unhealthy, e.g. the status of the response.
+    pub reason: String,
+
+    /// Moment the destination was found unhealthy at.
+    pub since: SystemTime,
+}
+
+/// A named group of [`ShortLink`]s tracked as one unit, e.g. the links of a
+/// marketing campaign.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Campaign {
+    /// Identifier of the campaign, following the same rules as custom
+    /// [`Slug`]s.
+    pub id: Slug,
+
+    /// Display name of the campaign.
+    pub name: String,
+
+    /// [`Slug`]s of the links in the campaign, in alphabetical order.
+    pub links: Vec<Slug>,
+}
+
+/// Statistics of a [`Campaign`], aggregated over its links.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CampaignStats {
+    /// Identifier of the [`Campaign`].
+    pub id: Slug,
+
+    /// Display name of the [`Campaign`].
+    pub name: String,
+
+    /// Count of redirects of all links in the [`Campaign`].
+    pub redirects: u64,
+
+    /// [`Stats`] of every link in the [`Campaign`], most redirected first.
+    /// Links with the same number of redirects are ordered by their
+    /// [`Slug`].
+    pub links: Vec<Stats>,
+}
+
+/// Service-wide statistics, counted over the whole event log.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct GlobalStats {
+    /// Count of created [`ShortLink`]s, including deleted ones.
+    pub links_created: u64,
+
+    /// Count of redirects served by all [`ShortLink`]s.
+    pub redirects: u64,
+
+    /// Count of changes of the original URLs.
+    pub url_changes: u64,
+
+    /// Count of events recorded in the event log, including the ones folded
+    /// by compaction.
+    pub events: u64,
+}
+
+/// Length of the time buckets redirects are grouped into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Interval {
+    /// Hours, in UTC.
+    Hour,
+
+    /// Days, starting at midnight UTC.
+    Day,
+
+    /// Weeks, starting on Monday at midnight UTC.
+    Week,
+}
+
+impl Interval {
+    //length of the bucket in hours
+    fn hours(self) -> u64 {
+        match self {
+            Interval::Hour => 1,
+            Interval::Day => 24,
+            Interval::Week => 7 * 24,
+        }
+    }
+
+    //first hour of the bucket the given hour falls into, the unix epoch was
+    //on thursday so weeks are shifted by 3 days
+    fn bucket_start(self, hour: u64) -> u64 {
+        let offset = match self {
+            Interval::Week => 3 * 24,
+            Interval::Hour | Interval::Day => 0,
+        };
+        hour.saturating_sub((hour + offset) % self.hours())
+    }
+}
+
+/// Redirects of a [`ShortLink`] to one of its weighted destinations.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct VariantStats {
+    /// URL of the destination.
+    pub url: Url,
+
+    /// Weight the destination is chosen with.
+    pub weight: u32,
+
+    /// Count of redirects to the destination.
+    pub redirects: u64,
+}
+
+/// Redirects of a [`ShortLink`] within a single time bucket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TimeBucket {
+    /// Start of the bucket.
+    pub start: SystemTime,
+
+    /// Count of redirects within the bucket.
+    pub redirects: u64,
+}
+
+/// Calendar date in UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Date {
+    /// Year, e.g. `2024`.
+    pub year: u32,
+
+    /// Month, from `1` to `12`.
+    pub month: u32,
+
+    /// Day of the month, from `1`.
+    pub day: u32,
+}
+
+impl Date {
+    /// Returns the date of the given moment in UTC.
+    pub fn of(time: SystemTime) -> Self {
+        Self::from_days(hour_of(time) / 24)
+    }
+
+    //date of the given day since the unix epoch, see
+    //http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    fn from_days(days: u64) -> Self {
+        let z = days + 719_468;
+        let era = z / 146_097;
+        let doe = z % 146_097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + u64::from(month <= 2);
+        Self {
+            year: year as u32,
+            month: month as u32,
+            day: day as u32,
+        }
+    }
+
+    //days since the unix epoch, 0 for dates before it, see
+    //http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+    fn days(self) -> u64 {
+        let year = i64::from(self.year) - i64::from(self.month <= 2);
+        let era = year.div_euclid(400);
+        let yoe = year - era * 400;
+        let mp = (i64::from(self.month) + 9) % 12;
+        let doy = (153 * mp + 2) / 5 + i64::from(self.day) - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        u64::try_from(era * 146_097 + doe - 719_468).unwrap_or(0)
+    }
+}
+
+impl fmt::Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+/// Point of the event log history queries are answered at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum PointInTime {
+    /// Including all the events recorded at or before the given moment.
+    Time(SystemTime),
+
+    /// Including all the events up to the given
+    /// [`EventEnvelope::sequence`].
+    Sequence(u64),
+}
+
+impl PointInTime {
+    //whether the event happened at or before this point
+    fn includes(&self, envelope: &EventEnvelope) -> bool {
+        match self {
+            PointInTime::Time(time) => envelope.occurred_at <= *time,
+            PointInTime::Sequence(sequence) => envelope.sequence <= *sequence,
+        }
+    }
+}
+
+/// Format of [`UrlShortenerService::export_stats()`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum ExportFormat {
+    /// Comma separated values with a header row.
+    Csv,
+
+    /// JSON array of objects.
+    Json,
+}
+
+/// Image format of [`UrlShortenerService::generate_qr()`].
+#[cfg(feature = "qr")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum QrFormat {
+    /// Grayscale PNG image.
+    Png,
+
+    /// SVG document.
+    Svg,
+}
+
+/// Commands for CQRS.
+pub mod commands {
+    use super::{
+        random_uuid, Campaign, ClickContext, Device, EventEnvelope, LinkOptions, OwnerId,
+        RedirectDecision, RedirectPolicy, ShortLink, ShortenerError, Slug, Url, Uuid,
+    };
+
+    /// Trait for command handlers.
+    pub trait CommandHandler {
+        /// Creates a new short link. It accepts the original url and an
+        /// optional [`Slug`]. If a [`Slug`] is not provided, the service will generate
+        /// one. Returns the newly created [`ShortLink`].
+        ///
+        /// ## Errors
+        ///
+        /// See [`ShortenerError`].
+        fn handle_create_short_link(
+            &mut self,
+            url: Url,
+            slug: Option<Slug>,
+        ) -> Result<ShortLink, ShortenerError>;
+
+        /// Processes a redirection by [`Slug`], returning the associated
+        /// [`ShortLink`] or a [`ShortenerError`].
+        fn handle_redirect(
+            &mut self,
+            slug: Slug,
+        ) -> Result<ShortLink, ShortenerError>;
+        
+        fn handle_change_short_link(
+        &mut self,
+        slug: Slug,
+        new_url: Url
+    ) -> Result<ShortLink, ShortenerError>;
+    }
+
+    /// Trait for command handlers with optimistic concurrency control.
+    ///
+    /// Every command accepts an optional version the link event stream is
+    /// expected to be at. If the link was modified in the meantime, the command
+    /// is rejected with [`ShortenerError::VersionConflict`]. Links which do not
+    /// exist yet are at version `0`.
+    pub trait CommandHandlerV2 {
+        /// Same as [`CommandHandler::handle_create_short_link()`], checking
+        /// the version of the provided [`Slug`] first.
+        fn handle_create_short_link_versioned(
+            &mut self,
+            url: Url,
+            slug: Option<Slug>,
+            expected_version: Option<u64>,
+        ) -> Result<ShortLink, ShortenerError>;
+
+        /// Same as [`CommandHandler::handle_redirect()`], checking the version
+        /// of the link first.
+        fn handle_redirect_versioned(
+            &mut self,
+            slug: Slug,
+            expected_version: Option<u64>,
+        ) -> Result<ShortLink, ShortenerError>;
+
+        /// Same as [`CommandHandler::handle_change_short_link()`], checking
+        /// the version of the link first.
+        fn handle_change_short_link_versioned(
+            &mut self,
+            slug: Slug,
+            new_url: Url,
+            expected_version: Option<u64>,
+        ) -> Result<ShortLink, ShortenerError>;
+    }
+
+    /// Trait for command handlers recording details of redirects.
+    pub trait RedirectHandler {
+        /// Same as [`CommandHandler::handle_redirect()`], recording the given
+        /// [`ClickContext`] together with the redirect.
+        fn handle_redirect_with_context(
+            &mut self,
+            slug: Slug,
+            context: ClickContext,
+        ) -> Result<ShortLink, ShortenerError>;
+
+        /// Same as [`RedirectHandler::handle_redirect_with_context()`],
+        /// applying the [`RedirectPolicy`] of the link to the query string
+        /// and fragment of the incoming request (both without their leading
+        /// `?` and `#`).
+        fn resolve_redirect(
+            &mut self,
+            slug: Slug,
+            context: ClickContext,
+            query: Option<&str>,
+            fragment: Option<&str>,
+        ) -> Result<RedirectDecision, ShortenerError>;
+    }
+
+    /// Trait for command handlers of links owned by an [`OwnerId`], so
+    /// multiple users can share one service without modifying each other's
+    /// links.
+    ///
+    /// Links can only be changed or deleted here by their owner. Commands of
+    /// the other handler traits don't check the owner and are meant for
+    /// trusted callers only.
+    pub trait OwnedLinkHandler {
+        /// Same as [`CommandHandler::handle_create_short_link()`], creating
+        /// the link owned by the given [`OwnerId`].
+        fn handle_create_owned_link(
+            &mut self,
+            owner: OwnerId,
+            url: Url,
+            slug: Option<Slug>,
+        ) -> Result<ShortLink, ShortenerError>;
+
+        /// Same as [`CommandHandler::handle_change_short_link()`], failing
+        /// with [`ShortenerError::NotOwner`] if the link is not owned by the
+        /// given [`OwnerId`].
+        fn handle_change_owned_link(
+            &mut self,
+            owner: OwnerId,
+            slug: Slug,
+            new_url: Url,
+        ) -> Result<ShortLink, ShortenerError>;
+
+        /// Same as [`LinkManagementHandler::handle_delete_short_link()`],
+        /// failing with [`ShortenerError::NotOwner`] if the link is not owned
+        /// by the given [`OwnerId`].
+        fn handle_delete_owned_link(
+            &mut self,
+            owner: OwnerId,
+            slug: Slug,
+        ) -> Result<ShortLink, ShortenerError>;
+    }
+
+    /// Trait for command handlers managing the lifecycle of short links.
+    pub trait LinkManagementHandler {
+        /// Same as [`CommandHandler::handle_create_short_link()`], creating
+        /// the link with the given [`LinkOptions`].
+        fn handle_create_short_link_with_options(
+            &mut self,
+            url: Url,
+            slug: Option<Slug>,
+            options: LinkOptions,
+        ) -> Result<ShortLink, ShortenerError>;
+
+        /// Deletes the link of the given [`Slug`], returning the deleted
+        /// [`ShortLink`]. The history of the link is kept in the event log,
+        /// but the link is no longer redirected to nor listed.
+        ///
+        /// ## Errors
+        ///
+        /// See [`ShortenerError`].
+        fn handle_delete_short_link(
+            &mut self,
+            slug: Slug,
+        ) -> Result<ShortLink, ShortenerError>;
+
+        /// Disables the link of the given [`Slug`], so it stops redirecting
+        /// while keeping its [`Stats`]. Disabling a disabled link does
+        /// nothing.
+        ///
+        /// [`Stats`]: super::Stats
+        fn handle_disable_link(
+            &mut self,
+            slug: Slug,
+        ) -> Result<ShortLink, ShortenerError>;
+
+        /// Enables the previously disabled link of the given [`Slug`].
+        /// Enabling an enabled link does nothing.
+        fn handle_enable_link(
+            &mut self,
+            slug: Slug,
+        ) -> Result<ShortLink, ShortenerError>;
+
+        /// Renames the [`Slug`] of the link, carrying its [`Stats`] over to
+        /// the new [`Slug`]. Depending on [`ServiceConfig::keep_renamed_slugs`]
+        /// the old [`Slug`] keeps forwarding to the link or is released.
+        ///
+        /// [`Stats`]: super::Stats
+        /// [`ServiceConfig::keep_renamed_slugs`]: super::ServiceConfig::keep_renamed_slugs
+        fn handle_rename_slug(
+            &mut self,
+            old: Slug,
+            new: Slug,
+        ) -> Result<ShortLink, ShortenerError>;
+
+        /// Attaches another [`Slug`] to the link, e.g. a branded one next to
+        /// the generated one. Redirects of the alias count towards the
+        /// [`Stats`] of the link, which keeps its own [`Slug`]. Attaching an
+        /// alias the link already has does nothing.
+        ///
+        /// ## Errors
+        ///
+        /// Returns [`ShortenerError::SlugAlreadyInUse`] if the alias is used
+        /// by another link, or the errors of custom [`Slug`]s when creating
+        /// links.
+        ///
+        /// [`Stats`]: super::Stats
+        fn handle_add_alias(
+            &mut self,
+            slug: Slug,
+            alias: Slug,
+        ) -> Result<ShortLink, ShortenerError>;
+
+        /// Reverts the last change of the original URL of the link by
+        /// recording a compensating URL change back to the previous URL. The
+        /// revert is a URL change itself, so reverting twice restores the URL
+        /// the first revert replaced.
+        ///
+        /// ## Errors
+        ///
+        /// Returns [`ShortenerError::NothingToRevert`] if the URL of the link
+        /// was never changed.
+        fn handle_revert_url_change(
+            &mut self,
+            slug: Slug,
+        ) -> Result<ShortLink, ShortenerError>;
+
+        /// Splits redirects of the link between the weighted destinations,
+        /// e.g. for A/B tests. Every redirect picks one of them at random,
+        /// proportionally to its weight, instead of the URL of the link. An
+        /// empty list restores redirects to the URL of the link.
+        ///
+        /// ## Errors
+        ///
+        /// Returns [`ShortenerError::InvalidUrl`] if any of the URLs is
+        /// invalid, or [`ShortenerError::InvalidWeights`] if there are
+        /// destinations but none of them has a positive weight.
+        fn handle_set_destinations(
+            &mut self,
+            slug: Slug,
+            destinations: Vec<(Url, u32)>,
+        ) -> Result<ShortLink, ShortenerError>;
+
+        /// Redirects visitors from the given countries, identified by the
+        /// country code of their [`ClickContext`], to the paired URLs. Other
+        /// visitors are redirected as before, so the URL of the link (or its
+        /// weighted destinations) is the default. An empty list removes all
+        /// rules.
+        ///
+        /// ## Errors
+        ///
+        /// Returns [`ShortenerError::InvalidCountry`] if a country code is
+        /// not two letters, or [`ShortenerError::InvalidUrl`] if any of the
+        /// URLs is invalid.
+        fn handle_set_geo_rules(
+            &mut self,
+            slug: Slug,
+            rules: Vec<(String, Url)>,
+        ) -> Result<ShortLink, ShortenerError>;
+
+        /// Redirects visitors on the given [`Device`], detected from the user
+        /// agent of their [`ClickContext`], to the URL, e.g. an app deep link
+        /// for mobile visitors. [`None`] removes the rule of the device.
+        /// Country rules take precedence over device rules, which take
+        /// precedence over weighted destinations.
+        ///
+        /// ## Errors
+        ///
+        /// Returns [`ShortenerError::InvalidUrl`] if the URL is invalid.
+        fn handle_set_device_rule(
+            &mut self,
+            slug: Slug,
+            device: Device,
+            url: Option<Url>,
+        ) -> Result<ShortLink, ShortenerError>;
+
+        /// Sets the [`RedirectPolicy`] the redirects of the link are
+        /// resolved with by [`RedirectHandler::resolve_redirect()`].
+        fn handle_set_redirect_policy(
+            &mut self,
+            slug: Slug,
+            policy: RedirectPolicy,
+        ) -> Result<ShortLink, ShortenerError>;
+    }
+
+    /// Trait for command handlers grouping links into [`Campaign`]s.
+    pub trait CampaignHandler {
+        /// Creates an empty [`Campaign`] with the given identifier and
+        /// display name.
+        ///
+        /// ## Errors
+        ///
+        /// Returns [`ShortenerError::InvalidSlug`] if the identifier breaks
+        /// the [`SlugPolicy`], or [`ShortenerError::CampaignAlreadyExists`]
+        /// if it is used by another [`Campaign`].
+        ///
+        /// [`SlugPolicy`]: super::validation::SlugPolicy
+        fn handle_create_campaign(
+            &mut self,
+            campaign: Slug,
+            name: String,
+        ) -> Result<Campaign, ShortenerError>;
+
+        /// Adds the link to the [`Campaign`], moving it out of the campaign it
+        /// was in. A link is in at most one campaign.
+        ///
+        /// ## Errors
+        ///
+        /// Returns [`ShortenerError::SlugNotFound`] if there is no such link,
+        /// or [`ShortenerError::CampaignNotFound`] if there is no such
+        /// [`Campaign`].
+        fn handle_add_to_campaign(
+            &mut self,
+            slug: Slug,
+            campaign: Slug,
+        ) -> Result<ShortLink, ShortenerError>;
+
+        /// Removes the link from its [`Campaign`]. Removing a link which is
+        /// not in any campaign does nothing.
+        fn handle_remove_from_campaign(
+            &mut self,
+            slug: Slug,
+        ) -> Result<ShortLink, ShortenerError>;
+    }
+
+    /// Trait for command handlers attaching free-form tags to links.
+    pub trait TagHandler {
+        /// Attaches the tag to the link. Surrounding whitespace of the tag is
+        /// removed and attaching a tag the link already has does nothing.
+        ///
+        /// ## Errors
+        ///
+        /// Returns [`ShortenerError::SlugNotFound`] if there is no such link,
+        /// or [`ShortenerError::InvalidTag`] if the tag is blank or too long.
+        fn handle_tag_link(
+            &mut self,
+            slug: Slug,
+            tag: String,
+        ) -> Result<ShortLink, ShortenerError>;
+
+        /// Detaches the tag from the link. Detaching a tag the link does not
+        /// have does nothing.
+        ///
+        /// ## Errors
+        ///
+        /// Returns [`ShortenerError::SlugNotFound`] if there is no such link.
+        fn handle_untag_link(
+            &mut self,
+            slug: Slug,
+            tag: String,
+        ) -> Result<ShortLink, ShortenerError>;
+    }
+
+    /// Command of the handler traits as a value, so it can be queued, logged,
+    /// sent over the wire or replayed, and executed later with
+    /// [`Command::execute()`].
+    #[derive(Debug, Clone, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub enum Command {
+        /// See [`CommandHandler::handle_create_short_link()`].
+        CreateShortLink { url: Url, slug: Option<Slug> },
+
+        /// See [`CommandHandler::handle_redirect()`].
+        Redirect { slug: Slug },
+
+        /// See [`CommandHandler::handle_change_short_link()`].
+        ChangeShortLink { slug: Slug, new_url: Url },
+
+        /// See [`RedirectHandler::handle_redirect_with_context()`].
+        RedirectWithContext { slug: Slug, context: ClickContext },
+
+        /// See [`OwnedLinkHandler::handle_create_owned_link()`].
+        CreateOwnedLink { owner: OwnerId, url: Url, slug: Option<Slug> },
+
+        /// See [`OwnedLinkHandler::handle_change_owned_link()`].
+        ChangeOwnedLink { owner: OwnerId, slug: Slug, new_url: Url },
+
+        /// See [`OwnedLinkHandler::handle_delete_owned_link()`].
+        DeleteOwnedLink { owner: OwnerId, slug: Slug },
+
+        /// See [`LinkManagementHandler::handle_create_short_link_with_options()`].
+        CreateShortLinkWithOptions { url: Url, slug: Option<Slug>, options: LinkOptions },
+
+        /// See [`LinkManagementHandler::handle_delete_short_link()`].
+        DeleteShortLink { slug: Slug },
+
+        /// See [`LinkManagementHandler::handle_disable_link()`].
+        DisableLink { slug: Slug },
+
+        /// See [`LinkManagementHandler::handle_enable_link()`].
+        EnableLink { slug: Slug },
+
+        /// See [`LinkManagementHandler::handle_rename_slug()`].
+        RenameSlug { old: Slug, new: Slug },
+
+        /// See [`LinkManagementHandler::handle_add_alias()`].
+        AddAlias { slug: Slug, alias: Slug },
+
+        /// See [`LinkManagementHandler::handle_revert_url_change()`].
+        RevertUrlChange { slug: Slug },
+
+        /// See [`LinkManagementHandler::handle_set_destinations()`].
+        SetDestinations { slug: Slug, destinations: Vec<(Url, u32)> },
+
+        /// See [`LinkManagementHandler::handle_set_geo_rules()`].
+        SetGeoRules { slug: Slug, rules: Vec<(String, Url)> },
+
+        /// See [`LinkManagementHandler::handle_set_device_rule()`].
+        SetDeviceRule { slug: Slug, device: Device, url: Option<Url> },
+
+        /// See [`LinkManagementHandler::handle_set_redirect_policy()`].
+        SetRedirectPolicy { slug: Slug, policy: RedirectPolicy },
+
+        /// See [`CampaignHandler::handle_create_campaign()`].
+        CreateCampaign { campaign: Slug, name: String },
+
+        /// See [`CampaignHandler::handle_add_to_campaign()`].
+        AddToCampaign { slug: Slug, campaign: Slug },
+
+        /// See [`CampaignHandler::handle_remove_from_campaign()`].
+        RemoveFromCampaign { slug: Slug },
+
+        /// See [`TagHandler::handle_tag_link()`].
+        TagLink { slug: Slug, tag: String },
+
+        /// See [`TagHandler::handle_untag_link()`].
+        UntagLink { slug: Slug, tag: String },
+    }
+
+    /// [`Command`] together with the identifiers tracing it, executed with
+    /// [`UrlShortenerService::execute()`].
+    ///
+    /// [`UrlShortenerService::execute()`]: super::UrlShortenerService::execute
+    #[derive(Debug, Clone, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub struct CommandEnvelope {
+        /// Unique identifier of the command, the causation id of the events
+        /// it records.
+        pub id: Uuid,
+
+        /// Identifier of the workflow the command is a part of, [`None`] if
+        /// the command starts one.
+        pub correlation_id: Option<Uuid>,
+
+        /// The [`Command`] itself.
+        pub command: Command,
+    }
+
+    impl CommandEnvelope {
+        /// Wraps the [`Command`] into a new envelope with a random id,
+        /// starting a new workflow.
+        pub fn new(command: Command) -> Self {
+            Self {
+                id: random_uuid(),
+                correlation_id: None,
+                command,
+            }
+        }
+
+        /// Same as [`CommandEnvelope::new()`], continuing the workflow the
+        /// given event is a part of.
+        pub fn caused_by(command: Command, cause: &EventEnvelope) -> Self {
+            Self {
+                correlation_id: Some(cause.correlation_id.unwrap_or(cause.id)),
+                ..Self::new(command)
+            }
+        }
+    }
+
+    /// [`Command`]s committed together with
+    /// [`UrlShortenerService::commit_unit()`], e.g. creating related links and
+    /// adding them to a [`Campaign`]: either all of them succeed and their
+    /// events are committed as one batch, or none of their events is stored.
+    ///
+    /// The commands are a workflow of their own, correlated by the id of the
+    /// unit.
+    ///
+    /// [`UrlShortenerService::commit_unit()`]: super::UrlShortenerService::commit_unit
+    #[derive(Debug, Clone, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub struct UnitOfWork {
+        id: Uuid,
+        commands: Vec<CommandEnvelope>,
+    }
+
+    impl Default for UnitOfWork {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl UnitOfWork {
+        /// Creates an empty unit with a random id.
+        pub fn new() -> Self {
+            Self {
+                id: random_uuid(),
+                commands: Vec::new(),
+            }
+        }
+
+        /// Adds the [`Command`] after the ones added so far.
+        pub fn with(mut self, command: Command) -> Self {
+            self.commands.push(CommandEnvelope {
+                correlation_id: Some(self.id),
+                ..CommandEnvelope::new(command)
+            });
+            self
+        }
+
+        /// Identifier of the unit, the correlation id of its commands.
+        pub fn id(&self) -> Uuid {
+            self.id
+        }
+
+        /// Commands of the unit in the order they are executed.
+        pub fn commands(&self) -> &[CommandEnvelope] {
+            &self.commands
+        }
+    }
+
+    impl Command {
+        /// [`Slug`] of the link the command targets, [`None`] for commands
+        /// creating a link without a custom slug or targeting a campaign.
+        /// The current slug for [`Command::RenameSlug`].
+        pub fn slug(&self) -> Option<&Slug> {
+            match self {
+                Command::CreateShortLink { slug, .. }
+                | Command::CreateOwnedLink { slug, .. }
+                | Command::CreateShortLinkWithOptions { slug, .. } => slug.as_ref(),
+                Command::Redirect { slug }
+                | Command::ChangeShortLink { slug, .. }
+                | Command::RedirectWithContext { slug, .. }
+                | Command::ChangeOwnedLink { slug, .. }
+                | Command::DeleteOwnedLink { slug, .. }
+                | Command::DeleteShortLink { slug }
+                | Command::DisableLink { slug }
+                | Command::EnableLink { slug }
+                | Command::RenameSlug { old: slug, .. }
+                | Command::AddAlias { slug, .. }
+                | Command::RevertUrlChange { slug }
+                | Command::SetDestinations { slug, .. }
+                | Command::SetGeoRules { slug, .. }
+                | Command::SetDeviceRule { slug, .. }
+                | Command::SetRedirectPolicy { slug, .. }
+                | Command::AddToCampaign { slug, .. }
+                | Command::RemoveFromCampaign { slug }
+                | Command::TagLink { slug, .. }
+                | Command::UntagLink { slug, .. } => Some(slug),
+                Command::CreateCampaign { .. } => None,
+            }
+        }
+    }
+
+    /// Result of a successfully executed [`Command`].
+    #[derive(Debug, Clone, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub enum CommandOutput {
+        /// The created or affected [`ShortLink`].
+        Link(ShortLink),
+
+        /// The created [`Campaign`].
+        Campaign(Campaign),
+    }
+
+    impl Command {
+        /// Executes the command with the matching method of the handler.
+        ///
+        /// ## Errors
+        ///
+        /// Returns the [`ShortenerError`] of the executed method.
+        pub fn execute<H>(self, handler: &mut H) -> Result<CommandOutput, ShortenerError>
+        where
+            H: CommandHandler
+                + RedirectHandler
+                + OwnedLinkHandler
+                + LinkManagementHandler
+                + CampaignHandler
+                + TagHandler,
+        {
+            let link = match self {
+                Command::CreateShortLink { url, slug } => {
+                    handler.handle_create_short_link(url, slug)
+                }
+                Command::Redirect { slug } => handler.handle_redirect(slug),
+                Command::ChangeShortLink { slug, new_url } => {
+                    handler.handle_change_short_link(slug, new_url)
+                }
+                Command::RedirectWithContext { slug, context } => {
+                    handler.handle_redirect_with_context(slug, context)
+                }
+                Command::CreateOwnedLink { owner, url, slug } => {
+                    handler.handle_create_owned_link(owner, url, slug)
+                }
+                Command::ChangeOwnedLink { owner, slug, new_url } => {
+                    handler.handle_change_owned_link(owner, slug, new_url)
+                }
+                Command::DeleteOwnedLink { owner, slug } => {
+                    handler.handle_delete_owned_link(owner, slug)
+                }
+                Command::CreateShortLinkWithOptions { url, slug, options } => {
+                    handler.handle_create_short_link_with_options(url, slug, options)
+                }
+                Command::DeleteShortLink { slug } => handler.handle_delete_short_link(slug),
+                Command::DisableLink { slug } => handler.handle_disable_link(slug),
+                Command::EnableLink { slug } => handler.handle_enable_link(slug),
+                Command::RenameSlug { old, new } => handler.handle_rename_slug(old, new),
+                Command::AddAlias { slug, alias } => handler.handle_add_alias(slug, alias),
+                Command::RevertUrlChange { slug } => handler.handle_revert_url_change(slug),
+                Command::SetDestinations { slug, destinations } => {
+                    handler.handle_set_destinations(slug, destinations)
+                }
+                Command::SetGeoRules { slug, rules } => handler.handle_set_geo_rules(slug, rules),
+                Command::SetDeviceRule { slug, device, url } => {
+                    handler.handle_set_device_rule(slug, device, url)
+                }
+                Command::SetRedirectPolicy { slug, policy } => {
+                    handler.handle_set_redirect_policy(slug, policy)
+                }
+                Command::CreateCampaign { campaign, name } => {
+                    return handler
+                        .handle_create_campaign(campaign, name)
+                        .map(CommandOutput::Campaign);
+                }
+                Command::AddToCampaign { slug, campaign } => {
+                    handler.handle_add_to_campaign(slug, campaign)
+                }
+                Command::RemoveFromCampaign { slug } => handler.handle_remove_from_campaign(slug),
+                Command::TagLink { slug, tag } => handler.handle_tag_link(slug, tag),
+                Command::UntagLink { slug, tag } => handler.handle_untag_link(slug, tag),
+            };
+            link.map(CommandOutput::Link)
+        }
+    }
+}
+
+/// Queries for CQRS
+pub mod queries {
+    use super::{
+        BrokenLink, Campaign, CampaignStats, Date, DetailedStats, EventEnvelope, GlobalStats,
+        Interval, LinkDetails, OwnerId, PointInTime, ShortLink, ShortenerError, Slug, Snapshot,
+        Stats, TimeBucket, Url, Uuid, VariantStats,
+    };
+
+    /// Trait for query handlers.
+    pub trait QueryHandler {
+        /// Returns the [`Stats`] for a specific [`ShortLink`], such as the
+        /// number of redirects (clicks).
+        ///
+        /// [`ShortLink`]: super::ShortLink
+        fn get_stats(&self, slug: Slug) -> Result<Stats, ShortenerError>;
+    }
+
+    /// Trait for query handlers looking up short links.
+    pub trait LinkQueryHandler {
+        /// Returns the [`ShortLink`] of the given [`Slug`] without counting a
+        /// redirect, e.g. for previews. Disabled and exhausted links are
+        /// returned too.
+        ///
+        /// ## Errors
+        ///
+        /// Returns [`ShortenerError::SlugNotFound`] if there is no such link.
+        fn get_link(&self, slug: Slug) -> Result<ShortLink, ShortenerError>;
+
+        /// Returns [`LinkDetails`] of the given [`Slug`] without counting a
+        /// redirect, including the metadata of its destination page.
+        ///
+        /// ## Errors
+        ///
+        /// Returns [`ShortenerError::SlugNotFound`] if there is no such link.
+        fn get_link_details(&self, slug: Slug) -> Result<LinkDetails, ShortenerError>;
+
+        /// Returns all [`ShortLink`]s pointing to the given [`Url`], in the
+        /// order they were created. The [`Url`] is normalized the same way as
+        /// when creating links.
+        fn find_by_url(&self, url: Url) -> Vec<ShortLink>;
+
+        /// Returns all [`ShortLink`]s owned by the given [`OwnerId`], ordered
+        /// by [`Slug`].
+        fn list_links_by_owner(&self, owner: OwnerId) -> Vec<ShortLink>;
+
+        /// Returns [`Stats`] of up to `n` most redirected links, in
+        /// descending order of redirects. Links with the same number of
+        /// redirects are ordered by their [`Slug`].
+        fn top_links(&self, n: usize) -> Vec<Stats>;
+
+        /// Returns the service-wide [`GlobalStats`].
+        fn global_stats(&self) -> GlobalStats;
+
+        /// Returns the aliases of the link, i.e. the [`Slug`]s forwarding to
+        /// it besides its own, in alphabetical order. Old [`Slug`]s kept when
+        /// renaming the link are aliases too.
+        ///
+        /// ## Errors
+        ///
+        /// Returns [`ShortenerError::SlugNotFound`] if there is no such link.
+        fn list_aliases(&self, slug: Slug) -> Result<Vec<Slug>, ShortenerError>;
+    }
+
+    /// Trait for query handlers answering from the history of the event log.
+    ///
+    /// Only the events available in the [`EventStore`] are replayed, so
+    /// events of a service restored from a [`Snapshot`] that were recorded
+    /// before the snapshot are not taken into account.
+    ///
+    /// [`EventStore`]: super::store::EventStore
+    /// [`Snapshot`]: super::Snapshot
+    pub trait HistoryQueryHandler {
+        /// Returns the state of the service at the given [`PointInTime`],
+        /// rebuilt from the event log.
+        fn state_at(&self, at: PointInTime) -> Snapshot;
+
+        /// Returns the [`Stats`] the link had at the given [`PointInTime`],
+        /// e.g. how many clicks it had last Monday.
+        ///
+        /// ## Errors
+        ///
+        /// Returns [`ShortenerError::SlugNotFound`] if there was no such link
+        /// at that point.
+        fn stats_at(&self, slug: Slug, at: PointInTime) -> Result<Stats, ShortenerError>;
+
+        /// Returns all events of the link the [`Slug`] currently points to, in
+        /// the order they were recorded, starting with its creation. Events
+        /// recorded under previous [`Slug`]s of a renamed link are included,
+        /// events of earlier deleted links with the same [`Slug`] are not.
+        /// Returns an empty list for an unknown [`Slug`].
+        fn get_history(&self, slug: Slug) -> Vec<EventEnvelope>;
+
+        /// Returns all events of the workflow with the given correlation id,
+        /// in the order they were recorded, including the event which
+        /// started it if its id is the correlation id.
+        fn get_correlated_events(&self, correlation_id: Uuid) -> Vec<EventEnvelope>;
+    }
+
+    /// Trait for query handlers of link analytics.
+    pub trait StatsQueryHandler {
+        /// Returns redirects of the link grouped into buckets of the given
+        /// [`Interval`], oldest first. Buckets without redirects are skipped.
+        ///
+        /// ## Errors
+        ///
+        /// Returns [`ShortenerError::SlugNotFound`] if there is no such link.
+        fn get_stats_over_time(
+            &self,
+            slug: Slug,
+            bucket: Interval,
+        ) -> Result<Vec<TimeBucket>, ShortenerError>;
+
+        /// Returns redirects of the link per day in UTC, from `from` to `to`
+        /// inclusive, oldest first. Days without redirects are skipped.
+        ///
+        /// ## Errors
+        ///
+        /// Returns [`ShortenerError::SlugNotFound`] if there is no such link.
+        fn daily_clicks(
+            &self,
+            slug: Slug,
+            from: Date,
+            to: Date,
+        ) -> Result<Vec<(Date, u64)>, ShortenerError>;
+
+        /// Returns redirects of the link by referrer, most frequent first.
+        /// Only redirects recorded with a [`ClickContext`] containing the
+        /// referrer are counted.
+        ///
+        /// [`ClickContext`]: super::ClickContext
+        ///
+        /// ## Errors
+        ///
+        /// Returns [`ShortenerError::SlugNotFound`] if there is no such link.
+        fn referrer_breakdown(&self, slug: Slug) -> Result<Vec<(String, u64)>, ShortenerError>;
+
+        /// Returns up to `n` domains referring the most redirects to the link,
+        /// paired with their redirects, most frequent first. Domains are the
+        /// lowercase hosts of the referrers without the `www.` prefix, so
+        /// every page of a site counts towards the same domain. Referrers
+        /// which are not absolute URLs are not counted.
+        ///
+        /// ## Errors
+        ///
+        /// Returns [`ShortenerError::SlugNotFound`] if there is no such link.
+        fn top_referrers(
+            &self,
+            slug: Slug,
+            n: usize,
+        ) -> Result<Vec<(String, u64)>, ShortenerError>;
+
+        /// Returns redirects of the link by user agent, most frequent first.
+        /// Only redirects recorded with a [`ClickContext`] containing the user
+        /// agent are counted.
+        ///
+        /// [`ClickContext`]: super::ClickContext
+        ///
+        /// ## Errors
+        ///
+        /// Returns [`ShortenerError::SlugNotFound`] if there is no such link.
+        fn user_agent_breakdown(&self, slug: Slug) -> Result<Vec<(String, u64)>, ShortenerError>;
+
+        /// Returns the [`DetailedStats`] of the link.
+        ///
+        /// ## Errors
+        ///
+        /// Returns [`ShortenerError::SlugNotFound`] if there is no such link.
+        fn get_detailed_stats(&self, slug: Slug) -> Result<DetailedStats, ShortenerError>;
+
+        /// Returns [`VariantStats`] of the current weighted destinations of
+        /// the link, in the order they were set. Redirects are counted since
+        /// the link was created, also while a destination was not set.
+        ///
+        /// ## Errors
+        ///
+        /// Returns [`ShortenerError::SlugNotFound`] if there is no such link.
+        fn get_variant_stats(&self, slug: Slug) -> Result<Vec<VariantStats>, ShortenerError>;
+
+        /// Returns redirects of the link by the country code of the visitor,
+        /// sorted from the most common. Redirects without a known country
+        /// are not included.
+        ///
+        /// ## Errors
+        ///
+        /// Returns [`ShortenerError::SlugNotFound`] if there is no such link.
+        fn country_breakdown(&self, slug: Slug) -> Result<Vec<(String, u64)>, ShortenerError>;
+    }
+
+    /// Trait for query handlers of [`Campaign`]s.
+    pub trait CampaignQueryHandler {
+        /// Returns the [`Campaign`] with the given identifier.
+        ///
+        /// ## Errors
+        ///
+        /// Returns [`ShortenerError::CampaignNotFound`] if there is no such
+        /// [`Campaign`].
+        fn get_campaign(&self, campaign: Slug) -> Result<Campaign, ShortenerError>;
+
+        /// Returns all [`Campaign`]s, ordered by their identifiers.
+        fn list_campaigns(&self) -> Vec<Campaign>;
+
+        /// Returns [`CampaignStats`] of the [`Campaign`]. Deleted links are
+        /// left out.
+        ///
+        /// ## Errors
+        ///
+        /// Returns [`ShortenerError::CampaignNotFound`] if there is no such
+        /// [`Campaign`].
+        fn get_campaign_stats(&self, campaign: Slug) -> Result<CampaignStats, ShortenerError>;
+    }
+
+    /// Trait for query handlers of the health of link destinations.
+    pub trait HealthQueryHandler {
+        /// Returns not deleted links whose destination was unhealthy when last
+        /// checked, ordered by their [`Slug`]s.
+        fn broken_links(&self) -> Vec<BrokenLink>;
+
+        /// Returns every domain destinations were rejected for by the
+        /// [`DomainPolicy`] paired with the number of rejected attempts,
+        /// most attempted first.
+        ///
+        /// [`DomainPolicy`]: super::validation::DomainPolicy
+        fn blocked_domains(&self) -> Vec<(String, u64)>;
+    }
+
+    /// Trait for query handlers of link tags.
+    pub trait TagQueryHandler {
+        /// Returns not deleted links with the given tag, ordered by their
+        /// [`Slug`]s.
+        fn list_links_by_tag(&self, tag: &str) -> Vec<ShortLink>;
+
+        /// Returns every tag in use paired with the number of not deleted
+        /// links having it, most used first. Tags used equally often are
+        /// ordered alphabetically.
+        fn tag_counts(&self) -> Vec<(String, u64)>;
+    }
+
+    /// Query of the handler traits as a value, so it can be queued, logged or
+    /// sent over the wire, and answered later with [`Query::execute()`].
+    #[derive(Debug, Clone, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub enum Query {
+        /// See [`QueryHandler::get_stats()`].
+        GetStats { slug: Slug },
+
+        /// See [`LinkQueryHandler::get_link()`].
+        GetLink { slug: Slug },
+
+        /// See [`LinkQueryHandler::get_link_details()`].
+        GetLinkDetails { slug: Slug },
+
+        /// See [`LinkQueryHandler::find_by_url()`].
+        FindByUrl { url: Url },
+
+        /// See [`LinkQueryHandler::list_links_by_owner()`].
+        ListLinksByOwner { owner: OwnerId },
+
+        /// See [`LinkQueryHandler::top_links()`].
+        TopLinks { n: usize },
+
+        /// See [`LinkQueryHandler::global_stats()`].
+        GlobalStats,
+
+        /// See [`LinkQueryHandler::list_aliases()`].
+        ListAliases { slug: Slug },
+
+        /// See [`HistoryQueryHandler::state_at()`].
+        StateAt { at: PointInTime },
+
+        /// See [`HistoryQueryHandler::stats_at()`].
+        StatsAt { slug: Slug, at: PointInTime },
+
+        /// See [`HistoryQueryHandler::get_history()`].
+        GetHistory { slug: Slug },
+
+        /// See [`HistoryQueryHandler::get_correlated_events()`].
+        GetCorrelatedEvents { correlation_id: Uuid },
+
+        /// See [`StatsQueryHandler::get_stats_over_time()`].
+        GetStatsOverTime { slug: Slug, bucket: Interval },
+
+        /// See [`StatsQueryHandler::daily_clicks()`].
+        DailyClicks { slug: Slug, from: Date, to: Date },
+
+        /// See [`StatsQueryHandler::referrer_breakdown()`].
+        ReferrerBreakdown { slug: Slug },
+
+        /// See [`StatsQueryHandler::top_referrers()`].
+        TopReferrers { slug: Slug, n: usize },
+
+        /// See [`StatsQueryHandler::user_agent_breakdown()`].
+        UserAgentBreakdown { slug: Slug },
+
+        /// See [`StatsQueryHandler::get_detailed_stats()`].
+        GetDetailedStats { slug: Slug },
+
+        /// See [`StatsQueryHandler::get_variant_stats()`].
+        GetVariantStats { slug: Slug },
+
+        /// See [`StatsQueryHandler::country_breakdown()`].
+        CountryBreakdown { slug: Slug },
+
+        /// See [`CampaignQueryHandler::get_campaign()`].
+        GetCampaign { campaign: Slug },
+
+        /// See [`CampaignQueryHandler::list_campaigns()`].
+        ListCampaigns,
+
+        /// See [`CampaignQueryHandler::get_campaign_stats()`].
+        GetCampaignStats { campaign: Slug },
+
+        /// See [`HealthQueryHandler::broken_links()`].
+        BrokenLinks,
+
+        /// See [`HealthQueryHandler::blocked_domains()`].
+        BlockedDomains,
+
+        /// See [`TagQueryHandler::list_links_by_tag()`].
+        ListLinksByTag { tag: String },
+
+        /// See [`TagQueryHandler::tag_counts()`].
+        TagCounts,
+    }
+
+    /// Answer to a successfully executed [`Query`].
+    #[derive(Debug, Clone, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub enum QueryOutput {
+        /// [`Stats`] of a single link.
+        Stats(Stats),
+
+        /// A single [`ShortLink`].
+        Link(ShortLink),
+
+        /// [`LinkDetails`] of a single link.
+        LinkDetails(LinkDetails),
+
+        /// List of [`ShortLink`]s.
+        Links(Vec<ShortLink>),
+
+        /// List of [`Slug`]s, e.g. aliases of a link.
+        Slugs(Vec<Slug>),
+
+        /// List of [`Stats`] of links.
+        StatsList(Vec<Stats>),
+
+        /// Service-wide [`GlobalStats`].
+        GlobalStats(GlobalStats),
+
+        /// [`Snapshot`] of the state of the service.
+        Snapshot(Snapshot),
+
+        /// History of a link.
+        History(Vec<EventEnvelope>),
+
+        /// Redirects grouped into [`TimeBucket`]s.
+        TimeBuckets(Vec<TimeBucket>),
+
+        /// Redirects per [`Date`].
+        DailyClicks(Vec<(Date, u64)>),
+
+        /// Counts by key, e.g. by referrer or tag.
+        Counts(Vec<(String, u64)>),
+
+        /// [`DetailedStats`] of a single link.
+        DetailedStats(DetailedStats),
+
+        /// [`VariantStats`] of the destinations of a link.
+        VariantStats(Vec<VariantStats>),
+
+        /// A single [`Campaign`].
+        Campaign(Campaign),
+
+        /// List of [`Campaign`]s.
+        Campaigns(Vec<Campaign>),
+
+        /// [`CampaignStats`] of a single campaign.
+        CampaignStats(CampaignStats),
+
+        /// List of [`BrokenLink`]s.
+        BrokenLinks(Vec<BrokenLink>),
+    }
+
+    impl Query {
+        /// Answers the query with the matching method of the handler.
+        ///
+        /// ## Errors
+        ///
+        /// Returns the [`ShortenerError`] of the executed method.
+        pub fn execute<H>(self, handler: &H) -> Result<QueryOutput, ShortenerError>
+        where
+            H: QueryHandler
+                + LinkQueryHandler
+                + HistoryQueryHandler
+                + StatsQueryHandler
+                + CampaignQueryHandler
+                + HealthQueryHandler
+                + TagQueryHandler,
+        {
+            Ok(match self {
+                Query::GetStats { slug } => QueryOutput::Stats(handler.get_stats(slug)?),
+                Query::GetLink { slug } => QueryOutput::Link(handler.get_link(slug)?),
+                Query::GetLinkDetails { slug } => {
+                    QueryOutput::LinkDetails(handler.get_link_details(slug)?)
+                }
+                Query::FindByUrl { url } => QueryOutput::Links(handler.find_by_url(url)),
+                Query::ListLinksByOwner { owner } => {
+                    QueryOutput::Links(handler.list_links_by_owner(owner))
+                }
+                Query::TopLinks { n } => QueryOutput::StatsList(handler.top_links(n)),
+                Query::GlobalStats => QueryOutput::GlobalStats(handler.global_stats()),
+                Query::ListAliases { slug } => QueryOutput::Slugs(handler.list_aliases(slug)?),
+                Query::StateAt { at } => QueryOutput::Snapshot(handler.state_at(at)),
+                Query::StatsAt { slug, at } => QueryOutput::Stats(handler.stats_at(slug, at)?),
+                Query::GetHistory { slug } => QueryOutput::History(handler.get_history(slug)),
+                Query::GetCorrelatedEvents { correlation_id } => {
+                    QueryOutput::History(handler.get_correlated_events(correlation_id))
+                }
+                Query::GetStatsOverTime { slug, bucket } => {
+                    QueryOutput::TimeBuckets(handler.get_stats_over_time(slug, bucket)?)
+                }
+                Query::DailyClicks { slug, from, to } => {
+                    QueryOutput::DailyClicks(handler.daily_clicks(slug, from, to)?)
+                }
+                Query::ReferrerBreakdown { slug } => {
+                    QueryOutput::Counts(handler.referrer_breakdown(slug)?)
+                }
+                Query::TopReferrers { slug, n } => {
+                    QueryOutput::Counts(handler.top_referrers(slug, n)?)
+                }
+                Query::UserAgentBreakdown { slug } => {
+                    QueryOutput::Counts(handler.user_agent_breakdown(slug)?)
+                }
+                Query::GetDetailedStats { slug } => {
+                    QueryOutput::DetailedStats(handler.get_detailed_stats(slug)?)
+                }
+                Query::GetVariantStats { slug } => {
+                    QueryOutput::VariantStats(handler.get_variant_stats(slug)?)
+                }
+                Query::CountryBreakdown { slug } => {
+                    QueryOutput::Counts(handler.country_breakdown(slug)?)
+                }
+                Query::GetCampaign { campaign } => {
+                    QueryOutput::Campaign(handler.get_campaign(campaign)?)
+                }
+                Query::ListCampaigns => QueryOutput::Campaigns(handler.list_campaigns()),
+                Query::GetCampaignStats { campaign } => {
+                    QueryOutput::CampaignStats(handler.get_campaign_stats(campaign)?)
+                }
+                Query::BrokenLinks => QueryOutput::BrokenLinks(handler.broken_links()),
+                Query::BlockedDomains => QueryOutput::Counts(handler.blocked_domains()),
+                Query::ListLinksByTag { tag } => {
+                    QueryOutput::Links(handler.list_links_by_tag(&tag))
+                }
+                Query::TagCounts => QueryOutput::Counts(handler.tag_counts()),
+            })
+        }
+    }
+}
+
+/// Event storage for Event Sourcing.
+pub mod store {
+    use std::io;
+
+    use std::collections::{HashMap, HashSet};
+    use std::sync::Arc;
+    use std::time::SystemTime;
+
+    use super::outbox::OutboxMessage;
+    use super::{hour_of, Event, EventEnvelope, Slug, Uuid};
+
+    /// Identifier of the event stream of a single link.
+    #[derive(Clone, Debug, PartialEq, Eq, Hash)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub struct StreamId(pub Slug);
+
+    /// Append-only storage of [`Event`]s the service is built from.
+    ///
+    /// Events are grouped into per-link streams identified by [`StreamId`],
+    /// each of them versioned independently.
+    pub trait EventStore {
+        /// Appends a new [`EventEnvelope`] at the end of the log and of the
+        /// stream it belongs to.
+        ///
+        /// ## Errors
+        ///
+        /// Returns an error if the event could not be persisted. The event must
+        /// not be visible in the store in such case.
+        fn append(&mut self, envelope: EventEnvelope) -> io::Result<()>;
+
+        /// Returns all stored envelopes in the order they were appended.
+        fn read_envelopes(&self) -> Vec<EventEnvelope>;
+
+        /// Returns all envelopes of the given stream in the order they were
+        /// appended.
+        fn read_stream(&self, stream: &StreamId) -> Vec<EventEnvelope>;
+
+        /// Returns the current version of the given stream, which is the
+        /// version of its last event (`0` for an unknown stream).
+        fn stream_version(&self, stream: &StreamId) -> u64;
+
+        /// Returns the number of stored envelopes. The default implementation
+        /// counts [`EventStore::read_envelopes()`].
+        fn len(&self) -> usize {
+            self.read_envelopes().len()
+        }
+
+        /// Returns whether there are no stored envelopes.
+        fn is_empty(&self) -> bool {
+            self.len() == 0
+        }
+
+        /// Returns the sequence of the last stored envelope, or `None` if
+        /// there are none. The default implementation reads
+        /// [`EventStore::read_envelopes()`].
+        fn last_sequence(&self) -> Option<u64> {
+            self.read_envelopes().last().map(|envelope| envelope.sequence)
+        }
+
+        /// Returns the stored envelopes with a sequence in the range, in the
+        /// order they were appended. The default implementation filters
+        /// [`EventStore::read_envelopes()`].
+        fn read_range(&self, sequences: std::ops::Range<u64>) -> Vec<EventEnvelope> {
+            self.read_envelopes()
+                .into_iter()
+                .filter(|envelope| sequences.contains(&envelope.sequence))
+                .collect()
+        }
+
+        /// Returns all stored events in the order they were appended.
+        fn read_all(&self) -> Vec<Event> {
+            self.read_envelopes()
+                .into_iter()
+                .map(|envelope| envelope.event)
+                .collect()
+        }
+
+        /// Shrinks the log by folding runs of [`Event::LinkAccessed`] of a
+        /// link into single [`Event::ClicksAggregated`] events, returning the
+        /// number of removed events. Counts of redirects, including the ones
+        /// per hour, are preserved, other details of the folded events are
+        /// not.
+        ///
+        /// A run is cut by any other event of the link or by the start of an
+        /// hour, and only events up to `up_to_sequence` are folded. Passing
+        /// the [`Snapshot::last_event_index`] keeps the events recorded after
+        /// the snapshot intact, so the snapshot can still be restored from
+        /// them. The default implementation doesn't compact anything.
+        ///
+        /// ## Errors
+        ///
+        /// Returns an error if the compacted log could not be persisted, in
+        /// which case the log is left unchanged.
+        ///
+        /// [`Snapshot::last_event_index`]: super::Snapshot::last_event_index
+        fn compact(&mut self, up_to_sequence: u64) -> io::Result<usize> {
+            Ok(0)
+        }
+
+        /// Appends the envelope like [`EventStore::append()`] together with
+        /// the [`OutboxMessage`]s derived from it, atomically: either all of
+        /// them are stored or none is. The messages stay in the outbox until
+        /// they are removed with [`EventStore::remove_from_outbox()`].
+        ///
+        /// ## Errors
+        ///
+        /// Returns an error if the envelope or the messages could not be
+        /// persisted. The default implementation has no outbox and fails with
+        /// [`io::ErrorKind::Unsupported`] unless there are no messages.
+        fn append_with_outbox(
+            &mut self,
+            envelope: EventEnvelope,
+            messages: Vec<OutboxMessage>,
+        ) -> io::Result<()> {
+            if !messages.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "the event store has no outbox",
+                ));
+            }
+            self.append(envelope)
+        }
+
+        /// Appends the envelopes like [`EventStore::append_with_outbox()`]
+        /// together with the [`OutboxMessage`]s derived from any of them,
+        /// atomically: either all of them are stored or none is.
+        ///
+        /// The default implementation appends the envelopes one by one, and
+        /// removes the ones appended already with [`EventStore::truncate()`]
+        /// if an append fails.
+        ///
+        /// ## Errors
+        ///
+        /// Returns an error if the envelopes or the messages could not be
+        /// persisted. With the default implementation, the envelopes appended
+        /// already stay stored if they could not be removed either.
+        fn append_batch(
+            &mut self,
+            envelopes: Vec<EventEnvelope>,
+            mut messages: Vec<OutboxMessage>,
+        ) -> io::Result<()> {
+            let len = self.len();
+            for envelope in envelopes {
+                let (own, rest) = messages
+                    .into_iter()
+                    .partition(|message| message.event_id == envelope.id);
+                messages = rest;
+                if let Err(e) = self.append_with_outbox(envelope, own) {
+                    //best effort, see Errors
+                    let _ = self.truncate(len);
+                    return Err(e);
+                }
+            }
+            Ok(())
+        }
+
+        /// Removes all but the first `len` envelopes together with their
+        /// outbox messages, rolling back the default
+        /// [`EventStore::append_batch()`]. Nothing is removed if there are no
+        /// more envelopes than that.
+        ///
+        /// ## Errors
+        ///
+        /// Returns an error if the removal could not be persisted. The
+        /// default implementation fails with [`io::ErrorKind::Unsupported`],
+        /// stores which can't remove envelopes should implement
+        /// [`EventStore::append_batch()`] instead.
+        fn truncate(&mut self, len: usize) -> io::Result<()> {
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the event store can't remove envelopes",
+            ))
+        }
+
+        /// Returns the messages in the outbox in the order they were
+        /// appended. The default implementation has no outbox.
+        fn read_outbox(&self) -> Vec<OutboxMessage> {
+            Vec::new()
+        }
+
+        /// Removes a delivered message from the outbox, doing nothing if it
+        /// is not there.
+        ///
+        /// ## Errors
+        ///
+        /// Returns an error if the removal could not be persisted, in which
+        /// case the message stays in the outbox.
+        fn remove_from_outbox(&mut self, id: Uuid) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    //log with runs of clicks folded, see EventStore::compact
+    pub(super) fn compact_envelopes(
+        envelopes: Vec<EventEnvelope>,
+        up_to_sequence: u64,
+    ) -> Vec<EventEnvelope> {
+        let mut compacted: Vec<Option<EventEnvelope>> = Vec::with_capacity(envelopes.len());
+        //position of the last event of the open run of every stream
+        let mut runs: HashMap<StreamId, usize> = HashMap::new();
+        for envelope in envelopes {
+            let stream = envelope.event.stream_id();
+            let (clicks, folded) = match &envelope.event {
+                Event::LinkAccessed { .. } => (1, 0),
+                Event::ClicksAggregated { count, folded, .. } => (*count, *folded),
+                _ => (0, 0),
+            };
+            if clicks == 0 || envelope.sequence > up_to_sequence {
+                runs.remove(&stream);
+                compacted.push(Some(envelope));
+                continue;
+            }
+            let previous = runs
+                .get(&stream)
+                .and_then(|&position| compacted[position].as_ref())
+                .filter(|previous| hour_of(previous.occurred_at) == hour_of(envelope.occurred_at))
+                .map(|previous| match previous.event {
+                    Event::ClicksAggregated { count, folded, .. } => (count, folded),
+                    _ => (1, 0),
+                });
+            let envelope = match previous {
+                Some((count, previous_folded)) => {
+                    compacted[runs[&stream]] = None;
+                    EventEnvelope {
+                        event: Event::ClicksAggregated {
+                            slug: stream.0.clone(),
+                            count: count + clicks,
+                            up_to_sequence: envelope.sequence,
+                            folded: previous_folded + folded + 1,
+                        },
+                        ..envelope
+                    }
+                }
+                None => envelope,
+            };
+            runs.insert(stream, compacted.len());
+            compacted.push(Some(envelope));
+        }
+        compacted.into_iter().flatten().collect()
+    }
+
+    //envelopes changed by compaction, the merged ones taking the place of
+    //the last event they fold and the removed ones
+    #[cfg(any(feature = "sled", feature = "postgres", feature = "sqlite"))]
+    fn compaction_changes(
+        envelopes: Vec<EventEnvelope>,
+        up_to_sequence: u64,
+    ) -> (Vec<EventEnvelope>, Vec<EventEnvelope>) {
+        let compacted = compact_envelopes(envelopes.clone(), up_to_sequence);
+        let kept: std::collections::HashSet<u64> =
+            compacted.iter().map(|envelope| envelope.sequence).collect();
+        let removed: Vec<EventEnvelope> = envelopes
+            .into_iter()
+            .filter(|envelope| !kept.contains(&envelope.sequence))
+            .collect();
+        let merged = compacted
+            .into_iter()
+            .filter(|envelope| {
+                envelope.sequence <= up_to_sequence
+                    && matches!(envelope.event, Event::ClicksAggregated { .. })
+            })
+            .collect();
+        (merged, removed)
+    }
+
+    /// [`EventStore`] keeping all events in memory.
+    ///
+    /// The [`Slug`]s of [`Event::LinkAccessed`] events, by far the most
+    /// frequent ones, are interned, so the events of a link share a single
+    /// copy of its [`Slug`].
+    #[derive(Debug, Default, Clone)]
+    pub struct InMemoryEventStore {
+        envelopes: Vec<StoredEnvelope>,
+        //positions of every stream's events in `envelopes`
+        streams: HashMap<StreamId, Vec<usize>>,
+        //slugs shared by the stored events
+        slugs: HashSet<Arc<str>>,
+        outbox: Vec<OutboxMessage>,
+    }
+
+    //envelope as kept by the in-memory store
+    #[derive(Debug, Clone)]
+    struct StoredEnvelope {
+        id: Uuid,
+        sequence: u64,
+        version: u64,
+        occurred_at: SystemTime,
+        correlation_id: Option<Uuid>,
+        causation_id: Option<Uuid>,
+        event: StoredEvent,
+    }
+
+    //event with the slug interned if it's a click, boxed otherwise to keep
+    //clicks small
+    #[derive(Debug, Clone)]
+    enum StoredEvent {
+        LinkAccessed(Arc<str>),
+        Other(Box<Event>),
+    }
+
+    impl StoredEnvelope {
+        fn to_envelope(&self) -> EventEnvelope {
+            EventEnvelope {
+                id: self.id,
+                sequence: self.sequence,
+                version: self.version,
+                occurred_at: self.occurred_at,
+                event: match &self.event {
+                    StoredEvent::LinkAccessed(slug) => Event::LinkAccessed {
+                        slug: Slug(slug.to_string()),
+                    },
+                    StoredEvent::Other(event) => (**event).clone(),
+                },
+                correlation_id: self.correlation_id,
+                causation_id: self.causation_id,
+            }
+        }
+    }
+
+    impl InMemoryEventStore {
+        /// Creates an empty store.
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Creates a store already containing the given envelopes.
+        pub fn from_envelopes(envelopes: Vec<EventEnvelope>) -> Self {
+            let mut store = Self::new();
+            for envelope in envelopes {
+                store.push(envelope);
+            }
+            store
+        }
+
+        fn push(&mut self, envelope: EventEnvelope) {
+            self.streams
+                .entry(envelope.event.stream_id())
+                .or_default()
+                .push(self.envelopes.len());
+            let event = match envelope.event {
+                Event::LinkAccessed { slug } => StoredEvent::LinkAccessed(self.intern(slug)),
+                event => StoredEvent::Other(Box::new(event)),
+            };
+            self.envelopes.push(StoredEnvelope {
+                id: envelope.id,
+                sequence: envelope.sequence,
+                version: envelope.version,
+                occurred_at: envelope.occurred_at,
+                correlation_id: envelope.correlation_id,
+                causation_id: envelope.causation_id,
+                event,
+            });
+        }
+
+        fn intern(&mut self, slug: Slug) -> Arc<str> {
+            match self.slugs.get(slug.0.as_str()) {
+                Some(interned) => interned.clone(),
+                None => {
+                    let interned: Arc<str> = slug.0.into();
+                    self.slugs.insert(interned.clone());
+                    interned
+                }
+            }
+        }
+    }
+
+    impl EventStore for InMemoryEventStore {
+        fn append(&mut self, envelope: EventEnvelope) -> io::Result<()> {
+            self.push(envelope);
+            Ok(())
+        }
+
+        fn read_envelopes(&self) -> Vec<EventEnvelope> {
+            self.envelopes.iter().map(StoredEnvelope::to_envelope).collect()
+        }
+
+        fn read_stream(&self, stream: &StreamId) -> Vec<EventEnvelope> {
+            self.streams
+                .get(stream)
+                .map(|positions| {
+                    positions
+                        .iter()
+                        .map(|&position| self.envelopes[position].to_envelope())
+                        .collect()
+                })
+                .unwrap_or_default()
+        }
+
+        fn stream_version(&self, stream: &StreamId) -> u64 {
+            self.streams
+                .get(stream)
+                .and_then(|positions| positions.last())
+                .map_or(0, |&position| self.envelopes[position].version)
+        }
+
+        fn len(&self) -> usize {
+            self.envelopes.len()
+        }
+
+        fn last_sequence(&self) -> Option<u64> {
+            self.envelopes.last().map(|envelope| envelope.sequence)
+        }
+
+        fn read_range(&self, sequences: std::ops::Range<u64>) -> Vec<EventEnvelope> {
+            let start = self
+                .envelopes
+                .partition_point(|envelope| envelope.sequence < sequences.start);
+            self.envelopes[start..]
+                .iter()
+                .take_while(|envelope| envelope.sequence < sequences.end)
+                .map(StoredEnvelope::to_envelope)
+                .collect()
+        }
+
+        fn compact(&mut self, up_to_sequence: u64) -> io::Result<usize> {
+            let before = self.envelopes.len();
+            let outbox = std::mem::take(&mut self.outbox);
+            *self = Self::from_envelopes(compact_envelopes(self.read_envelopes(), up_to_sequence));
+            self.outbox = outbox;
+            Ok(before - self.envelopes.len())
+        }
+
+        fn append_with_outbox(
+            &mut self,
+            envelope: EventEnvelope,
+            messages: Vec<OutboxMessage>,
+        ) -> io::Result<()> {
+            self.append_batch(vec![envelope], messages)
+        }
+
+        fn append_batch(
+            &mut self,
+            envelopes: Vec<EventEnvelope>,
+            messages: Vec<OutboxMessage>,
+        ) -> io::Result<()> {
+            for envelope in envelopes {
+                self.push(envelope);
+            }
+            self.outbox.extend(messages);
+            Ok(())
+        }
+
+        fn truncate(&mut self, len: usize) -> io::Result<()> {
+            if len >= self.envelopes.len() {
+                return Ok(());
+            }
+            let removed: HashSet<Uuid> =
+                self.envelopes[len..].iter().map(|envelope| envelope.id).collect();
+            let mut outbox = std::mem::take(&mut self.outbox);
+            outbox.retain(|message| !removed.contains(&message.event_id));
+            let mut envelopes = self.read_envelopes();
+            envelopes.truncate(len);
+            *self = Self::from_envelopes(envelopes);
+            self.outbox = outbox;
+            Ok(())
+        }
+
+        fn read_outbox(&self) -> Vec<OutboxMessage> {
+            self.outbox.clone()
+        }
+
+        fn remove_from_outbox(&mut self, id: Uuid) -> io::Result<()> {
+            self.outbox.retain(|message| message.id != id);
+            Ok(())
+        }
+    }
+
+    /// [`EventStore`] persisting events in an append-only file of
+    /// newline-delimited JSON, one [`EventEnvelope`] per line, or an array of
+    /// the envelopes appended together with [`EventStore::append_batch()`].
+    ///
+    /// Every append is flushed and synced to disk before returning. An append
+    /// which fails is cut off the file again, so a batch is either stored
+    /// whole or not at all. All events are also kept in an
+    /// [`InMemoryEventStore`], so reads never touch the file.
+    ///
+    /// The outbox is kept in a file next to the log, with the `outbox`
+    /// extension, created once the first message is appended. Messages are
+    /// written to it before their event is written to the log, and the ones
+    /// whose event never made it to the log are dropped when the store is
+    /// opened.
+    #[cfg(feature = "serde")]
+    #[derive(Debug)]
+    pub struct FileEventStore {
+        path: std::path::PathBuf,
+        file: std::fs::File,
+        outbox: Option<std::fs::File>,
+        cache: InMemoryEventStore,
+    }
+
+    //line of the outbox file
+    #[cfg(feature = "serde")]
+    #[derive(serde::Serialize, serde::Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    enum OutboxRecord {
+        Appended(OutboxMessage),
+        Removed(Uuid),
+    }
+
+    #[cfg(feature = "serde")]
+    impl FileEventStore {
+        /// Opens (or creates) the event log at the given path and loads all
+        /// events stored in it.
+        ///
+        /// A trailing line that cannot be parsed is considered a write
+        /// interrupted by a crash and is cut off the log. Any other malformed
+        /// line is reported as [`io::ErrorKind::InvalidData`].
+        pub fn open(path: impl AsRef<std::path::Path>) -> io::Result<Self> {
+            use std::io::BufRead;
+
+            let path = path.as_ref().to_path_buf();
+            let file = std::fs::OpenOptions::new()
+                .read(true)
+                .append(true)
+                .create(true)
+                .open(&path)?;
+
+            let mut cache = InMemoryEventStore::new();
+            let mut valid_len = 0;
+            let mut reader = io::BufReader::new(&file);
+            let mut line = Vec::new();
+            loop {
+                line.clear();
+                let read = reader.read_until(b'\n', &mut line)?;
+                if read == 0 {
+                    break;
+                }
+                match Self::parse_line(&line) {
+                    Ok(envelopes) if line.ends_with(b"\n") => {
+                        for envelope in envelopes {
+                            cache.push(envelope);
+                        }
+                        valid_len += read as u64;
+                    }
+                    Err(e) if !reader.fill_buf()?.is_empty() => {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+                    }
+                    //torn write at the end of the log
+                    _ => break,
+                }
+            }
+
+            if valid_len != file.metadata()?.len() {
+                file.set_len(valid_len)?;
+                file.sync_all()?;
+            }
+
+            cache.outbox = Self::open_outbox(&path.with_extension("outbox"), &cache)?;
+            Ok(Self {
+                path,
+                file,
+                outbox: None,
+                cache,
+            })
+        }
+
+        //envelopes of a line of the log, a single one or a whole batch
+        fn parse_line(line: &[u8]) -> serde_json::Result<Vec<EventEnvelope>> {
+            match line.first() {
+                Some(b'[') => serde_json::from_slice(line),
+                _ => serde_json::from_slice(line).map(|envelope| vec![envelope]),
+            }
+        }
+
+        //loads the messages left in the outbox file, rewriting it with only
+        //those of the events in the log, or compacted away from it
+        fn open_outbox(
+            path: &std::path::Path,
+            log: &InMemoryEventStore,
+        ) -> io::Result<Vec<OutboxMessage>> {
+            use std::io::{BufRead, Write};
+
+            let file = match std::fs::File::open(path) {
+                Ok(file) => file,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+                Err(e) => return Err(e),
+            };
+            let mut messages: Vec<OutboxMessage> = Vec::new();
+            let mut reader = io::BufReader::new(file);
+            let mut line = Vec::new();
+            loop {
+                line.clear();
+                if reader.read_until(b'\n', &mut line)? == 0 {
+                    break;
+                }
+                match serde_json::from_slice::<OutboxRecord>(&line) {
+                    Ok(OutboxRecord::Appended(message)) if line.ends_with(b"\n") => {
+                        messages.push(message);
+                    }
+                    Ok(OutboxRecord::Removed(id)) if line.ends_with(b"\n") => {
+                        messages.retain(|message| message.id != id);
+                    }
+                    Err(e) if !reader.fill_buf()?.is_empty() => {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+                    }
+                    //torn write at the end of the outbox
+                    _ => break,
+                }
+            }
+            let next = log.envelopes.last().map_or(0, |envelope| envelope.sequence + 1);
+            let ids: HashMap<u64, Uuid> = log
+                .envelopes
+                .iter()
+                .map(|envelope| (envelope.sequence, envelope.id))
+                .collect();
+            messages.retain(|message| {
+                message.sequence < next
+                    && ids.get(&message.sequence).is_none_or(|&id| id == message.event_id)
+            });
+
+            let temporary = path.with_extension("outbox-compacting");
+            let mut writer = io::BufWriter::new(std::fs::File::create(&temporary)?);
+            for message in &messages {
+                serde_json::to_writer(&mut writer, &OutboxRecord::Appended(message.clone()))?;
+                writer.write_all(b"\n")?;
+            }
+            writer.into_inner().map_err(io::IntoInnerError::into_error)?.sync_all()?;
+            std::fs::rename(&temporary, path)?;
+            Ok(messages)
+        }
+
+        fn write_outbox(&mut self, records: &[OutboxRecord]) -> io::Result<()> {
+            use std::io::Write;
+
+            let mut lines = Vec::new();
+            for record in records {
+                serde_json::to_writer(&mut lines, record)?;
+                lines.push(b'\n');
+            }
+            let file = match &mut self.outbox {
+                Some(file) => file,
+                None => self.outbox.insert(
+                    std::fs::OpenOptions::new()
+                        .append(true)
+                        .create(true)
+                        .open(self.path.with_extension("outbox"))?,
+                ),
+            };
+            file.write_all(&lines)?;
+            file.sync_data()
+        }
+
+        //writes and syncs the lines, cutting them off the log again if it fails
+        //so they neither show up when opening it nor precede later appends
+        fn write_log(&mut self, lines: &[u8]) -> io::Result<()> {
+            use std::io::Write;
+
+            let len = self.file.metadata()?.len();
+            let written = self
+                .file
+                .write_all(lines)
+                .and_then(|()| self.file.sync_data());
+            if written.is_err() {
+                //best effort, a torn last line is cut off when opening anyway
+                let _ = self.file.set_len(len).and_then(|()| self.file.sync_data());
+            }
+            written
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    impl EventStore for FileEventStore {
+        fn append(&mut self, envelope: EventEnvelope) -> io::Result<()> {
+            self.append_batch(vec![envelope], Vec::new())
+        }
+
+        fn read_envelopes(&self) -> Vec<EventEnvelope> {
+            self.cache.read_envelopes()
+        }
+
+        fn read_stream(&self, stream: &StreamId) -> Vec<EventEnvelope> {
+            self.cache.read_stream(stream)
+        }
+
+        fn stream_version(&self, stream: &StreamId) -> u64 {
+            self.cache.stream_version(stream)
+        }
+
+        fn len(&self) -> usize {
+            self.cache.len()
+        }
+
+        fn last_sequence(&self) -> Option<u64> {
+            self.cache.last_sequence()
+        }
+
+        fn read_range(&self, sequences: std::ops::Range<u64>) -> Vec<EventEnvelope> {
+            self.cache.read_range(sequences)
+        }
+
+        //the compacted log is written aside and renamed over the old one
+        fn compact(&mut self, up_to_sequence: u64) -> io::Result<usize> {
+            use std::io::Write;
+
+            let compacted = compact_envelopes(self.cache.read_envelopes(), up_to_sequence);
+            let removed = self.cache.envelopes.len() - compacted.len();
+            if removed == 0 {
+                return Ok(0);
+            }
+            let temporary = self.path.with_extension("compacting");
+            let mut writer = io::BufWriter::new(std::fs::File::create(&temporary)?);
+            for envelope in &compacted {
+                serde_json::to_writer(&mut writer, envelope)?;
+                writer.write_all(b"\n")?;
+            }
+            writer.into_inner().map_err(io::IntoInnerError::into_error)?.sync_all()?;
+            std::fs::rename(&temporary, &self.path)?;
+            self.file = std::fs::OpenOptions::new().read(true).append(true).open(&self.path)?;
+            let outbox = std::mem::take(&mut self.cache.outbox);
+            self.cache = InMemoryEventStore::from_envelopes(compacted);
+            self.cache.outbox = outbox;
+            Ok(removed)
+        }
+
+        fn append_with_outbox(
+            &mut self,
+            envelope: EventEnvelope,
+            messages: Vec<OutboxMessage>,
+        ) -> io::Result<()> {
+            self.append_batch(vec![envelope], messages)
+        }
+
+        //the envelopes are written as a single line, after the messages, see
+        //FileEventStore
+        fn append_batch(
+            &mut self,
+            envelopes: Vec<EventEnvelope>,
+            messages: Vec<OutboxMessage>,
+        ) -> io::Result<()> {
+            let mut line = match envelopes.as_slice() {
+                [envelope] => serde_json::to_vec(envelope)?,
+                envelopes => serde_json::to_vec(envelopes)?,
+            };
+            line.push(b'\n');
+            if !messages.is_empty() {
+                let records: Vec<OutboxRecord> =
+                    messages.iter().cloned().map(OutboxRecord::Appended).collect();
+                self.write_outbox(&records)?;
+            }
+            if let Err(e) = self.write_log(&line) {
+                let records: Vec<OutboxRecord> =
+                    messages.iter().map(|message| OutboxRecord::Removed(message.id)).collect();
+                //best effort, such messages are dropped when opening anyway
+                let _ = self.write_outbox(&records);
+                return Err(e);
+            }
+            for envelope in envelopes {
+                self.cache.push(envelope);
+            }
+            self.cache.outbox.extend(messages);
+            Ok(())
+        }
+
+        fn read_outbox(&self) -> Vec<OutboxMessage> {
+            self.cache.read_outbox()
+        }
+
+        fn remove_from_outbox(&mut self, id: Uuid) -> io::Result<()> {
+            if self.cache.outbox.iter().any(|message| message.id == id) {
+                self.write_outbox(&[OutboxRecord::Removed(id)])?;
+                self.cache.remove_from_outbox(id)?;
+            }
+            Ok(())
+        }
+    }
+
+    /// [`EventStore`] persisting events in an embedded
+    /// [sled](https://docs.rs/sled) database, so no external database is
+    /// needed.
+    ///
+    /// Envelopes are kept as JSON keyed by their sequence, which orders the
+    /// whole log, and every stream is indexed by its versions. Every append,
+    /// or batch of them, is written in a single transaction and flushed to
+    /// disk before returning, together with its outbox messages, kept as JSON
+    /// keyed by the sequence of their event.
+    ///
+    /// ## Panics
+    ///
+    /// Reads panic if the database fails or was corrupted after it was
+    /// opened, as [`EventStore`] reads can't report errors.
+    #[cfg(feature = "sled")]
+    #[derive(Debug, Clone)]
+    pub struct SledEventStore {
+        db: sled::Db,
+        //sequence -> envelope
+        events: sled::Tree,
+        //stream and version -> sequence
+        streams: sled::Tree,
+        //sequence and position -> outbox message
+        outbox: sled::Tree,
+    }
+
+    #[cfg(feature = "sled")]
+    impl SledEventStore {
+        /// Opens (or creates) the database at the given path.
+        ///
+        /// All stored envelopes are checked to be readable, malformed ones are
+        /// reported as [`io::ErrorKind::InvalidData`].
+        pub fn open(path: impl AsRef<std::path::Path>) -> io::Result<Self> {
+            Self::from_db(sled::open(path)?)
+        }
+
+        /// Creates a store in an already opened database, using its `events`,
+        /// `streams` and `outbox` trees.
+        pub fn from_db(db: sled::Db) -> io::Result<Self> {
+            let store = Self {
+                events: db.open_tree("events")?,
+                streams: db.open_tree("streams")?,
+                outbox: db.open_tree("outbox")?,
+                db,
+            };
+            for entry in &store.events {
+                let (_, value) = entry?;
+                serde_json::from_slice::<EventEnvelope>(&value)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            }
+            Ok(store)
+        }
+
+        //prefix of all keys of the stream in `streams`
+        fn stream_prefix(stream: &StreamId) -> Vec<u8> {
+            let slug = stream.0 .0.as_bytes();
+            let mut prefix = Vec::with_capacity(4 + slug.len() + 8);
+            prefix.extend_from_slice(&(slug.len() as u32).to_be_bytes());
+            prefix.extend_from_slice(slug);
+            prefix
+        }
+
+        fn stream_key(envelope: &EventEnvelope) -> Vec<u8> {
+            let mut key = Self::stream_prefix(&envelope.event.stream_id());
+            key.extend_from_slice(&envelope.version.to_be_bytes());
+            key
+        }
+
+        fn read(&self, sequence: &[u8]) -> EventEnvelope {
+            let value = self
+                .events
+                .get(sequence)
+                .expect("reading the event store failed")
+                .expect("event store index points to a missing event");
+            serde_json::from_slice(&value).expect("event store contains a malformed event")
+        }
+
+        //writes and removes envelopes in a single transaction
+        fn write(&self, inserted: &[EventEnvelope], removed: &[EventEnvelope]) -> io::Result<()> {
+            self.write_with_outbox(inserted, removed, &[])
+        }
+
+        //writes and removes envelopes, and writes outbox messages, in a
+        //single transaction
+        fn write_with_outbox(
+            &self,
+            inserted: &[EventEnvelope],
+            removed: &[EventEnvelope],
+            messages: &[OutboxMessage],
+        ) -> io::Result<()> {
+            use sled::transaction::{ConflictableTransactionError, TransactionError};
+            use sled::Transactional;
+
+            let inserted = inserted
+                .iter()
+                .map(|envelope| {
+                    Ok((
+                        envelope.sequence.to_be_bytes(),
+                        Self::stream_key(envelope),
+                        serde_json::to_vec(envelope)?,
+                    ))
+                })
+                .collect::<io::Result<Vec<_>>>()?;
+            let messages = messages
+                .iter()
+                .zip(0u32..)
+                .map(|(message, position)| {
+                    let mut key = message.sequence.to_be_bytes().to_vec();
+                    key.extend_from_slice(&position.to_be_bytes());
+                    Ok((key, serde_json::to_vec(message)?))
+                })
+                .collect::<io::Result<Vec<_>>>()?;
+            (&self.events, &self.streams, &self.outbox)
+                .transaction(|(events, streams, outbox)| {
+                    for envelope in removed {
+                        events.remove(&envelope.sequence.to_be_bytes())?;
+                        streams.remove(Self::stream_key(envelope))?;
+                    }
+                    for (sequence, stream_key, value) in &inserted {
+                        events.insert(sequence, value.as_slice())?;
+                        streams.insert(stream_key.as_slice(), sequence)?;
+                    }
+                    for (key, value) in &messages {
+                        outbox.insert(key.as_slice(), value.as_slice())?;
+                    }
+                    Ok::<_, ConflictableTransactionError<io::Error>>(())
+                })
+                .map_err(|e| match e {
+                    TransactionError::Abort(e) => e,
+                    TransactionError::Storage(e) => e.into(),
+                })?;
+            self.db.flush()?;
+            Ok(())
+        }
+    }
+
+    #[cfg(feature = "sled")]
+    impl EventStore for SledEventStore {
+        fn append(&mut self, envelope: EventEnvelope) -> io::Result<()> {
+            self.write(&[envelope], &[])
+        }
+
+        fn read_envelopes(&self) -> Vec<EventEnvelope> {
+            self.events
+                .iter()
+                .values()
+                .map(|value| {
+                    let value = value.expect("reading the event store failed");
+                    serde_json::from_slice(&value).expect("event store contains a malformed event")
+                })
+                .collect()
+        }
+
+        fn read_stream(&self, stream: &StreamId) -> Vec<EventEnvelope> {
+            self.streams
+                .scan_prefix(Self::stream_prefix(stream))
+                .values()
+                .map(|sequence| self.read(&sequence.expect("reading the event store failed")))
+                .collect()
+        }
+
+        fn stream_version(&self, stream: &StreamId) -> u64 {
+            self.streams
+                .scan_prefix(Self::stream_prefix(stream))
+                .keys()
+                .next_back()
+                .map_or(0, |key| {
+                    let key = key.expect("reading the event store failed");
+                    let mut version = [0; 8];
+                    version.copy_from_slice(&key[key.len() - 8..]);
+                    u64::from_be_bytes(version)
+                })
+        }
+
+        //counts the keys without decoding the envelopes
+        fn len(&self) -> usize {
+            self.events.len()
+        }
+
+        fn last_sequence(&self) -> Option<u64> {
+            let (key, _) = self.events.last().expect("reading the event store failed")?;
+            let mut sequence = [0; 8];
+            sequence.copy_from_slice(&key);
+            Some(u64::from_be_bytes(sequence))
+        }
+
+        fn read_range(&self, sequences: std::ops::Range<u64>) -> Vec<EventEnvelope> {
+            self.events
+                .range(sequences.start.to_be_bytes()..sequences.end.to_be_bytes())
+                .values()
+                .map(|value| {
+                    let value = value.expect("reading the event store failed");
+                    serde_json::from_slice(&value).expect("event store contains a malformed event")
+                })
+                .collect()
+        }
+
+        fn compact(&mut self, up_to_sequence: u64) -> io::Result<usize> {
+            let (merged, removed) = compaction_changes(self.read_envelopes(), up_to_sequence);
+            if removed.is_empty() {
+                return Ok(0);
+            }
+            self.write(&merged, &removed)?;
+            Ok(removed.len())
+        }
+
+        fn append_with_outbox(
+            &mut self,
+            envelope: EventEnvelope,
+            messages: Vec<OutboxMessage>,
+        ) -> io::Result<()> {
+            self.write_with_outbox(&[envelope], &[], &messages)
+        }
+
+        fn append_batch(
+            &mut self,
+            envelopes: Vec<EventEnvelope>,
+            messages: Vec<OutboxMessage>,
+        ) -> io::Result<()> {
+            self.write_with_outbox(&envelopes, &[], &messages)
+        }
+
+        fn read_outbox(&self) -> Vec<OutboxMessage> {
+            self.outbox
+                .iter()
+                .values()
+                .map(|value| {
+                    let value = value.expect("reading the event store failed");
+                    serde_json::from_slice(&value)
+                        .expect("event store contains a malformed outbox message")
+                })
+                .collect()
+        }
+
+        fn remove_from_outbox(&mut self, id: Uuid) -> io::Result<()> {
+            for entry in &self.outbox {
+                let (key, value) = entry?;
+                let message: OutboxMessage = serde_json::from_slice(&value)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                if message.id == id {
+                    self.outbox.remove(key)?;
+                    self.db.flush()?;
+                    break;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    /// [`EventStore`] persisting events in a PostgreSQL table, for
+    /// deployments where an external database is available.
+    ///
+    /// Envelopes are stored as JSON in the `events` table, keyed by their
+    /// sequence with every stream's versions kept unique, so a conflicting
+    /// append of another writer is rejected instead of overwriting events.
+    /// All events are also kept in an [`InMemoryEventStore`], so reads never
+    /// touch the database; events appended by other writers are picked up
+    /// with [`catch_up()`](Self::catch_up). Outbox messages are stored in the
+    /// `outbox` table, in the transaction of their event.
+    ///
+    /// The [`EventStore`] methods block the current thread on the
+    /// multi-threaded tokio runtime the store was created in. Async code can
+    /// use [`append_async()`](Self::append_async) instead.
+    #[cfg(feature = "postgres")]
+    #[derive(Debug)]
+    pub struct PostgresEventStore {
+        pool: sqlx::PgPool,
+        runtime: tokio::runtime::Handle,
+        cache: InMemoryEventStore,
+    }
+
+    #[cfg(feature = "postgres")]
+    impl PostgresEventStore {
+        /// Connects to the database at the given URL, creating the `events`
+        /// and `outbox` tables if needed, and loads all events stored in it.
+        pub async fn connect(url: &str) -> Result<Self, sqlx::Error> {
+            Self::from_pool(sqlx::PgPool::connect(url).await?).await
+        }
+
+        /// Creates a store using an already created connection pool, creating
+        /// the `events` and `outbox` tables if needed, and loads all events
+        /// stored in it.
+        pub async fn from_pool(pool: sqlx::PgPool) -> Result<Self, sqlx::Error> {
+            sqlx::query(
+                "CREATE TABLE IF NOT EXISTS events (
+                    sequence BIGINT PRIMARY KEY,
+                    stream TEXT NOT NULL,
+                    version BIGINT NOT NULL,
+                    envelope JSONB NOT NULL,
+                    UNIQUE (stream, version)
+                )",
+            )
+            .execute(&pool)
+            .await?;
+            sqlx::query(
+                "CREATE TABLE IF NOT EXISTS outbox (
+                    id TEXT PRIMARY KEY,
+                    sequence BIGINT NOT NULL,
+                    position INTEGER NOT NULL,
+                    message JSONB NOT NULL
+                )",
+            )
+            .execute(&pool)
+            .await?;
+            let mut store = Self {
+                pool,
+                runtime: tokio::runtime::Handle::current(),
+                cache: InMemoryEventStore::new(),
+            };
+            store.catch_up().await?;
+            Ok(store)
+        }
+
+        /// Loads the events appended by other writers since the last read,
+        /// returning them in the order of their sequence, and reloads the
+        /// outbox.
+        pub async fn catch_up(&mut self) -> Result<Vec<EventEnvelope>, sqlx::Error> {
+            let after = self
+                .cache
+                .envelopes
+                .last()
+                .map_or(-1, |envelope| envelope.sequence as i64);
+            let rows: Vec<(sqlx::types::Json<EventEnvelope>,)> =
+                sqlx::query_as("SELECT envelope FROM events WHERE sequence > $1 ORDER BY sequence")
+                    .bind(after)
+                    .fetch_all(&self.pool)
+                    .await?;
+            let envelopes: Vec<EventEnvelope> = rows.into_iter().map(|(row,)| row.0).collect();
+            for envelope in &envelopes {
+                self.cache.push(envelope.clone());
+            }
+            let rows: Vec<(sqlx::types::Json<OutboxMessage>,)> =
+                sqlx::query_as("SELECT message FROM outbox ORDER BY sequence, position")
+                    .fetch_all(&self.pool)
+                    .await?;
+            self.cache.outbox = rows.into_iter().map(|(row,)| row.0).collect();
+            Ok(envelopes)
+        }
+
+        /// Appends an envelope in its own transaction, like
+        /// [`EventStore::append()`] without blocking the thread.
+        pub async fn append_async(&mut self, envelope: EventEnvelope) -> Result<(), sqlx::Error> {
+            self.append_with_outbox_async(envelope, Vec::new()).await
+        }
+
+        /// Appends an envelope and its outbox messages in one transaction,
+        /// like [`EventStore::append_with_outbox()`] without blocking the
+        /// thread.
+        pub async fn append_with_outbox_async(
+            &mut self,
+            envelope: EventEnvelope,
+            messages: Vec<OutboxMessage>,
+        ) -> Result<(), sqlx::Error> {
+            self.append_batch_async(vec![envelope], messages).await
+        }
+
+        /// Appends envelopes and their outbox messages in one transaction,
+        /// like [`EventStore::append_batch()`] without blocking the thread.
+        pub async fn append_batch_async(
+            &mut self,
+            envelopes: Vec<EventEnvelope>,
+            messages: Vec<OutboxMessage>,
+        ) -> Result<(), sqlx::Error> {
+            let mut transaction = self.pool.begin().await?;
+            for envelope in &envelopes {
+                Self::insert(&mut transaction, envelope).await?;
+            }
+            for (message, position) in messages.iter().zip(0i32..) {
+                sqlx::query(
+                    "INSERT INTO outbox (id, sequence, position, message) VALUES ($1, $2, $3, $4)",
+                )
+                .bind(message.id.to_string())
+                .bind(message.sequence as i64)
+                .bind(position)
+                .bind(sqlx::types::Json(message))
+                .execute(&mut *transaction)
+                .await?;
+            }
+            transaction.commit().await?;
+            for envelope in envelopes {
+                self.cache.push(envelope);
+            }
+            self.cache.outbox.extend(messages);
+            Ok(())
+        }
+
+        async fn remove_from_outbox_async(&self, id: Uuid) -> Result<(), sqlx::Error> {
+            sqlx::query("DELETE FROM outbox WHERE id = $1")
+                .bind(id.to_string())
+                .execute(&self.pool)
+                .await?;
+            Ok(())
+        }
+
+        async fn insert(
+            connection: &mut sqlx::PgConnection,
+            envelope: &EventEnvelope,
+        ) -> Result<(), sqlx::Error> {
+            sqlx::query(
+                "INSERT INTO events (sequence, stream, version, envelope) VALUES ($1, $2, $3, $4)",
+            )
+            .bind(envelope.sequence as i64)
+            .bind(&envelope.event.stream_id().0 .0)
+            .bind(envelope.version as i64)
+            .bind(sqlx::types::Json(envelope))
+            .execute(connection)
+            .await?;
+            Ok(())
+        }
+
+        async fn compact_async(
+            &self,
+            merged: &[EventEnvelope],
+            removed: &[EventEnvelope],
+        ) -> Result<(), sqlx::Error> {
+            let sequences: Vec<i64> = removed
+                .iter()
+                .map(|envelope| envelope.sequence as i64)
+                .collect();
+            let mut transaction = self.pool.begin().await?;
+            sqlx::query("DELETE FROM events WHERE sequence = ANY($1)")
+                .bind(&sequences)
+                .execute(&mut *transaction)
+                .await?;
+            for envelope in merged {
+                sqlx::query("UPDATE events SET envelope = $2 WHERE sequence = $1")
+                    .bind(envelope.sequence as i64)
+                    .bind(sqlx::types::Json(envelope))
+                    .execute(&mut *transaction)
+                    .await?;
+            }
+            transaction.commit().await
+        }
+
+        //runs the future to completion on the current thread
+        fn block_on<T>(
+            runtime: tokio::runtime::Handle,
+            future: impl std::future::Future<Output = Result<T, sqlx::Error>>,
+        ) -> io::Result<T> {
+            tokio::task::block_in_place(|| runtime.block_on(future)).map_err(io::Error::other)
+        }
+    }
+
+    #[cfg(feature = "postgres")]
+    impl EventStore for PostgresEventStore {
+        fn append(&mut self, envelope: EventEnvelope) -> io::Result<()> {
+            Self::block_on(self.runtime.clone(), self.append_async(envelope))
+        }
+
+        fn read_envelopes(&self) -> Vec<EventEnvelope> {
+            self.cache.read_envelopes()
+        }
+
+        fn read_stream(&self, stream: &StreamId) -> Vec<EventEnvelope> {
+            self.cache.read_stream(stream)
+        }
+
+        fn stream_version(&self, stream: &StreamId) -> u64 {
+            self.cache.stream_version(stream)
+        }
+
+        fn len(&self) -> usize {
+            self.cache.len()
+        }
+
+        fn last_sequence(&self) -> Option<u64> {
+            self.cache.last_sequence()
+        }
+
+        fn read_range(&self, sequences: std::ops::Range<u64>) -> Vec<EventEnvelope> {
+            self.cache.read_range(sequences)
+        }
+
+        fn compact(&mut self, up_to_sequence: u64) -> io::Result<usize> {
+            let (merged, removed) =
+                compaction_changes(self.cache.read_envelopes(), up_to_sequence);
+            if removed.is_empty() {
+                return Ok(0);
+            }
+            Self::block_on(self.runtime.clone(), self.compact_async(&merged, &removed))?;
+            let outbox = std::mem::take(&mut self.cache.outbox);
+            self.cache = InMemoryEventStore::from_envelopes(compact_envelopes(
+                self.cache.read_envelopes(),
+                up_to_sequence,
+            ));
+            self.cache.outbox = outbox;
+            Ok(removed.len())
+        }
+
+        fn append_with_outbox(
+            &mut self,
+            envelope: EventEnvelope,
+            messages: Vec<OutboxMessage>,
+        ) -> io::Result<()> {
+            Self::block_on(
+                self.runtime.clone(),
+                self.append_with_outbox_async(envelope, messages),
+            )
+        }
+
+        fn append_batch(
+            &mut self,
+            envelopes: Vec<EventEnvelope>,
+            messages: Vec<OutboxMessage>,
+        ) -> io::Result<()> {
+            Self::block_on(self.runtime.clone(), self.append_batch_async(envelopes, messages))
+        }
+
+        fn read_outbox(&self) -> Vec<OutboxMessage> {
+            self.cache.read_outbox()
+        }
+
+        fn remove_from_outbox(&mut self, id: Uuid) -> io::Result<()> {
+            Self::block_on(self.runtime.clone(), self.remove_from_outbox_async(id))?;
+            self.cache.remove_from_outbox(id)
+        }
+    }
+
+    /// [`EventStore`] persisting events in an [SQLite](https://sqlite.org)
+    /// database with [rusqlite](https://docs.rs/rusqlite), a middle ground
+    /// between the embedded stores and [`PostgresEventStore`].
+    ///
+    /// Envelopes are stored as JSON in the `events` table, keyed by their
+    /// sequence with every stream's versions kept unique, so two links can't
+    /// be created with the same slug even by separate writers: the second
+    /// append fails with [`io::ErrorKind::AlreadyExists`]. Reads query the
+    /// database, reading a part of the log with
+    /// [`EventStore::read_range()`] only touches that part. Outbox messages
+    /// are stored in the `outbox` table, in the transaction of their event.
+    ///
+    /// The database runs in WAL mode, so reads don't wait for writes, and
+    /// every append is synced to disk before returning.
+    ///
+    /// ## Panics
+    ///
+    /// Reads panic if the database fails or was corrupted after it was
+    /// opened, as [`EventStore`] reads can't report errors.
+    #[cfg(feature = "sqlite")]
+    #[derive(Debug)]
+    pub struct SqliteEventStore {
+        connection: std::sync::Mutex<rusqlite::Connection>,
+    }
+
+    #[cfg(feature = "sqlite")]
+    impl SqliteEventStore {
+        /// Opens (or creates) the database at the given path, creating the
+        /// `events` and `outbox` tables if needed.
+        pub fn open(path: impl AsRef<std::path::Path>) -> rusqlite::Result<Self> {
+            Self::from_connection(rusqlite::Connection::open(path)?)
+        }
+
+        /// Creates a store in a private in-memory database, gone once the
+        /// store is dropped.
+        pub fn open_in_memory() -> rusqlite::Result<Self> {
+            Self::from_connection(rusqlite::Connection::open_in_memory()?)
+        }
+
+        /// Creates a store using an already opened connection, switching it
+        /// to WAL mode and creating the `events` and `outbox` tables if
+        /// needed.
+        pub fn from_connection(connection: rusqlite::Connection) -> rusqlite::Result<Self> {
+            //in-memory databases stay in the memory journal mode
+            connection.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+            connection.pragma_update(None, "synchronous", "FULL")?;
+            connection.execute_batch(
+                "CREATE TABLE IF NOT EXISTS events (
+                    sequence INTEGER PRIMARY KEY,
+                    stream TEXT NOT NULL,
+                    version INTEGER NOT NULL,
+                    envelope TEXT NOT NULL,
+                    UNIQUE (stream, version)
+                );
+                CREATE TABLE IF NOT EXISTS outbox (
+                    id TEXT PRIMARY KEY,
+                    sequence INTEGER NOT NULL,
+                    position INTEGER NOT NULL,
+                    message TEXT NOT NULL
+                );",
+            )?;
+            Ok(Self {
+                connection: std::sync::Mutex::new(connection),
+            })
+        }
+
+        fn lock(&self) -> std::sync::MutexGuard<'_, rusqlite::Connection> {
+            self.connection
+                .lock()
+                .unwrap_or_else(std::sync::PoisonError::into_inner)
+        }
+
+        //envelopes returned by the query, in the order of their sequence
+        fn query(&self, sql: &str, params: impl rusqlite::Params) -> Vec<EventEnvelope> {
+            let connection = self.lock();
+            let mut statement = connection
+                .prepare_cached(sql)
+                .expect("reading the event store failed");
+            statement
+                .query_map(params, |row| row.get::<_, String>(0))
+                .expect("reading the event store failed")
+                .map(|envelope| {
+                    let envelope = envelope.expect("reading the event store failed");
+                    serde_json::from_str(&envelope).expect("event store contains a malformed event")
+                })
+                .collect()
+        }
+
+        fn insert(
+            transaction: &rusqlite::Transaction<'_>,
+            envelope: &EventEnvelope,
+        ) -> io::Result<()> {
+            let inserted = transaction.execute(
+                "INSERT INTO events (sequence, stream, version, envelope) VALUES (?1, ?2, ?3, ?4)",
+                rusqlite::params![
+                    envelope.sequence as i64,
+                    &envelope.event.stream_id().0 .0,
+                    envelope.version as i64,
+                    serde_json::to_string(envelope)?,
+                ],
+            );
+            match inserted {
+                Ok(_) => Ok(()),
+                Err(rusqlite::Error::SqliteFailure(e, message))
+                    if e.code == rusqlite::ErrorCode::ConstraintViolation =>
+                {
+                    Err(io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        message.unwrap_or_else(|| e.to_string()),
+                    ))
+                }
+                Err(e) => Err(io::Error::other(e)),
+            }
+        }
+    }
+
+    #[cfg(feature = "sqlite")]
+    impl EventStore for SqliteEventStore {
+        fn append(&mut self, envelope: EventEnvelope) -> io::Result<()> {
+            self.append_with_outbox(envelope, Vec::new())
+        }
+
+        fn read_envelopes(&self) -> Vec<EventEnvelope> {
+            self.query("SELECT envelope FROM events ORDER BY sequence", [])
+        }
+
+        fn read_stream(&self, stream: &StreamId) -> Vec<EventEnvelope> {
+            self.query(
+                "SELECT envelope FROM events WHERE stream = ?1 ORDER BY version",
+                [&stream.0 .0],
+            )
+        }
+
+        fn stream_version(&self, stream: &StreamId) -> u64 {
+            self.lock()
+                .prepare_cached("SELECT MAX(version) FROM events WHERE stream = ?1")
+                .and_then(|mut statement| {
+                    statement.query_row([&stream.0 .0], |row| row.get::<_, Option<i64>>(0))
+                })
+                .expect("reading the event store failed")
+                .map_or(0, |version| version as u64)
+        }
+
+        fn len(&self) -> usize {
+            self.lock()
+                .prepare_cached("SELECT COUNT(*) FROM events")
+                .and_then(|mut statement| statement.query_row([], |row| row.get::<_, i64>(0)))
+                .expect("reading the event store failed") as usize
+        }
+
+        fn last_sequence(&self) -> Option<u64> {
+            self.lock()
+                .prepare_cached("SELECT MAX(sequence) FROM events")
+                .and_then(|mut statement| {
+                    statement.query_row([], |row| row.get::<_, Option<i64>>(0))
+                })
+                .expect("reading the event store failed")
+                .map(|sequence| sequence as u64)
+        }
+
+        fn read_range(&self, sequences: std::ops::Range<u64>) -> Vec<EventEnvelope> {
+            let start = i64::try_from(sequences.start).unwrap_or(i64::MAX);
+            let end = i64::try_from(sequences.end).unwrap_or(i64::MAX);
+            self.query(
+                "SELECT envelope FROM events WHERE sequence >= ?1 AND sequence < ?2
+                ORDER BY sequence",
+                [start, end],
+            )
+        }
+
+        fn compact(&mut self, up_to_sequence: u64) -> io::Result<usize> {
+            let (merged, removed) = compaction_changes(self.read_envelopes(), up_to_sequence);
+            if removed.is_empty() {
+                return Ok(0);
+            }
+            let mut connection = self.lock();
+            let transaction = connection.transaction().map_err(io::Error::other)?;
+            for envelope in &removed {
+                transaction
+                    .execute(
+                        "DELETE FROM events WHERE sequence = ?1",
+                        [envelope.sequence as i64],
+                    )
+                    .map_err(io::Error::other)?;
+            }
+            for envelope in &merged {
+                transaction
+                    .execute(
+                        "UPDATE events SET envelope = ?2 WHERE sequence = ?1",
+                        rusqlite::params![
+                            envelope.sequence as i64,
+                            serde_json::to_string(envelope)?
+                        ],
+                    )
+                    .map_err(io::Error::other)?;
+            }
+            transaction.commit().map_err(io::Error::other)?;
+            Ok(removed.len())
+        }
+
+        fn append_with_outbox(
+            &mut self,
+            envelope: EventEnvelope,
+            messages: Vec<OutboxMessage>,
+        ) -> io::Result<()> {
+            self.append_batch(vec![envelope], messages)
+        }
+
+        fn append_batch(
+            &mut self,
+            envelopes: Vec<EventEnvelope>,
+            messages: Vec<OutboxMessage>,
+        ) -> io::Result<()> {
+            let mut connection = self.lock();
+            let transaction = connection.transaction().map_err(io::Error::other)?;
+            for envelope in &envelopes {
+                Self::insert(&transaction, envelope)?;
+            }
+            for (message, position) in messages.iter().zip(0i64..) {
+                transaction
+                    .execute(
+                        "INSERT INTO outbox (id, sequence, position, message)
+                        VALUES (?1, ?2, ?3, ?4)",
+                        rusqlite::params![
+                            message.id.to_string(),
+                            message.sequence as i64,
+                            position,
+                            serde_json::to_string(message)?,
+                        ],
+                    )
+                    .map_err(io::Error::other)?;
+            }
+            transaction.commit().map_err(io::Error::other)
+        }
+
+        fn read_outbox(&self) -> Vec<OutboxMessage> {
+            let connection = self.lock();
+            let mut statement = connection
+                .prepare_cached("SELECT message FROM outbox ORDER BY sequence, position")
+                .expect("reading the event store failed");
+            statement
+                .query_map([], |row| row.get::<_, String>(0))
+                .expect("reading the event store failed")
+                .map(|message| {
+                    let message = message.expect("reading the event store failed");
+                    serde_json::from_str(&message)
+                        .expect("event store contains a malformed outbox message")
+                })
+                .collect()
+        }
+
+        fn remove_from_outbox(&mut self, id: Uuid) -> io::Result<()> {
+            self.lock()
+                .execute("DELETE FROM outbox WHERE id = ?1", [id.to_string()])
+                .map_err(io::Error::other)?;
+            Ok(())
+        }
+    }
+}
+
+/// Encodings of [`EventEnvelope`]s: human-readable JSON with the `serde`
+/// feature, and compact binary ones with the `bincode` and `rkyv` features
+/// for deployments where the size of the log and the speed of replaying it
+/// matter more.
+#[cfg(any(feature = "serde", feature = "rkyv"))]
+pub mod encoding {
+    use std::io::{self, Read, Write};
+
+    use super::EventEnvelope;
+
+    /// Maximum length of a frame of a log of [`EventEnvelope`]s, 16 MiB.
+    pub const MAX_FRAME_LEN: u32 = 16 << 20;
+
+    /// Encoding of single [`EventEnvelope`]s and of logs of them.
+    pub trait EventCodec {
+        /// Encodes the envelope.
+        ///
+        /// ## Errors
+        ///
+        /// Returns an error if the envelope can't be encoded.
+        fn encode(&self, envelope: &EventEnvelope) -> io::Result<Vec<u8>>;
+
+        /// Decodes an envelope encoded with [`EventCodec::encode()`].
+        ///
+        /// ## Errors
+        ///
+        /// Returns [`io::ErrorKind::InvalidData`] if the bytes are not an
+        /// encoded envelope.
+        fn decode(&self, bytes: &[u8]) -> io::Result<EventEnvelope>;
+
+        /// Writes the envelopes as a log of frames, every encoded envelope
+        /// preceded by its length as a little-endian `u32`.
+        ///
+        /// ## Errors
+        ///
+        /// Returns [`io::ErrorKind::InvalidInput`] if an encoded envelope is
+        /// longer than [`MAX_FRAME_LEN`], or an error if an envelope can't be
+        /// encoded or writing fails.
+        fn write_envelopes(
+            &self,
+            writer: &mut dyn Write,
+            envelopes: &[EventEnvelope],
+        ) -> io::Result<()> {
+            for envelope in envelopes {
+                let frame = self.encode(envelope)?;
+                let length = u32::try_from(frame.len())
+                    .ok()
+                    .filter(|length| *length <= MAX_FRAME_LEN)
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "frame too long"))?;
+                writer.write_all(&length.to_le_bytes())?;
+                writer.write_all(&frame)?;
+            }
+            writer.flush()
+        }
+
+        /// Reads a log written with [`EventCodec::write_envelopes()`] up to
+        /// the end of the reader.
+        ///
+        /// ## Errors
+        ///
+        /// Returns [`io::ErrorKind::UnexpectedEof`] if the last frame is cut
+        /// off, [`io::ErrorKind::InvalidData`] if a frame is longer than
+        /// [`MAX_FRAME_LEN`], or the error of decoding or reading.
+        fn read_envelopes(&self, reader: &mut dyn Read) -> io::Result<Vec<EventEnvelope>> {
+            let mut envelopes = Vec::new();
+            let mut frame = Vec::new();
+            loop {
+                let mut length = [0; 4];
+                if reader.read(&mut length[..1])? == 0 {
+                    return Ok(envelopes);
+                }
+                reader.read_exact(&mut length[1..])?;
+                let length = u32::from_le_bytes(length);
+                if length > MAX_FRAME_LEN {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "frame too long"));
+                }
+                //the buffer grows with the bytes actually read
+                frame.clear();
+                Read::take(&mut *reader, u64::from(length)).read_to_end(&mut frame)?;
+                if frame.len() < length as usize {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                envelopes.push(self.decode(&frame)?);
+            }
+        }
+    }
+
+    /// [`EventCodec`] encoding envelopes as JSON, the format of
+    /// [`UrlShortenerService::export_events_json()`].
+    ///
+    /// [`UrlShortenerService::export_events_json()`]:
+    /// super::UrlShortenerService::export_events_json
+    #[cfg(feature = "serde")]
+    #[derive(Debug, Clone, Copy, Default)]
+    pub struct JsonCodec;
+
+    #[cfg(feature = "serde")]
+    impl EventCodec for JsonCodec {
+        fn encode(&self, envelope: &EventEnvelope) -> io::Result<Vec<u8>> {
+            Ok(serde_json::to_vec(envelope)?)
+        }
+
+        fn decode(&self, bytes: &[u8]) -> io::Result<EventEnvelope> {
+            Ok(serde_json::from_slice(bytes)?)
+        }
+    }
+
+    /// [`EventCodec`] encoding envelopes with
+    /// [bincode](https://docs.rs/bincode), through the same `serde`
+    /// implementations as JSON, so every field is kept but none is named.
+    #[cfg(feature = "bincode")]
+    #[derive(Debug, Clone, Copy, Default)]
+    pub struct BincodeCodec;
+
+    #[cfg(feature = "bincode")]
+    impl EventCodec for BincodeCodec {
+        fn encode(&self, envelope: &EventEnvelope) -> io::Result<Vec<u8>> {
+            bincode::serialize(envelope).map_err(io::Error::other)
+        }
+
+        fn decode(&self, bytes: &[u8]) -> io::Result<EventEnvelope> {
+            bincode::deserialize(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        }
+    }
+
+    /// [`EventCodec`] encoding envelopes with [rkyv](https://docs.rs/rkyv),
+    /// whose archives are validated before they are decoded.
+    #[cfg(feature = "rkyv")]
+    #[derive(Debug, Clone, Copy, Default)]
+    pub struct RkyvCodec;
+
+    #[cfg(feature = "rkyv")]
+    impl EventCodec for RkyvCodec {
+        fn encode(&self, envelope: &EventEnvelope) -> io::Result<Vec<u8>> {
+            rkyv::to_bytes::<rkyv::rancor::Error>(envelope)
+                .map(|bytes| bytes.to_vec())
+                .map_err(io::Error::other)
+        }
+
+        //archives are read from aligned memory, which frames of a log aren't
+        fn decode(&self, bytes: &[u8]) -> io::Result<EventEnvelope> {
+            let mut aligned = rkyv::util::AlignedVec::<16>::with_capacity(bytes.len());
+            aligned.extend_from_slice(bytes);
+            rkyv::from_bytes::<EventEnvelope, rkyv::rancor::Error>(&aligned)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        }
+    }
+}
+
+/// Validation of the input of commands.
+pub mod validation {
+    use super::commands::Command;
+    use super::queries::LinkQueryHandler;
+    use super::{ShortenerError, Slug, Url};
+
+    /// Policy deciding whether a [`Url`] may be shortened.
+    pub trait UrlValidator {
+        /// Checks the given [`Url`].
+        ///
+        /// ## Errors
+        ///
+        /// Returns [`ShortenerError::InvalidUrl`] (or any other
+        /// [`ShortenerError`] fitting better) if the [`Url`] is rejected.
+        fn validate(&self, url: &Url) -> Result<(), ShortenerError>;
+    }
+
+    /// Default [`UrlValidator`] accepting absolute URLs with a host, one of
+    /// the allowed schemes and a limited length.
+    ///
+    /// Only `http` and `https` are allowed by default, others can be opted
+    /// into with [`DefaultUrlValidator::allow_scheme()`]. The
+    /// [`DefaultUrlValidator::BLOCKED_SCHEMES`] are rejected even if allowed,
+    /// as redirecting to them is an XSS hazard.
+    #[derive(Debug, Clone)]
+    pub struct DefaultUrlValidator {
+        /// Schemes the [`Url`] may use, in lowercase.
+        pub allowed_schemes: Vec<String>,
+
+        /// Maximum length of the [`Url`] in bytes.
+        pub max_length: usize,
+    }
+
+    impl Default for DefaultUrlValidator {
+        fn default() -> Self {
+            Self {
+                allowed_schemes: vec!["http".to_string(), "https".to_string()],
+                max_length: 2048,
+            }
+        }
+    }
+
+    impl DefaultUrlValidator {
+        /// Schemes which are never accepted, as they run code in or read
+        /// files of the browser of the visitor.
+        pub const BLOCKED_SCHEMES: &'static [&'static str] =
+            &["javascript", "vbscript", "data", "file", "blob"];
+
+        /// Allows [`Url`]s of the given scheme besides the already allowed
+        /// ones, e.g. `ftp`. The letter case and a trailing colon are
+        /// ignored.
+        pub fn allow_scheme(mut self, scheme: &str) -> Self {
+            let scheme = scheme.trim_end_matches(':').to_ascii_lowercase();
+            if !self.allowed_schemes.contains(&scheme) {
+                self.allowed_schemes.push(scheme);
+            }
+            self
+        }
+    }
+
+    impl UrlValidator for DefaultUrlValidator {
+        fn validate(&self, url: &Url) -> Result<(), ShortenerError> {
+            if url.0.len() > self.max_length || url.0.chars().any(char::is_whitespace) {
+                return Err(ShortenerError::InvalidUrl);
+            }
+            let parsed = url::Url::parse(&url.0).map_err(|_| ShortenerError::InvalidUrl)?;
+            let scheme = parsed.scheme();
+            if Self::BLOCKED_SCHEMES.contains(&scheme)
+                || !self.allowed_schemes.iter().any(|allowed| allowed == scheme)
+            {
+                return Err(ShortenerError::InvalidUrl);
+            }
+            match parsed.host_str() {
+                Some(host) if !host.is_empty() => Ok(()),
+                _ => Err(ShortenerError::InvalidUrl),
+            }
+        }
+    }
+
+    /// Policy of the domains links may point to, checked when links are
+    /// created and when their destinations change.
+    ///
+    /// A domain matches its subdomains too, e.g. blocking `example.com`
+    /// blocks `www.example.com`. Blocked domains are rejected even if
+    /// allowed.
+    ///
+    /// As a command [`Validator`], it rejects commands without recording an
+    /// [`Event::DestinationBlocked`].
+    ///
+    /// [`Event::DestinationBlocked`]: super::Event::DestinationBlocked
+    #[derive(Debug, Clone, Default, PartialEq, Eq)]
+    pub struct DomainPolicy {
+        /// Domains links may not point to, in lowercase.
+        pub blocked: Vec<String>,
+
+        /// Domains links may only point to, in lowercase, or [`None`] if
+        /// every domain which is not blocked is allowed.
+        pub allowed: Option<Vec<String>>,
+    }
+
+    impl DomainPolicy {
+        /// Blocks the given domain. The letter case and a trailing dot are
+        /// ignored.
+        pub fn block(mut self, domain: &str) -> Self {
+            self.blocked.push(domain_name(domain));
+            self
+        }
+
+        /// Allows the given domain, rejecting every domain which was not
+        /// allowed explicitly. The letter case and a trailing dot are
+        /// ignored.
+        pub fn allow_only(mut self, domain: &str) -> Self {
+            self.allowed.get_or_insert_with(Vec::new).push(domain_name(domain));
+            self
+        }
+
+        /// Returns the domain of the [`Url`] in lowercase if the policy
+        /// rejects it, [`None`] if the [`Url`] may be used. [`Url`]s without
+        /// a host are left to the [`UrlValidator`].
+        pub fn rejected_domain(&self, url: &Url) -> Option<String> {
+            let parsed = url::Url::parse(&url.0).ok()?;
+            let host = domain_name(parsed.host_str()?);
+            let matches = |domain: &String| {
+                host.strip_suffix(domain.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.ends_with('.'))
+            };
+            let allowed = self
+                .allowed
+                .as_ref()
+                .is_none_or(|allowed| allowed.iter().any(matches));
+            (!allowed || self.blocked.iter().any(matches)).then_some(host)
+        }
+    }
+
+    //lowercase domain without the trailing dot of a fully qualified name
+    fn domain_name(domain: &str) -> String {
+        domain.trim_end_matches('.').to_ascii_lowercase()
+    }
+
+    /// Policy rejecting destinations which would make redirects loop, failing
+    /// with [`ShortenerError::RedirectLoop`].
+    ///
+    /// Destinations under one of the base URLs the service is served under
+    /// are always rejected. Destinations on known URL shorteners are counted
+    /// as hops of a chain, following the URLs embedded in their query, e.g.
+    /// `https://t.co/x?u=https://bit.ly/y` chains through two shorteners.
+    /// Links of shorteners are not resolved, so longer chains hidden behind
+    /// them go unnoticed.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct LoopPolicy {
+        /// Domains of known URL shorteners, in lowercase. A domain matches its
+        /// subdomains too.
+        pub shorteners: Vec<String>,
+
+        /// Maximum number of known shorteners a destination may chain
+        /// through, unlimited if [`None`].
+        pub max_chain_depth: Option<usize>,
+    }
+
+    impl Default for LoopPolicy {
+        fn default() -> Self {
+            Self {
+                shorteners: Self::DEFAULT_SHORTENERS.iter().map(|&domain| domain.into()).collect(),
+                max_chain_depth: Some(Self::DEFAULT_MAX_CHAIN_DEPTH),
+            }
+        }
+    }
+
+    impl LoopPolicy {
+        /// Commonly used URL shorteners.
+        pub const DEFAULT_SHORTENERS: &'static [&'static str] = &[
+            "bit.ly", "buff.ly", "cutt.ly", "goo.gl", "is.gd", "ow.ly", "rebrand.ly", "t.co",
+            "t.ly", "tinyurl.com",
+        ];
+
+        /// Maximum chain depth unless configured otherwise.
+        pub const DEFAULT_MAX_CHAIN_DEPTH: usize = 2;
+
+        /// Checks the given destination of a link of the service served under
+        /// the given base URLs.
+        ///
+        /// ## Errors
+        ///
+        /// Returns [`ShortenerError::RedirectLoop`] if the destination points
+        /// back at one of the base URLs, directly or through shorteners, or
+        /// chains through more than [`LoopPolicy::max_chain_depth`]
+        /// shorteners.
+        pub fn check(&self, url: &Url, base_urls: &[Url]) -> Result<(), ShortenerError> {
+            let bases: Vec<url::Url> = base_urls
+                .iter()
+                .filter_map(|base| url::Url::parse(&base.0).ok())
+                .collect();
+            let mut depth = 0;
+            let mut next = url::Url::parse(&url.0).ok();
+            while let Some(current) = next.take() {
+                if bases.iter().any(|base| is_under(&current, base)) {
+                    return Err(ShortenerError::RedirectLoop);
+                }
+                let host = domain_name(current.host_str().unwrap_or_default());
+                let is_shortener = self.shorteners.iter().any(|domain| {
+                    host.strip_suffix(domain.as_str())
+                        .is_some_and(|rest| rest.is_empty() || rest.ends_with('.'))
+                });
+                if !is_shortener {
+                    break;
+                }
+                depth += 1;
+                if self.max_chain_depth.is_some_and(|max| depth > max) {
+                    return Err(ShortenerError::RedirectLoop);
+                }
+                next = current.query_pairs().find_map(|(_, value)| {
+                    url::Url::parse(&value).ok().filter(url::Url::has_host)
+                });
+            }
+            Ok(())
+        }
+    }
+
+    //whether the url is on the host of the base url, under its path
+    fn is_under(url: &url::Url, base: &url::Url) -> bool {
+        let prefix = base.path().trim_end_matches('/');
+        url.host_str().map(domain_name) == base.host_str().map(domain_name)
+            && url
+                .path()
+                .strip_prefix(prefix)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    }
+
+    /// Letter case custom [`Slug`]s must use.
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    #[cfg_attr(feature = "http", derive(utoipa::ToSchema))]
+    pub enum SlugCase {
+        /// Both lowercase and uppercase letters are allowed.
+        #[default]
+        Any,
+
+        /// Only lowercase letters are allowed.
+        Lowercase,
+
+        /// Only uppercase letters are allowed.
+        Uppercase,
+    }
+
+    /// Reason of rejecting a custom [`Slug`] by the [`SlugPolicy`].
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    #[cfg_attr(feature = "http", derive(utoipa::ToSchema))]
+    pub enum SlugViolation {
+        /// The [`Slug`] is shorter than the minimum length.
+        TooShort {
+            /// Minimum length of a [`Slug`].
+            min: usize,
+        },
+
+        /// The [`Slug`] is longer than the maximum length.
+        TooLong {
+            /// Maximum length of a [`Slug`].
+            max: usize,
+        },
+
+        /// The [`Slug`] contains a character which is not allowed.
+        InvalidCharacter(char),
+
+        /// The [`Slug`] starts or ends with a dash.
+        LeadingOrTrailingDash,
+
+        /// The [`Slug`] contains a letter of the wrong case.
+        WrongCase(SlugCase),
+    }
+
+    impl std::fmt::Display for SlugViolation {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                SlugViolation::TooShort { min } => write!(f, "shorter than {min} characters"),
+                SlugViolation::TooLong { max } => write!(f, "longer than {max} characters"),
+                SlugViolation::InvalidCharacter(c) => write!(f, "character {c:?} is not allowed"),
+                SlugViolation::LeadingOrTrailingDash => f.write_str("starts or ends with a dash"),
+                SlugViolation::WrongCase(SlugCase::Lowercase) => {
+                    f.write_str("only lowercase letters are allowed")
+                }
+                SlugViolation::WrongCase(SlugCase::Uppercase) => {
+                    f.write_str("only uppercase letters are allowed")
+                }
+                SlugViolation::WrongCase(SlugCase::Any) => f.write_str("letter of the wrong case"),
+            }
+        }
+    }
+
+    /// Rules custom [`Slug`]s must follow.
+    #[derive(Debug, Clone)]
+    pub struct SlugPolicy {
+        /// Minimum length of a [`Slug`] in characters.
+        pub min_length: usize,
+
+        /// Maximum length of a [`Slug`] in characters.
+        pub max_length: usize,
+
+        /// Characters allowed besides ASCII letters and digits.
+        pub extra_chars: String,
+
+        /// Whether a [`Slug`] may start or end with a dash.
+        pub allow_edge_dashes: bool,
+
+        /// Letter case of a [`Slug`].
+        pub case: SlugCase,
+    }
+
+    impl Default for SlugPolicy {
+        fn default() -> Self {
+            Self {
+                min_length: 1,
+                max_length: 64,
+                extra_chars: "-_".to_string(),
+                allow_edge_dashes: false,
+                case: SlugCase::Any,
+            }
+        }
+    }
+
+    impl SlugPolicy {
+        /// Checks the given [`Slug`] against the policy.
+        ///
+        /// ## Errors
+        ///
+        /// Returns the first [`SlugViolation`] found.
+        pub fn check(&self, slug: &Slug) -> Result<(), SlugViolation> {
+            let length = slug.0.chars().count();
+            if length < self.min_length {
+                return Err(SlugViolation::TooShort { min: self.min_length });
+            }
+            if length > self.max_length {
+                return Err(SlugViolation::TooLong { max: self.max_length });
+            }
+            if let Some(c) = slug
+                .0
+                .chars()
+                .find(|&c| !c.is_ascii_alphanumeric() && !self.extra_chars.contains(c))
+            {
+                return Err(SlugViolation::InvalidCharacter(c));
+            }
+            if !self.allow_edge_dashes && (slug.0.starts_with('-') || slug.0.ends_with('-')) {
+                return Err(SlugViolation::LeadingOrTrailingDash);
+            }
+            let wrong_case = match self.case {
+                SlugCase::Any => false,
+                SlugCase::Lowercase => slug.0.chars().any(|c| c.is_ascii_uppercase()),
+                SlugCase::Uppercase => slug.0.chars().any(|c| c.is_ascii_lowercase()),
+            };
+            if wrong_case {
+                return Err(SlugViolation::WrongCase(self.case));
+            }
+            Ok(())
+        }
+    }
+
+    /// Reason a [`Validator`] rejected a command, see
+    /// [`ShortenerError::Rejected`].
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    #[cfg_attr(feature = "http", derive(utoipa::ToSchema))]
+    pub struct Rejection {
+        /// Name of the rule which rejected the command in snake case, e.g.
+        /// `slug_policy`.
+        pub rule: String,
+
+        /// Human-readable reason of the rejection.
+        pub reason: String,
+    }
+
+    impl Rejection {
+        /// Creates a rejection by the given rule.
+        pub fn new(rule: impl Into<String>, reason: impl Into<String>) -> Self {
+            Self {
+                rule: rule.into(),
+                reason: reason.into(),
+            }
+        }
+    }
+
+    impl std::fmt::Display for Rejection {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{}: {}", self.rule, self.reason)
+        }
+    }
+
+    /// Check of commands executed before they are handled, composed with
+    /// other checks into a [`ValidatorChain`] per deployment.
+    ///
+    /// Implemented for closures with the same signature as
+    /// [`Validator::validate()`] as well.
+    pub trait Validator<C> {
+        /// Checks the command, with `queries` reading the current state of
+        /// the service, e.g. to count the links of an owner.
+        ///
+        /// ## Errors
+        ///
+        /// Returns the [`Rejection`] if the command may not be handled.
+        fn validate(&self, command: &C, queries: &dyn LinkQueryHandler) -> Result<(), Rejection>;
+    }
+
+    impl<C, F> Validator<C> for F
+    where
+        F: Fn(&C, &dyn LinkQueryHandler) -> Result<(), Rejection>,
+    {
+        fn validate(&self, command: &C, queries: &dyn LinkQueryHandler) -> Result<(), Rejection> {
+            self(command, queries)
+        }
+    }
+
+    /// [`Validator`]s run in the order they were added, stopping at the first
+    /// [`Rejection`]. An empty chain accepts every command.
+    pub struct ValidatorChain<C> {
+        validators: Vec<Box<dyn Validator<C> + Send + Sync>>,
+    }
+
+    impl<C> Default for ValidatorChain<C> {
+        fn default() -> Self {
+            Self {
+                validators: Vec::new(),
+            }
+        }
+    }
+
+    impl<C> ValidatorChain<C> {
+        /// Creates an empty chain.
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Adds the [`Validator`] at the end of the chain.
+        pub fn with(mut self, validator: impl Validator<C> + Send + Sync + 'static) -> Self {
+            self.validators.push(Box::new(validator));
+            self
+        }
+
+        /// Number of [`Validator`]s in the chain.
+        pub fn len(&self) -> usize {
+            self.validators.len()
+        }
+
+        /// Whether the chain has no [`Validator`]s.
+        pub fn is_empty(&self) -> bool {
+            self.validators.is_empty()
+        }
+    }
+
+    impl<C> Validator<C> for ValidatorChain<C> {
+        fn validate(&self, command: &C, queries: &dyn LinkQueryHandler) -> Result<(), Rejection> {
+            self.validators
+                .iter()
+                .try_for_each(|validator| validator.validate(command, queries))
+        }
+    }
+
+    /// [`Validator`] checking the destinations set by commands with the
+    /// [`UrlValidator`], e.g. a stricter one than the [`UrlValidator`] of
+    /// the service for commands of a single deployment.
+    #[derive(Debug, Clone, Default)]
+    pub struct UrlPolicy<V: UrlValidator>(pub V);
+
+    impl<V: UrlValidator> Validator<Command> for UrlPolicy<V> {
+        fn validate(&self, command: &Command, _: &dyn LinkQueryHandler) -> Result<(), Rejection> {
+            destinations(command).into_iter().try_for_each(|url| {
+                self.0
+                    .validate(url)
+                    .map_err(|error| Rejection::new("url_policy", format!("{}: {error}", url.0)))
+            })
+        }
+    }
+
+    impl Validator<Command> for SlugPolicy {
+        fn validate(&self, command: &Command, _: &dyn LinkQueryHandler) -> Result<(), Rejection> {
+            let slug = match command {
+                Command::CreateShortLink { slug, .. }
+                | Command::CreateOwnedLink { slug, .. }
+                | Command::CreateShortLinkWithOptions { slug, .. } => slug.as_ref(),
+                Command::RenameSlug { new: slug, .. }
+                | Command::AddAlias { alias: slug, .. }
+                | Command::CreateCampaign { campaign: slug, .. } => Some(slug),
+                _ => None,
+            };
+            slug.map_or(Ok(()), |slug| {
+                self.check(slug).map_err(|violation| {
+                    Rejection::new("slug_policy", format!("invalid slug `{}`: {violation}", slug.0))
+                })
+            })
+        }
+    }
+
+    impl Validator<Command> for DomainPolicy {
+        fn validate(&self, command: &Command, _: &dyn LinkQueryHandler) -> Result<(), Rejection> {
+            destinations(command).into_iter().try_for_each(|url| {
+                self.rejected_domain(url).map_or(Ok(()), |domain| {
+                    Err(Rejection::new("domain_policy", format!("domain {domain} is blocked")))
+                })
+            })
+        }
+    }
+
+    /// [`Validator`] limiting the number of links an owner may have, counting
+    /// the links listed by [`LinkQueryHandler::list_links_by_owner()`].
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct OwnerQuota {
+        /// Maximum number of links of a single owner.
+        pub max_links: usize,
+    }
+
+    impl Validator<Command> for OwnerQuota {
+        fn validate(
+            &self,
+            command: &Command,
+            queries: &dyn LinkQueryHandler,
+        ) -> Result<(), Rejection> {
+            let owner = match command {
+                Command::CreateOwnedLink { owner, .. } => owner,
+                Command::CreateShortLinkWithOptions { options, .. } => match &options.owner {
+                    Some(owner) => owner,
+                    None => return Ok(()),
+                },
+                _ => return Ok(()),
+            };
+            if queries.list_links_by_owner(owner.clone()).len() >= self.max_links {
+                let reason = format!("owner already has {} links", self.max_links);
+                return Err(Rejection::new("owner_quota", reason));
+            }
+            Ok(())
+        }
+    }
+
+    //destination URLs the command sets
+    fn destinations(command: &Command) -> Vec<&Url> {
+        match command {
+            Command::CreateShortLink { url, .. } | Command::CreateOwnedLink { url, .. } => {
+                vec![url]
+            }
+            Command::CreateShortLinkWithOptions { url, options, .. } => {
+                std::iter::once(url).chain(&options.fallback_url).collect()
+            }
+            Command::ChangeShortLink { new_url, .. } | Command::ChangeOwnedLink { new_url, .. } => {
+                vec![new_url]
+            }
+            Command::SetDestinations { destinations, .. } => {
+                destinations.iter().map(|(url, _)| url).collect()
+            }
+            Command::SetGeoRules { rules, .. } => rules.iter().map(|(_, url)| url).collect(),
+            Command::SetDeviceRule { url, .. } => url.iter().collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Normalization of URLs before they are stored.
+pub mod normalization {
+    use super::Url;
+
+    /// Policy rewriting a [`Url`] into its canonical form, so links differing
+    /// only in trivial formatting point to the same destination.
+    pub trait UrlNormalizer {
+        /// Returns the normalized form of the given, already validated,
+        /// [`Url`].
+        fn normalize(&self, url: &Url) -> Url;
+    }
+
+    /// Default [`UrlNormalizer`].
+    ///
+    /// Always lowercases the scheme and host, strips the default port and
+    /// decodes percent-encoded unreserved characters (uppercasing the hex
+    /// digits of the remaining ones). Stripping trailing slashes and tracking
+    /// query parameters is opt-in.
+    #[derive(Debug, Clone)]
+    pub struct DefaultUrlNormalizer {
+        /// Whether a trailing slash of the path should be removed.
+        pub strip_trailing_slash: bool,
+
+        /// Whether tracking query parameters should be removed.
+        pub strip_tracking_params: bool,
+
+        /// Names of the query parameters considered tracking ones. A name
+        /// ending with `*` matches every parameter starting with it.
+        pub tracking_params: Vec<String>,
+    }
+
+    impl Default for DefaultUrlNormalizer {
+        fn default() -> Self {
+            Self {
+                strip_trailing_slash: false,
+                strip_tracking_params: false,
+                tracking_params: ["utm_*", "gclid", "fbclid", "mc_cid", "mc_eid"]
+                    .into_iter()
+                    .map(String::from)
+                    .collect(),
+            }
+        }
+    }
+
+    impl DefaultUrlNormalizer {
+        fn is_tracking_param(&self, name: &str) -> bool {
+            self.tracking_params.iter().any(|param| match param.strip_suffix('*') {
+                Some(prefix) => name.starts_with(prefix),
+                None => name == param,
+            })
+        }
+    }
+
+    impl UrlNormalizer for DefaultUrlNormalizer {
+        fn normalize(&self, url: &Url) -> Url {
+            //parsing takes care of the scheme, host and default port
+            let Ok(mut parsed) = url::Url::parse(&url.0) else {
+                return url.clone();
+            };
+
+            let mut path = normalize_percent_encoding(parsed.path());
+            if self.strip_trailing_slash && path.len() > 1 && path.ends_with('/') {
+                path.pop();
+            }
+            parsed.set_path(&path);
+
+            let query = parsed.query().map(|query| {
+                query
+                    .split('&')
+                    .filter(|pair| {
+                        let name = pair.split('=').next().unwrap_or_default();
+                        !(self.strip_tracking_params && self.is_tracking_param(name))
+                    })
+                    .map(normalize_percent_encoding)
+                    .collect::<Vec<_>>()
+                    .join("&")
+            });
+            parsed.set_query(query.as_deref().filter(|query| !query.is_empty()));
+
+            let fragment = parsed.fragment().map(normalize_percent_encoding);
+            parsed.set_fragment(fragment.as_deref());
+
+            //a slash after the path belongs to the query or fragment
+            let ends_with_path = parsed.query().is_none() && parsed.fragment().is_none();
+            let mut normalized = String::from(parsed);
+            //root path can't be removed from the parsed url itself
+            if self.strip_trailing_slash && ends_with_path && normalized.ends_with('/') {
+                normalized.pop();
+            }
+            Url(normalized)
+        }
+    }
+
+    //decodes percent-encoded unreserved characters and uppercases the rest
+    fn normalize_percent_encoding(input: &str) -> String {
+        let bytes = input.as_bytes();
+        let mut output = String::with_capacity(input.len());
+        let mut i = 0;
+        while i < bytes.len() {
+            let decoded = (bytes[i] == b'%')
+                .then(|| input.get(i + 1..i + 3))
+                .flatten()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+            match decoded {
+                Some(byte) if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) => {
+                    output.push(char::from(byte));
+                    i += 3;
+                }
+                Some(byte) => {
+                    output.push_str(&format!("%{byte:02X}"));
+                    i += 3;
+                }
+                None => {
+                    let c = input[i..].chars().next().unwrap_or_default();
+                    output.push(c);
+                    i += c.len_utf8();
+                }
+            }
+        }
+        output
+    }
+}
+
+/// Generation of [`Slug`]s for links created without one.
+pub mod generation {
+    use rand::distributions::Alphanumeric;
+    use rand::{thread_rng, Rng};
+    use sha2::{Digest, Sha256};
+
+    use super::{Slug, Url};
+
+    /// Strategy of generating [`Slug`]s.
+    pub trait SlugGenerator {
+        /// Generates a [`Slug`] for a link to the given (normalized) [`Url`].
+        ///
+        /// `attempt` starts at `0` and is increased every time the previously
+        /// generated [`Slug`] turned out to be taken.
+        fn generate(&mut self, url: &Url, attempt: u32) -> Slug;
+    }
+
+    /// [`SlugGenerator`] producing random alphanumeric [`Slug`]s, the default
+    /// one.
+    #[derive(Debug, Clone)]
+    pub struct RandomAlphanumeric {
+        /// Length of generated [`Slug`]s.
+        pub len: usize,
+    }
+
+    impl Default for RandomAlphanumeric {
+        fn default() -> Self {
+            Self { len: 6 }
+        }
+    }
+
+    impl SlugGenerator for RandomAlphanumeric {
+        fn generate(&mut self, url: &Url, attempt: u32) -> Slug {
+            random_slug(&mut thread_rng(), self.len)
+        }
+    }
+
+    /// [`SlugGenerator`] producing random alphanumeric [`Slug`]s like
+    /// [`RandomAlphanumeric`], but drawn from the given random number
+    /// generator, e.g. a seeded one to reproduce [`Slug`] sequences.
+    #[derive(Debug, Clone)]
+    pub struct RngAlphanumeric<R> {
+        /// Length of generated [`Slug`]s.
+        pub len: usize,
+
+        /// Source of randomness.
+        pub rng: R,
+    }
+
+    impl<R: Rng> RngAlphanumeric<R> {
+        /// Creates the generator of [`Slug`]s of the default length.
+        pub fn new(rng: R) -> Self {
+            Self {
+                len: RandomAlphanumeric::default().len,
+                rng,
+            }
+        }
+    }
+
+    impl<R: Rng> SlugGenerator for RngAlphanumeric<R> {
+        fn generate(&mut self, url: &Url, attempt: u32) -> Slug {
+            random_slug(&mut self.rng, self.len)
+        }
+    }
+
+    fn random_slug(rng: &mut impl Rng, len: usize) -> Slug {
+        let random_slug: String = rng
+            .sample_iter(&Alphanumeric)
+            .take(len)
+            .map(char::from)
+            .collect();
+        Slug(random_slug)
+    }
+
+    /// [`SlugGenerator`] encoding an increasing counter in base62, producing
+    /// the shortest possible [`Slug`]s.
+    #[derive(Debug, Clone, Default)]
+    pub struct Base62Counter {
+        /// Value the next [`Slug`] is generated from.
+        pub next: u64,
+    }
+
+    impl SlugGenerator for Base62Counter {
+        fn generate(&mut self, url: &Url, attempt: u32) -> Slug {
+            let slug = Slug(to_base62(&self.next.to_be_bytes()));
+            self.next += 1;
+            slug
+        }
+    }
+
+    /// [`SlugGenerator`] deriving the [`Slug`] from the SHA-256 hash of the
+    /// [`Url`], so the same [`Url`] always gets the same [`Slug`].
+    ///
+    /// On a collision the [`Slug`] is extended by one more character of the
+    /// hash, and once the whole hash is used up the [`Url`] is hashed again
+    /// together with the attempt. Combine with [`CreatePolicy::ReuseExisting`] to get the existing
+    /// link back instead of an extended [`Slug`] for an already shortened
+    /// [`Url`].
+    ///
+    /// [`CreatePolicy::ReuseExisting`]: super::CreatePolicy::ReuseExisting
+    #[derive(Debug, Clone)]
+    pub struct HashOfUrl {
+        /// Length of generated [`Slug`]s before any extension.
+        pub len: usize,
+    }
+
+    impl Default for HashOfUrl {
+        fn default() -> Self {
+            Self { len: 7 }
+        }
+    }
+
+    impl SlugGenerator for HashOfUrl {
+        fn generate(&mut self, url: &Url, attempt: u32) -> Slug {
+            let hash = to_base62(&Sha256::digest(url.0.as_bytes()));
+            let len = self.len.saturating_add(attempt as usize);
+            if len <= hash.len() {
+                return Slug(hash[..len].to_string());
+            }
+            //the whole hash is used up, hash the url again with the attempt
+            let mut hasher = Sha256::new();
+            hasher.update(url.0.as_bytes());
+            hasher.update(attempt.to_be_bytes());
+            let hash = to_base62(&hasher.finalize());
+            Slug(hash[..self.len.min(hash.len())].to_string())
+        }
+    }
+
+    const BASE62: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+    //encodes big-endian number of any length
+    fn to_base62(bytes: &[u8]) -> String {
+        let mut number = bytes.to_vec();
+        let mut digits = Vec::new();
+        loop {
+            //divide the number by 62 in place, keeping the remainder
+            let mut remainder = 0u32;
+            for byte in number.iter_mut() {
+                let value = (remainder << 8) | u32::from(*byte);
+                *byte = (value / 62) as u8;
+                remainder = value % 62;
+            }
+            digits.push(BASE62[remainder as usize]);
+            if number.iter().all(|&byte| byte == 0) {
+                break;
+            }
+        }
+        digits.reverse();
+        String::from_utf8(digits).unwrap_or_default()
+    }
+}
+
+/// Source of the current time of the service.
+pub mod clock {
+    use std::sync::{Arc, Mutex, PoisonError};
+    use std::time::{Duration, SystemTime};
+
+    /// Source of the current time, used for event timestamps and everything
+    /// derived from them.
+    pub trait Clock {
+        /// Returns the current time.
+        fn now(&self) -> SystemTime;
+    }
+
+    /// [`Clock`] returning the system time.
+    #[derive(Debug, Clone, Copy, Default)]
+    pub struct SystemClock;
+
+    impl Clock for SystemClock {
+        fn now(&self) -> SystemTime {
+            SystemTime::now()
+        }
+    }
+
+    /// [`Clock`] standing still until set or advanced, for tests and
+    /// simulations. Clones share the time.
+    #[derive(Debug, Clone)]
+    pub struct MockClock {
+        now: Arc<Mutex<SystemTime>>,
+    }
+
+    impl MockClock {
+        /// Creates a clock stopped at the given time.
+        pub fn new(now: SystemTime) -> Self {
+            Self {
+                now: Arc::new(Mutex::new(now)),
+            }
+        }
+
+        /// Sets the current time.
+        pub fn set(&self, now: SystemTime) {
+            *self.now.lock().unwrap_or_else(PoisonError::into_inner) = now;
+        }
+
+        /// Moves the current time forward by the given duration.
+        pub fn advance(&self, duration: Duration) {
+            *self.now.lock().unwrap_or_else(PoisonError::into_inner) += duration;
+        }
+    }
+
+    impl Default for MockClock {
+        fn default() -> Self {
+            Self::new(SystemTime::UNIX_EPOCH)
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> SystemTime {
+            *self.now.lock().unwrap_or_else(PoisonError::into_inner)
+        }
+    }
+}
+
+/// Recognition of redirects made by crawlers and other bots, which are not
+/// counted as clicks.
+pub mod filtering {
+    use super::ClickContext;
+
+    /// Policy deciding whether a redirect was made by a bot. Redirects of
+    /// bots are recorded as [`Event::BotAccess`] and left out of the redirect
+    /// counts.
+    ///
+    /// [`Event::BotAccess`]: super::Event::BotAccess
+    pub trait ClickFilter {
+        /// Whether the redirect with the given [`ClickContext`] was made by a
+        /// bot.
+        fn is_bot(&self, context: &ClickContext) -> bool;
+    }
+
+    /// Default [`ClickFilter`] recognizing bots by well-known fragments of
+    /// their user agents, e.g. `Googlebot` or `facebookexternalhit`.
+    /// Redirects without a user agent are not considered made by bots.
+    #[derive(Debug, Clone)]
+    pub struct DefaultClickFilter {
+        /// Fragments of user agents of bots, in lowercase. A user agent
+        /// containing any of them, regardless of case, belongs to a bot.
+        pub patterns: Vec<String>,
+    }
+
+    impl DefaultClickFilter {
+        /// Fragments of user agents of bots used by default.
+        pub const DEFAULT_PATTERNS: [&'static str; 10] = [
+            "bot",
+            "crawler",
+            "spider",
+            "slurp",
+            "facebookexternalhit",
+            "embedly",
+            "preview",
+            "headlesschrome",
+            "curl/",
+            "wget/",
+        ];
+    }
+
+    impl Default for DefaultClickFilter {
+        fn default() -> Self {
+            Self {
+                patterns: Self::DEFAULT_PATTERNS.map(str::to_string).to_vec(),
+            }
+        }
+    }
+
+    impl ClickFilter for DefaultClickFilter {
+        fn is_bot(&self, context: &ClickContext) -> bool {
+            let Some(user_agent) = &context.user_agent else {
+                return false;
+            };
+            let user_agent = user_agent.to_lowercase();
+            self.patterns
+                .iter()
+                .any(|pattern| user_agent.contains(pattern.as_str()))
+        }
+    }
+
+    /// [`ClickFilter`] counting every redirect, bots included.
+    #[derive(Debug, Clone, Copy, Default)]
+    pub struct NoFilter;
+
+    impl ClickFilter for NoFilter {
+        fn is_bot(&self, _: &ClickContext) -> bool {
+            false
+        }
+    }
+}
+
+/// Checks of link destinations for phishing, malware and other threats.
+pub mod safety {
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::mpsc::{self, RecvTimeoutError};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::store::EventStore;
+    use super::{SharedUrlShortenerService, ShortenerError, Url};
+
+    /// Future returned by [`SafetyChecker::check()`].
+    pub type SafetyFuture<'a> = Pin<Box<dyn Future<Output = SafetyVerdict> + Send + 'a>>;
+
+    /// Outcome of checking a destination with a [`SafetyChecker`].
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub enum SafetyVerdict {
+        /// No threat is known for the destination.
+        Safe,
+
+        /// The destination is known to be malicious, with the reason shown to
+        /// visitors, e.g. `phishing`.
+        Flagged(String),
+    }
+
+    /// Policy checking destinations against threat lists, e.g. Google Safe
+    /// Browsing or an internal threat feed. Links with flagged destinations
+    /// are still created, recorded with [`Event::LinkFlagged`] and resolved
+    /// to an interstitial warning, see [`RedirectDecision::warning`].
+    ///
+    /// The service checks the destination when a link is created or its URL
+    /// changes. [`SharedUrlShortenerService`] checks it before taking the
+    /// write lock, awaiting the check in its HTTP and GraphQL handlers and
+    /// parking the calling thread otherwise, so slow checks don't hold back
+    /// other commands. Checkers needing an async runtime, e.g. for an HTTP
+    /// client, should spawn their work onto it and await its handle, and
+    /// can't be used synchronously from within a single-threaded runtime.
+    /// Checkers failing to reach their threat list should return
+    /// [`SafetyVerdict::Safe`] rather than hold back links.
+    ///
+    /// [`Event::LinkFlagged`]: super::Event::LinkFlagged
+    /// [`RedirectDecision::warning`]: super::RedirectDecision::warning
+    pub trait SafetyChecker {
+        /// Checks the given destination.
+        fn check<'a>(&'a self, url: &'a Url) -> SafetyFuture<'a>;
+    }
+
+    impl<F> SafetyChecker for F
+    where
+        F: Fn(&Url) -> SafetyVerdict,
+    {
+        fn check<'a>(&'a self, url: &'a Url) -> SafetyFuture<'a> {
+            Box::pin(std::future::ready(self(url)))
+        }
+    }
+
+    /// Checks the destinations of all the links of the service once, one
+    /// after another, with its [`SafetyChecker`], returning the number of
+    /// links newly flagged. Nothing is checked if the service has no
+    /// [`SafetyChecker`].
+    ///
+    /// ## Errors
+    ///
+    /// Returns [`ShortenerError::StorageFailure`] if a verdict could not be
+    /// recorded.
+    pub fn scan<S: EventStore>(
+        service: &SharedUrlShortenerService<S>,
+    ) -> Result<usize, ShortenerError> {
+        let (checker, destinations) = {
+            let service = service.read();
+            (service.safety_checker.clone(), service.destinations())
+        };
+        let Some(checker) = checker else {
+            return Ok(0);
+        };
+        let mut flagged = 0;
+        for (slug, url) in destinations {
+            let verdict = super::block_on(checker.check(&url));
+            match service.write().record_safety_verdict(slug, url, verdict) {
+                Ok(true) => flagged += 1,
+                //deleted while being checked
+                Ok(false) | Err(ShortenerError::SlugNotFound) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(flagged)
+    }
+
+    /// Background thread checking the destinations of a
+    /// [`SharedUrlShortenerService`] periodically with [`scan()`], so links
+    /// whose destinations turned malicious after they were created are
+    /// flagged too. Dropping it stops the thread.
+    #[derive(Debug)]
+    pub struct SafetyScanner {
+        stop: mpsc::Sender<()>,
+        thread: thread::JoinHandle<()>,
+    }
+
+    impl SafetyScanner {
+        /// Interval between scans unless configured otherwise.
+        pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+        /// Starts scanning the service every `interval`. Scans failing to
+        /// record their verdicts are retried by the next scan.
+        pub fn spawn<S>(service: SharedUrlShortenerService<S>, interval: Duration) -> Self
+        where
+            S: EventStore + Send + Sync + 'static,
+        {
+            let (stop, stopped) = mpsc::channel();
+            let thread = thread::spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    let _ = scan(&service);
+                }
+            });
+            Self { stop, thread }
+        }
+
+        /// Stops the thread, waiting for the running scan to finish.
+        pub fn stop(self) {
+            let _ = self.stop.send(());
+            self.thread
+                .join()
+                .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+        }
+    }
+}
+
+/// Current state of a single link in the read model.
+#[derive(Debug, Clone)]
+struct LinkState {
+    link: ShortLink,
+    redirects: u64,
+    //redirects of bots, not included in redirects
+    bot_redirects: u64,
+    //version of the link event stream
+    version: u64,
+    deleted: bool,
+    max_clicks: Option<u64>,
+    exhausted: bool,
+    disabled: bool,
+    //redirects by hours since the unix epoch
+    redirects_per_hour: BTreeMap<u64, u64>,
+    //redirects by days since the unix epoch
+    redirects_per_day: BTreeMap<u64, u64>,
+    //redirects by referrer and by user agent, if known
+    referrers: HashMap<String, u64>,
+    //redirects by host of the referrer, without www.
+    referrer_domains: HashMap<String, u64>,
+    user_agents: HashMap<String, u64>,
+    visitors: VisitorCounter,
+    //unknown for links restored from old snapshots
+    created_at: Option<SystemTime>,
+    last_accessed: Option<SystemTime>,
+    url_changes: u64,
+    owner: Option<OwnerId>,
+    //metadata of the current destination
+    metadata: Option<LinkMetadata>,
+    //weighted destinations overriding the url, if any
+    destinations: Vec<(Url, u32)>,
+    variant_redirects: HashMap<Url, u64>,
+    //destinations by uppercase country code, overriding all others
+    geo_rules: HashMap<String, Url>,
+    //redirects by uppercase country code, if known
+    countries: HashMap<String, u64>,
+    //destinations by device of the visitor, overriding weighted ones
+    device_rules: HashMap<Device, Url>,
+    redirect_policy: RedirectPolicy,
+    active_from: Option<SystemTime>,
+    active_until: Option<SystemTime>,
+    fallback_url: Option<Url>,
+    //whether the link was outside of its window when last recorded
+    inactive: bool,
+    campaign: Option<Slug>,
+    tags: BTreeSet<String>,
+    //reason and moment the destination was found unhealthy at
+    unhealthy: Option<(String, SystemTime)>,
+    //reason the destination was flagged by the safety checker
+    flagged: Option<String>,
+}
+
+//state of a single campaign
+#[derive(Debug, Clone)]
+struct CampaignState {
+    name: String,
+    version: u64,
+    links: BTreeSet<Slug>,
+}
+
+impl LinkState {
+    //whether the link may be redirected to
+    fn check_redirect(&self) -> Result<(), ShortenerError> {
+        if self.disabled {
+            return Err(ShortenerError::LinkDisabled);
+        }
+        if self.exhausted || self.max_clicks.is_some_and(|max| self.redirects >= max) {
+            return Err(ShortenerError::LinkExhausted);
+        }
+        Ok(())
+    }
+
+    fn stats(&self) -> Stats {
+        Stats {
+            link: self.link.clone(),
+            redirects: self.redirects,
+        }
+    }
+
+    //whether the time is within the activation window
+    fn is_active_at(&self, time: SystemTime) -> bool {
+        self.active_from.is_none_or(|from| from <= time)
+            && self.active_until.is_none_or(|until| time < until)
+    }
+
+    //destination of the country of the visitor, if there is a rule for it
+    fn geo_destination(&self, context: Option<&ClickContext>) -> Option<&Url> {
+        let country = context?.country.as_ref()?;
+        self.geo_rules.get(&country.to_ascii_uppercase())
+    }
+
+    //destination of the device of the visitor, if there is a rule for it
+    fn device_destination(&self, context: Option<&ClickContext>) -> Option<&Url> {
+        if self.device_rules.is_empty() {
+            return None;
+        }
+        let user_agent = context?.user_agent.as_ref()?;
+        self.device_rules.get(&Device::detect(user_agent))
+    }
+
+    //destination picked by weight, the url if there are no destinations
+    fn pick_destination(&self, rng: &mut impl Rng) -> Option<&Url> {
+        let total: u64 = self.destinations.iter().map(|(_, weight)| u64::from(*weight)).sum();
+        if total == 0 {
+            return None;
+        }
+        let mut pick = rng.gen_range(0..total);
+        for (url, weight) in &self.destinations {
+            match pick.checked_sub(u64::from(*weight)) {
+                Some(rest) => pick = rest,
+                None => return Some(url),
+            }
+        }
+        None
+    }
+}
+
+//hyperloglog estimating the number of distinct visitor hashes
+#[cfg(not(feature = "exact-visitors"))]
+#[derive(Debug, Clone, Default)]
+struct VisitorCounter {
+    //allocated on the first visitor
+    registers: Vec<u8>,
+}
+
+#[cfg(not(feature = "exact-visitors"))]
+impl VisitorCounter {
+    //2^10 registers
+    const INDEX_BITS: u32 = 10;
+
+    fn insert(&mut self, hash: u64) {
+        if self.registers.is_empty() {
+            self.registers = vec![0; 1 << Self::INDEX_BITS];
+        }
+        let index = (hash >> (64 - Self::INDEX_BITS)) as usize;
+        let rank = ((hash << Self::INDEX_BITS).leading_zeros() + 1)
+            .min(64 - Self::INDEX_BITS + 1) as u8;
+        self.registers[index] = self.registers[index].max(rank);
+    }
+
+    fn count(&self) -> u64 {
+        if self.registers.is_empty() {
+            return 0;
+        }
+        let m = self.registers.len() as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = self.registers.iter().map(|&rank| 2f64.powi(-i32::from(rank))).sum();
+        let estimate = alpha * m * m / sum;
+        let zeros = self.registers.iter().filter(|&&rank| rank == 0).count();
+        //small range correction
+        if estimate <= 2.5 * m && zeros > 0 {
+            return (m * (m / zeros as f64).ln()).round() as u64;
+        }
+        estimate.round() as u64
+    }
+
+    fn snapshot(&self) -> VisitorsSnapshot {
+        VisitorsSnapshot::Estimated(self.registers.clone())
+    }
+
+    fn from_snapshot(snapshot: VisitorsSnapshot) -> Self {
+        match snapshot {
+            VisitorsSnapshot::Estimated(registers) => Self { registers },
+            VisitorsSnapshot::Exact(hashes) => {
+                let mut counter = Self::default();
+                for hash in hashes {
+                    counter.insert(hash);
+                }
+                counter
+            }
+        }
+    }
+}
+
+//exact set of distinct visitor hashes
+#[cfg(feature = "exact-visitors")]
+#[derive(Debug, Clone, Default)]
+struct VisitorCounter {
+    hashes: HashSet<u64>,
+}
+
+#[cfg(feature = "exact-visitors")]
+impl VisitorCounter {
+    fn insert(&mut self, hash: u64) {
+        self.hashes.insert(hash);
+    }
+
+    fn count(&self) -> u64 {
+        self.hashes.len() as u64
+    }
+
+    fn snapshot(&self) -> VisitorsSnapshot {
+        let mut hashes: Vec<u64> = self.hashes.iter().copied().collect();
+        hashes.sort_unstable();
+        VisitorsSnapshot::Exact(hashes)
+    }
+
+    //hyperloglog registers can't be turned into hashes, so counting starts over
+    fn from_snapshot(snapshot: VisitorsSnapshot) -> Self {
+        match snapshot {
+            VisitorsSnapshot::Exact(hashes) => Self { hashes: hashes.into_iter().collect() },
+            VisitorsSnapshot::Estimated(_) => Self::default(),
+        }
+    }
+}
+
+//rfc 3339 timestamp in utc with seconds precision
+fn format_timestamp(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (date, secs) = (Date::from_days(secs / 86400), secs % 86400);
+    format!(
+        "{date}T{:02}:{:02}:{:02}Z",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+//field quoted if it contains a separator, quote or line break
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn json_string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+    escaped.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+//results of traced operations, recorded by their slug
+trait TracedResult {
+    fn slug(&self) -> &Slug;
+}
+
+impl TracedResult for ShortLink {
+    fn slug(&self) -> &Slug {
+        &self.slug
+    }
+}
+
+impl TracedResult for Stats {
+    fn slug(&self) -> &Slug {
+        &self.link.slug
+    }
+}
+
+//runs the operation inside a span recording its slug, outcome and duration
+#[cfg(feature = "tracing")]
+fn traced<T: TracedResult>(
+    operation: &'static str,
+    slug: Option<Slug>,
+    f: impl FnOnce() -> Result<T, ShortenerError>,
+) -> Result<T, ShortenerError> {
+    use tracing::field::Empty;
+
+    let span = tracing::info_span!(
+        "url_shortener",
+        operation,
+        slug = Empty,
+        outcome = Empty,
+        duration_us = Empty,
+    );
+    let _entered = span.enter();
+    let started = Instant::now();
+    let result = f();
+    let duration_us = started.elapsed().as_micros() as u64;
+    span.record("duration_us", duration_us);
+    let slug = result.as_ref().map(TracedResult::slug).ok().or(slug.as_ref());
+    if let Some(slug) = slug {
+        span.record("slug", slug.0.as_str());
+    }
+    match &result {
+        Ok(_) => {
+            span.record("outcome", "ok");
+            tracing::debug!("handled");
+        }
+        Err(e) => {
+            span.record("outcome", tracing::field::debug(e));
+            tracing::info!(error = ?e, "failed");
+        }
+    }
+    result
+}
+
+#[cfg(not(feature = "tracing"))]
+fn traced<T: TracedResult>(
+    operation: &'static str,
+    slug: Option<Slug>,
+    f: impl FnOnce() -> Result<T, ShortenerError>,
+) -> Result<T, ShortenerError> {
+    f()
+}
+
+//base url joined with the slug by a single slash
+fn render_short_url(base: &str, slug: &Slug) -> Url {
+    Url(format!("{}/{}", base.trim_end_matches('/'), slug.0))
+}
+
+//runs the future to completion on the current thread, parking it while the
+//future is pending
+fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    struct Unpark(thread::Thread);
+
+    impl std::task::Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    let waker = Waker::from(Arc::new(Unpark(thread::current())));
+    let mut context = Context::from_waker(&waker);
+    let mut future = std::pin::pin!(future);
+    loop {
+        match future.as_mut().poll(&mut context) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+//random version 4 uuid
+fn random_uuid() -> Uuid {
+    uuid::Builder::from_random_bytes(thread_rng().gen()).into_uuid()
+}
+
+//hours since the unix epoch
+fn hour_of(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / 3600
+}
+
+//events replayed between progress reports of rebuild_projections
+const REBUILD_PROGRESS_INTERVAL: usize = 1000;
+
+//logs shorter than this are replayed sequentially, as splitting them costs more
+#[cfg(feature = "parallel")]
+const PARALLEL_REPLAY_THRESHOLD: usize = 10_000;
+
+//tag without surrounding whitespace, if not blank and at most 64 characters
+fn normalize_tag(tag: &str) -> Result<String, ShortenerError> {
+    let tag = tag.trim();
+    if tag.is_empty() || tag.chars().count() > 64 {
+        return Err(ShortenerError::InvalidTag);
+    }
+    Ok(tag.to_string())
+}
+
+//lowercase host of the referrer url without the www. prefix, if it has one
+fn referrer_domain(referrer: &str) -> Option<String> {
+    let url = url::Url::parse(referrer).ok()?;
+    let host = url.host_str().filter(|host| !host.is_empty())?.to_lowercase();
+    Some(host.strip_prefix("www.").map(str::to_string).unwrap_or(host))
+}
+
+//counts sorted from the highest, ties by key
+fn breakdown(counts: &HashMap<String, u64>) -> Vec<(String, u64)> {
+    let mut breakdown: Vec<(String, u64)> = counts
+        .iter()
+        .map(|(key, count)| (key.clone(), *count))
+        .collect();
+    breakdown.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    breakdown
+}
+
+/// In-memory projection of the event log, updated as every event is recorded
+/// so commands and queries don't have to replay the whole log.
+#[derive(Debug, Default, Clone)]
+struct ReadModel {
+    links: HashMap<Slug, LinkState>,
+    //old slugs of renamed links which still forward to them
+    aliases: HashMap<Slug, Slug>,
+    //slugs of not deleted links by their url, in creation order
+    slugs_by_url: HashMap<Url, Vec<Slug>>,
+    //link slugs and aliases by their lowercase form
+    folded: HashMap<String, Vec<Slug>>,
+    //not deleted links, most redirected first
+    ranking: BTreeSet<(Reverse<u64>, Slug)>,
+    //counters over the whole log
+    totals: GlobalStats,
+    //whether lookups ignore the case of slugs
+    case_insensitive: bool,
+    //sequence of the next event, the number of events applied unless compacted
+    applied: usize,
+    campaigns: BTreeMap<Slug, CampaignState>,
+    //slugs of not deleted links by their tags
+    tags: BTreeMap<String, BTreeSet<Slug>>,
+    //rejected attempts to use a destination by its domain
+    blocked: HashMap<String, u64>,
+}
+
+impl ReadModel {
+    //apply single event to the projection
+    fn apply(&mut self, envelope: &EventEnvelope) {
+        self.applied = self.applied.max(envelope.sequence as usize + 1);
+        self.totals.events += 1;
+        match &envelope.event {
+            Event::LinkCreated {
+                slug,
+                url,
+                max_clicks,
+                owner,
+                active_from,
+                active_until,
+                fallback_url,
+                ..
+            } => {
+                self.index_url(url, slug);
+                self.fold(slug);
+                self.ranking.insert((Reverse(0), slug.clone()));
+                self.totals.links_created += 1;
+                let mut state = LinkState {
+                    link: ShortLink { slug: slug.clone(), url: url.clone() },
+                    redirects: 0,
+                    bot_redirects: 0,
+                    version: 0,
+                    deleted: false,
+                    max_clicks: *max_clicks,
+                    exhausted: false,
+                    disabled: false,
+                    redirects_per_hour: BTreeMap::new(),
+                    redirects_per_day: BTreeMap::new(),
+                    referrers: HashMap::new(),
+                    referrer_domains: HashMap::new(),
+                    user_agents: HashMap::new(),
+                    visitors: VisitorCounter::default(),
+                    created_at: Some(envelope.occurred_at),
+                    last_accessed: None,
+                    url_changes: 0,
+                    owner: owner.clone(),
+                    metadata: None,
+                    destinations: Vec::new(),
+                    variant_redirects: HashMap::new(),
+                    geo_rules: HashMap::new(),
+                    countries: HashMap::new(),
+                    device_rules: HashMap::new(),
+                    redirect_policy: RedirectPolicy::default(),
+                    active_from: *active_from,
+                    active_until: *active_until,
+                    fallback_url: fallback_url.clone(),
+                    inactive: false,
+                    campaign: None,
+                    tags: BTreeSet::new(),
+                    unhealthy: None,
+                    flagged: None,
+                };
+                state.inactive = !state.is_active_at(envelope.occurred_at);
+                self.links.insert(slug.clone(), state);
+            }
+            Event::ClicksAggregated { slug, count, folded, .. } => {
+                //the events folded by compaction count as recorded
+                self.totals.events += folded;
+                self.count_redirects(slug, *count, envelope.occurred_at);
+            }
+            Event::LinkAccessed { slug } | Event::LinkAccessedV2 { slug, .. } => {
+                let state = self.count_redirects(slug, 1, envelope.occurred_at);
+                if let Some(state) = state {
+                    if let Event::LinkAccessedV2 { context, .. } = &envelope.event {
+                        if let Some(referrer) = &context.referrer {
+                            *state.referrers.entry(referrer.clone()).or_default() += 1;
+                            if let Some(domain) = referrer_domain(referrer) {
+                                *state.referrer_domains.entry(domain).or_default() += 1;
+                            }
+                        }
+                        if let Some(user_agent) = &context.user_agent {
+                            *state.user_agents.entry(user_agent.clone()).or_default() += 1;
+                        }
+                        if let Some(country) = &context.country {
+                            *state.countries.entry(country.to_ascii_uppercase()).or_default() += 1;
+                        }
+                        if let Some(hash) = context.visitor_hash() {
+                            state.visitors.insert(hash);
+                        }
+                    }
+                }
+            }
+            Event::UrlChanged { slug, new_url } => {
+                self.totals.url_changes += 1;
+                if let Some(state) = self.links.get_mut(slug) {
+                    state.url_changes += 1;
+                    state.metadata = None;
+                    state.unhealthy = None;
+                    state.flagged = None;
+                    let old_url = std::mem::replace(&mut state.link.url, new_url.clone());
+                    self.unindex_url(&old_url, slug);
+                    self.index_url(new_url, slug);
+                }
+            }
+            Event::LinkDeleted { slug } => {
+                if let Some(state) = self.links.get_mut(slug) {
+                    state.deleted = true;
+                    self.ranking.remove(&(Reverse(state.redirects), slug.clone()));
+                    let url = state.link.url.clone();
+                    let tags = state.tags.clone();
+                    let campaign = state.campaign.take();
+                    self.unindex_url(&url, slug);
+                    for tag in &tags {
+                        self.untag(tag, slug);
+                    }
+                    if let Some(campaign) =
+                        campaign.and_then(|campaign| self.campaigns.get_mut(&campaign))
+                    {
+                        campaign.links.remove(slug);
+                    }
+                }
+            }
+            Event::LinkExhausted { slug } => {
+                if let Some(state) = self.links.get_mut(slug) {
+                    state.exhausted = true;
+                }
+            }
+            Event::LinkDisabled { slug } => {
+                if let Some(state) = self.links.get_mut(slug) {
+                    state.disabled = true;
+                }
+            }
+            Event::LinkEnabled { slug } => {
+                if let Some(state) = self.links.get_mut(slug) {
+                    state.disabled = false;
+                }
+            }
+            Event::LinkActivated { slug } => {
+                if let Some(state) = self.links.get_mut(slug) {
+                    state.inactive = false;
+                }
+            }
+            Event::LinkDeactivated { slug } => {
+                if let Some(state) = self.links.get_mut(slug) {
+                    state.inactive = true;
+                }
+            }
+            Event::LinkMetadataFetched { slug, metadata } => {
+                if let Some(state) = self.links.get_mut(slug) {
+                    state.metadata = Some(metadata.clone());
+                }
+            }
+            Event::DestinationsSet { slug, destinations } => {
+                if let Some(state) = self.links.get_mut(slug) {
+                    state.destinations = destinations.clone();
+                }
+            }
+            Event::VariantServed { slug, url } => {
+                if let Some(state) = self.links.get_mut(slug) {
+                    *state.variant_redirects.entry(url.clone()).or_default() += 1;
+                }
+            }
+            //the redirects reaching the milestone were counted already
+            Event::MilestoneReached { .. } => {}
+            Event::CampaignCreated { campaign, name } => {
+                self.campaigns.insert(campaign.clone(), CampaignState {
+                    name: name.clone(),
+                    version: envelope.version,
+                    links: BTreeSet::new(),
+                });
+                return;
+            }
+            Event::LinkAddedToCampaign { slug, campaign } => {
+                if let Some(state) = self.links.get_mut(slug) {
+                    let previous = state.campaign.replace(campaign.clone());
+                    if let Some(previous) =
+                        previous.and_then(|previous| self.campaigns.get_mut(&previous))
+                    {
+                        previous.links.remove(slug);
+                    }
+                    if let Some(campaign) = self.campaigns.get_mut(campaign) {
+                        campaign.links.insert(slug.clone());
+                    }
+                }
+            }
+            Event::LinkRemovedFromCampaign { slug, campaign } => {
+                if let Some(state) = self.links.get_mut(slug) {
+                    state.campaign = None;
+                }
+                if let Some(campaign) = self.campaigns.get_mut(campaign) {
+                    campaign.links.remove(slug);
+                }
+            }
+            Event::DestinationUnhealthy { slug, reason, .. } => {
+                if let Some(state) = self.links.get_mut(slug) {
+                    state.unhealthy = Some((reason.clone(), envelope.occurred_at));
+                }
+            }
+            Event::DestinationHealthy { slug, .. } => {
+                if let Some(state) = self.links.get_mut(slug) {
+                    state.unhealthy = None;
+                }
+            }
+            Event::LinkFlagged { slug, reason, .. } => {
+                if let Some(state) = self.links.get_mut(slug) {
+                    state.flagged = Some(reason.clone());
+                }
+            }
+            Event::AliasAdded { slug, alias } => {
+                self.aliases.insert(alias.clone(), slug.clone());
+                self.fold(alias);
+            }
+            Event::BotAccess { slug, .. } => {
+                if let Some(state) = self.links.get_mut(slug) {
+                    state.bot_redirects += 1;
+                }
+            }
+            Event::LinkTagged { slug, tag } => {
+                if let Some(state) = self.links.get_mut(slug) {
+                    state.tags.insert(tag.clone());
+                    if !state.deleted {
+                        self.tags.entry(tag.clone()).or_default().insert(slug.clone());
+                    }
+                }
+            }
+            Event::LinkUntagged { slug, tag } => {
+                if let Some(state) = self.links.get_mut(slug) {
+                    state.tags.remove(tag);
+                    self.untag(tag, slug);
+                }
+            }
+            //the requested slug may belong to an unrelated link
+            Event::DestinationBlocked { domain, .. } => {
+                *self.blocked.entry(domain.clone()).or_default() += 1;
+                return;
+            }
+            Event::GeoRulesSet { slug, rules } => {
+                if let Some(state) = self.links.get_mut(slug) {
+                    state.geo_rules = rules.iter().cloned().collect();
+                }
+            }
+            Event::RedirectPolicySet { slug, policy } => {
+                if let Some(state) = self.links.get_mut(slug) {
+                    state.redirect_policy = *policy;
+                }
+            }
+            Event::RedirectRuleSet { slug, device, url } => {
+                if let Some(state) = self.links.get_mut(slug) {
+                    match url {
+                        Some(url) => state.device_rules.insert(*device, url.clone()),
+                        None => state.device_rules.remove(device),
+                    };
+                }
+            }
+            Event::SlugRenamed { slug, new_slug, keep_alias } => {
+                if let Some(mut state) = self.links.remove(slug) {
+                    if let Some(slugs) = self.slugs_by_url.get_mut(&state.link.url) {
+                        for indexed in slugs.iter_mut().filter(|indexed| *indexed == slug) {
+                            *indexed = new_slug.clone();
+                        }
+                    }
+                    if self.ranking.remove(&(Reverse(state.redirects), slug.clone())) {
+                        self.ranking.insert((Reverse(state.redirects), new_slug.clone()));
+                    }
+                    let campaign = state.campaign.as_ref();
+                    if let Some(campaign) =
+                        campaign.and_then(|campaign| self.campaigns.get_mut(campaign))
+                    {
+                        campaign.links.remove(slug);
+                        campaign.links.insert(new_slug.clone());
+                    }
+                    for tag in &state.tags {
+                        if let Some(slugs) = self.tags.get_mut(tag) {
+                            if slugs.remove(slug) {
+                                slugs.insert(new_slug.clone());
+                            }
+                        }
+                    }
+                    state.link.slug = new_slug.clone();
+                    state.version = envelope.version;
+                    self.links.insert(new_slug.clone(), state);
+                    self.aliases.remove(new_slug);
+                    for target in self.aliases.values_mut() {
+                        if target == slug {
+                            *target = new_slug.clone();
+                        }
+                    }
+                    self.unfold(slug);
+                    self.fold(new_slug);
+                    if *keep_alias {
+                        self.aliases.insert(slug.clone(), new_slug.clone());
+                        self.fold(slug);
+                    }
+                }
+                return;
+            }
+        }
+        if let Some(state) = self.links.get_mut(envelope.event.slug()) {
+            state.version = envelope.version;
+        }
+    }
+
+    //redirects counted at the given time, returning the redirected link
+    fn count_redirects(
+        &mut self,
+        slug: &Slug,
+        count: u64,
+        at: SystemTime,
+    ) -> Option<&mut LinkState> {
+        self.totals.redirects += count;
+        let state = self.links.get_mut(slug)?;
+        if self.ranking.remove(&(Reverse(state.redirects), slug.clone())) {
+            self.ranking.insert((Reverse(state.redirects + count), slug.clone()));
+        }
+        state.redirects += count;
+        state.last_accessed = Some(at);
+        *state.redirects_per_hour.entry(hour_of(at)).or_default() += count;
+        *state.redirects_per_day.entry(hour_of(at) / 24).or_default() += count;
+        Some(state)
+    }
+
+    fn index_url(&mut self, url: &Url, slug: &Slug) {
+        self.slugs_by_url.entry(url.clone()).or_default().push(slug.clone());
+    }
+
+    fn untag(&mut self, tag: &str, slug: &Slug) {
+        if let Some(slugs) = self.tags.get_mut(tag) {
+            slugs.remove(slug);
+            if slugs.is_empty() {
+                self.tags.remove(tag);
+            }
+        }
+    }
+
+    fn unindex_url(&mut self, url: &Url, slug: &Slug) {
+        if let Some(slugs) = self.slugs_by_url.get_mut(url) {
+            slugs.retain(|indexed| indexed != slug);
+            if slugs.is_empty() {
+                self.slugs_by_url.remove(url);
+            }
+        }
+    }
+
+    //not deleted links pointing to the given url, in creation order
+    fn find_by_url(&self, url: &Url) -> impl Iterator<Item = &LinkState> {
+        self.slugs_by_url
+            .get(url)
+            .into_iter()
+            .flatten()
+            .filter_map(|slug| self.links.get(slug))
+    }
+
+    fn fold(&mut self, slug: &Slug) {
+        let slugs = self.folded.entry(slug.0.to_lowercase()).or_default();
+        if !slugs.contains(slug) {
+            slugs.push(slug.clone());
+        }
+    }
+
+    fn unfold(&mut self, slug: &Slug) {
+        let key = slug.0.to_lowercase();
+        if let Some(slugs) = self.folded.get_mut(&key) {
+            slugs.retain(|folded| folded != slug);
+            if slugs.is_empty() {
+                self.folded.remove(&key);
+            }
+        }
+    }
+
+    //slugs and aliases equal to the given one, ignoring case if enabled
+    fn candidates<'a>(&'a self, slug: &'a Slug) -> Box<dyn Iterator<Item = &'a Slug> + 'a> {
+        if self.case_insensitive {
+            Box::new(self.folded.get(&slug.0.to_lowercase()).into_iter().flatten())
+        } else {
+            Box::new(std::iter::once(slug))
+        }
+    }
+
+    fn is_live(&self, slug: &Slug) -> bool {
+        self.links.get(slug).is_some_and(|state| !state.deleted)
+    }
+
+    //slug of the link the given slug or alias points to, preferring exact
+    //matches and links which were not deleted
+    fn resolve<'a>(&'a self, slug: &'a Slug) -> &'a Slug {
+        let exact = self.aliases.get(slug).unwrap_or(slug);
+        if !self.case_insensitive || self.is_live(exact) {
+            return exact;
+        }
+        self.candidates(slug)
+            .map(|candidate| self.aliases.get(candidate).unwrap_or(candidate))
+            .find(|candidate| self.is_live(candidate))
+            .unwrap_or(exact)
+    }
+
+    //version of the link stream, 0 for links which do not exist yet
+    fn version(&self, slug: &Slug) -> u64 {
+        self.links
+            .get(self.resolve(slug))
+            .map_or(0, |state| state.version)
+    }
+
+    //version of the stream the event belongs to
+    fn stream_version(&self, event: &Event) -> u64 {
+        match event {
+            Event::CampaignCreated { campaign, .. } => {
+                self.campaigns.get(campaign).map_or(0, |state| state.version)
+            }
+            Event::DestinationBlocked { domain, .. } => {
+                self.blocked.get(domain).copied().unwrap_or_default()
+            }
+            event => self.version(event.slug()),
+        }
+    }
+
+    fn campaign(&self, id: &Slug) -> Result<Campaign, ShortenerError> {
+        let state = self.campaigns.get(id).ok_or(ShortenerError::CampaignNotFound)?;
+        Ok(Campaign {
+            id: id.clone(),
+            name: state.name.clone(),
+            links: state.links.iter().cloned().collect(),
+        })
+    }
+
+    //deleted links are reported as not found
+    fn get(&self, slug: &Slug) -> Result<&LinkState, ShortenerError> {
+        self.links
+            .get(self.resolve(slug))
+            .filter(|state| !state.deleted)
+            .ok_or(ShortenerError::SlugNotFound)
+    }
+
+    //whether the slug can't be used for a new link
+    fn is_taken(&self, slug: &Slug, allow_reuse: bool) -> bool {
+        self.candidates(slug).any(|candidate| {
+            self.aliases.contains_key(candidate)
+                || self
+                    .links
+                    .get(candidate)
+                    .is_some_and(|state| !state.deleted || !allow_reuse)
+        })
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        let mut links: Vec<LinkSnapshot> = self
+            .links
+            .values()
+            .map(|state| LinkSnapshot {
+                stats: state.stats(),
+                bot_redirects: state.bot_redirects,
+                version: state.version,
+                deleted: state.deleted,
+                max_clicks: state.max_clicks,
+                exhausted: state.exhausted,
+                disabled: state.disabled,
+                redirects_per_hour: state
+                    .redirects_per_hour
+                    .iter()
+                    .map(|(hour, redirects)| (*hour, *redirects))
+                    .collect(),
+                referrers: breakdown(&state.referrers),
+                user_agents: breakdown(&state.user_agents),
+                visitors: state.visitors.snapshot(),
+                created_at: state.created_at,
+                last_accessed: state.last_accessed,
+                url_changes: state.url_changes,
+                owner: state.owner.clone(),
+                metadata: state.metadata.clone(),
+                destinations: state.destinations.clone(),
+                variant_redirects: {
+                    let mut redirects: Vec<(Url, u64)> = state
+                        .variant_redirects
+                        .iter()
+                        .map(|(url, redirects)| (url.clone(), *redirects))
+                        .collect();
+                    redirects.sort_by(|a, b| a.0 .0.cmp(&b.0 .0));
+                    redirects
+                },
+                geo_rules: {
+                    let mut rules: Vec<(String, Url)> = state
+                        .geo_rules
+                        .iter()
+                        .map(|(country, url)| (country.clone(), url.clone()))
+                        .collect();
+                    rules.sort_by(|a, b| a.0.cmp(&b.0));
+                    rules
+                },
+                countries: breakdown(&state.countries),
+                device_rules: {
+                    let mut rules: Vec<(Device, Url)> = state
+                        .device_rules
+                        .iter()
+                        .map(|(device, url)| (*device, url.clone()))
+                        .collect();
+                    rules.sort_by_key(|rule| rule.0);
+                    rules
+                },
+                redirect_policy: state.redirect_policy,
+                active_from: state.active_from,
+                active_until: state.active_until,
+                fallback_url: state.fallback_url.clone(),
+                inactive: state.inactive,
+                tags: state.tags.iter().cloned().collect(),
+                unhealthy: state.unhealthy.clone(),
+                flagged: state.flagged.clone(),
+            })
+            .collect();
+        links.sort_by(|a, b| a.stats.link.slug.0.cmp(&b.stats.link.slug.0));
+        let mut aliases: Vec<(Slug, Slug)> = self
+            .aliases
+            .iter()
+            .map(|(alias, slug)| (alias.clone(), slug.clone()))
+            .collect();
+        aliases.sort_by(|a, b| a.0 .0.cmp(&b.0 .0));
+        Snapshot {
+            links,
+            aliases,
+            last_event_index: self.applied.checked_sub(1),
+            global_stats: self.totals.clone(),
+            campaigns: self
+                .campaigns
+                .keys()
+                .filter_map(|id| self.campaign(id).ok())
+                .collect(),
+            blocked_domains: breakdown(&self.blocked),
+        }
+    }
+
+    fn from_snapshot(snapshot: Snapshot) -> Self {
+        let links = snapshot
+            .links
+            .into_iter()
+            .map(|link| {
+                (link.stats.link.slug.clone(), LinkState {
+                    link: link.stats.link,
+                    redirects: link.stats.redirects,
+                    bot_redirects: link.bot_redirects,
+                    version: link.version,
+                    deleted: link.deleted,
+                    max_clicks: link.max_clicks,
+                    exhausted: link.exhausted,
+                    disabled: link.disabled,
+                    redirects_per_day: link.redirects_per_hour.iter().fold(
+                        BTreeMap::new(),
+                        |mut days, (hour, redirects)| {
+                            *days.entry(hour / 24).or_default() += redirects;
+                            days
+                        },
+                    ),
+                    redirects_per_hour: link.redirects_per_hour.into_iter().collect(),
+                    referrer_domains: link.referrers.iter().fold(
+                        HashMap::new(),
+                        |mut domains, (referrer, redirects)| {
+                            if let Some(domain) = referrer_domain(referrer) {
+                                *domains.entry(domain).or_default() += redirects;
+                            }
+                            domains
+                        },
+                    ),
+                    referrers: link.referrers.into_iter().collect(),
+                    user_agents: link.user_agents.into_iter().collect(),
+                    visitors: VisitorCounter::from_snapshot(link.visitors),
+                    created_at: link.created_at,
+                    last_accessed: link.last_accessed,
+                    url_changes: link.url_changes,
+                    owner: link.owner,
+                    metadata: link.metadata,
+                    destinations: link.destinations,
+                    variant_redirects: link.variant_redirects.into_iter().collect(),
+                    geo_rules: link.geo_rules.into_iter().collect(),
+                    countries: link.countries.into_iter().collect(),
+                    device_rules: link.device_rules.into_iter().collect(),
+                    redirect_policy: link.redirect_policy,
+                    active_from: link.active_from,
+                    active_until: link.active_until,
+                    fallback_url: link.fallback_url,
+                    inactive: link.inactive,
+                    campaign: None,
+                    tags: link.tags.into_iter().collect(),
+                    unhealthy: link.unhealthy,
+                    flagged: link.flagged,
+                })
+            })
+            .collect::<HashMap<Slug, LinkState>>();
+        let mut read_model = Self {
+            links,
+            aliases: snapshot.aliases.into_iter().collect(),
+            totals: snapshot.global_stats,
+            applied: snapshot.last_event_index.map_or(0, |index| index + 1),
+            blocked: snapshot.blocked_domains.into_iter().collect(),
+            ..Self::default()
+        };
+        read_model.reindex();
+        for campaign in snapshot.campaigns {
+            for slug in &campaign.links {
+                if let Some(state) = read_model.links.get_mut(slug) {
+                    state.campaign = Some(campaign.id.clone());
+                }
+            }
+            //the creation is the only event of a campaign stream
+            read_model.campaigns.insert(campaign.id, CampaignState {
+                name: campaign.name,
+                version: 1,
+                links: campaign.links.into_iter().collect(),
+            });
+        }
+        read_model
+    }
+
+    //indexes over the links and aliases rebuilt from scratch
+    fn reindex(&mut self) {
+        let mut live: Vec<&LinkState> =
+            self.links.values().filter(|state| !state.deleted).collect();
+        live.sort_by_key(|state| state.created_at);
+        let mut slugs_by_url: HashMap<Url, Vec<Slug>> = HashMap::new();
+        let mut tags: BTreeMap<String, BTreeSet<Slug>> = BTreeMap::new();
+        for state in &live {
+            let slug = &state.link.slug;
+            slugs_by_url.entry(state.link.url.clone()).or_default().push(slug.clone());
+            for tag in &state.tags {
+                tags.entry(tag.clone()).or_default().insert(slug.clone());
+            }
+        }
+        self.ranking = live
+            .iter()
+            .map(|state| (Reverse(state.redirects), state.link.slug.clone()))
+            .collect();
+        self.slugs_by_url = slugs_by_url;
+        self.tags = tags;
+        self.folded.clear();
+        let keys: Vec<Slug> = self.links.keys().chain(self.aliases.keys()).cloned().collect();
+        for slug in &keys {
+            self.fold(slug);
+        }
+    }
+}
+
+#[cfg(feature = "parallel")]
+impl ReadModel {
+    //replays the envelopes split into partitions of related streams on the
+    //rayon thread pool, reporting the number of applied events every
+    //REBUILD_PROGRESS_INTERVAL events of a partition
+    fn replay_parallel(
+        envelopes: &[EventEnvelope],
+        case_insensitive: bool,
+        replayed: impl Fn(usize) + Sync,
+    ) -> Self {
+        use rayon::prelude::*;
+
+        let partitions = partition(envelopes, rayon::current_num_threads() * 4);
+        let read_models: Vec<ReadModel> = partitions
+            .into_par_iter()
+            .map(|partition| {
+                let mut read_model = ReadModel { case_insensitive, ..ReadModel::default() };
+                for (i, envelope) in partition.iter().enumerate() {
+                    read_model.apply(envelope);
+                    if (i + 1) % REBUILD_PROGRESS_INTERVAL == 0 {
+                        replayed(REBUILD_PROGRESS_INTERVAL);
+                    }
+                }
+                replayed(partition.len() % REBUILD_PROGRESS_INTERVAL);
+                read_model
+            })
+            .collect();
+        let mut merged = ReadModel { case_insensitive, ..ReadModel::default() };
+        for read_model in read_models {
+            merged.links.extend(read_model.links);
+            merged.aliases.extend(read_model.aliases);
+            merged.campaigns.extend(read_model.campaigns);
+            merged.blocked.extend(read_model.blocked);
+            merged.totals.links_created += read_model.totals.links_created;
+            merged.totals.redirects += read_model.totals.redirects;
+            merged.totals.url_changes += read_model.totals.url_changes;
+            merged.totals.events += read_model.totals.events;
+            merged.applied = merged.applied.max(read_model.applied);
+        }
+        merged.reindex();
+        merged
+    }
+}
+
+//envelopes split into at most `count` partitions in log order, keeping the
+//streams of renamed links and of campaigns together with their links
+#[cfg(feature = "parallel")]
+fn partition(envelopes: &[EventEnvelope], count: usize) -> Vec<Vec<&EventEnvelope>> {
+    let mut streams = StreamSets::default();
+    let keys: Vec<usize> = envelopes
+        .iter()
+        .map(|envelope| {
+            let key = streams.key(envelope.event.stream_id());
+            match &envelope.event {
+                Event::SlugRenamed { new_slug, .. } => {
+                    let renamed = streams.key(store::StreamId(new_slug.clone()));
+                    streams.union(key, renamed);
+                }
+                Event::LinkAddedToCampaign { campaign, .. }
+                | Event::LinkRemovedFromCampaign { campaign, .. } => {
+                    let campaign = Slug(format!("campaign:{}", campaign.0));
+                    let campaign = streams.key(store::StreamId(campaign));
+                    streams.union(key, campaign);
+                }
+                _ => {}
+            }
+            key
+        })
+        .collect();
+    let count = count.max(1);
+    let mut partitions = vec![Vec::new(); count];
+    for (envelope, key) in envelopes.iter().zip(keys) {
+        partitions[streams.root(key) % count].push(envelope);
+    }
+    partitions
+}
+
+//disjoint sets of streams whose events depend on each other
+#[cfg(feature = "parallel")]
+#[derive(Default)]
+struct StreamSets {
+    keys: HashMap<store::StreamId, usize>,
+    parents: Vec<usize>,
+}
+
+#[cfg(feature = "parallel")]
+impl StreamSets {
+    fn key(&mut self, stream: store::StreamId) -> usize {
+        let next = self.parents.len();
+        let key = *self.keys.entry(stream).or_insert(next);
+        if key == next {
+            self.parents.push(next);
+        }
+        key
+    }
+
+    fn root(&mut self, mut key: usize) -> usize {
+        while self.parents[key] != key {
+            self.parents[key] = self.parents[self.parents[key]];
+            key = self.parents[key];
+        }
+        key
+    }
+
+    fn union(&mut self, a: usize, b: usize) {
+        let (a, b) = (self.root(a), self.root(b));
+        self.parents[a.max(b)] = a.min(b);
+    }
+}
+
+/// Point-in-time copy of the service read model, used to restore the service
+/// without replaying the whole event log.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Snapshot {
+    /// State of every [`ShortLink`] known when the snapshot was taken.
+    pub links: Vec<LinkSnapshot>,
+
+    /// Old [`Slug`]s of renamed links paired with the [`Slug`]s they forward
+    /// to.
+    pub aliases: Vec<(Slug, Slug)>,
+
+    /// Index of the last event applied to the read model, or [`None`] if no
+    /// event was applied yet. It is the sequence of that event, which
+    /// differs from its position in the log once the log was compacted.
+    pub last_event_index: Option<usize>,
+
+    /// [`GlobalStats`] at the time the snapshot was taken.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub global_stats: GlobalStats,
+
+    /// Every [`Campaign`] known when the snapshot was taken.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub campaigns: Vec<Campaign>,
+
+    /// Domains of blocked destinations paired with the number of rejected
+    /// attempts, see [`HealthQueryHandler::blocked_domains()`].
+    ///
+    /// [`HealthQueryHandler::blocked_domains()`]: queries::HealthQueryHandler::blocked_domains
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub blocked_domains: Vec<(String, u64)>,
+}
+
+/// Statistics of the event log of the service, returned by
+/// [`UrlShortenerService::store_stats()`] to decide when to compact the log
+/// or take a [`Snapshot`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StoreStats {
+    /// Number of events in the [`EventStore`].
+    pub events: usize,
+
+    /// Approximate number of bytes the events take in memory, including
+    /// their strings.
+    pub approximate_bytes: usize,
+
+    /// Number of distinct [`Slug`]s of links the events belong to, including
+    /// deleted links and slugs of renamed links.
+    pub slugs: usize,
+
+    /// Time since the last [`Snapshot`] was taken with
+    /// [`UrlShortenerService::snapshot()`], or [`None`] if none was taken
+    /// by this instance of the service.
+    pub snapshot_age: Option<Duration>,
+
+    /// Number of events recorded since the last [`Snapshot`], or all of them
+    /// if none was taken.
+    pub events_since_snapshot: usize,
+
+    /// Number of events [`EventStore::compact()`] would remove when
+    /// compacting the whole log.
+    pub compaction_candidates: usize,
+}
+
+/// Complete state of the service in a portable form, written by
+/// [`UrlShortenerService::export_state()`] and read back by
+/// [`UrlShortenerService::import_state()`] to migrate or back up an instance.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StateExport {
+    /// Version of the format, [`StateExport::FORMAT_VERSION`] when written
+    /// by this release.
+    pub format_version: u32,
+
+    /// [`Snapshot`] of the read model taken together with the event log, if
+    /// requested. It spares replaying the whole log on import.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub snapshot: Option<Snapshot>,
+
+    /// The whole event log, in the order it was recorded.
+    pub events: Vec<EventEnvelope>,
+}
+
+impl StateExport {
+    /// Current version of the format.
+    pub const FORMAT_VERSION: u32 = 1;
+}
+
+/// Result of [`UrlShortenerService::verify_consistency()`].
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ConsistencyReport {
+    /// Number of checked events.
+    pub events: usize,
+
+    /// Every broken invariant, in the order it was found.
+    pub violations: Vec<ConsistencyViolation>,
+}
+
+impl ConsistencyReport {
+    /// Whether no invariant is broken.
+    pub fn is_consistent(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
+
+/// Invariant of the event log or the read model found broken by
+/// [`UrlShortenerService::verify_consistency()`].
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ConsistencyViolation {
+    /// The sequence of the event is not greater than the one of the event
+    /// before it.
+    SequenceOutOfOrder {
+        sequence: u64,
+        previous: u64,
+    },
+
+    /// The version of the event is not greater than the one of the event
+    /// before it in the same stream.
+    VersionOutOfOrder {
+        sequence: u64,
+        version: u64,
+        previous: u64,
+    },
+
+    /// The event creates, or renames a link to, a [`Slug`] of a link which
+    /// is not deleted.
+    DuplicateSlug {
+        sequence: u64,
+        slug: Slug,
+    },
+
+    /// The event belongs to a link which was never created or was deleted.
+    UnknownSlug {
+        sequence: u64,
+        slug: Slug,
+    },
+
+    /// The event creates a [`Campaign`] which already exists.
+    DuplicateCampaign {
+        sequence: u64,
+        campaign: Slug,
+    },
+
+    /// The event refers to a [`Campaign`] which was never created.
+    UnknownCampaign {
+        sequence: u64,
+        campaign: Slug,
+    },
+
+    /// The redirects of the link counted by the service differ from the
+    /// ones counted by replaying the log.
+    RedirectCountMismatch {
+        slug: Slug,
+        recorded: u64,
+        replayed: u64,
+    },
+
+    /// The [`GlobalStats`] of the service differ from the ones counted by
+    /// replaying the log.
+    GlobalStatsMismatch {
+        recorded: GlobalStats,
+        replayed: GlobalStats,
+    },
+}
+
+impl fmt::Display for ConsistencyViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConsistencyViolation::SequenceOutOfOrder { sequence, previous } => {
+                write!(f, "event {sequence} recorded after event {previous}")
+            }
+            ConsistencyViolation::VersionOutOfOrder { sequence, version, previous } => {
+                write!(f, "event {sequence} has version {version} after version {previous}")
+            }
+            ConsistencyViolation::DuplicateSlug { sequence, slug } => {
+                write!(f, "event {sequence} reuses slug {} of an existing link", slug.0)
+            }
+            ConsistencyViolation::UnknownSlug { sequence, slug } => {
+                write!(f, "event {sequence} belongs to unknown link {}", slug.0)
+            }
+            ConsistencyViolation::DuplicateCampaign { sequence, campaign } => {
+                write!(f, "event {sequence} creates existing campaign {}", campaign.0)
+            }
+            ConsistencyViolation::UnknownCampaign { sequence, campaign } => {
+                write!(f, "event {sequence} refers to unknown campaign {}", campaign.0)
+            }
+            ConsistencyViolation::RedirectCountMismatch { slug, recorded, replayed } => write!(
+                f,
+                "link {} has {recorded} redirects but {replayed} were replayed",
+                slug.0
+            ),
+            ConsistencyViolation::GlobalStatsMismatch { recorded, replayed } => {
+                write!(f, "global stats {recorded:?} differ from replayed {replayed:?}")
+            }
+        }
+    }
+}
+
+/// State of a single [`ShortLink`] captured in a [`Snapshot`].
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LinkSnapshot {
+    /// [`Stats`] of the [`ShortLink`].
+    pub stats: Stats,
+
+    /// Count of redirects of bots, see [`DetailedStats::bot_redirects`].
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub bot_redirects: u64,
+
+    /// Version of the event stream of the [`ShortLink`].
+    pub version: u64,
+
+    /// Whether the [`ShortLink`] was deleted.
+    pub deleted: bool,
+
+    /// Maximum number of redirects of the [`ShortLink`].
+    pub max_clicks: Option<u64>,
+
+    /// Whether the [`ShortLink`] reached its maximum number of redirects.
+    pub exhausted: bool,
+
+    /// Whether the [`ShortLink`] was disabled.
+    pub disabled: bool,
+
+    /// Redirects of the [`ShortLink`] by hours since the Unix epoch.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub redirects_per_hour: Vec<(u64, u64)>,
+
+    /// Redirects of the [`ShortLink`] by referrer.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub referrers: Vec<(String, u64)>,
+
+    /// Redirects of the [`ShortLink`] by user agent.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub user_agents: Vec<(String, u64)>,
+
+    /// Unique visitors of the [`ShortLink`].
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub visitors: VisitorsSnapshot,
+
+    /// Moment the [`ShortLink`] was created at, if known.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub created_at: Option<SystemTime>,
+
+    /// Moment of the last redirect of the [`ShortLink`].
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub last_accessed: Option<SystemTime>,
+
+    /// Count of changes of the original URL of the [`ShortLink`].
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub url_changes: u64,
+
+    /// Owner of the [`ShortLink`].
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub owner: Option<OwnerId>,
+
+    /// Metadata of the destination page of the [`ShortLink`].
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub metadata: Option<LinkMetadata>,
+
+    /// Weighted destinations of the [`ShortLink`].
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub destinations: Vec<(Url, u32)>,
+
+    /// Redirects of the [`ShortLink`] by destination.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub variant_redirects: Vec<(Url, u64)>,
+
+    /// Destinations of the [`ShortLink`] by country code.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub geo_rules: Vec<(String, Url)>,
+
+    /// Redirects of the [`ShortLink`] by country code, most common first.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub countries: Vec<(String, u64)>,
+
+    /// Destinations of the [`ShortLink`] by device.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub device_rules: Vec<(Device, Url)>,
+
+    /// How redirects of the [`ShortLink`] are issued.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub redirect_policy: RedirectPolicy,
+
+    /// Moment the [`ShortLink`] starts redirecting at.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub active_from: Option<SystemTime>,
+
+    /// Moment the [`ShortLink`] stops redirecting at.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub active_until: Option<SystemTime>,
+
+    /// URL redirected to outside of the activation window.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub fallback_url: Option<Url>,
+
+    /// Whether the [`ShortLink`] was outside of its activation window when
+    /// last accessed.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub inactive: bool,
+
+    /// Tags attached to the [`ShortLink`], in alphabetical order.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub tags: Vec<String>,
+
+    /// Reason and moment the destination of the [`ShortLink`] was found
+    /// unhealthy at, if it was by the last check.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub unhealthy: Option<(String, SystemTime)>,
+
+    /// Why the destination of the [`ShortLink`] was flagged by the
+    /// [`SafetyChecker`], if it was since it was last changed.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub flagged: Option<String>,
+}
+
+/// What happens when a link is created without a [`Slug`] for a [`Url`]
+/// which was already shortened.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CreatePolicy {
+    /// A new link with a fresh [`Slug`] is always created.
+    #[default]
+    AlwaysCreate,
+
+    /// The existing, still redirecting, [`ShortLink`] is returned instead.
+    ReuseExisting,
+}
+
+/// Configuration of the [`UrlShortenerService`].
+#[derive(Debug, Clone, Default)]
+pub struct ServiceConfig {
+    /// Policy of creating links for already shortened URLs.
+    pub create_policy: CreatePolicy,
+
+    /// Whether slugs of deleted links may be used again for new links.
+    pub allow_slug_reuse: bool,
+
+    /// Whether old slugs of renamed links keep forwarding to them.
+    pub keep_renamed_slugs: bool,
+
+    /// Slugs which can't be used for links, e.g. because they collide with
+    /// HTTP routes of the service. See [`ServiceConfig::DEFAULT_RESERVED_SLUGS`].
+    pub reserved_slugs: HashSet<Slug>,
+
+    /// Rules custom slugs must follow.
+    pub slug_policy: validation::SlugPolicy,
+
+    /// Whether slugs differing only in letter case refer to the same link,
+    /// both for lookups and uniqueness checks. Links keep the casing they
+    /// were created with.
+    pub case_insensitive_slugs: bool,
+
+    /// How many times a generated slug colliding with an existing one is
+    /// regenerated.
+    pub slug_retry_policy: SlugRetryPolicy,
+
+    /// Limit of link creations per caller, unlimited if [`None`].
+    pub rate_limit_policy: Option<RateLimitPolicy>,
+
+    /// Public URL the short links are served under, e.g. `https://sho.rt`,
+    /// [`ServiceConfig::DEFAULT_BASE_URL`] if [`None`].
+    pub base_url: Option<Url>,
+
+    /// Other public URLs the short links are served under too, e.g. branded
+    /// domains.
+    pub alternate_base_urls: Vec<Url>,
+
+    /// Redirect counts at which [`Event::MilestoneReached`] is recorded, e.g.
+    /// [`ServiceConfig::DEFAULT_CLICK_MILESTONES`].
+    pub click_milestones: Vec<u64>,
+
+    /// Whether redirects without a [`ClickContext`] are counted in memory
+    /// and recorded later as a single [`Event::ClicksAggregated`] per link,
+    /// see [`UrlShortenerService::flush()`]. Buffered clicks are not part
+    /// of the stats until flushed, and are lost if the service stops before.
+    pub buffer_clicks: bool,
+
+    /// Domains links may or may not point to. Rejected attempts are recorded
+    /// as [`Event::DestinationBlocked`] for abuse monitoring.
+    pub domain_policy: validation::DomainPolicy,
+
+    /// Policy rejecting destinations pointing back at the
+    /// [`ServiceConfig::base_url`] and the
+    /// [`ServiceConfig::alternate_base_urls`], or chaining through too many
+    /// other URL shorteners.
+    pub loop_policy: validation::LoopPolicy,
+}
+
+/// Token bucket limit of link creations per caller.
+///
+/// Every caller starts with `capacity` tokens and every creation takes one,
+/// failing with [`ShortenerError::RateLimited`] when there are none left.
+/// Tokens are refilled at the rate of one per `refill_interval`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimitPolicy {
+    /// Maximum number of tokens, i.e. creations in a burst.
+    pub capacity: u32,
+
+    /// Time in which a single token is refilled.
+    pub refill_interval: Duration,
+}
+
+impl Default for RateLimitPolicy {
+    fn default() -> Self {
+        Self {
+            capacity: 10,
+            refill_interval: Duration::from_secs(6),
+        }
+    }
+}
+
+//token buckets of callers
+#[derive(Debug, Default)]
+struct RateLimiter {
+    buckets: HashMap<String, (f64, SystemTime)>,
+}
+
+impl RateLimiter {
+    //buckets kept before full ones are dropped
+    const MAX_BUCKETS: usize = 1024;
+
+    //take a token of the caller if there is one
+    fn try_acquire(&mut self, policy: &RateLimitPolicy, key: &str, now: SystemTime) -> bool {
+        let capacity = f64::from(policy.capacity);
+        let refill = |tokens: f64, updated: SystemTime| {
+            let elapsed = now.duration_since(updated).unwrap_or_default();
+            let interval = policy.refill_interval.as_secs_f64();
+            if interval > 0.0 {
+                (tokens + elapsed.as_secs_f64() / interval).min(capacity)
+            } else {
+                capacity
+            }
+        };
+        if self.buckets.len() >= Self::MAX_BUCKETS {
+            self.buckets
+                .retain(|_, (tokens, updated)| refill(*tokens, *updated) < capacity);
+        }
+        let (tokens, updated) = self
+            .buckets
+            .entry(key.to_string())
+            .or_insert((capacity, now));
+        *tokens = refill(*tokens, *updated);
+        *updated = now;
+        if *tokens < 1.0 {
+            return false;
+        }
+        *tokens -= 1.0;
+        true
+    }
+}
+
+/// Policy of regenerating slugs which collide with existing ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlugRetryPolicy {
+    /// Maximum number of generated slugs tried before giving up with
+    /// [`ShortenerError::SlugAlreadyInUse`].
+    pub max_attempts: u32,
+}
+
+impl Default for SlugRetryPolicy {
+    fn default() -> Self {
+        Self { max_attempts: 10 }
+    }
+}
+
+impl ServiceConfig {
+    /// Commonly reserved slugs, worth reserving when the service is mounted on
+    /// a domain root.
+    pub const DEFAULT_RESERVED_SLUGS: &'static [&'static str] =
+        &["api", "admin", "health", "links", "metrics", "stats"];
+
+    /// Base URL of short links unless configured otherwise.
+    pub const DEFAULT_BASE_URL: &'static str = "http://localhost";
+
+    /// Commonly used redirect counts worth alerting owners of links about.
+    pub const DEFAULT_CLICK_MILESTONES: &'static [u64] = &[100, 1_000, 10_000];
+
+    /// Reserves the given slugs in addition to already reserved ones.
+    pub fn reserve_slugs<I, T>(mut self, slugs: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<String>,
+    {
+        self.reserved_slugs
+            .extend(slugs.into_iter().map(|slug| Slug(slug.into())));
+        self
+    }
+}
+
+/// Builder of a [`UrlShortenerService`], created with
+/// [`UrlShortenerService::builder()`]. Everything not set is left at the
+/// defaults of [`UrlShortenerService::new()`].
+pub struct ServiceBuilder<S: EventStore = InMemoryEventStore> {
+    store: S,
+    config: ServiceConfig,
+    url_validator: Option<Box<dyn UrlValidator + Send + Sync>>,
+    url_normalizer: Option<Box<dyn UrlNormalizer + Send + Sync>>,
+    slug_generator: Option<Box<dyn SlugGenerator + Send + Sync>>,
+    clock: Option<Box<dyn Clock + Send + Sync>>,
+    click_filter: Option<Box<dyn ClickFilter + Send + Sync>>,
+    audit_log: Option<Box<dyn AuditLog + Send + Sync>>,
+    safety_checker: Option<Arc<dyn SafetyChecker + Send + Sync>>,
+    command_validators: ValidatorChain<commands::Command>,
+    outbox: Option<OutboxMapper>,
+    #[cfg(feature = "metrics")]
+    metrics: Option<metrics::ServiceMetrics>,
+}
+
+impl<S: EventStore> ServiceBuilder<S> {
+    /// Uses the given [`EventStore`], the read model is rebuilt from the
+    /// events it already contains.
+    pub fn store<T: EventStore>(self, store: T) -> ServiceBuilder<T> {
+        ServiceBuilder {
+            store,
+            config: self.config,
+            url_validator: self.url_validator,
+            url_normalizer: self.url_normalizer,
+            slug_generator: self.slug_generator,
+            clock: self.clock,
+            click_filter: self.click_filter,
+            audit_log: self.audit_log,
+            safety_checker: self.safety_checker,
+            command_validators: self.command_validators,
+            outbox: self.outbox,
+            #[cfg(feature = "metrics")]
+            metrics: self.metrics,
+        }
+    }
+
+    /// Replaces the whole [`ServiceConfig`], including the settings made by
+    /// other methods of the builder so far.
+    pub fn config(mut self, config: ServiceConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Sets the rules custom slugs must follow.
+    pub fn slug_policy(mut self, policy: validation::SlugPolicy) -> Self {
+        self.config.slug_policy = policy;
+        self
+    }
+
+    /// Sets the domains links may or may not point to, see
+    /// [`ServiceConfig::domain_policy`].
+    pub fn domain_policy(mut self, policy: validation::DomainPolicy) -> Self {
+        self.config.domain_policy = policy;
+        self
+    }
+
+    /// Sets the policy rejecting destinations which would make redirects
+    /// loop, see [`ServiceConfig::loop_policy`].
+    pub fn loop_policy(mut self, policy: validation::LoopPolicy) -> Self {
+        self.config.loop_policy = policy;
+        self
+    }
+
+    /// Sets the [`SlugRetryPolicy`] applied when a generated [`Slug`] is
+    /// already taken.
+    pub fn slug_retry_policy(mut self, policy: SlugRetryPolicy) -> Self {
+        self.config.slug_retry_policy = policy;
+        self
+    }
+
+    /// Limits link creations per caller with the given [`RateLimitPolicy`].
+    pub fn rate_limit_policy(mut self, policy: RateLimitPolicy) -> Self {
+        self.config.rate_limit_policy = Some(policy);
+        self
+    }
+
+    /// Sets the public URL the short links are served under.
+    pub fn base_url(mut self, base_url: Url) -> Self {
+        self.config.base_url = Some(base_url);
+        self
+    }
+
+    /// Sets the redirect counts at which [`Event::MilestoneReached`] is
+    /// recorded.
+    pub fn click_milestones(mut self, milestones: impl IntoIterator<Item = u64>) -> Self {
+        self.config.click_milestones = milestones.into_iter().collect();
+        self
+    }
+
+    /// Buffers redirects without a [`ClickContext`], see
+    /// [`ServiceConfig::buffer_clicks`].
+    pub fn buffer_clicks(mut self, buffer_clicks: bool) -> Self {
+        self.config.buffer_clicks = buffer_clicks;
+        self
+    }
+
+    /// Sets the [`UrlValidator`] checking URLs of created and changed links.
+    pub fn url_validator(mut self, validator: impl UrlValidator + Send + Sync + 'static) -> Self {
+        self.url_validator = Some(Box::new(validator));
+        self
+    }
+
+    /// Sets the [`UrlNormalizer`] applied to URLs of created and changed
+    /// links.
+    pub fn url_normalizer(
+        mut self,
+        normalizer: impl UrlNormalizer + Send + Sync + 'static,
+    ) -> Self {
+        self.url_normalizer = Some(Box::new(normalizer));
+        self
+    }
+
+    /// Sets the [`SlugGenerator`] used for links created without a [`Slug`].
+    pub fn generator(mut self, generator: impl SlugGenerator + Send + Sync + 'static) -> Self {
+        self.slug_generator = Some(Box::new(generator));
+        self
+    }
+
+    /// Makes random [`Slug`]s drawn from the given random number generator,
+    /// see [`UrlShortenerService::with_rng()`].
+    pub fn rng(self, rng: impl Rng + Send + Sync + 'static) -> Self {
+        self.generator(generation::RngAlphanumeric::new(rng))
+    }
+
+    /// Sets the [`Clock`] events are timestamped with.
+    pub fn clock(mut self, clock: impl Clock + Send + Sync + 'static) -> Self {
+        self.clock = Some(Box::new(clock));
+        self
+    }
+
+    /// Sets the [`ClickFilter`] recognizing redirects made by bots.
+    pub fn click_filter(mut self, filter: impl ClickFilter + Send + Sync + 'static) -> Self {
+        self.click_filter = Some(Box::new(filter));
+        self
+    }
+
+    /// Sets the [`AuditLog`] executed commands are recorded in.
+    pub fn audit_log(mut self, log: impl AuditLog + Send + Sync + 'static) -> Self {
+        self.audit_log = Some(Box::new(log));
+        self
+    }
+
+    /// Sets the [`SafetyChecker`] destinations of created and changed links
+    /// are checked with.
+    pub fn safety_checker(mut self, checker: impl SafetyChecker + Send + Sync + 'static) -> Self {
+        self.safety_checker = Some(Arc::new(checker));
+        self
+    }
+
+    /// Adds a [`Validator`] of the commands executed with
+    /// [`UrlShortenerService::execute()`], after the ones added so far.
+    pub fn command_validator(
+        mut self,
+        validator: impl Validator<commands::Command> + Send + Sync + 'static,
+    ) -> Self {
+        self.command_validators = self.command_validators.with(validator);
+        self
+    }
+
+    /// Sets the function deriving the integration events stored in the
+    /// outbox together with every recorded event, see
+    /// [`UrlShortenerService::with_outbox()`].
+    pub fn outbox(
+        mut self,
+        mapper: impl Fn(&EventEnvelope) -> Vec<OutboxMessage> + Send + Sync + 'static,
+    ) -> Self {
+        self.outbox = Some(Box::new(mapper));
+        self
+    }
+
+    /// Records [`ServiceMetrics`] of the service.
+    ///
+    /// [`ServiceMetrics`]: metrics::ServiceMetrics
+    #[cfg(feature = "metrics")]
+    pub fn metrics(mut self, metrics: metrics::ServiceMetrics) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Builds the service, replaying the events of the [`EventStore`].
+    pub fn build(self) -> UrlShortenerService<S> {
+        let mut service = UrlShortenerService::with_store(self.store).with_config(self.config);
+        if let Some(validator) = self.url_validator {
+            service.url_validator = validator;
+        }
+        if let Some(normalizer) = self.url_normalizer {
+            service.url_normalizer = normalizer;
+        }
+        if let Some(generator) = self.slug_generator {
+            service.slug_generator = generator;
+        }
+        if let Some(clock) = self.clock {
+            service.clock = clock;
+        }
+        if let Some(filter) = self.click_filter {
+            service.click_filter = filter;
+        }
+        if let Some(log) = self.audit_log {
+            service.audit_log = log;
+        }
+        if let Some(checker) = self.safety_checker {
+            service.safety_checker = Some(checker);
+        }
+        service.command_validators = self.command_validators;
+        service.outbox = self.outbox;
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = self.metrics {
+            service = service.with_metrics(metrics);
+        }
+        service
+    }
+}
+
+//hooks called with a batch of events before and after it is committed
+type BeforeCommitHook = Box<dyn Fn(&[EventEnvelope]) -> Result<(), Rejection> + Send + Sync>;
+type AfterCommitHook = Box<dyn FnMut(&[EventEnvelope]) + Send + Sync>;
+
+//integration events derived from a recorded event, see with_outbox
+type OutboxMapper = Box<dyn Fn(&EventEnvelope) -> Vec<OutboxMessage> + Send + Sync>;
+
+//events staged to be committed together, and the state to restore if they
+//are not committed
+struct Batch {
+    staged: Vec<EventEnvelope>,
+    read_model: ReadModel,
+    pending_clicks: HashMap<Slug, u64>,
+}
+
+/// CQRS and Event Sourcing-based service implementation
+pub struct UrlShortenerService<S: EventStore = InMemoryEventStore> {
+    store: S,
+    read_model: ReadModel,
+    config: ServiceConfig,
+    url_validator: Box<dyn UrlValidator + Send + Sync>,
+    url_normalizer: Box<dyn UrlNormalizer + Send + Sync>,
+    slug_generator: Box<dyn SlugGenerator + Send + Sync>,
+    listeners: Vec<Box<dyn EventListener + Send + Sync>>,
+    projections: Vec<Box<dyn Projection + Send + Sync>>,
+    subscriptions: Subscriptions,
+    before_commit: Vec<BeforeCommitHook>,
+    after_commit: Vec<AfterCommitHook>,
+    //events of the command being executed, committed together
+    batch: Option<Batch>,
+    clock: Box<dyn Clock + Send + Sync>,
+    click_filter: Box<dyn ClickFilter + Send + Sync>,
+    rate_limiter: RateLimiter,
+    //correlation and causation ids of the events being recorded
+    correlation: Option<(Uuid, Uuid)>,
+    audit_log: Box<dyn AuditLog + Send + Sync>,
+    //ids of the events recorded by the command being executed
+    recorded: Option<Vec<Uuid>>,
+    //redirects not recorded yet, see ServiceConfig::buffer_clicks, counted
+    //under a shared borrow by SharedUrlShortenerService
+    pending_clicks: Mutex<HashMap<Slug, u64>>,
+    //index of the shard the service is and the number of shards, see sharding
+    shard: Option<(usize, usize)>,
+    //moment the last snapshot was taken at and the number of events it covers
+    last_snapshot: Mutex<Option<(SystemTime, usize)>>,
+    safety_checker: Option<Arc<dyn SafetyChecker + Send + Sync>>,
+    //destination checked by SharedUrlShortenerService before taking the
+    //write lock, and its verdict
+    checked_destination: Option<(Url, SafetyVerdict)>,
+    command_validators: ValidatorChain<commands::Command>,
+    outbox: Option<OutboxMapper>,
+    #[cfg(feature = "metrics")]
+    metrics: Option<metrics::ServiceMetrics>,
+}
+
+impl UrlShortenerService {
+    /// Creates a new instance of the service
+    pub fn new() -> Self {
+        Self::with_store(InMemoryEventStore::new())
+    }
+
+    /// Returns a [`ServiceBuilder`] to configure the service in one place,
+    /// e.g. with another [`EventStore`] or [`Clock`]. Building it without
+    /// any settings is the same as [`UrlShortenerService::new()`].
+    pub fn builder() -> ServiceBuilder {
+        ServiceBuilder {
+            store: InMemoryEventStore::new(),
+            config: ServiceConfig::default(),
+            url_validator: None,
+            url_normalizer: None,
+            slug_generator: None,
+            clock: None,
+            click_filter: None,
+            audit_log: None,
+            safety_checker: None,
+            command_validators: ValidatorChain::new(),
+            outbox: None,
+            #[cfg(feature = "metrics")]
+            metrics: None,
+        }
+    }
+
+    /// Restores the service from a [`Snapshot`] and the events recorded after
+    /// it was taken, without replaying the whole event log.
+    ///
+    /// Only `remaining_events` end up in the event store of the restored
+    /// service.
+    pub fn from_snapshot(snapshot: Snapshot, remaining_events: Vec<EventEnvelope>) -> Self {
+        let mut read_model = ReadModel::from_snapshot(snapshot);
+        for envelope in &remaining_events {
+            read_model.apply(envelope);
+        }
+        Self::from_parts(InMemoryEventStore::from_envelopes(remaining_events), read_model)
+    }
+
+    /// Rehydrates the service from an event log previously produced by
+    /// [`UrlShortenerService::export_events_json()`].
+    #[cfg(feature = "serde")]
+    pub fn import_events_json(json: &str) -> Result<Self, serde_json::Error> {
+        let envelopes: Vec<EventEnvelope> = serde_json::from_str(json)?;
+        Ok(Self::with_store(InMemoryEventStore::from_envelopes(envelopes)))
+    }
+
+    /// Rehydrates the service from an event log previously written by
+    /// [`UrlShortenerService::export_events()`] with the same codec.
+    ///
+    /// ## Errors
+    ///
+    /// Returns an error if reading or decoding the log fails.
+    #[cfg(any(feature = "serde", feature = "rkyv"))]
+    pub fn import_events(
+        mut reader: impl io::Read,
+        codec: &impl encoding::EventCodec,
+    ) -> io::Result<Self> {
+        let envelopes = codec.read_envelopes(&mut reader)?;
+        Ok(Self::with_store(InMemoryEventStore::from_envelopes(envelopes)))
+    }
+}
+
+impl<S: EventStore> UrlShortenerService<S> {
+    /// Creates a new instance of the service on top of the given
+    /// [`EventStore`], rebuilding the read model from the events it already
+    /// contains.
+    pub fn with_store(store: S) -> Self {
+        let read_model = Self::replay(&store.read_envelopes());
+        Self::from_parts(store, read_model)
+    }
+
+    /// Restores the service from a [`StateExport`] written by
+    /// [`UrlShortenerService::export_state()`], appending the exported event
+    /// log to the given empty [`EventStore`]. The read model is restored from
+    /// the exported [`Snapshot`] and the events recorded after it if there is
+    /// one, or by replaying the whole log otherwise.
+    ///
+    /// ## Errors
+    ///
+    /// Returns an error if reading from the `reader` fails, the export is
+    /// malformed or of an unsupported format version, the `store` is not
+    /// empty or the events could not be appended to it.
+    #[cfg(feature = "serde")]
+    pub fn import_state(reader: impl io::Read, mut store: S) -> io::Result<Self> {
+        let export: StateExport = serde_json::from_reader(reader)?;
+        if export.format_version != StateExport::FORMAT_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported export format version {}", export.format_version),
+            ));
+        }
+        if !store.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "event store is not empty"));
+        }
+        for envelope in &export.events {
+            store.append(envelope.clone())?;
+        }
+        let read_model = match export.snapshot {
+            Some(snapshot) => {
+                let after = snapshot.last_event_index.map_or(0, |index| index as u64 + 1);
+                let mut read_model = ReadModel::from_snapshot(snapshot);
+                let remaining = export.events.iter().filter(|envelope| envelope.sequence >= after);
+                for envelope in remaining {
+                    read_model.apply(envelope);
+                }
+                read_model
+            }
+            None => Self::replay(&export.events),
+        };
+        Ok(Self::from_parts(store, read_model))
+    }
+
+    fn from_parts(store: S, read_model: ReadModel) -> Self {
+        Self {
+            store,
+            read_model,
+            config: ServiceConfig::default(),
+            url_validator: Box::new(DefaultUrlValidator::default()),
+            url_normalizer: Box::new(DefaultUrlNormalizer::default()),
+            slug_generator: Box::new(RandomAlphanumeric::default()),
+            listeners: Vec::new(),
+            projections: Vec::new(),
+            subscriptions: Subscriptions::default(),
+            before_commit: Vec::new(),
+            after_commit: Vec::new(),
+            batch: None,
+            clock: Box::new(SystemClock),
+            click_filter: Box::new(DefaultClickFilter::default()),
+            rate_limiter: RateLimiter::default(),
+            correlation: None,
+            audit_log: Box::new(InMemoryAuditLog::new()),
+            recorded: None,
+            pending_clicks: Mutex::new(HashMap::new()),
+            shard: None,
+            last_snapshot: Mutex::new(None),
+            safety_checker: None,
+            checked_destination: None,
+            command_validators: ValidatorChain::new(),
+            outbox: None,
+            #[cfg(feature = "metrics")]
+            metrics: None,
+        }
+    }
+
+    /// Replaces the [`ServiceConfig`] of the service.
+    pub fn with_config(mut self, config: ServiceConfig) -> Self {
+        self.read_model.case_insensitive = config.case_insensitive_slugs;
+        self.config = config;
+        self
+    }
+
+    /// Replaces the [`UrlValidator`] checking URLs of created and changed
+    /// links, [`DefaultUrlValidator`] by default.
+    pub fn with_url_validator(
+        mut self,
+        validator: impl UrlValidator + Send + Sync + 'static,
+    ) -> Self {
+        self.url_validator = Box::new(validator);
+        self
+    }
+
+    /// Replaces the [`UrlNormalizer`] applied to URLs of created and changed
+    /// links, [`DefaultUrlNormalizer`] by default.
+    pub fn with_url_normalizer(
+        mut self,
+        normalizer: impl UrlNormalizer + Send + Sync + 'static,
+    ) -> Self {
+        self.url_normalizer = Box::new(normalizer);
+        self
+    }
+
+    /// Replaces the [`Clock`] events are timestamped with, [`SystemClock`]
+    /// by default.
+    pub fn with_clock(mut self, clock: impl Clock + Send + Sync + 'static) -> Self {
+        self.clock = Box::new(clock);
+        self
+    }
+
+    /// Replaces the [`ClickFilter`] recognizing redirects made by bots,
+    /// [`DefaultClickFilter`] by default.
+    pub fn with_click_filter(mut self, filter: impl ClickFilter + Send + Sync + 'static) -> Self {
+        self.click_filter = Box::new(filter);
+        self
+    }
+
+    /// Replaces the [`AuditLog`] executed commands are recorded in,
+    /// [`InMemoryAuditLog`] by default.
+    pub fn with_audit_log(mut self, log: impl AuditLog + Send + Sync + 'static) -> Self {
+        self.audit_log = Box::new(log);
+        self
+    }
+
+    /// Sets the [`SafetyChecker`] destinations of created and changed links
+    /// are checked with, none by default.
+    pub fn with_safety_checker(
+        mut self,
+        checker: impl SafetyChecker + Send + Sync + 'static,
+    ) -> Self {
+        self.safety_checker = Some(Arc::new(checker));
+        self
+    }
+
+    /// Adds a [`Validator`] of the commands executed with
+    /// [`UrlShortenerService::execute()`], run after the ones added so far.
+    /// Rejected commands fail with [`ShortenerError::Rejected`].
+    pub fn with_command_validator(
+        mut self,
+        validator: impl Validator<commands::Command> + Send + Sync + 'static,
+    ) -> Self {
+        self.command_validators = std::mem::take(&mut self.command_validators).with(validator);
+        self
+    }
+
+    /// Sets the function deriving the integration events of every recorded
+    /// event, which are stored in the outbox of the [`EventStore`]
+    /// atomically with the event, see [`EventStore::append_with_outbox()`].
+    /// They are delivered to other systems by an
+    /// [`OutboxDispatcher`](outbox::OutboxDispatcher).
+    ///
+    /// Recording events fails with [`ShortenerError::StorageFailure`] if the
+    /// store has no outbox and the function returns any messages.
+    pub fn with_outbox(
+        mut self,
+        mapper: impl Fn(&EventEnvelope) -> Vec<OutboxMessage> + Send + Sync + 'static,
+    ) -> Self {
+        self.outbox = Some(Box::new(mapper));
+        self
+    }
+
+    /// Registers an [`EventListener`] called after every event recorded from
+    /// now on. Listeners are called in the order they were subscribed.
+    pub fn subscribe(&mut self, listener: Box<dyn EventListener + Send + Sync>) {
+        self.listeners.push(listener);
+    }
+
+    /// Registers a hook called with every batch of events before it is
+    /// committed, which vetoes the batch by returning a [`Rejection`], e.g.
+    /// to enforce policies of an organization. Nothing of a vetoed batch is
+    /// stored, and the command fails with [`ShortenerError::Rejected`].
+    ///
+    /// The events of a command executed with [`UrlShortenerService::execute()`]
+    /// make up a single batch, while every event recorded by the handler
+    /// traits directly is a batch of its own. Hooks are called in the order
+    /// they were registered, until one of them vetoes the batch. While there
+    /// are any hooks, batches cost a copy of the read model, to be restored
+    /// if the batch is vetoed.
+    ///
+    /// [`Rejection`]: validation::Rejection
+    pub fn on_before_commit(
+        &mut self,
+        hook: impl Fn(&[EventEnvelope]) -> Result<(), Rejection> + Send + Sync + 'static,
+    ) {
+        self.before_commit.push(Box::new(hook));
+    }
+
+    /// Registers a hook called with every batch of events once it is stored
+    /// and applied, after the [`EventListener`]s were notified about its
+    /// events, e.g. to hand them to an outbox. See
+    /// [`UrlShortenerService::on_before_commit()`] for what makes up a batch.
+    pub fn on_after_commit(&mut self, hook: impl FnMut(&[EventEnvelope]) + Send + Sync + 'static) {
+        self.after_commit.push(Box::new(hook));
+    }
+
+    /// Returns the messages waiting in the outbox to be delivered, oldest
+    /// first, see [`UrlShortenerService::with_outbox()`].
+    pub fn pending_outbox(&self) -> Vec<OutboxMessage> {
+        self.store.read_outbox()
+    }
+
+    /// Removes a delivered message from the outbox.
+    ///
+    /// ## Errors
+    ///
+    /// Returns [`ShortenerError::StorageFailure`] if the removal could not be
+    /// persisted, the message is then still pending.
+    pub fn acknowledge_outbox(&mut self, id: Uuid) -> Result<(), ShortenerError> {
+        self.store
+            .remove_from_outbox(id)
+            .map_err(|_| ShortenerError::StorageFailure)
+    }
+
+    /// Registers a [`Projection`] after replaying the event log into it, and
+    /// returns it shared with the service, which applies every event recorded
+    /// from now on to it before notifying [`EventListener`]s.
+    ///
+    /// A service restored with [`UrlShortenerService::from_snapshot()`] has
+    /// only the events recorded after the [`Snapshot`] in its log, so the
+    /// projection catches up with those only.
+    pub fn register_projection<P: Projection + Send + 'static>(
+        &mut self,
+        mut projection: P,
+    ) -> Arc<Mutex<P>> {
+        for envelope in &self.store.read_envelopes() {
+            projection.apply(envelope);
+        }
+        let projection = Arc::new(Mutex::new(projection));
+        self.projections.push(Box::new(Arc::clone(&projection)));
+        projection
+    }
+
+    /// Starts a [`Subscription`] to the event log for consumers outside the
+    /// service, e.g. data pipelines. It yields the stored events with a
+    /// sequence of at least `sequence`, usually a [`Subscription::checkpoint()`]
+    /// kept by the consumer, and then every event recorded from now on.
+    ///
+    /// Clicks buffered with [`ServiceConfig::buffer_clicks`] are yielded once
+    /// they are flushed to the log.
+    pub fn subscribe_from(&mut self, sequence: u64) -> Subscription {
+        let feed = Arc::new(SubscriptionFeed::default());
+        feed.lock().events = self.store.read_range(sequence..u64::MAX).into();
+        self.subscriptions.0.push(Arc::downgrade(&feed));
+        Subscription {
+            feed,
+            checkpoint: sequence,
+        }
+    }
+
+    /// Records [`ServiceMetrics`] of the service from now on. Links created
+    /// so far are counted right away.
+    ///
+    /// [`ServiceMetrics`]: metrics::ServiceMetrics
+    #[cfg(feature = "metrics")]
+    pub fn with_metrics(mut self, metrics: metrics::ServiceMetrics) -> Self {
+        metrics.init(&self.read_model.totals);
+        self.subscribe(Box::new(metrics.clone()));
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Returns the [`ServiceMetrics`] attached with
+    /// [`UrlShortenerService::with_metrics()`].
+    ///
+    /// [`ServiceMetrics`]: metrics::ServiceMetrics
+    #[cfg(feature = "metrics")]
+    pub fn metrics(&self) -> Option<&metrics::ServiceMetrics> {
+        self.metrics.as_ref()
+    }
+
+    /// Replaces the [`SlugRetryPolicy`] applied when a generated [`Slug`] is
+    /// already taken.
+    pub fn with_slug_retry_policy(mut self, policy: SlugRetryPolicy) -> Self {
+        self.config.slug_retry_policy = policy;
+        self
+    }
+
+    /// Limits link creations per caller with the given [`RateLimitPolicy`].
+    pub fn with_rate_limit_policy(mut self, policy: RateLimitPolicy) -> Self {
+        self.config.rate_limit_policy = Some(policy);
+        self
+    }
+
+    /// Makes random [`Slug`]s drawn from the given random number generator,
+    /// replacing the [`SlugGenerator`] with [`RngAlphanumeric`]. Useful with
+    /// a seeded generator to reproduce [`Slug`] sequences.
+    ///
+    /// [`RngAlphanumeric`]: generation::RngAlphanumeric
+    pub fn with_rng(self, rng: impl Rng + Send + Sync + 'static) -> Self {
+        self.with_generator(generation::RngAlphanumeric::new(rng))
+    }
+
+    /// Replaces the [`SlugGenerator`] used for links created without a
+    /// [`Slug`], [`RandomAlphanumeric`] by default.
+    pub fn with_generator(
+        mut self,
+        generator: impl SlugGenerator + Send + Sync + 'static,
+    ) -> Self {
+        self.slug_generator = Box::new(generator);
+        self
+    }
+
+    /// Returns the underlying [`EventStore`].
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Returns the current version of the event stream of the link, to be
+    /// passed as the expected version to [`CommandHandlerV2`] commands.
+    ///
+    /// [`CommandHandlerV2`]: commands::CommandHandlerV2
+    pub fn link_version(&self, slug: &Slug) -> Result<u64, ShortenerError> {
+        Ok(self.read_model.get(slug)?.version)
+    }
+
+    /// Compacts the event log with [`EventStore::compact()`], returning the
+    /// number of removed events. The state of the service is not affected.
+    ///
+    /// ## Errors
+    ///
+    /// Returns [`ShortenerError::StorageFailure`] if the compacted log could
+    /// not be persisted.
+    pub fn compact(&mut self, up_to_sequence: u64) -> Result<usize, ShortenerError> {
+        self.store
+            .compact(up_to_sequence)
+            .map_err(|_| ShortenerError::StorageFailure)
+    }
+
+    /// Handles the command with the [`Aggregate`] rebuilt from the given
+    /// stream of the log, recording the resulting events like the events of
+    /// any other command, and returns them.
+    ///
+    /// Created links and changed destinations are checked like the commands
+    /// of the service check them: their [`Url`]s are validated and
+    /// normalized, and their [`Slug`]s checked against the
+    /// [`ServiceConfig::slug_policy`], the reserved and the taken ones.
+    /// Destinations are checked with the [`SafetyChecker`] too.
+    ///
+    /// ## Errors
+    ///
+    /// Returns the error the command or the checks reject it with, or
+    /// [`ShortenerError::StorageFailure`] if an event could not be stored.
+    ///
+    /// [`Aggregate`]: aggregate::Aggregate
+    pub fn execute_aggregate<A: aggregate::Aggregate>(
+        &mut self,
+        stream: &store::StreamId,
+        command: A::Command,
+    ) -> Result<Vec<Event>, ShortenerError> {
+        let aggregate = A::load(&self.store.read_stream(stream));
+        let mut events = aggregate.handle(command)?;
+        let mut verdicts = Vec::new();
+        for event in &mut events {
+            verdicts.extend(self.check_aggregate_event(event)?);
+        }
+        let batched = self.begin_batch();
+        let recorded = events
+            .iter()
+            .try_for_each(|event| self.record_event(event.clone()))
+            .and_then(|()| {
+                verdicts.into_iter().try_for_each(|(slug, url, verdict)| {
+                    self.record_safety_verdict(slug, url, verdict).map(drop)
+                })
+            });
+        let committed = if batched { self.commit_batch() } else { Ok(()) };
+        recorded.and(committed)?;
+        Ok(events)
+    }
+
+    //checks a link created or a destination changed by an aggregate like the
+    //commands do, normalizing its url, returning the verdict on the destination
+    fn check_aggregate_event(
+        &mut self,
+        event: &mut Event,
+    ) -> Result<Option<(Slug, Url, SafetyVerdict)>, ShortenerError> {
+        let (slug, url) = match event {
+            Event::LinkCreated { slug, url, raw_url, .. } => {
+                self.config
+                    .slug_policy
+                    .check(slug)
+                    .map_err(ShortenerError::InvalidSlug)?;
+                if self.config.reserved_slugs.contains(slug) {
+                    return Err(ShortenerError::SlugReserved);
+                }
+                if self.read_model.is_taken(slug, self.config.allow_slug_reuse) {
+                    return Err(ShortenerError::SlugAlreadyInUse);
+                }
+                self.url_validator.validate(url)?;
+                let normalized = self.url_normalizer.normalize(url);
+                *raw_url = Some(std::mem::replace(url, normalized));
+                (slug.clone(), url.clone())
+            }
+            Event::UrlChanged { slug, new_url } => {
+                self.url_validator.validate(new_url)?;
+                *new_url = self.url_normalizer.normalize(new_url);
+                (slug.clone(), new_url.clone())
+            }
+            _ => return Ok(None),
+        };
+        self.check_destination(Some(&slug), &url)?;
+        let verdict = self.check_safety(&url);
+        Ok(Some((slug, url, verdict)))
+    }
+
+    /// Replays the whole event log into a fresh read model which replaces
+    /// the current one, returning the number of replayed events. It recovers
+    /// from bugs in projections and fills projections added after the events
+    /// were recorded. Registered [`Projection`]s are reset and replayed too,
+    /// before the read model.
+    ///
+    /// `progress` is called with the number of replayed events and their
+    /// total after every thousand events and once all of them are replayed.
+    /// With the `parallel` feature, large logs are replayed on the rayon
+    /// thread pool, reporting the progress of all threads together.
+    pub fn rebuild_projections(&mut self, mut progress: impl FnMut(usize, usize)) -> usize {
+        let envelopes = self.store.read_envelopes();
+        let total = envelopes.len();
+        for projection in &mut self.projections {
+            projection.reset();
+            for envelope in &envelopes {
+                projection.apply(envelope);
+            }
+        }
+        let case_insensitive = self.config.case_insensitive_slugs;
+        #[cfg(feature = "parallel")]
+        if total >= PARALLEL_REPLAY_THRESHOLD {
+            let (sender, receiver) = mpsc::channel();
+            let envelopes = &envelopes;
+            self.read_model = thread::scope(|scope| {
+                let replay = scope.spawn(move || {
+                    ReadModel::replay_parallel(envelopes, case_insensitive, |count| {
+                        let _ = sender.send(count);
+                    })
+                });
+                //the sender is dropped once the replay is done
+                let mut replayed = 0;
+                for count in receiver {
+                    replayed += count;
+                    if replayed < total {
+                        progress(replayed, total);
+                    }
+                }
+                replay.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+            });
+            progress(total, total);
+            return total;
+        }
+        let mut read_model = ReadModel {
+            case_insensitive,
+            ..ReadModel::default()
+        };
+        for (i, envelope) in envelopes.iter().enumerate() {
+            read_model.apply(envelope);
+            if (i + 1) % REBUILD_PROGRESS_INTERVAL == 0 && i + 1 < total {
+                progress(i + 1, total);
+            }
+        }
+        progress(total, total);
+        self.read_model = read_model;
+        total
+    }
+
+    /// Replays the event log checking its invariants: sequences and stream
+    /// versions only grow, [`Slug`]s of links which are not deleted are
+    /// unique, events belong to existing links and [`Campaign`]s, and the
+    /// redirects counted by the service match the replayed ones.
+    ///
+    /// A service restored from a [`Snapshot`] has no events recorded before
+    /// it in its log, so events of links created before the snapshot are
+    /// reported as [`ConsistencyViolation::UnknownSlug`].
+    pub fn verify_consistency(&self) -> ConsistencyReport {
+        let envelopes = self.store.read_envelopes();
+        let mut violations = Vec::new();
+        let mut previous: Option<u64> = None;
+        let mut versions: HashMap<store::StreamId, u64> = HashMap::new();
+        let mut live: HashSet<Slug> = HashSet::new();
+        let mut campaigns: HashSet<Slug> = HashSet::new();
+        for envelope in &envelopes {
+            let sequence = envelope.sequence;
+            if let Some(previous) = previous.filter(|previous| sequence <= *previous) {
+                violations.push(ConsistencyViolation::SequenceOutOfOrder { sequence, previous });
+            }
+            previous = Some(sequence);
+            let version = envelope.version;
+            if let Some(previous) = versions
+                .insert(envelope.event.stream_id(), version)
+                .filter(|previous| version <= *previous)
+            {
+                violations.push(ConsistencyViolation::VersionOutOfOrder {
+                    sequence,
+                    version,
+                    previous,
+                });
+            }
+            let duplicate = |slug: &Slug| ConsistencyViolation::DuplicateSlug {
+                sequence,
+                slug: slug.clone(),
+            };
+            let unknown = |slug: &Slug| ConsistencyViolation::UnknownSlug {
+                sequence,
+                slug: slug.clone(),
+            };
+            match &envelope.event {
+                Event::LinkCreated { slug, .. } => {
+                    if !live.insert(slug.clone()) {
+                        violations.push(duplicate(slug));
+                    }
+                }
+                Event::LinkDeleted { slug } => {
+                    if !live.remove(slug) {
+                        violations.push(unknown(slug));
+                    }
+                }
+                Event::SlugRenamed { slug, new_slug, .. } => {
+                    if !live.remove(slug) {
+                        violations.push(unknown(slug));
+                    }
+                    if !live.insert(new_slug.clone()) {
+                        violations.push(duplicate(new_slug));
+                    }
+                }
+                Event::CampaignCreated { campaign, .. } => {
+                    if !campaigns.insert(campaign.clone()) {
+                        violations.push(ConsistencyViolation::DuplicateCampaign {
+                            sequence,
+                            campaign: campaign.clone(),
+                        });
+                    }
+                }
+                Event::AliasAdded { slug, alias } => {
+                    if !live.contains(slug) {
+                        violations.push(unknown(slug));
+                    }
+                    if live.contains(alias) {
+                        violations.push(duplicate(alias));
+                    }
+                }
+                Event::DestinationBlocked { .. } => {}
+                event => {
+                    if !live.contains(event.slug()) {
+                        violations.push(unknown(event.slug()));
+                    }
+                    if let Event::LinkAddedToCampaign { campaign, .. }
+                    | Event::LinkRemovedFromCampaign { campaign, .. } = event
+                    {
+                        if !campaigns.contains(campaign) {
+                            violations.push(ConsistencyViolation::UnknownCampaign {
+                                sequence,
+                                campaign: campaign.clone(),
+                            });
+                        }
+                    }
+                }
+            }
+        }
+
+        let replayed = Self::replay(&envelopes);
+        let slugs: BTreeSet<&Slug> = self
+            .read_model
+            .links
+            .keys()
+            .chain(replayed.links.keys())
+            .collect();
+        for slug in slugs {
+            let redirects = |read_model: &ReadModel| {
+                read_model.links.get(slug).map_or(0, |state| state.redirects)
+            };
+            let (recorded, replayed) = (redirects(&self.read_model), redirects(&replayed));
+            if recorded != replayed {
+                violations.push(ConsistencyViolation::RedirectCountMismatch {
+                    slug: slug.clone(),
+                    recorded,
+                    replayed,
+                });
+            }
+        }
+        if self.read_model.totals != replayed.totals {
+            violations.push(ConsistencyViolation::GlobalStatsMismatch {
+                recorded: self.read_model.totals.clone(),
+                replayed: replayed.totals,
+            });
+        }
+        ConsistencyReport { events: envelopes.len(), violations }
+    }
+
+    /// Runs `f` with every event it records tagged with the given
+    /// correlation and causation ids, see [`EventEnvelope::correlation_id`].
+    pub fn correlated<R>(
+        &mut self,
+        correlation_id: Uuid,
+        causation_id: Uuid,
+        f: impl FnOnce(&mut Self) -> R,
+    ) -> R {
+        let outer = self.correlation.replace((correlation_id, causation_id));
+        let result = f(self);
+        self.correlation = outer;
+        result
+    }
+
+    /// Executes the [`Command`] of the envelope, recording its events with
+    /// the id of the command as their causation id, and the correlation id
+    /// of the command (or its id if it starts a workflow) as their
+    /// correlation id.
+    ///
+    /// The command is checked by the command [`Validator`]s of the service
+    /// first. The command and its outcome are appended to the [`AuditLog`],
+    /// even if it was rejected, see [`UrlShortenerService::query_audit()`].
+    /// Commands run through the handler traits directly are neither
+    /// validated nor audited.
+    ///
+    /// ## Errors
+    ///
+    /// Returns the [`ShortenerError`] of the executed command, or
+    /// [`ShortenerError::StorageFailure`] if the audit entry could not be
+    /// appended. The events of the command are recorded in that case.
+    ///
+    /// [`Command`]: commands::Command
+    pub fn execute(
+        &mut self,
+        envelope: commands::CommandEnvelope,
+    ) -> Result<commands::CommandOutput, ShortenerError> {
+        let received_at = self.clock.now();
+        let correlation_id = envelope.correlation_id.unwrap_or(envelope.id);
+        let outer = self.recorded.replace(Vec::new());
+        let result = match self.command_validators.validate(&envelope.command, &*self) {
+            Ok(()) => {
+                let batched = self.begin_batch();
+                let result = self.correlated(correlation_id, envelope.id, |service| {
+                    envelope.command.clone().execute(service)
+                });
+                //events of failed commands, e.g. blocked attempts, are committed too
+                let committed = if batched { self.commit_batch() } else { Ok(()) };
+                result.and_then(|output| committed.map(|()| output))
+            }
+            Err(rejection) => Err(ShortenerError::Rejected(rejection)),
+        };
+        let events = std::mem::replace(&mut self.recorded, outer).unwrap_or_default();
+        if let Some(outer) = &mut self.recorded {
+            outer.extend(&events);
+        }
+        let outcome = match &result {
+            Ok(_) => AuditOutcome::Accepted { events },
+            Err(error) => AuditOutcome::Rejected {
+                error: error.clone(),
+            },
+        };
+        self.audit_log
+            .append(AuditEntry::new(&envelope, received_at, outcome))
+            .map_err(|_| ShortenerError::StorageFailure)?;
+        result
+    }
+
+    /// Executes the [`Command`]s of the [`UnitOfWork`] in order and commits
+    /// all their events as a single batch, returning the outputs of the
+    /// commands. Every command is checked by the command [`Validator`]s
+    /// right before it is executed, seeing the changes of the commands
+    /// before it.
+    ///
+    /// If any command fails or the batch is vetoed by a hook registered with
+    /// [`UrlShortenerService::on_before_commit()`], the service is restored
+    /// to the state before the unit and none of its events is stored, not
+    /// even the blocked attempts. Every command is appended to the
+    /// [`AuditLog`], as rejected with the error of the unit if it failed.
+    ///
+    /// ## Errors
+    ///
+    /// Returns the [`ShortenerError`] of the first failed command, or the
+    /// error of committing the batch, e.g. [`ShortenerError::StorageFailure`]
+    /// if it could not be stored with [`EventStore::append_batch()`].
+    ///
+    /// [`Command`]: commands::Command
+    /// [`UnitOfWork`]: commands::UnitOfWork
+    pub fn commit_unit(
+        &mut self,
+        unit: commands::UnitOfWork,
+    ) -> Result<Vec<commands::CommandOutput>, ShortenerError> {
+        let received_at = self.clock.now();
+        self.start_batch();
+        let mut outputs = Vec::new();
+        //number of staged events after every command
+        let mut boundaries = Vec::new();
+        let mut result = Ok(());
+        for envelope in unit.commands() {
+            let correlation_id = envelope.correlation_id.unwrap_or(envelope.id);
+            let output = match self.command_validators.validate(&envelope.command, &*self) {
+                Ok(()) => self.correlated(correlation_id, envelope.id, |service| {
+                    envelope.command.clone().execute(service)
+                }),
+                Err(rejection) => Err(ShortenerError::Rejected(rejection)),
+            };
+            match output {
+                Ok(output) => outputs.push(output),
+                Err(error) => {
+                    result = Err(error);
+                    break;
+                }
+            }
+            boundaries.push(self.batch.as_ref().map_or(0, |batch| batch.staged.len()));
+        }
+        let staged: Vec<Uuid> = self
+            .batch
+            .iter()
+            .flat_map(|batch| &batch.staged)
+            .map(|envelope| envelope.id)
+            .collect();
+        let result = match result {
+            Ok(()) => self.commit_batch(),
+            Err(error) => {
+                self.rollback_batch();
+                Err(error)
+            }
+        };
+        let outcomes = match &result {
+            Ok(()) => boundaries
+                .into_iter()
+                .scan(0, |start, end| {
+                    let events = staged[*start..end].to_vec();
+                    *start = end;
+                    Some(AuditOutcome::Accepted { events })
+                })
+                .collect(),
+            Err(error) => {
+                let outcome = AuditOutcome::Rejected { error: error.clone() };
+                vec![outcome; unit.commands().len()]
+            }
+        };
+        for (envelope, outcome) in unit.commands().iter().zip(outcomes) {
+            self.audit_log
+                .append(AuditEntry::new(envelope, received_at, outcome))
+                .map_err(|_| ShortenerError::StorageFailure)?;
+        }
+        result.map(|()| outputs)
+    }
+
+    /// Returns the [`AuditEntry`]s of the executed commands matching the
+    /// filter, in the order the commands were received.
+    pub fn query_audit(&self, filter: &AuditFilter) -> Vec<AuditEntry> {
+        self.audit_log
+            .entries()
+            .into_iter()
+            .filter(|entry| filter.matches(entry))
+            .collect()
+    }
+
+    /// Records the clicks buffered since the last flush as a single
+    /// [`Event::ClicksAggregated`] per link, returning the number of recorded
+    /// events. Call it before shutting down, as buffered clicks are lost
+    /// otherwise, see [`ServiceConfig::buffer_clicks`] and [`ClickFlusher`].
+    ///
+    /// ## Errors
+    ///
+    /// Returns [`ShortenerError::StorageFailure`] if an event could not be
+    /// persisted. Clicks not recorded yet stay buffered.
+    pub fn flush(&mut self) -> Result<usize, ShortenerError> {
+        let mut slugs: Vec<Slug> = self.pending_clicks().keys().cloned().collect();
+        slugs.sort();
+        let mut flushed = 0;
+        for slug in slugs {
+            if self.flush_link(&slug)? {
+                flushed += 1;
+            }
+        }
+        Ok(flushed)
+    }
+
+    /// Takes a [`Snapshot`] of the current read model.
+    pub fn snapshot(&self) -> Snapshot {
+        let mut last_snapshot = self.last_snapshot.lock().unwrap_or_else(PoisonError::into_inner);
+        *last_snapshot = Some((self.clock.now(), self.read_model.applied));
+        self.read_model.snapshot()
+    }
+
+    /// Returns [`StoreStats`] of the event log, reading all of its events.
+    pub fn store_stats(&self) -> StoreStats {
+        let envelopes = self.store.read_envelopes();
+        let events = envelopes.len();
+        let approximate_bytes = envelopes
+            .iter()
+            .map(|envelope| std::mem::size_of::<EventEnvelope>() + envelope.event.heap_size())
+            .sum();
+        let slugs = envelopes
+            .iter()
+            .filter(|envelope| {
+                !matches!(
+                    envelope.event,
+                    Event::CampaignCreated { .. } | Event::DestinationBlocked { .. }
+                )
+            })
+            .map(|envelope| envelope.event.slug())
+            .collect::<HashSet<_>>()
+            .len();
+        let last_snapshot = *self.last_snapshot.lock().unwrap_or_else(PoisonError::into_inner);
+        let snapshot_age = last_snapshot
+            .map(|(taken_at, _)| self.clock.now().duration_since(taken_at).unwrap_or_default());
+        let covered = last_snapshot.map_or(0, |(_, applied)| applied);
+        let events_since_snapshot = envelopes
+            .iter()
+            .filter(|envelope| envelope.sequence as usize >= covered)
+            .count();
+        let compaction_candidates = events - store::compact_envelopes(envelopes, u64::MAX).len();
+        StoreStats {
+            events,
+            approximate_bytes,
+            slugs,
+            snapshot_age,
+            events_since_snapshot,
+            compaction_candidates,
+        }
+    }
+
+    /// Returns all recorded events together with their metadata, in the
+    /// order they were recorded.
+    pub fn read_envelopes(&self) -> Vec<EventEnvelope> {
+        self.store.read_envelopes()
+    }
+
+    /// Serializes the whole event log into a JSON array of
+    /// [`EventEnvelope`]s.
+    #[cfg(feature = "serde")]
+    pub fn export_events_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string(&self.store.read_envelopes())
+    }
+
+    /// Writes the whole event log with the codec, see
+    /// [`EventCodec::write_envelopes()`]. Binary codecs produce smaller logs,
+    /// faster to import with [`UrlShortenerService::import_events()`], than
+    /// JSON.
+    ///
+    /// ## Errors
+    ///
+    /// Returns an error if encoding the log or writing to the `writer` fails.
+    ///
+    /// [`EventCodec::write_envelopes()`]: encoding::EventCodec::write_envelopes
+    #[cfg(any(feature = "serde", feature = "rkyv"))]
+    pub fn export_events(
+        &self,
+        codec: &impl encoding::EventCodec,
+        mut writer: impl Write,
+    ) -> io::Result<()> {
+        codec.write_envelopes(&mut writer, &self.store.read_envelopes())
+    }
+
+    /// Writes the complete state of the service as a JSON [`StateExport`]:
+    /// the whole event log and, if `include_snapshot` is set, a [`Snapshot`]
+    /// of the read model. See [`UrlShortenerService::import_state()`].
+    ///
+    /// ## Errors
+    ///
+    /// Returns an error if writing to the `writer` fails.
+    #[cfg(feature = "serde")]
+    pub fn export_state(&self, mut writer: impl Write, include_snapshot: bool) -> io::Result<()> {
+        let export = StateExport {
+            format_version: StateExport::FORMAT_VERSION,
+            snapshot: include_snapshot.then(|| self.snapshot()),
+            events: self.store.read_envelopes(),
+        };
+        serde_json::to_writer(&mut writer, &export)?;
+        writer.flush()
+    }
+    
+    /// Writes [`Stats`] of all the links, ordered by [`Slug`], in the given
+    /// [`ExportFormat`]. Every link has its `slug`, `url`, `redirects`,
+    /// `created_at` and `last_accessed` exported, timestamps formatted as
+    /// RFC 3339 in UTC and empty (or `null`) if unknown.
+    ///
+    /// ## Errors
+    ///
+    /// Returns an error if writing to the `writer` fails.
+    pub fn export_stats(&self, format: ExportFormat, mut writer: impl Write) -> io::Result<()> {
+        let mut links: Vec<&LinkState> = self
+            .read_model
+            .links
+            .values()
+            .filter(|state| !state.deleted)
+            .collect();
+        links.sort_by(|a, b| a.link.slug.cmp(&b.link.slug));
+        match format {
+            ExportFormat::Csv => {
+                writeln!(writer, "slug,url,redirects,created_at,last_accessed")?;
+                for state in links {
+                    writeln!(
+                        writer,
+                        "{},{},{},{},{}",
+                        csv_field(&state.link.slug.0),
+                        csv_field(&state.link.url.0),
+                        state.redirects,
+                        state.created_at.map(format_timestamp).unwrap_or_default(),
+                        state.last_accessed.map(format_timestamp).unwrap_or_default(),
+                    )?;
+                }
+            }
+            ExportFormat::Json => {
+                let timestamp = |time: Option<SystemTime>| {
+                    time.map_or("null".to_string(), |time| json_string(&format_timestamp(time)))
+                };
+                write!(writer, "[")?;
+                for (i, state) in links.into_iter().enumerate() {
+                    if i > 0 {
+                        write!(writer, ",")?;
+                    }
+                    write!(
+                        writer,
+                        "{{\"slug\":{},\"url\":{},\"redirects\":{},\"created_at\":{},\"last_accessed\":{}}}",
+                        json_string(&state.link.slug.0),
+                        json_string(&state.link.url.0),
+                        state.redirects,
+                        timestamp(state.created_at),
+                        timestamp(state.last_accessed),
+                    )?;
+                }
+                writeln!(writer, "]")?;
+            }
+        }
+        writer.flush()
+    }
+
+    /// Returns up to `n` available [`Slug`]s similar to the given one, for
+    /// UIs to offer when it is taken: the [`Slug`] with a number appended,
+    /// directly and after a dash, e.g. `sale2` and `sale-2` for `sale`. A
+    /// number the [`Slug`] already ends with is replaced, so `sale-2` leads
+    /// to `sale3` and `sale-3`. Variants breaking the
+    /// [`ServiceConfig::slug_policy`] or reserved are skipped.
+    pub fn suggest_slugs(&self, slug: &Slug, n: usize) -> Vec<Slug> {
+        let trimmed = slug.0.trim_end_matches(|c: char| c.is_ascii_digit());
+        let (base, first) = match trimmed.trim_end_matches('-') {
+            "" => (slug.0.as_str(), 2),
+            base => {
+                let number = slug.0[trimmed.len()..].parse::<usize>();
+                (base, number.map_or(2, |number| number.saturating_add(1)))
+            }
+        };
+        let shards = self.shard.map_or(1, |(_, shards)| shards);
+        //candidates of other shards can't be checked here
+        let attempts = n.saturating_mul(4).saturating_add(32).saturating_mul(shards);
+        (first..)
+            .take(attempts)
+            .flat_map(|number| [format!("{base}{number}"), format!("{base}-{number}")])
+            .map(Slug)
+            .filter(|candidate| {
+                *candidate != *slug
+                    && self.config.slug_policy.check(candidate).is_ok()
+                    && !self.config.reserved_slugs.contains(candidate)
+                    && self.in_shard(candidate)
+                    && !self.read_model.is_taken(candidate, self.config.allow_slug_reuse)
+            })
+            .take(n)
+            .collect()
+    }
+
+    /// Same as [`CommandHandler::handle_create_short_link()`] with a custom
+    /// [`Slug`], suggesting up to `n` alternatives with
+    /// [`UrlShortenerService::suggest_slugs()`] if it is taken or reserved.
+    ///
+    /// ## Errors
+    ///
+    /// Returns the [`Error`] of the creation with the [`Slug`] and [`Url`]
+    /// attached, and with the [`Error::suggestions()`] if the [`Slug`] is
+    /// taken or reserved.
+    pub fn create_with_suggestions(
+        &mut self,
+        url: Url,
+        slug: Slug,
+        n: usize,
+    ) -> Result<ShortLink, Error> {
+        match self.handle_create_short_link(url.clone(), Some(slug.clone())) {
+            Err(kind @ (ShortenerError::SlugAlreadyInUse | ShortenerError::SlugReserved)) => {
+                Err(Error {
+                    kind,
+                    suggestions: self.suggest_slugs(&slug, n),
+                    slug: Some(slug),
+                    url: Some(url),
+                })
+            }
+            result => result.with_slug(&slug).with_url(&url),
+        }
+    }
+
+    /// Renders the full short URL of the [`Slug`] under the configured
+    /// [`ServiceConfig::base_url`], e.g. `https://sho.rt/abc123`. The [`Slug`]
+    /// isn't looked up.
+    pub fn short_url(&self, slug: Slug) -> Url {
+        let base = self
+            .config
+            .base_url
+            .as_ref()
+            .map_or(ServiceConfig::DEFAULT_BASE_URL, |url| url.0.as_str());
+        render_short_url(base, &slug)
+    }
+
+    /// Renders the full short URLs of the [`Slug`] under the configured
+    /// [`ServiceConfig::base_url`] followed by all the
+    /// [`ServiceConfig::alternate_base_urls`].
+    pub fn short_urls(&self, slug: Slug) -> Vec<Url> {
+        let alternates = self
+            .config
+            .alternate_base_urls
+            .iter()
+            .map(|base| render_short_url(&base.0, &slug));
+        std::iter::once(self.short_url(slug.clone()))
+            .chain(alternates)
+            .collect()
+    }
+
+    /// Renders a QR code of the full short URL of the link, e.g. for print.
+    ///
+    /// ## Errors
+    ///
+    /// Returns [`ShortenerError::SlugNotFound`] if the link does not exist,
+    /// or [`ShortenerError::InvalidUrl`] if the short URL is too long to fit
+    /// into a QR code.
+    #[cfg(feature = "qr")]
+    pub fn generate_qr(&self, slug: Slug, format: QrFormat) -> Result<Vec<u8>, ShortenerError> {
+        let short_url = self.short_url(self.lookup(&slug)?.link.slug.clone());
+        let code = qrcode::QrCode::new(short_url.0.as_bytes())
+            .map_err(|_| ShortenerError::InvalidUrl)?;
+        match format {
+            QrFormat::Svg => Ok(code
+                .render::<qrcode::render::svg::Color>()
+                .build()
+                .into_bytes()),
+            QrFormat::Png => {
+                let image = code.render::<image::Luma<u8>>().build();
+                let mut png = Vec::new();
+                image
+                    .write_to(&mut io::Cursor::new(&mut png), image::ImageFormat::Png)
+                    .map_err(|_| ShortenerError::StorageFailure)?;
+                Ok(png)
+            }
+        }
+    }
+
+    /// Fetches the title, description and icon of the destination page of
+    /// the link and records them, so they are returned by
+    /// [`LinkQueryHandler::get_link_details()`].
+    ///
+    /// ## Errors
+    ///
+    /// Returns [`ShortenerError::SlugNotFound`] if the link does not exist,
+    /// or [`ShortenerError::MetadataUnavailable`] if the page can't be
+    /// fetched.
+    ///
+    /// [`LinkQueryHandler::get_link_details()`]: queries::LinkQueryHandler::get_link_details
+    #[cfg(feature = "metadata")]
+    pub async fn enrich_link(&mut self, slug: Slug) -> Result<LinkMetadata, ShortenerError> {
+        let link = self.lookup(&slug)?.link.clone();
+        let metadata = metadata::fetch(&link.url).await?;
+        self.record_event(Event::LinkMetadataFetched {
+            slug: link.slug,
+            metadata: metadata.clone(),
+        })?;
+        Ok(metadata)
+    }
+
+    /// Records the result of checking the health of the destination `url`
+    /// of the link: [`Event::DestinationUnhealthy`] with the reason if the
+    /// check failed, [`Event::DestinationHealthy`] if it passed. Nothing is
+    /// recorded if the health did not change or the link points elsewhere by
+    /// now. Returns whether an event was recorded.
+    ///
+    /// ## Errors
+    ///
+    /// Returns [`ShortenerError::SlugNotFound`] if the link does not exist.
+    pub fn record_destination_health(
+        &mut self,
+        slug: Slug,
+        url: Url,
+        result: Result<(), String>,
+    ) -> Result<bool, ShortenerError> {
+        let state = self.read_model.get(&slug)?;
+        if state.link.url != url {
+            return Ok(false);
+        }
+        let slug = state.link.slug.clone();
+        let event = match (result, &state.unhealthy) {
+            (Ok(()), None) => return Ok(false),
+            (Ok(()), Some(_)) => Event::DestinationHealthy { slug, url },
+            (Err(reason), Some((current, _))) if reason == *current => return Ok(false),
+            (Err(reason), _) => Event::DestinationUnhealthy { slug, url, reason },
+        };
+        self.record_event(event)?;
+        Ok(true)
+    }
+
+    /// Records the [`SafetyVerdict`] on the destination `url` of the link,
+    /// e.g. of a scheduled [`safety::scan()`]: [`Event::LinkFlagged`] if the
+    /// destination was flagged. Nothing is recorded if the link was flagged
+    /// for the same reason already or points elsewhere by now. Flags are
+    /// only cleared by changing the URL of the link. Returns whether an event
+    /// was recorded.
+    ///
+    /// ## Errors
+    ///
+    /// Returns [`ShortenerError::SlugNotFound`] if the link does not exist.
+    pub fn record_safety_verdict(
+        &mut self,
+        slug: Slug,
+        url: Url,
+        verdict: SafetyVerdict,
+    ) -> Result<bool, ShortenerError> {
+        let state = self.read_model.get(&slug)?;
+        let SafetyVerdict::Flagged(reason) = verdict else {
+            return Ok(false);
+        };
+        if state.link.url != url || state.flagged.as_ref() == Some(&reason) {
+            return Ok(false);
+        }
+        let slug = state.link.slug.clone();
+        self.record_event(Event::LinkFlagged { slug, url, reason })?;
+        Ok(true)
+    }
+
+    //verdict of the safety checker on the destination, safe without one,
+    //unless it was checked already
+    fn check_safety(&mut self, url: &Url) -> SafetyVerdict {
+        let checked = self.checked_destination.take_if(|(checked, _)| checked == url);
+        if let Some((_, verdict)) = checked {
+            return verdict;
+        }
+        match &self.safety_checker {
+            Some(checker) => block_on(checker.check(url)),
+            None => SafetyVerdict::Safe,
+        }
+    }
+
+    //destinations of the not deleted links, to be checked for health or safety
+    fn destinations(&self) -> Vec<(Slug, Url)> {
+        self.read_model
+            .links
+            .values()
+            .filter(|state| !state.deleted)
+            .map(|state| (state.link.slug.clone(), state.link.url.clone()))
+            .collect()
+    }
+
+    //my functions
+    
+    //record event and keep the read model in sync
+    fn record_event(&mut self, event: Event) -> Result<(), ShortenerError> {
+        if !matches!(
+            event,
+            Event::ClicksAggregated { .. }
+                | Event::LinkAccessedV2 { .. }
+                | Event::VariantServed { .. }
+                | Event::BotAccess { .. }
+                | Event::DestinationBlocked { .. }
+        ) {
+            //buffered clicks of the link precede its later changes
+            self.flush_link(event.slug())?;
+        }
+        let version = self.read_model.stream_version(&event) + 1;
+        let mut envelope = EventEnvelope::new_at(
+            self.read_model.applied as u64,
+            version,
+            event,
+            self.clock.now(),
+        );
+        if let Some((correlation_id, causation_id)) = self.correlation {
+            envelope.correlation_id = Some(correlation_id);
+            envelope.causation_id = Some(causation_id);
+        }
+        if let Some(batch) = &mut self.batch {
+            self.read_model.apply(&envelope);
+            batch.staged.push(envelope);
+            return Ok(());
+        }
+        let envelopes = std::slice::from_ref(&envelope);
+        self.check_commit(envelopes)?;
+        self.store_envelope(envelope.clone())
+            .map_err(|_| ShortenerError::StorageFailure)?;
+        self.read_model.apply(&envelope);
+        self.committed(envelopes);
+        Ok(())
+    }
+    //starts staging events to commit them as one batch, unless no hook needs
+    //batches or one was started already
+    fn begin_batch(&mut self) -> bool {
+        if self.batch.is_some() || (self.before_commit.is_empty() && self.after_commit.is_empty()) {
+            return false;
+        }
+        self.start_batch();
+        true
+    }
+    //starts staging events, whether any hook needs batches or not
+    fn start_batch(&mut self) {
+        self.batch = Some(Batch {
+            staged: Vec::new(),
+            read_model: self.read_model.clone(),
+            pending_clicks: self.pending_clicks().clone(),
+        });
+    }
+    //drops the staged events, restoring the state from before the batch
+    fn rollback_batch(&mut self) {
+        if let Some(batch) = self.batch.take() {
+            self.read_model = batch.read_model;
+            *self.pending_clicks() = batch.pending_clicks;
+        }
+    }
+    //stores the staged events atomically, restoring the state from before
+    //the batch if they are vetoed or fail to be stored
+    fn commit_batch(&mut self) -> Result<(), ShortenerError> {
+        let Some(batch) = self.batch.take() else {
+            return Ok(());
+        };
+        let stored = self.check_commit(&batch.staged).and_then(|()| {
+            if batch.staged.is_empty() {
+                return Ok(());
+            }
+            let messages = match &self.outbox {
+                Some(mapper) => batch.staged.iter().flat_map(mapper).collect(),
+                None => Vec::new(),
+            };
+            self.store
+                .append_batch(batch.staged.clone(), messages)
+                .map_err(|_| ShortenerError::StorageFailure)
+        });
+        if let Err(error) = stored {
+            self.batch = Some(batch);
+            self.rollback_batch();
+            return Err(error);
+        }
+        self.committed(&batch.staged);
+        Ok(())
+    }
+    //appends the envelope together with its outbox messages, if any
+    fn store_envelope(&mut self, envelope: EventEnvelope) -> io::Result<()> {
+        match &self.outbox {
+            Some(mapper) => {
+                let messages = mapper(&envelope);
+                self.store.append_with_outbox(envelope, messages)
+            }
+            None => self.store.append(envelope),
+        }
+    }
+    //asks the pre-commit hooks whether the events may be committed
+    fn check_commit(&self, envelopes: &[EventEnvelope]) -> Result<(), ShortenerError> {
+        self.before_commit
+            .iter()
+            .try_for_each(|hook| hook(envelopes))
+            .map_err(ShortenerError::Rejected)
+    }
+    //notifies everything following the log about the committed events
+    fn committed(&mut self, envelopes: &[EventEnvelope]) {
+        for envelope in envelopes {
+            for projection in &mut self.projections {
+                projection.apply(envelope);
+            }
+            if let Some(recorded) = &mut self.recorded {
+                recorded.push(envelope.id);
+            }
+            self.subscriptions.publish(envelope);
+            for listener in &mut self.listeners {
+                listener.on_event(envelope);
+            }
+        }
+        if !envelopes.is_empty() {
+            for hook in &mut self.after_commit {
+                hook(envelopes);
+            }
+        }
+    }
+    fn pending_clicks(&mut self) -> &mut HashMap<Slug, u64> {
+        self.pending_clicks.get_mut().unwrap_or_else(PoisonError::into_inner)
+    }
+    //records the buffered clicks of the link, returning whether there were any
+    fn flush_link(&mut self, slug: &Slug) -> Result<bool, ShortenerError> {
+        let Some(count) = self.pending_clicks().remove(slug) else {
+            return Ok(false);
+        };
+        let event = Event::ClicksAggregated {
+            slug: slug.clone(),
+            count,
+            up_to_sequence: self.read_model.applied as u64,
+            folded: 0,
+        };
+        if let Err(error) = self.record_event(event) {
+            self.pending_clicks().insert(slug.clone(), count);
+            return Err(error);
+        }
+        Ok(true)
+    }
+
+    fn create_link(
+        &mut self,
+        url: Url,
+        slug: Option<Slug>,
+        options: LinkOptions,
+    ) -> Result<ShortLink, ShortenerError> {
+        if let Some(policy) = &self.config.rate_limit_policy {
+            let key = options.rate_limit_key.as_deref().unwrap_or_default();
+            if !self.rate_limiter.try_acquire(policy, key, self.clock.now()) {
+                return Err(ShortenerError::RateLimited);
+            }
+        }
+        self.url_validator.validate(&url)?;
+        let raw_url = url;
+        let url = self.url_normalizer.normalize(&raw_url);
+        let fallback_url = options
+            .fallback_url
+            .as_ref()
+            .map(|fallback| {
+                self.url_validator.validate(fallback)?;
+                Ok(self.url_normalizer.normalize(fallback))
+            })
+            .transpose()?;
+        self.check_destination(slug.as_ref(), &url)?;
+        if let Some(fallback) = &fallback_url {
+            self.check_destination(slug.as_ref(), fallback)?;
+        }
+        if let Some(slug) = &slug {
+            self.config
+                .slug_policy
+                .check(slug)
+                .map_err(ShortenerError::InvalidSlug)?;
+        }
+        if slug.is_none() && self.config.create_policy == CreatePolicy::ReuseExisting {
+            let existing = self
+                .read_model
+                .find_by_url(&url)
+                .find(|state| state.check_redirect().is_ok());
+            if let Some(state) = existing {
+                return Ok(state.link.clone());
+            }
+        }
+        let slug = match slug {
+            Some(slug) => {
+                if self.config.reserved_slugs.contains(&slug) {
+                    return Err(ShortenerError::SlugReserved);
+                }
+                //check if slug is unique
+                if self.read_model.is_taken(&slug, self.config.allow_slug_reuse) {
+                    return Err(ShortenerError::SlugAlreadyInUse);
+                }
+                slug
+            }
+            None => self.generate_slug(&url)?,
+        };
+        let verdict = self.check_safety(&url);
+        //record event
+        self.record_event(Event::LinkCreated {
+            slug: slug.clone(),
+            url: url.clone(),
+            raw_url: Some(raw_url),
+            max_clicks: options.max_clicks,
+            owner: options.owner,
+            active_from: options.active_from,
+            active_until: options.active_until,
+            fallback_url,
+        })?;
+        self.record_safety_verdict(slug.clone(), url.clone(), verdict)?;
+
+        Ok(ShortLink { slug, url })
+    }
+    //reject a looping destination, or one on a domain not allowed recording the attempt
+    fn check_destination(&mut self, slug: Option<&Slug>, url: &Url) -> Result<(), ShortenerError> {
+        let base_urls: Vec<Url> = self
+            .config
+            .base_url
+            .iter()
+            .chain(&self.config.alternate_base_urls)
+            .cloned()
+            .collect();
+        self.config.loop_policy.check(url, &base_urls)?;
+        let Some(domain) = self.config.domain_policy.rejected_domain(url) else {
+            return Ok(());
+        };
+        self.record_event(Event::DestinationBlocked {
+            slug: slug.cloned(),
+            url: url.clone(),
+            domain,
+        })?;
+        Err(ShortenerError::DestinationBlocked)
+    }
+    //generate free slug, retrying on collisions
+    fn generate_slug(&mut self, url: &Url) -> Result<Slug, ShortenerError> {
+        let mut attempt = 0;
+        for _ in 0..self.config.slug_retry_policy.max_attempts {
+            let slug = self.generate_in_shard(url, &mut attempt)?;
+            if !self.config.reserved_slugs.contains(&slug)
+                && !self.read_model.is_taken(&slug, self.config.allow_slug_reuse)
+            {
+                return Ok(slug);
+            }
+        }
+        Err(ShortenerError::SlugAlreadyInUse)
+    }
+    //slug belonging to the shard of the service, see sharding
+    fn generate_in_shard(
+        &mut self,
+        url: &Url,
+        attempt: &mut u32,
+    ) -> Result<Slug, ShortenerError> {
+        let shards = self.shard.map_or(1, |(_, shards)| shards);
+        //slugs of other shards count as attempts, so deterministic generators move on
+        for _ in 0..shards * 8 {
+            let slug = self.slug_generator.generate(url, *attempt);
+            *attempt += 1;
+            if self.in_shard(&slug) {
+                return Ok(slug);
+            }
+        }
+        Err(ShortenerError::NoSlugInShard)
+    }
+    //whether the slug belongs to the shard of the service, see sharding
+    fn in_shard(&self, slug: &Slug) -> bool {
+        self.shard
+            .is_none_or(|(shard, shards)| sharding::shard_index(slug, shards) == shard)
+    }
+    //optimistic concurrency check
+    fn check_version(&self, slug: &Slug, expected_version: Option<u64>) -> Result<(), ShortenerError> {
+        match expected_version {
+            Some(expected) if expected != self.read_model.version(slug) => {
+                Err(ShortenerError::VersionConflict)
+            }
+            _ => Ok(()),
+        }
+    }
+    //validate, normalize and record the new url of the link
+    fn change_url(&mut self, slug: Slug, new_url: Url) -> Result<ShortLink, ShortenerError> {
+        let mut link = self.read_model.get(&slug)?.link.clone();
+        self.url_validator.validate(&new_url)?;
+        let new_url = self.url_normalizer.normalize(&new_url);
+        self.check_destination(Some(&link.slug), &new_url)?;
+        let verdict = self.check_safety(&new_url);
+        link.url = new_url.clone();
+        self.record_event(Event::UrlChanged {slug: link.slug.clone(), new_url: new_url.clone()})?;
+        self.record_safety_verdict(link.slug.clone(), new_url, verdict)?;
+        Ok(link)
+    }
+
+    //fail unless the link is owned by the given owner
+    fn check_owner(&self, slug: &Slug, owner: &OwnerId) -> Result<(), ShortenerError> {
+        if self.read_model.get(slug)?.owner.as_ref() != Some(owner) {
+            return Err(ShortenerError::NotOwner);
+        }
+        Ok(())
+    }
+
+    //read model lookup, timed if metrics are enabled
+    fn lookup(&self, slug: &Slug) -> Result<&LinkState, ShortenerError> {
+        let started = Instant::now();
+        let result = self.read_model.get(slug);
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = &self.metrics {
+            metrics.observe_lookup(started.elapsed());
+        }
+        result
+    }
+
+    //count a redirect, with the details of the request if known
+    fn redirect(
+        &mut self,
+        slug: Slug,
+        context: Option<ClickContext>,
+    ) -> Result<ShortLink, ShortenerError> {
+        let state = self.lookup(&slug)?;
+        state.check_redirect()?;
+        let active = state.is_active_at(self.clock.now());
+        let state = if active == state.inactive {
+            //boundary of the window crossed since the last access
+            let slug = state.link.slug.clone();
+            self.record_event(match active {
+                true => Event::LinkActivated { slug: slug.clone() },
+                false => Event::LinkDeactivated { slug: slug.clone() },
+            })?;
+            self.lookup(&slug)?
+        } else {
+            state
+        };
+        if !active {
+            let url = state.fallback_url.clone().ok_or(ShortenerError::LinkNotActive)?;
+            return Ok(ShortLink { slug: state.link.slug.clone(), url });
+        }
+        let mut link = state.link.clone();
+        let rule_url = state
+            .geo_destination(context.as_ref())
+            .or_else(|| state.device_destination(context.as_ref()))
+            .cloned();
+        let variant = match rule_url {
+            Some(_) => None,
+            None => state.pick_destination(&mut thread_rng()).cloned(),
+        };
+        let pending = self.pending_click_count(&link.slug);
+        let clicks = state.redirects + pending + 1;
+        let last_click = state.max_clicks.is_some_and(|max| clicks >= max);
+        let slug = link.slug.clone();
+        match context {
+            Some(context) if self.click_filter.is_bot(&context) => {
+                //bots are redirected without counting a click
+                self.record_event(Event::BotAccess { slug, context })?;
+                if let Some(url) = rule_url.or(variant) {
+                    link.url = url;
+                }
+                return Ok(link);
+            }
+            Some(context) => self.record_event(Event::LinkAccessedV2 { slug, context })?,
+            None if self.config.buffer_clicks => {
+                *self.pending_clicks().entry(slug).or_default() += 1;
+            }
+            None => self.record_event(Event::LinkAccessed { slug })?,
+        }
+        if let Some(url) = rule_url {
+            link.url = url;
+        } else if let Some(url) = variant {
+            link.url = url.clone();
+            self.record_event(Event::VariantServed { slug: link.slug.clone(), url })?;
+        }
+        if self.config.click_milestones.contains(&clicks) {
+            self.record_event(Event::MilestoneReached { slug: link.slug.clone(), clicks })?;
+        }
+        if last_click {
+            self.record_event(Event::LinkExhausted { slug: link.slug.clone() })?;
+        }
+        Ok(link)
+    }
+
+    fn pending_click_count(&self, slug: &Slug) -> u64 {
+        let pending = self.pending_clicks.lock().unwrap_or_else(PoisonError::into_inner);
+        pending.get(slug).copied().unwrap_or_default()
+    }
+    //redirect without a context which only buffers its click, done under a
+    //shared borrow, or None if it records events and needs redirect()
+    fn redirect_buffered(&self, slug: &Slug) -> Result<Option<ShortLink>, ShortenerError> {
+        if !self.config.buffer_clicks {
+            return Ok(None);
+        }
+        let state = self.lookup(slug)?;
+        state.check_redirect()?;
+        let active = state.is_active_at(self.clock.now());
+        if active == state.inactive {
+            return Ok(None);
+        }
+        if !active {
+            let url = state.fallback_url.clone().ok_or(ShortenerError::LinkNotActive)?;
+            return Ok(Some(ShortLink { slug: state.link.slug.clone(), url }));
+        }
+        //weighted destinations record the served one
+        if state.destinations.iter().any(|(_, weight)| *weight > 0) {
+            return Ok(None);
+        }
+        let mut pending = self.pending_clicks.lock().unwrap_or_else(PoisonError::into_inner);
+        let count = pending.get(&state.link.slug).copied().unwrap_or_default();
+        let clicks = state.redirects + count + 1;
+        if self.config.click_milestones.contains(&clicks)
+            || state.max_clicks.is_some_and(|max| clicks >= max)
+        {
+            return Ok(None);
+        }
+        pending.insert(state.link.slug.clone(), count + 1);
+        Ok(Some(state.link.clone()))
+    }
+
+    //where the redirect to the link goes, following its redirect policy
+    fn redirect_decision(
+        &self,
+        link: ShortLink,
+        query: Option<&str>,
+        fragment: Option<&str>,
+    ) -> Result<RedirectDecision, ShortenerError> {
+        let state = self.read_model.get(&link.slug)?;
+        let (policy, warning) = (state.redirect_policy, state.flagged.clone());
+        let query = query.filter(|query| policy.preserve_query && !query.is_empty());
+        let fragment = fragment.filter(|_| policy.preserve_fragment);
+        let mut location = link.url;
+        if query.is_some() || fragment.is_some() {
+            if let Ok(mut url) = url::Url::parse(&location.0) {
+                if let Some(query) = query {
+                    let merged = match url.query() {
+                        Some(own) if !own.is_empty() => format!("{own}&{query}"),
+                        _ => query.to_string(),
+                    };
+                    url.set_query(Some(&merged));
+                }
+                if let Some(fragment) = fragment {
+                    url.set_fragment(Some(fragment));
+                }
+                location = Url(url.into());
+            }
+        }
+        Ok(RedirectDecision {
+            slug: link.slug,
+            location,
+            permanent: policy.permanent,
+            warning,
+        })
+    }
+
+    //read model rebuilt from the stored events up to the given point
+    fn replay_until(&self, at: PointInTime) -> ReadModel {
+        let mut read_model = ReadModel {
+            case_insensitive: self.config.case_insensitive_slugs,
+            ..ReadModel::default()
+        };
+        for envelope in self
+            .store
+            .read_envelopes()
+            .iter()
+            .take_while(|envelope| at.includes(envelope))
+        {
+            read_model.apply(envelope);
+        }
+        read_model
+    }
+
+    //replay events into a fresh read model
+    fn replay(envelopes: &[EventEnvelope]) -> ReadModel {
+        #[cfg(feature = "parallel")]
+        if envelopes.len() >= PARALLEL_REPLAY_THRESHOLD {
+            return ReadModel::replay_parallel(envelopes, false, |_| {});
+        }
+        let mut read_model = ReadModel::default();
+        for envelope in envelopes {
+            read_model.apply(envelope);
+        }
+        read_model
+    }
+}
+
+impl<S: EventStore> commands::CommandHandler for UrlShortenerService<S> {
+    fn handle_create_short_link(
+        &mut self,
+        url: Url,
+        slug: Option<Slug>,
+    ) -> Result<ShortLink, ShortenerError> {
+        // todo!("Implement the logic for creating a short link")
+        traced("create_short_link", slug.clone(), || {
+            self.create_link(url, slug, LinkOptions::default())
+        })
+    }
+
+    fn handle_redirect(
+        &mut self,
+        slug: Slug,
+    ) -> Result<ShortLink, ShortenerError> {
+        //todo!("Implement the logic for redirection and incrementing the click counter")
+        traced("redirect", Some(slug.clone()), || self.redirect(slug, None))
+    }
+    
+    fn handle_change_short_link(
+        &mut self,
+        slug: Slug,
+        new_url: Url
+    ) -> Result<ShortLink, ShortenerError> {
+        traced("change_short_link", Some(slug.clone()), || self.change_url(slug, new_url))
+    }
+        
+}
+
+impl<S: EventStore> commands::CommandHandlerV2 for UrlShortenerService<S> {
+    fn handle_create_short_link_versioned(
+        &mut self,
+        url: Url,
+        slug: Option<Slug>,
+        expected_version: Option<u64>,
+    ) -> Result<ShortLink, ShortenerError> {
+        if let Some(slug) = &slug {
+            self.check_version(slug, expected_version)?;
+        }
+        self.handle_create_short_link(url, slug)
+    }
+
+    fn handle_redirect_versioned(
+        &mut self,
+        slug: Slug,
+        expected_version: Option<u64>,
+    ) -> Result<ShortLink, ShortenerError> {
+        self.check_version(&slug, expected_version)?;
+        self.handle_redirect(slug)
+    }
+
+    fn handle_change_short_link_versioned(
+        &mut self,
+        slug: Slug,
+        new_url: Url,
+        expected_version: Option<u64>,
+    ) -> Result<ShortLink, ShortenerError> {
+        self.check_version(&slug, expected_version)?;
+        self.handle_change_short_link(slug, new_url)
+    }
+}
+
+impl<S: EventStore> commands::RedirectHandler for UrlShortenerService<S> {
+    fn handle_redirect_with_context(
+        &mut self,
+        slug: Slug,
+        context: ClickContext,
+    ) -> Result<ShortLink, ShortenerError> {
+        self.redirect(slug, Some(context))
+    }
+
+    fn resolve_redirect(
+        &mut self,
+        slug: Slug,
+        context: ClickContext,
+        query: Option<&str>,
+        fragment: Option<&str>,
+    ) -> Result<RedirectDecision, ShortenerError> {
+        let link = self.redirect(slug, Some(context))?;
+        self.redirect_decision(link, query, fragment)
+    }
+}
+
+impl<S: EventStore> commands::OwnedLinkHandler for UrlShortenerService<S> {
+    fn handle_create_owned_link(
+        &mut self,
+        owner: OwnerId,
+        url: Url,
+        slug: Option<Slug>,
+    ) -> Result<ShortLink, ShortenerError> {
+        let options = LinkOptions {
+            rate_limit_key: Some(owner.0.clone()),
+            owner: Some(owner),
+            ..LinkOptions::default()
+        };
+        self.create_link(url, slug, options)
+    }
+
+    fn handle_change_owned_link(
+        &mut self,
+        owner: OwnerId,
+        slug: Slug,
+        new_url: Url,
+    ) -> Result<ShortLink, ShortenerError> {
+        self.check_owner(&slug, &owner)?;
+        self.handle_change_short_link(slug, new_url)
+    }
+
+    fn handle_delete_owned_link(
+        &mut self,
+        owner: OwnerId,
+        slug: Slug,
+    ) -> Result<ShortLink, ShortenerError> {
+        self.check_owner(&slug, &owner)?;
+        commands::LinkManagementHandler::handle_delete_short_link(self, slug)
+    }
+}
+
+impl<S: EventStore> commands::LinkManagementHandler for UrlShortenerService<S> {
+    fn handle_create_short_link_with_options(
+        &mut self,
+        url: Url,
+        slug: Option<Slug>,
+        options: LinkOptions,
+    ) -> Result<ShortLink, ShortenerError> {
+        self.create_link(url, slug, options)
+    }
+
+    fn handle_delete_short_link(
+        &mut self,
+        slug: Slug,
+    ) -> Result<ShortLink, ShortenerError> {
+        let link = self.read_model.get(&slug)?.link.clone();
+        self.record_event(Event::LinkDeleted { slug: link.slug.clone() })?;
+        Ok(link)
+    }
+
+    fn handle_disable_link(
+        &mut self,
+        slug: Slug,
+    ) -> Result<ShortLink, ShortenerError> {
+        let state = self.read_model.get(&slug)?;
+        let link = state.link.clone();
+        if !state.disabled {
+            self.record_event(Event::LinkDisabled { slug: link.slug.clone() })?;
+        }
+        Ok(link)
+    }
+
+    fn handle_enable_link(
+        &mut self,
+        slug: Slug,
+    ) -> Result<ShortLink, ShortenerError> {
+        let state = self.read_model.get(&slug)?;
+        let link = state.link.clone();
+        if state.disabled {
+            self.record_event(Event::LinkEnabled { slug: link.slug.clone() })?;
+        }
+        Ok(link)
+    }
+
+    fn handle_rename_slug(
+        &mut self,
+        old: Slug,
+        new: Slug,
+    ) -> Result<ShortLink, ShortenerError> {
+        let mut link = self.read_model.get(&old)?.link.clone();
+        //renaming back to an alias of the same link is fine
+        self.config
+            .slug_policy
+            .check(&new)
+            .map_err(ShortenerError::InvalidSlug)?;
+        if self.config.reserved_slugs.contains(&new) {
+            return Err(ShortenerError::SlugReserved);
+        }
+        let own_alias = self.read_model.aliases.get(&new) == Some(&link.slug);
+        if !own_alias && self.read_model.is_taken(&new, self.config.allow_slug_reuse) {
+            return Err(ShortenerError::SlugAlreadyInUse);
+        }
+        self.record_event(Event::SlugRenamed {
+            slug: link.slug.clone(),
+            new_slug: new.clone(),
+            keep_alias: self.config.keep_renamed_slugs,
+        })?;
+        link.slug = new;
+        Ok(link)
+    }
+
+    fn handle_add_alias(
+        &mut self,
+        slug: Slug,
+        alias: Slug,
+    ) -> Result<ShortLink, ShortenerError> {
+        let link = self.read_model.get(&slug)?.link.clone();
+        if self.read_model.aliases.get(&alias) == Some(&link.slug) {
+            return Ok(link);
+        }
+        self.config
+            .slug_policy
+            .check(&alias)
+            .map_err(ShortenerError::InvalidSlug)?;
+        if self.config.reserved_slugs.contains(&alias) {
+            return Err(ShortenerError::SlugReserved);
+        }
+        if self.read_model.is_taken(&alias, self.config.allow_slug_reuse) {
+            return Err(ShortenerError::SlugAlreadyInUse);
+        }
+        self.record_event(Event::AliasAdded { slug: link.slug.clone(), alias })?;
+        Ok(link)
+    }
+
+    fn handle_revert_url_change(
+        &mut self,
+        slug: Slug,
+    ) -> Result<ShortLink, ShortenerError> {
+        let mut link = self.read_model.get(&slug)?.link.clone();
+        let urls: Vec<Url> = self
+            .get_history(link.slug.clone())
+            .into_iter()
+            .filter_map(|envelope| match envelope.event {
+                Event::LinkCreated { url, .. } => Some(url),
+                Event::UrlChanged { new_url, .. } => Some(new_url),
+                _ => None,
+            })
+            .collect();
+        let [.., previous, _] = urls.as_slice() else {
+            return Err(ShortenerError::NothingToRevert);
+        };
+        link.url = previous.clone();
+        self.record_event(Event::UrlChanged {
+            slug: link.slug.clone(),
+            new_url: link.url.clone(),
+        })?;
+        Ok(link)
+    }
+
+    fn handle_set_destinations(
+        &mut self,
+        slug: Slug,
+        destinations: Vec<(Url, u32)>,
+    ) -> Result<ShortLink, ShortenerError> {
+        let link = self.read_model.get(&slug)?.link.clone();
+        if !destinations.is_empty() && destinations.iter().all(|(_, weight)| *weight == 0) {
+            return Err(ShortenerError::InvalidWeights);
+        }
+        let mut normalized = Vec::with_capacity(destinations.len());
+        for (url, weight) in destinations {
+            self.url_validator.validate(&url)?;
+            let url = self.url_normalizer.normalize(&url);
+            self.check_destination(Some(&link.slug), &url)?;
+            normalized.push((url, weight));
+        }
+        self.record_event(Event::DestinationsSet {
+            slug: link.slug.clone(),
+            destinations: normalized,
+        })?;
+        Ok(link)
+    }
+
+    fn handle_set_geo_rules(
+        &mut self,
+        slug: Slug,
+        rules: Vec<(String, Url)>,
+    ) -> Result<ShortLink, ShortenerError> {
+        let link = self.read_model.get(&slug)?.link.clone();
+        let mut normalized = Vec::with_capacity(rules.len());
+        for (country, url) in rules {
+            if country.len() != 2 || !country.chars().all(|c| c.is_ascii_alphabetic()) {
+                return Err(ShortenerError::InvalidCountry);
+            }
+            self.url_validator.validate(&url)?;
+            let url = self.url_normalizer.normalize(&url);
+            self.check_destination(Some(&link.slug), &url)?;
+            normalized.push((country.to_ascii_uppercase(), url));
+        }
+        self.record_event(Event::GeoRulesSet {
+            slug: link.slug.clone(),
+            rules: normalized,
+        })?;
+        Ok(link)
+    }
+
+    fn handle_set_device_rule(
+        &mut self,
+        slug: Slug,
+        device: Device,
+        url: Option<Url>,
+    ) -> Result<ShortLink, ShortenerError> {
+        let link = self.read_model.get(&slug)?.link.clone();
+        let url = url
+            .map(|url| {
+                self.url_validator.validate(&url)?;
+                Ok(self.url_normalizer.normalize(&url))
+            })
+            .transpose()?;
+        if let Some(url) = &url {
+            self.check_destination(Some(&link.slug), url)?;
+        }
+        self.record_event(Event::RedirectRuleSet {
+            slug: link.slug.clone(),
+            device,
+            url,
+        })?;
+        Ok(link)
+    }
+
+    fn handle_set_redirect_policy(
+        &mut self,
+        slug: Slug,
+        policy: RedirectPolicy,
+    ) -> Result<ShortLink, ShortenerError> {
+        let link = self.read_model.get(&slug)?.link.clone();
+        self.record_event(Event::RedirectPolicySet {
+            slug: link.slug.clone(),
+            policy,
+        })?;
+        Ok(link)
+    }
+}
+
+impl<S: EventStore> commands::CampaignHandler for UrlShortenerService<S> {
+    fn handle_create_campaign(
+        &mut self,
+        campaign: Slug,
+        name: String,
+    ) -> Result<Campaign, ShortenerError> {
+        self.config
+            .slug_policy
+            .check(&campaign)
+            .map_err(ShortenerError::InvalidSlug)?;
+        if self.read_model.campaigns.contains_key(&campaign) {
+            return Err(ShortenerError::CampaignAlreadyExists);
+        }
+        self.record_event(Event::CampaignCreated { campaign: campaign.clone(), name })?;
+        self.read_model.campaign(&campaign)
+    }
+
+    fn handle_add_to_campaign(
+        &mut self,
+        slug: Slug,
+        campaign: Slug,
+    ) -> Result<ShortLink, ShortenerError> {
+        let state = self.read_model.get(&slug)?;
+        let link = state.link.clone();
+        if !self.read_model.campaigns.contains_key(&campaign) {
+            return Err(ShortenerError::CampaignNotFound);
+        }
+        if state.campaign.as_ref() != Some(&campaign) {
+            self.record_event(Event::LinkAddedToCampaign { slug: link.slug.clone(), campaign })?;
+        }
+        Ok(link)
+    }
+
+    fn handle_remove_from_campaign(
+        &mut self,
+        slug: Slug,
+    ) -> Result<ShortLink, ShortenerError> {
+        let state = self.read_model.get(&slug)?;
+        let link = state.link.clone();
+        if let Some(campaign) = state.campaign.clone() {
+            self.record_event(Event::LinkRemovedFromCampaign {
+                slug: link.slug.clone(),
+                campaign,
+            })?;
+        }
+        Ok(link)
+    }
+}
+
+impl<S: EventStore> commands::TagHandler for UrlShortenerService<S> {
+    fn handle_tag_link(&mut self, slug: Slug, tag: String) -> Result<ShortLink, ShortenerError> {
+        let state = self.read_model.get(&slug)?;
+        let link = state.link.clone();
+        let tag = normalize_tag(&tag)?;
+        if !state.tags.contains(&tag) {
+            self.record_event(Event::LinkTagged { slug: link.slug.clone(), tag })?;
+        }
+        Ok(link)
+    }
+
+    fn handle_untag_link(&mut self, slug: Slug, tag: String) -> Result<ShortLink, ShortenerError> {
+        let state = self.read_model.get(&slug)?;
+        let link = state.link.clone();
+        let tag = tag.trim();
+        if state.tags.contains(tag) {
+            self.record_event(Event::LinkUntagged {
+                slug: link.slug.clone(),
+                tag: tag.to_string(),
+            })?;
+        }
+        Ok(link)
+    }
+}
+
+impl<S: EventStore> queries::QueryHandler for UrlShortenerService<S> {
+    fn get_stats(&self, slug: Slug) -> Result<Stats, ShortenerError> {
+        //todo!("Implement the logic for retrieving link statistics")
+        traced("get_stats", Some(slug.clone()), || Ok(self.lookup(&slug)?.stats()))
+    }
+}
+
+impl<S: EventStore> queries::LinkQueryHandler for UrlShortenerService<S> {
+    fn get_link(&self, slug: Slug) -> Result<ShortLink, ShortenerError> {
+        Ok(self.lookup(&slug)?.link.clone())
+    }
+
+    fn get_link_details(&self, slug: Slug) -> Result<LinkDetails, ShortenerError> {
+        let state = self.lookup(&slug)?;
+        Ok(LinkDetails {
+            stats: state.stats(),
+            short_url: self.short_url(state.link.slug.clone()),
+            alternate_short_urls: self.short_urls(state.link.slug.clone()).split_off(1),
+            created_at: state.created_at,
+            metadata: state.metadata.clone(),
+            flagged: state.flagged.clone(),
+        })
+    }
+
+    fn find_by_url(&self, url: Url) -> Vec<ShortLink> {
+        let url = self.url_normalizer.normalize(&url);
+        self.read_model
+            .find_by_url(&url)
+            .map(|state| state.link.clone())
+            .collect()
+    }
+
+    fn list_links_by_owner(&self, owner: OwnerId) -> Vec<ShortLink> {
+        let mut links: Vec<ShortLink> = self
+            .read_model
+            .links
+            .values()
+            .filter(|state| !state.deleted && state.owner.as_ref() == Some(&owner))
+            .map(|state| state.link.clone())
+            .collect();
+        links.sort_by(|a, b| a.slug.cmp(&b.slug));
+        links
+    }
+
+    fn top_links(&self, n: usize) -> Vec<Stats> {
+        self.read_model
+            .ranking
+            .iter()
+            .take(n)
+            .filter_map(|(_, slug)| self.read_model.links.get(slug))
+            .map(LinkState::stats)
+            .collect()
+    }
+
+    fn global_stats(&self) -> GlobalStats {
+        self.read_model.totals.clone()
+    }
+
+    fn list_aliases(&self, slug: Slug) -> Result<Vec<Slug>, ShortenerError> {
+        let link = &self.lookup(&slug)?.link;
+        let mut aliases: Vec<Slug> = self
+            .read_model
+            .aliases
+            .iter()
+            .filter(|(_, target)| **target == link.slug)
+            .map(|(alias, _)| alias.clone())
+            .collect();
+        aliases.sort();
+        Ok(aliases)
+    }
+}
+
+impl<S: EventStore> queries::HistoryQueryHandler for UrlShortenerService<S> {
+    fn state_at(&self, at: PointInTime) -> Snapshot {
+        self.replay_until(at).snapshot()
+    }
+
+    fn stats_at(&self, slug: Slug, at: PointInTime) -> Result<Stats, ShortenerError> {
+        Ok(self.replay_until(at).get(&slug)?.stats())
+    }
+
+    fn get_history(&self, slug: Slug) -> Vec<EventEnvelope> {
+        let slug = self.read_model.resolve(&slug).clone();
+        if !self.read_model.links.contains_key(&slug) {
+            return Vec::new();
+        }
+        //events of the link currently using each slug
+        let mut histories: HashMap<Slug, Vec<EventEnvelope>> = HashMap::new();
+        for envelope in self.store.read_envelopes() {
+            match &envelope.event {
+                Event::LinkCreated { slug, .. } => {
+                    histories.insert(slug.clone(), vec![envelope]);
+                }
+                Event::SlugRenamed { slug, new_slug, .. } => {
+                    let mut history = histories.remove(slug).unwrap_or_default();
+                    history.push(envelope.clone());
+                    histories.insert(new_slug.clone(), history);
+                }
+                Event::CampaignCreated { .. } | Event::DestinationBlocked { .. } => {}
+                event => histories.entry(event.slug().clone()).or_default().push(envelope),
+            }
+        }
+        histories.remove(&slug).unwrap_or_default()
+    }
+
+    fn get_correlated_events(&self, correlation_id: Uuid) -> Vec<EventEnvelope> {
+        self.store
+            .read_envelopes()
+            .into_iter()
+            .filter(|envelope| {
+                envelope.id == correlation_id || envelope.correlation_id == Some(correlation_id)
+            })
+            .collect()
+    }
+}
+
+impl<S: EventStore> queries::CampaignQueryHandler for UrlShortenerService<S> {
+    fn get_campaign(&self, campaign: Slug) -> Result<Campaign, ShortenerError> {
+        self.read_model.campaign(&campaign)
+    }
+
+    fn list_campaigns(&self) -> Vec<Campaign> {
+        self.read_model
+            .campaigns
+            .keys()
+            .filter_map(|id| self.read_model.campaign(id).ok())
+            .collect()
+    }
+
+    fn get_campaign_stats(&self, campaign: Slug) -> Result<CampaignStats, ShortenerError> {
+        let state = self
+            .read_model
+            .campaigns
+            .get(&campaign)
+            .ok_or(ShortenerError::CampaignNotFound)?;
+        let mut links: Vec<Stats> = state
+            .links
+            .iter()
+            .filter_map(|slug| self.read_model.get(slug).ok())
+            .map(LinkState::stats)
+            .collect();
+        links.sort_by(|a, b| {
+            b.redirects
+                .cmp(&a.redirects)
+                .then_with(|| a.link.slug.cmp(&b.link.slug))
+        });
+        Ok(CampaignStats {
+            id: campaign,
+            name: state.name.clone(),
+            redirects: links.iter().map(|stats| stats.redirects).sum(),
+            links,
+        })
+    }
+}
+
+impl<S: EventStore> queries::HealthQueryHandler for UrlShortenerService<S> {
+    fn broken_links(&self) -> Vec<BrokenLink> {
+        let mut links: Vec<BrokenLink> = self
+            .read_model
+            .links
+            .values()
+            .filter(|state| !state.deleted)
+            .filter_map(|state| {
+                let (reason, since) = state.unhealthy.clone()?;
+                Some(BrokenLink { link: state.link.clone(), reason, since })
+            })
+            .collect();
+        links.sort_by(|a, b| a.link.slug.cmp(&b.link.slug));
+        links
+    }
+
+    fn blocked_domains(&self) -> Vec<(String, u64)> {
+        breakdown(&self.read_model.blocked)
+    }
+}
+
+impl<S: EventStore> queries::TagQueryHandler for UrlShortenerService<S> {
+    fn list_links_by_tag(&self, tag: &str) -> Vec<ShortLink> {
+        self.read_model
+            .tags
+            .get(tag.trim())
+            .into_iter()
+            .flatten()
+            .filter_map(|slug| self.read_model.links.get(slug))
+            .map(|state| state.link.clone())
+            .collect()
+    }
+
+    fn tag_counts(&self) -> Vec<(String, u64)> {
+        let mut counts: Vec<(String, u64)> = self
+            .read_model
+            .tags
+            .iter()
+            .map(|(tag, slugs)| (tag.clone(), slugs.len() as u64))
+            .collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        counts
+    }
+}
+
+impl<S: EventStore> queries::StatsQueryHandler for UrlShortenerService<S> {
+    fn get_stats_over_time(
+        &self,
+        slug: Slug,
+        bucket: Interval,
+    ) -> Result<Vec<TimeBucket>, ShortenerError> {
+        let mut buckets: Vec<TimeBucket> = Vec::new();
+        for (hour, redirects) in &self.read_model.get(&slug)?.redirects_per_hour {
+            let start = SystemTime::UNIX_EPOCH
+                + Duration::from_secs(bucket.bucket_start(*hour) * 3600);
+            match buckets.last_mut() {
+                Some(last) if last.start == start => last.redirects += redirects,
+                _ => buckets.push(TimeBucket { start, redirects: *redirects }),
+            }
+        }
+        Ok(buckets)
+    }
+
+    fn daily_clicks(
+        &self,
+        slug: Slug,
+        from: Date,
+        to: Date,
+    ) -> Result<Vec<(Date, u64)>, ShortenerError> {
+        let state = self.read_model.get(&slug)?;
+        if from > to {
+            return Ok(Vec::new());
+        }
+        Ok(state
+            .redirects_per_day
+            .range(from.days()..=to.days())
+            .map(|(day, redirects)| (Date::from_days(*day), *redirects))
+            .collect())
+    }
+
+    fn referrer_breakdown(&self, slug: Slug) -> Result<Vec<(String, u64)>, ShortenerError> {
+        Ok(breakdown(&self.read_model.get(&slug)?.referrers))
+    }
+
+    fn top_referrers(&self, slug: Slug, n: usize) -> Result<Vec<(String, u64)>, ShortenerError> {
+        let mut domains = breakdown(&self.read_model.get(&slug)?.referrer_domains);
+        domains.truncate(n);
+        Ok(domains)
+    }
+
+    fn user_agent_breakdown(&self, slug: Slug) -> Result<Vec<(String, u64)>, ShortenerError> {
+        Ok(breakdown(&self.read_model.get(&slug)?.user_agents))
+    }
+
+    fn get_detailed_stats(&self, slug: Slug) -> Result<DetailedStats, ShortenerError> {
+        let state = self.read_model.get(&slug)?;
+        Ok(DetailedStats {
+            stats: state.stats(),
+            unique_visitors: state.visitors.count(),
+            bot_redirects: state.bot_redirects,
+            created_at: state.created_at,
+            last_accessed_at: state.last_accessed,
+            url_change_count: state.url_changes,
+        })
+    }
+
+    fn get_variant_stats(&self, slug: Slug) -> Result<Vec<VariantStats>, ShortenerError> {
+        let state = self.read_model.get(&slug)?;
+        Ok(state
+            .destinations
+            .iter()
+            .map(|(url, weight)| VariantStats {
+                url: url.clone(),
+                weight: *weight,
+                redirects: state.variant_redirects.get(url).copied().unwrap_or_default(),
+            })
+            .collect())
+    }
+
+    fn country_breakdown(&self, slug: Slug) -> Result<Vec<(String, u64)>, ShortenerError> {
+        Ok(breakdown(&self.read_model.get(&slug)?.countries))
+    }
+}
+/// Thread-safe handle to a [`UrlShortenerService`] which can be cloned and
+/// shared between threads.
+///
+/// Queries run concurrently, while commands are serialized. Redirects are
+/// serialized too, as they record an event, unless
+/// [`ServiceConfig::buffer_clicks`] is enabled: then redirects which only
+/// buffer their click run concurrently with each other and with queries,
+/// and only those recording an event, e.g. reaching a milestone, take the
+/// write lock. Both handler traits are implemented for
+/// `&SharedUrlShortenerService` too, so commands can be issued through a
+/// shared reference.
+pub struct SharedUrlShortenerService<S: EventStore = InMemoryEventStore> {
+    inner: Arc<RwLock<UrlShortenerService<S>>>,
+}
+
+impl<S: EventStore> SharedUrlShortenerService<S> {
+    /// Wraps the given service to be shared between threads.
+    pub fn new(service: UrlShortenerService<S>) -> Self {
+        Self {
+            inner: Arc::new(RwLock::new(service)),
+        }
+    }
+
+    /// Locks the service for reading.
+    pub fn read(&self) -> RwLockReadGuard<'_, UrlShortenerService<S>> {
+        self.inner.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Locks the service for writing.
+    pub fn write(&self) -> RwLockWriteGuard<'_, UrlShortenerService<S>> {
+        self.inner.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    //valid destination normalized like the service does and the verdict of
+    //its safety checker, checked without holding the lock
+    async fn check_ahead(&self, url: &Url) -> Option<(Url, SafetyVerdict)> {
+        let (checker, url) = {
+            let service = self.read();
+            service.url_validator.validate(url).ok()?;
+            (service.safety_checker.clone()?, service.url_normalizer.normalize(url))
+        };
+        let verdict = checker.check(&url).await;
+        Some((url, verdict))
+    }
+
+    //runs the command under the write lock with the destination checked ahead
+    fn write_checked<T>(
+        &self,
+        checked: Option<(Url, SafetyVerdict)>,
+        command: impl FnOnce(&mut UrlShortenerService<S>) -> T,
+    ) -> T {
+        let mut service = self.write();
+        service.checked_destination = checked;
+        let result = command(&mut service);
+        service.checked_destination = None;
+        result
+    }
+
+    //creates the link, checking its destination before taking the write lock
+    async fn create_checked(
+        &self,
+        url: Url,
+        slug: Option<Slug>,
+    ) -> Result<ShortLink, ShortenerError> {
+        let checked = self.check_ahead(&url).await;
+        self.write_checked(checked, |service| service.handle_create_short_link(url, slug))
+    }
+
+    //changes the url, checking the destination before taking the write lock
+    async fn change_checked(&self, slug: Slug, new_url: Url) -> Result<ShortLink, ShortenerError> {
+        let checked = self.check_ahead(&new_url).await;
+        self.write_checked(checked, |service| service.handle_change_short_link(slug, new_url))
+    }
+}
+
+impl<S: EventStore> Clone for SharedUrlShortenerService<S> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+/// Background thread flushing the clicks buffered by a
+/// [`SharedUrlShortenerService`] periodically, see
+/// [`ServiceConfig::buffer_clicks`]. Dropping it stops the thread after a
+/// final flush.
+#[derive(Debug)]
+pub struct ClickFlusher {
+    stop: mpsc::Sender<()>,
+    thread: thread::JoinHandle<Result<usize, ShortenerError>>,
+}
+
+impl ClickFlusher {
+    /// Interval between flushes unless configured otherwise.
+    pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(5);
+
+    /// Starts flushing the service every `interval` with
+    /// [`UrlShortenerService::flush()`]. Clicks which fail to be flushed are
+    /// retried by the next flush.
+    pub fn spawn<S>(service: SharedUrlShortenerService<S>, interval: Duration) -> Self
+    where
+        S: EventStore + Send + Sync + 'static,
+    {
+        let (stop, stopped) = mpsc::channel();
+        let thread = thread::spawn(move || loop {
+            match stopped.recv_timeout(interval) {
+                Err(RecvTimeoutError::Timeout) => {
+                    let _ = service.write().flush();
+                }
+                //stopped explicitly or dropped
+                _ => return service.write().flush(),
+            }
+        });
+        Self { stop, thread }
+    }
+
+    /// Stops the thread for a graceful shutdown, returning the result of
+    /// the final flush.
+    ///
+    /// ## Errors
+    ///
+    /// Returns [`ShortenerError::StorageFailure`] if the final flush failed.
+    pub fn stop(self) -> Result<usize, ShortenerError> {
+        let _ = self.stop.send(());
+        self.thread
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+    }
+}
+
+impl<S: EventStore> commands::CommandHandler for &SharedUrlShortenerService<S> {
+    fn handle_create_short_link(
+        &mut self,
+        url: Url,
+        slug: Option<Slug>,
+    ) -> Result<ShortLink, ShortenerError> {
+        block_on(self.create_checked(url, slug))
+    }
+
+    fn handle_redirect(
+        &mut self,
+        slug: Slug,
+    ) -> Result<ShortLink, ShortenerError> {
+        traced("redirect", Some(slug.clone()), || {
+            let buffered = self.read().redirect_buffered(&slug)?;
+            match buffered {
+                Some(link) => Ok(link),
+                None => self.write().redirect(slug, None),
+            }
+        })
+    }
+
+    fn handle_change_short_link(
+        &mut self,
+        slug: Slug,
+        new_url: Url
+    ) -> Result<ShortLink, ShortenerError> {
+        block_on(self.change_checked(slug, new_url))
+    }
+}
+
+impl<S: EventStore> commands::CommandHandler for SharedUrlShortenerService<S> {
+    fn handle_create_short_link(
+        &mut self,
+        url: Url,
+        slug: Option<Slug>,
+    ) -> Result<ShortLink, ShortenerError> {
+        (&*self).handle_create_short_link(url, slug)
+    }
+
+    fn handle_redirect(
+        &mut self,
+        slug: Slug,
+    ) -> Result<ShortLink, ShortenerError> {
+        (&*self).handle_redirect(slug)
+    }
+
+    fn handle_change_short_link(
+        &mut self,
+        slug: Slug,
+        new_url: Url
+    ) -> Result<ShortLink, ShortenerError> {
+        (&*self).handle_change_short_link(slug, new_url)
+    }
+}
+
+/// Redirects without any details of the request buffer their click under the
+/// shared lock like [`CommandHandler::handle_redirect()`], if the service
+/// buffers clicks. Other redirects take the exclusive lock to record them.
+///
+/// [`CommandHandler::handle_redirect()`]: commands::CommandHandler::handle_redirect
+impl<S: EventStore> commands::RedirectHandler for &SharedUrlShortenerService<S> {
+    fn handle_redirect_with_context(
+        &mut self,
+        slug: Slug,
+        context: ClickContext,
+    ) -> Result<ShortLink, ShortenerError> {
+        if context == ClickContext::default() {
+            if let Some(link) = self.read().redirect_buffered(&slug)? {
+                return Ok(link);
+            }
+        }
+        self.write().handle_redirect_with_context(slug, context)
+    }
+
+    fn resolve_redirect(
+        &mut self,
+        slug: Slug,
+        context: ClickContext,
+        query: Option<&str>,
+        fragment: Option<&str>,
+    ) -> Result<RedirectDecision, ShortenerError> {
+        if context == ClickContext::default() {
+            let service = self.read();
+            if let Some(link) = service.redirect_buffered(&slug)? {
+                return service.redirect_decision(link, query, fragment);
+            }
+        }
+        self.write().resolve_redirect(slug, context, query, fragment)
+    }
+}
+
+impl<S: EventStore> commands::RedirectHandler for SharedUrlShortenerService<S> {
+    fn handle_redirect_with_context(
+        &mut self,
+        slug: Slug,
+        context: ClickContext,
+    ) -> Result<ShortLink, ShortenerError> {
+        (&*self).handle_redirect_with_context(slug, context)
+    }
+
+    fn resolve_redirect(
+        &mut self,
+        slug: Slug,
+        context: ClickContext,
+        query: Option<&str>,
+        fragment: Option<&str>,
+    ) -> Result<RedirectDecision, ShortenerError> {
+        (&*self).resolve_redirect(slug, context, query, fragment)
+    }
+}
+
+impl<S: EventStore> QueryHandler for &SharedUrlShortenerService<S> {
+    fn get_stats(&self, slug: Slug) -> Result<Stats, ShortenerError> {
+        self.read().get_stats(slug)
+    }
+}
+
+impl<S: EventStore> QueryHandler for SharedUrlShortenerService<S> {
+    fn get_stats(&self, slug: Slug) -> Result<Stats, ShortenerError> {
+        self.read().get_stats(slug)
+    }
+}
+
+/// Service split into shards by the hash of the [`Slug`], so commands on
+/// links of different shards don't contend on a single lock.
+pub mod sharding {
+    use super::commands::CommandHandler;
+    use super::queries::QueryHandler;
+    use super::store::{EventStore, InMemoryEventStore};
+    use super::{
+        SharedUrlShortenerService, ShortLink, ShortenerError, Slug, Stats, Url, UrlShortenerService,
+    };
+
+    /// Number of shards unless configured otherwise.
+    pub const DEFAULT_SHARDS: usize = 16;
+
+    /// [`UrlShortenerService`] split into shards, each a
+    /// [`SharedUrlShortenerService`] with its own lock, [`EventStore`] and
+    /// read model. Links live in the shard of the hash of their [`Slug`], so
+    /// redirects of links in different shards run concurrently.
+    ///
+    /// Links created without a [`Slug`] live in the shard of the hash of
+    /// their normalized [`Url`], so [`CreatePolicy::ReuseExisting`] still
+    /// finds the existing link. Their [`Slug`]s are generated again until
+    /// one hashes into that shard too, each try counting as another attempt
+    /// of the [`SlugGenerator`], and the create fails with
+    /// [`ShortenerError::NoSlugInShard`] if none does.
+    ///
+    /// [`CreatePolicy::ReuseExisting`]: super::CreatePolicy::ReuseExisting
+    /// [`SlugGenerator`]: super::generation::SlugGenerator
+    pub struct ShardedUrlShortenerService<S: EventStore = InMemoryEventStore> {
+        shards: Vec<SharedUrlShortenerService<S>>,
+    }
+
+    impl ShardedUrlShortenerService {
+        /// Creates a service of the given number of default shards keeping
+        /// the events in memory.
+        ///
+        /// ## Panics
+        ///
+        /// Panics if `shards` is `0`.
+        pub fn new(shards: usize) -> Self {
+            Self::builder().shards(shards).build()
+        }
+
+        /// Returns a [`ShardedServiceBuilder`] of [`DEFAULT_SHARDS`] default
+        /// shards keeping the events in memory.
+        pub fn builder() -> ShardedServiceBuilder {
+            ShardedServiceBuilder {
+                shards: DEFAULT_SHARDS,
+                make_shard: Box::new(|_| UrlShortenerService::new()),
+            }
+        }
+    }
+
+    impl<S: EventStore> ShardedUrlShortenerService<S> {
+        /// Number of shards of the service.
+        pub fn shard_count(&self) -> usize {
+            self.shards.len()
+        }
+
+        /// All shards of the service, e.g. to take their snapshots.
+        pub fn shards(&self) -> &[SharedUrlShortenerService<S>] {
+            &self.shards
+        }
+
+        /// The shard the link of the [`Slug`] lives in, to run commands and
+        /// queries not supported by the sharded service itself. Renaming a
+        /// link there to a [`Slug`] of another shard makes it unreachable
+        /// through the sharded service.
+        pub fn shard(&self, slug: &Slug) -> &SharedUrlShortenerService<S> {
+            &self.shards[shard_index(slug, self.shards.len())]
+        }
+
+        //shard new links to the url are created in
+        fn shard_of_url(&self, url: &Url) -> &SharedUrlShortenerService<S> {
+            let url = self.shards[0].read().url_normalizer.normalize(url);
+            &self.shards[hash(&url.0) as usize % self.shards.len()]
+        }
+    }
+
+    impl<S: EventStore> Clone for ShardedUrlShortenerService<S> {
+        fn clone(&self) -> Self {
+            Self {
+                shards: self.shards.clone(),
+            }
+        }
+    }
+
+    impl<S: EventStore> CommandHandler for &ShardedUrlShortenerService<S> {
+        fn handle_create_short_link(
+            &mut self,
+            url: Url,
+            slug: Option<Slug>,
+        ) -> Result<ShortLink, ShortenerError> {
+            let shard = match &slug {
+                Some(slug) => self.shard(slug),
+                None => self.shard_of_url(&url),
+            };
+            shard.write().handle_create_short_link(url, slug)
+        }
+
+        fn handle_redirect(&mut self, slug: Slug) -> Result<ShortLink, ShortenerError> {
+            self.shard(&slug).handle_redirect(slug)
+        }
+
+        fn handle_change_short_link(
+            &mut self,
+            slug: Slug,
+            new_url: Url,
+        ) -> Result<ShortLink, ShortenerError> {
+            self.shard(&slug).write().handle_change_short_link(slug, new_url)
+        }
+    }
+
+    impl<S: EventStore> CommandHandler for ShardedUrlShortenerService<S> {
+        fn handle_create_short_link(
+            &mut self,
+            url: Url,
+            slug: Option<Slug>,
+        ) -> Result<ShortLink, ShortenerError> {
+            (&*self).handle_create_short_link(url, slug)
+        }
+
+        fn handle_redirect(&mut self, slug: Slug) -> Result<ShortLink, ShortenerError> {
+            (&*self).handle_redirect(slug)
+        }
+
+        fn handle_change_short_link(
+            &mut self,
+            slug: Slug,
+            new_url: Url,
+        ) -> Result<ShortLink, ShortenerError> {
+            (&*self).handle_change_short_link(slug, new_url)
+        }
+    }
+
+    impl<S: EventStore> QueryHandler for &ShardedUrlShortenerService<S> {
+        fn get_stats(&self, slug: Slug) -> Result<Stats, ShortenerError> {
+            self.shard(&slug).read().get_stats(slug)
+        }
+    }
+
+    impl<S: EventStore> QueryHandler for ShardedUrlShortenerService<S> {
+        fn get_stats(&self, slug: Slug) -> Result<Stats, ShortenerError> {
+            self.shard(&slug).read().get_stats(slug)
+        }
+    }
+
+    /// Builder of a [`ShardedUrlShortenerService`], created with
+    /// [`ShardedUrlShortenerService::builder()`].
+    pub struct ShardedServiceBuilder<S: EventStore = InMemoryEventStore> {
+        shards: usize,
+        make_shard: Box<dyn FnMut(usize) -> UrlShortenerService<S>>,
+    }
+
+    impl<S: EventStore> ShardedServiceBuilder<S> {
+        /// Sets the number of shards. Links are assigned to shards by their
+        /// number, so it can't be changed for a service with persisted
+        /// events.
+        pub fn shards(mut self, shards: usize) -> Self {
+            self.shards = shards;
+            self
+        }
+
+        /// Creates every shard with the given function, called with the index
+        /// of the shard, e.g. to give each shard its own [`EventStore`].
+        pub fn shard_with<T: EventStore>(
+            self,
+            make_shard: impl FnMut(usize) -> UrlShortenerService<T> + 'static,
+        ) -> ShardedServiceBuilder<T> {
+            ShardedServiceBuilder {
+                shards: self.shards,
+                make_shard: Box::new(make_shard),
+            }
+        }
+
+        /// Builds the [`ShardedUrlShortenerService`].
+        ///
+        /// ## Panics
+        ///
+        /// Panics if the number of shards is `0`.
+        pub fn build(mut self) -> ShardedUrlShortenerService<S> {
+            assert!(self.shards > 0, "a sharded service needs at least one shard");
+            let shards = (0..self.shards)
+                .map(|index| {
+                    let mut service = (self.make_shard)(index);
+                    service.shard = Some((index, self.shards));
+                    SharedUrlShortenerService::new(service)
+                })
+                .collect();
+            ShardedUrlShortenerService { shards }
+        }
+    }
+
+    //index of the shard of the slug, stable across runs and platforms
+    pub(crate) fn shard_index(slug: &Slug, shards: usize) -> usize {
+        //letter case ignored, so case insensitive slugs share a shard
+        (hash(&slug.0.to_ascii_lowercase()) % shards as u64) as usize
+    }
+
+    //64-bit FNV-1a
+    fn hash(value: &str) -> u64 {
+        value.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        })
+    }
+}
+
+/// Process managers reacting to recorded events with follow-up commands,
+/// e.g. to automate workflows spanning multiple links or events.
+pub mod process {
+    use super::commands::Command;
+    use super::store::EventStore;
+    use super::{Event, EventEnvelope, ShortenerError, Slug, UrlShortenerService};
+
+    /// Reacts to recorded events by issuing follow-up [`Command`]s, run by a
+    /// [`ProcessRunner`].
+    pub trait ProcessManager {
+        /// Returns the [`Command`]s to execute in reaction to the event, in
+        /// order.
+        fn handle(&mut self, envelope: &EventEnvelope) -> Vec<Command>;
+
+        /// Called when a [`Command`] returned by
+        /// [`ProcessManager::handle()`] fails, e.g. because the link was
+        /// deleted in the meantime. Does nothing by default.
+        fn command_failed(&mut self, command: Command, error: ShortenerError) {}
+    }
+
+    /// Runs a [`ProcessManager`] over the events of a service, tracking the
+    /// position of the next event to handle, so it can resume where it
+    /// stopped after a restart if the position is persisted.
+    ///
+    /// Events are handled at least once: if recording the result of a
+    /// [`Command`] fails, the event is handled again by the next run. The
+    /// events recorded by the commands continue the workflow of the event
+    /// they were issued for, see [`EventEnvelope::correlation_id`].
+    #[derive(Debug)]
+    pub struct ProcessRunner<P: ProcessManager> {
+        manager: P,
+        position: u64,
+    }
+
+    impl<P: ProcessManager> ProcessRunner<P> {
+        /// Creates a runner handling all the events, from the first one.
+        pub fn new(manager: P) -> Self {
+            Self::starting_at(manager, 0)
+        }
+
+        /// Creates a runner handling the events from the given sequence on,
+        /// e.g. a previously persisted [`ProcessRunner::position()`].
+        pub fn starting_at(manager: P, position: u64) -> Self {
+            Self { manager, position }
+        }
+
+        /// Sequence of the next event to handle.
+        pub fn position(&self) -> u64 {
+            self.position
+        }
+
+        /// Returns the [`ProcessManager`].
+        pub fn manager(&self) -> &P {
+            &self.manager
+        }
+
+        /// Handles the events recorded since the last run, including the
+        /// ones recorded by the issued commands, returning the number of
+        /// handled events.
+        ///
+        /// ## Errors
+        ///
+        /// Returns [`ShortenerError::StorageFailure`] if the event recorded by
+        /// a command could not be stored. The position stays at the event
+        /// the command was issued for.
+        pub fn run<S: EventStore>(
+            &mut self,
+            service: &mut UrlShortenerService<S>,
+        ) -> Result<usize, ShortenerError> {
+            let mut handled = 0;
+            loop {
+                let pending: Vec<EventEnvelope> = service
+                    .read_envelopes()
+                    .into_iter()
+                    .filter(|envelope| envelope.sequence >= self.position)
+                    .collect();
+                if pending.is_empty() {
+                    return Ok(handled);
+                }
+                for envelope in pending {
+                    //follow-up commands continue the workflow of the event
+                    let correlation_id = envelope.correlation_id.unwrap_or(envelope.id);
+                    for command in self.manager.handle(&envelope) {
+                        let result = service.correlated(correlation_id, envelope.id, |service| {
+                            command.clone().execute(service)
+                        });
+                        match result {
+                            Ok(_) => {}
+                            Err(ShortenerError::StorageFailure) => {
+                                return Err(ShortenerError::StorageFailure);
+                            }
+                            Err(error) => self.manager.command_failed(command, error),
+                        }
+                    }
+                    self.position = envelope.sequence + 1;
+                    handled += 1;
+                }
+            }
+        }
+    }
+
+    /// [`ProcessManager`] disabling links whose destination was found
+    /// unhealthy, see [`Event::DestinationUnhealthy`]. Links are not enabled
+    /// again when their destination recovers.
+    #[derive(Debug, Clone, Copy, Default)]
+    pub struct DisableUnhealthyLinks;
+
+    impl ProcessManager for DisableUnhealthyLinks {
+        fn handle(&mut self, envelope: &EventEnvelope) -> Vec<Command> {
+            match &envelope.event {
+                Event::DestinationUnhealthy { slug, .. } => {
+                    vec![Command::DisableLink { slug: slug.clone() }]
+                }
+                _ => Vec::new(),
+            }
+        }
+    }
+
+    /// [`ProcessManager`] calling the function with the [`Slug`] and the
+    /// number of redirects of every [`Event::MilestoneReached`], e.g. to
+    /// notify the owner of the link. It issues no commands.
+    pub struct MilestoneNotifier<F: FnMut(&Slug, u64)> {
+        notify: F,
+    }
+
+    impl<F: FnMut(&Slug, u64)> MilestoneNotifier<F> {
+        /// Creates a notifier calling the given function.
+        pub fn new(notify: F) -> Self {
+            Self { notify }
+        }
+    }
+
+    impl<F: FnMut(&Slug, u64)> ProcessManager for MilestoneNotifier<F> {
+        fn handle(&mut self, envelope: &EventEnvelope) -> Vec<Command> {
+            if let Event::MilestoneReached { slug, clicks } = &envelope.event {
+                (self.notify)(slug, *clicks);
+            }
+            Vec::new()
+        }
+    }
+}
+
+/// Aggregates, the consistency boundaries of the domain rebuilt from the
+/// events of their own stream, and the [`AggregateRepository`] loading and
+/// persisting them. New behaviors of a link are added to [`LinkAggregate`]
+/// as a [`LinkCommand`] and the events it results in.
+pub mod aggregate {
+    use super::store::{EventStore, StreamId};
+    use super::{Event, EventEnvelope, ShortLink, ShortenerError, Slug, Url};
+
+    /// State rebuilt from the events of a single stream which decides the
+    /// events resulting from its commands.
+    pub trait Aggregate: Default {
+        /// Commands handled by the aggregate.
+        type Command;
+
+        /// Returns the events resulting from the command, in order, without
+        /// changing the aggregate. No events means there is nothing to do.
+        ///
+        /// ## Errors
+        ///
+        /// Returns the [`ShortenerError`] the command is rejected with.
+        fn handle(&self, command: Self::Command) -> Result<Vec<Event>, ShortenerError>;
+
+        /// Applies an event of the stream of the aggregate.
+        fn apply(&mut self, event: &Event);
+
+        /// Rebuilds the aggregate from the envelopes of its stream.
+        fn load<'a>(envelopes: impl IntoIterator<Item = &'a EventEnvelope>) -> Self {
+            let mut aggregate = Self::default();
+            for envelope in envelopes {
+                aggregate.apply(&envelope.event);
+            }
+            aggregate
+        }
+    }
+
+    /// Commands of a [`LinkAggregate`].
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum LinkCommand {
+        /// Creates the link, unless its [`Slug`] is used by a link which is
+        /// not deleted.
+        Create { slug: Slug, url: Url },
+
+        /// Counts a redirect of the link, exhausting it with the last click
+        /// allowed.
+        Access,
+
+        /// Changes the destination of the link.
+        ChangeUrl { url: Url },
+
+        /// Disables the link until it is enabled again.
+        Disable,
+
+        /// Enables the disabled link.
+        Enable,
+
+        /// Deletes the link.
+        Delete,
+    }
+
+    /// Aggregate of a single link, built from the stream of its [`Slug`].
+    ///
+    /// It only guards the invariants of the link itself: validating
+    /// [`Url`]s and [`Slug`]s and keeping [`Slug`]s unique across streams is
+    /// up to the caller, e.g. the [`UrlShortenerService`]. Renamed links
+    /// continue in the stream of the new [`Slug`], which doesn't contain the
+    /// creation of the link.
+    ///
+    /// [`UrlShortenerService`]: super::UrlShortenerService
+    #[derive(Debug, Clone, Default, PartialEq)]
+    pub struct LinkAggregate {
+        link: Option<ShortLink>,
+        deleted: bool,
+        disabled: bool,
+        exhausted: bool,
+        max_clicks: Option<u64>,
+        redirects: u64,
+    }
+
+    impl LinkAggregate {
+        /// Returns the link unless it was never created or is deleted.
+        pub fn link(&self) -> Option<&ShortLink> {
+            self.link.as_ref().filter(|_| !self.deleted)
+        }
+
+        /// Whether the link is disabled.
+        pub fn is_disabled(&self) -> bool {
+            self.disabled
+        }
+
+        /// Number of redirects of the link.
+        pub fn redirects(&self) -> u64 {
+            self.redirects
+        }
+    }
+
+    impl Aggregate for LinkAggregate {
+        type Command = LinkCommand;
+
+        fn handle(&self, command: LinkCommand) -> Result<Vec<Event>, ShortenerError> {
+            let Some(link) = self.link() else {
+                return match command {
+                    LinkCommand::Create { slug, url } => Ok(vec![Event::LinkCreated {
+                        slug,
+                        url,
+                        raw_url: None,
+                        max_clicks: None,
+                        owner: None,
+                        active_from: None,
+                        active_until: None,
+                        fallback_url: None,
+                    }]),
+                    _ => Err(ShortenerError::SlugNotFound),
+                };
+            };
+            let slug = link.slug.clone();
+            let events = match command {
+                LinkCommand::Create { .. } => return Err(ShortenerError::SlugAlreadyInUse),
+                LinkCommand::Access if self.disabled => {
+                    return Err(ShortenerError::LinkDisabled);
+                }
+                LinkCommand::Access if self.exhausted => {
+                    return Err(ShortenerError::LinkExhausted);
+                }
+                LinkCommand::Access => {
+                    let last_click = self.max_clicks.is_some_and(|max| self.redirects + 1 >= max);
+                    let mut events = vec![Event::LinkAccessed { slug: slug.clone() }];
+                    if last_click {
+                        events.push(Event::LinkExhausted { slug });
+                    }
+                    events
+                }
+                LinkCommand::ChangeUrl { url } if url == link.url => Vec::new(),
+                LinkCommand::ChangeUrl { url } => vec![Event::UrlChanged { slug, new_url: url }],
+                LinkCommand::Disable if self.disabled => Vec::new(),
+                LinkCommand::Disable => vec![Event::LinkDisabled { slug }],
+                LinkCommand::Enable if !self.disabled => Vec::new(),
+                LinkCommand::Enable => vec![Event::LinkEnabled { slug }],
+                LinkCommand::Delete => vec![Event::LinkDeleted { slug }],
+            };
+            Ok(events)
+        }
+
+        fn apply(&mut self, event: &Event) {
+            match event {
+                Event::LinkCreated { slug, url, max_clicks, .. } => {
+                    *self = Self {
+                        link: Some(ShortLink { slug: slug.clone(), url: url.clone() }),
+                        max_clicks: *max_clicks,
+                        ..Self::default()
+                    };
+                }
+                Event::LinkAccessed { .. } | Event::LinkAccessedV2 { .. } => self.redirects += 1,
+                Event::ClicksAggregated { count, .. } => self.redirects += count,
+                Event::LinkExhausted { .. } => self.exhausted = true,
+                Event::UrlChanged { new_url, .. } => {
+                    if let Some(link) = &mut self.link {
+                        link.url = new_url.clone();
+                    }
+                }
+                Event::LinkDisabled { .. } => self.disabled = true,
+                Event::LinkEnabled { .. } => self.disabled = false,
+                Event::LinkDeleted { .. } => self.deleted = true,
+                Event::SlugRenamed { new_slug, .. } => {
+                    if let Some(link) = &mut self.link {
+                        link.slug = new_slug.clone();
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Loads [`Aggregate`]s from an [`EventStore`] and persists the events
+    /// resulting from their commands, with optimistic concurrency on the
+    /// version of their stream.
+    ///
+    /// The repository writes to the store directly, so a
+    /// [`UrlShortenerService`] on top of the same store doesn't see the
+    /// events until it is rebuilt. Use
+    /// [`UrlShortenerService::execute_aggregate()`] to keep it up to date.
+    ///
+    /// [`UrlShortenerService`]: super::UrlShortenerService
+    /// [`UrlShortenerService::execute_aggregate()`]: super::UrlShortenerService::execute_aggregate
+    #[derive(Debug, Default)]
+    pub struct AggregateRepository<S: EventStore> {
+        store: S,
+    }
+
+    impl<S: EventStore> AggregateRepository<S> {
+        /// Creates a repository on top of the given [`EventStore`].
+        pub fn new(store: S) -> Self {
+            Self { store }
+        }
+
+        /// Returns the underlying [`EventStore`].
+        pub fn store(&self) -> &S {
+            &self.store
+        }
+
+        /// Returns the underlying [`EventStore`], consuming the repository.
+        pub fn into_inner(self) -> S {
+            self.store
+        }
+
+        /// Rebuilds the aggregate from its stream, returning it with the
+        /// current version of the stream.
+        pub fn load<A: Aggregate>(&self, stream: &StreamId) -> (A, u64) {
+            let aggregate = A::load(&self.store.read_stream(stream));
+            (aggregate, self.store.stream_version(stream))
+        }
+
+        /// Loads the aggregate, handles the command and persists the
+        /// resulting events, returning their envelopes.
+        ///
+        /// ## Errors
+        ///
+        /// Returns the error the command is rejected with, or the errors of
+        /// [`AggregateRepository::save()`].
+        pub fn execute<A: Aggregate>(
+            &mut self,
+            stream: &StreamId,
+            command: A::Command,
+        ) -> Result<Vec<EventEnvelope>, ShortenerError> {
+            let (aggregate, version) = self.load::<A>(stream);
+            let events = aggregate.handle(command)?;
+            self.save(stream, version, events)
+        }
+
+        /// Appends the events to the log, provided the stream of the aggregate
+        /// is still at the expected version.
+        ///
+        /// ## Errors
+        ///
+        /// Returns [`ShortenerError::VersionConflict`] if the stream was
+        /// modified since it was loaded, or
+        /// [`ShortenerError::StorageFailure`] if an event could not be
+        /// stored. Events appended before the failing one stay stored.
+        pub fn save(
+            &mut self,
+            stream: &StreamId,
+            expected_version: u64,
+            events: Vec<Event>,
+        ) -> Result<Vec<EventEnvelope>, ShortenerError> {
+            if self.store.stream_version(stream) != expected_version {
+                return Err(ShortenerError::VersionConflict);
+            }
+            let next = self.store.last_sequence().map_or(0, |sequence| sequence + 1);
+            let mut envelopes = Vec::with_capacity(events.len());
+            for (sequence, event) in (next..).zip(events) {
+                let version = self.store.stream_version(&event.stream_id()) + 1;
+                let envelope = EventEnvelope::new(sequence, version, event);
+                self.store
+                    .append(envelope.clone())
+                    .map_err(|_| ShortenerError::StorageFailure)?;
+                envelopes.push(envelope);
+            }
+            Ok(envelopes)
+        }
+    }
+}
+
+/// Transactional outbox of integration events: messages for other systems
+/// derived from the recorded events, see
+/// [`UrlShortenerService::with_outbox()`], stored atomically with them and
+/// delivered by an [`OutboxDispatcher`].
+///
+/// A message is removed from the outbox only after it was delivered, so none
+/// is lost if the process crashes in between; it is delivered again instead.
+pub mod outbox {
+    use std::sync::mpsc::{self, Receiver, RecvTimeoutError, TryRecvError};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::store::EventStore;
+    use super::{random_uuid, EventEnvelope, SharedUrlShortenerService, Uuid};
+
+    /// Integration event waiting in the outbox of an [`EventStore`] to be
+    /// delivered.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub struct OutboxMessage {
+        /// Unique identifier of the message, for deduplicating messages
+        /// delivered more than once.
+        pub id: Uuid,
+
+        /// Identifier of the event the message was derived from.
+        pub event_id: Uuid,
+
+        /// Sequence of the event the message was derived from.
+        pub sequence: u64,
+
+        /// Topic (or queue) the message is delivered to.
+        pub topic: String,
+
+        /// Content of the message, e.g. JSON.
+        pub payload: String,
+    }
+
+    impl OutboxMessage {
+        /// Creates a message derived from the event.
+        pub fn new(
+            envelope: &EventEnvelope,
+            topic: impl Into<String>,
+            payload: impl Into<String>,
+        ) -> Self {
+            Self {
+                id: random_uuid(),
+                event_id: envelope.id,
+                sequence: envelope.sequence,
+                topic: topic.into(),
+                payload: payload.into(),
+            }
+        }
+    }
+
+    /// Destination the [`OutboxDispatcher`] delivers messages to, implemented
+    /// for closures.
+    pub trait OutboxSink {
+        /// Delivers the message, returning once the destination accepted it.
+        ///
+        /// ## Errors
+        ///
+        /// Returns the description of the failure, the message is then
+        /// delivered again after a backoff.
+        fn deliver(&mut self, message: &OutboxMessage) -> Result<(), String>;
+    }
+
+    impl<F> OutboxSink for F
+    where
+        F: FnMut(&OutboxMessage) -> Result<(), String>,
+    {
+        fn deliver(&mut self, message: &OutboxMessage) -> Result<(), String> {
+            self(message)
+        }
+    }
+
+    /// Configuration of the [`OutboxDispatcher`].
+    #[derive(Debug, Clone)]
+    pub struct OutboxConfig {
+        /// Interval between checks of the outbox for new messages.
+        pub poll_interval: Duration,
+
+        /// Delay before the first retry, doubled after every failed attempt.
+        pub initial_backoff: Duration,
+
+        /// Upper bound of the delay between retries.
+        pub max_backoff: Duration,
+    }
+
+    impl Default for OutboxConfig {
+        fn default() -> Self {
+            Self {
+                poll_interval: Duration::from_secs(1),
+                initial_backoff: Duration::from_millis(500),
+                max_backoff: Duration::from_secs(30),
+            }
+        }
+    }
+
+    /// Background thread draining the outbox of a
+    /// [`SharedUrlShortenerService`] into an [`OutboxSink`], in order.
+    ///
+    /// A message the sink failed to accept is retried with exponential backoff
+    /// until it is, so later messages wait for it. Delivered messages are
+    /// removed with [`UrlShortenerService::acknowledge_outbox()`]. Dropping the
+    /// dispatcher stops the thread once the message being delivered is done.
+    ///
+    /// [`UrlShortenerService::acknowledge_outbox()`]:
+    /// super::UrlShortenerService::acknowledge_outbox
+    #[derive(Debug)]
+    pub struct OutboxDispatcher {
+        stop: mpsc::Sender<()>,
+        thread: thread::JoinHandle<()>,
+    }
+
+    impl OutboxDispatcher {
+        /// Starts draining the outbox of the service, checking it every
+        /// [`OutboxConfig::poll_interval`].
+        pub fn spawn<S, K>(
+            service: SharedUrlShortenerService<S>,
+            sink: K,
+            config: OutboxConfig,
+        ) -> Self
+        where
+            S: EventStore + Send + Sync + 'static,
+            K: OutboxSink + Send + 'static,
+        {
+            let (stop, stopped) = mpsc::channel();
+            let thread = thread::spawn(move || dispatch_all(&service, sink, &config, &stopped));
+            Self { stop, thread }
+        }
+
+        /// Stops the thread for a graceful shutdown, waiting for the message
+        /// being delivered.
+        pub fn stop(self) {
+            let _ = self.stop.send(());
+            self.thread
+                .join()
+                .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+        }
+    }
+
+    //dispatching thread loop
+    fn dispatch_all<S: EventStore, K: OutboxSink>(
+        service: &SharedUrlShortenerService<S>,
+        mut sink: K,
+        config: &OutboxConfig,
+        stopped: &Receiver<()>,
+    ) {
+        loop {
+            let messages = service.read().pending_outbox();
+            for message in messages {
+                if !matches!(stopped.try_recv(), Err(TryRecvError::Empty)) {
+                    return;
+                }
+                let mut backoff = config.initial_backoff;
+                while sink.deliver(&message).is_err() {
+                    if !matches!(stopped.recv_timeout(backoff), Err(RecvTimeoutError::Timeout)) {
+                        return;
+                    }
+                    backoff = (backoff * 2).min(config.max_backoff);
+                }
+                //a message which failed to be removed is delivered again
+                let _ = service.write().acknowledge_outbox(message.id);
+            }
+            //stopped explicitly or dropped
+            if !matches!(
+                stopped.recv_timeout(config.poll_interval),
+                Err(RecvTimeoutError::Timeout)
+            ) {
+                return;
+            }
+        }
+    }
+}
+
+/// Append-only audit log of the executed commands, including the rejected
+/// ones, see [`UrlShortenerService::query_audit()`].
+pub mod audit {
+    use std::io;
+    use std::time::SystemTime;
+
+    use super::commands::{Command, CommandEnvelope};
+    use super::{ShortenerError, Slug, Uuid};
+
+    /// Record of a [`Command`] received by the service with its outcome.
+    #[derive(Debug, Clone, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub struct AuditEntry {
+        /// Identifier of the command, see [`CommandEnvelope::id`].
+        pub id: Uuid,
+
+        /// Identifier of the workflow the command is a part of, see
+        /// [`CommandEnvelope::correlation_id`].
+        pub correlation_id: Option<Uuid>,
+
+        /// Moment the command was received at.
+        pub received_at: SystemTime,
+
+        /// The received [`Command`].
+        pub command: Command,
+
+        /// Whether the command was accepted or rejected.
+        pub outcome: AuditOutcome,
+    }
+
+    impl AuditEntry {
+        pub(crate) fn new(
+            envelope: &CommandEnvelope,
+            received_at: SystemTime,
+            outcome: AuditOutcome,
+        ) -> Self {
+            Self {
+                id: envelope.id,
+                correlation_id: envelope.correlation_id,
+                received_at,
+                command: envelope.command.clone(),
+                outcome,
+            }
+        }
+    }
+
+    /// Outcome of an audited [`Command`].
+    #[derive(Debug, Clone, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub enum AuditOutcome {
+        /// The command succeeded, recording the events with the given ids.
+        /// Commands which change nothing record no events.
+        Accepted { events: Vec<Uuid> },
+
+        /// The command failed with the given error.
+        Rejected { error: ShortenerError },
+    }
+
+    /// Append-only storage of [`AuditEntry`]s, kept apart from the
+    /// [`EventStore`] as it also holds commands which changed nothing.
+    ///
+    /// [`EventStore`]: super::store::EventStore
+    pub trait AuditLog {
+        /// Appends the entry at the end of the log.
+        ///
+        /// ## Errors
+        ///
+        /// Returns an [`io::Error`] if the entry could not be persisted.
+        fn append(&mut self, entry: AuditEntry) -> io::Result<()>;
+
+        /// Returns all entries in the order they were appended.
+        fn entries(&self) -> Vec<AuditEntry>;
+    }
+
+    /// [`AuditLog`] keeping all entries in memory.
+    #[derive(Debug, Default, Clone)]
+    pub struct InMemoryAuditLog {
+        entries: Vec<AuditEntry>,
+    }
+
+    impl InMemoryAuditLog {
+        /// Creates an empty log.
+        pub fn new() -> Self {
+            Self::default()
+        }
+    }
+
+    impl AuditLog for InMemoryAuditLog {
+        fn append(&mut self, entry: AuditEntry) -> io::Result<()> {
+            self.entries.push(entry);
+            Ok(())
+        }
+
+        fn entries(&self) -> Vec<AuditEntry> {
+            self.entries.clone()
+        }
+    }
+
+    /// Criteria of [`UrlShortenerService::query_audit()`], matching every
+    /// entry by default.
+    ///
+    /// [`UrlShortenerService::query_audit()`]: super::UrlShortenerService::query_audit
+    #[derive(Debug, Clone, Default, PartialEq)]
+    pub struct AuditFilter {
+        slug: Option<Slug>,
+        rejected: Option<bool>,
+        correlation_id: Option<Uuid>,
+        from: Option<SystemTime>,
+        until: Option<SystemTime>,
+    }
+
+    impl AuditFilter {
+        /// Creates a filter matching every entry.
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Matches only commands targeting the link, see [`Command::slug()`].
+        pub fn slug(mut self, slug: Slug) -> Self {
+            self.slug = Some(slug);
+            self
+        }
+
+        /// Matches only rejected commands if `true`, or only accepted ones
+        /// if `false`.
+        pub fn rejected(mut self, rejected: bool) -> Self {
+            self.rejected = Some(rejected);
+            self
+        }
+
+        /// Matches only commands of the workflow, including the command
+        /// which started it.
+        pub fn correlation_id(mut self, correlation_id: Uuid) -> Self {
+            self.correlation_id = Some(correlation_id);
+            self
+        }
+
+        /// Matches only commands received at or after the moment.
+        pub fn from(mut self, from: SystemTime) -> Self {
+            self.from = Some(from);
+            self
+        }
+
+        /// Matches only commands received before the moment.
+        pub fn until(mut self, until: SystemTime) -> Self {
+            self.until = Some(until);
+            self
+        }
+
+        /// Whether the entry meets all the criteria.
+        pub fn matches(&self, entry: &AuditEntry) -> bool {
+            let rejected = matches!(entry.outcome, AuditOutcome::Rejected { .. });
+            self.slug.as_ref().is_none_or(|slug| entry.command.slug() == Some(slug))
+                && self.rejected.is_none_or(|expected| rejected == expected)
+                && self
+                    .correlation_id
+                    .is_none_or(|id| entry.id == id || entry.correlation_id == Some(id))
+                && self.from.is_none_or(|from| entry.received_at >= from)
+                && self.until.is_none_or(|until| entry.received_at < until)
+        }
+    }
+}
+
+/// Caching of query results for deployments where queries vastly outnumber
+/// commands.
+pub mod cache {
+    use std::collections::{HashMap, HashSet};
+    use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+    use std::time::{Duration, Instant};
+
+    use super::commands::CommandHandler;
+    use super::queries::{LinkQueryHandler, QueryHandler};
+    use super::{
+        EventEnvelope, EventListener, GlobalStats, LinkDetails, OwnerId, ShortLink, ShortenerError,
+        Slug, Stats, Url,
+    };
+
+    struct Entry<T> {
+        value: T,
+        cached_at: Instant,
+    }
+
+    #[derive(Default)]
+    struct Entries {
+        stats: HashMap<Slug, Entry<Stats>>,
+        links: HashMap<Slug, Entry<ShortLink>>,
+        //slugs results were looked up by, by the slug of their link
+        keys: HashMap<Slug, HashSet<Slug>>,
+        //number of invalidations so far
+        generation: u64,
+        //lookups in flight, and the generation the links were last
+        //invalidated at while there were any
+        lookups: usize,
+        invalidated: HashMap<Slug, u64>,
+    }
+
+    impl Entries {
+        //drops results of the link, whichever slug they were looked up by
+        fn invalidate(&mut self, slug: &Slug) {
+            self.generation += 1;
+            if self.lookups > 0 {
+                self.invalidated.insert(slug.clone(), self.generation);
+            }
+            let mut keys = self.keys.remove(slug).unwrap_or_default();
+            keys.insert(slug.clone());
+            for key in &keys {
+                self.stats.remove(key);
+                self.links.remove(key);
+            }
+        }
+
+        //whether the link was invalidated after the generation
+        fn invalidated_since(&self, slug: &Slug, generation: u64) -> bool {
+            self.invalidated.get(slug).is_some_and(|&at| at > generation)
+        }
+    }
+
+    /// [`QueryHandler`] decorator caching [`Stats`] and [`ShortLink`] lookups
+    /// of the wrapped handler for a time to live.
+    ///
+    /// Cached results are dropped as soon as an event of their link is
+    /// recorded, once the [`CacheInvalidator`] returned by
+    /// [`QueryCache::invalidator()`] is subscribed to the service. Commands
+    /// are passed through to the wrapped handler.
+    pub struct QueryCache<Q> {
+        inner: Q,
+        ttl: Duration,
+        entries: Arc<Mutex<Entries>>,
+    }
+
+    impl<Q> QueryCache<Q> {
+        /// Wraps the handler, caching its results for `ttl`.
+        pub fn new(inner: Q, ttl: Duration) -> Self {
+            Self {
+                inner,
+                ttl,
+                entries: Arc::default(),
+            }
+        }
+
+        /// Returns the [`EventListener`] invalidating the cache, to be
+        /// subscribed to the service recording the events.
+        pub fn invalidator(&self) -> CacheInvalidator {
+            CacheInvalidator {
+                entries: Arc::clone(&self.entries),
+            }
+        }
+
+        /// Drops all cached results.
+        pub fn clear(&self) {
+            let mut entries = self.entries();
+            entries.stats.clear();
+            entries.links.clear();
+            entries.keys.clear();
+        }
+
+        /// Returns the wrapped handler.
+        pub fn inner(&self) -> &Q {
+            &self.inner
+        }
+
+        /// Returns the wrapped handler, e.g. to subscribe the
+        /// [`CacheInvalidator`] to it.
+        pub fn inner_mut(&mut self) -> &mut Q {
+            &mut self.inner
+        }
+
+        /// Unwraps the handler.
+        pub fn into_inner(self) -> Q {
+            self.inner
+        }
+
+        fn entries(&self) -> MutexGuard<'_, Entries> {
+            self.entries.lock().unwrap_or_else(PoisonError::into_inner)
+        }
+
+        //cached result if still fresh, otherwise looked up and cached unless
+        //failed or its link was invalidated while looking it up
+        fn cached<T: Clone>(
+            &self,
+            slug: Slug,
+            map: fn(&mut Entries) -> &mut HashMap<Slug, Entry<T>>,
+            owner: fn(&T) -> &Slug,
+            lookup: impl FnOnce(Slug) -> Result<T, ShortenerError>,
+        ) -> Result<T, ShortenerError> {
+            let started = {
+                let mut entries = self.entries();
+                if let Some(entry) = map(&mut entries).get(&slug) {
+                    if entry.cached_at.elapsed() < self.ttl {
+                        return Ok(entry.value.clone());
+                    }
+                }
+                entries.lookups += 1;
+                entries.generation
+            };
+            let result = lookup(slug.clone());
+            let mut entries = self.entries();
+            entries.lookups -= 1;
+            if let Ok(value) = &result {
+                let link = owner(value);
+                if !entries.invalidated_since(&slug, started)
+                    && !entries.invalidated_since(link, started)
+                {
+                    entries.keys.entry(link.clone()).or_default().insert(slug.clone());
+                    map(&mut entries).insert(slug, Entry {
+                        value: value.clone(),
+                        cached_at: Instant::now(),
+                    });
+                }
+            }
+            if entries.lookups == 0 {
+                entries.invalidated.clear();
+            }
+            result
+        }
+    }
+
+    impl<Q: QueryHandler> QueryHandler for QueryCache<Q> {
+        fn get_stats(&self, slug: Slug) -> Result<Stats, ShortenerError> {
+            self.cached(
+                slug,
+                |entries| &mut entries.stats,
+                |stats| &stats.link.slug,
+                |slug| self.inner.get_stats(slug),
+            )
+        }
+    }
+
+    impl<Q: LinkQueryHandler> LinkQueryHandler for QueryCache<Q> {
+        fn get_link(&self, slug: Slug) -> Result<ShortLink, ShortenerError> {
+            self.cached(
+                slug,
+                |entries| &mut entries.links,
+                |link| &link.slug,
+                |slug| self.inner.get_link(slug),
+            )
+        }
+
+        fn get_link_details(&self, slug: Slug) -> Result<LinkDetails, ShortenerError> {
+            self.inner.get_link_details(slug)
+        }
+
+        fn find_by_url(&self, url: Url) -> Vec<ShortLink> {
+            self.inner.find_by_url(url)
+        }
+
+        fn list_links_by_owner(&self, owner: OwnerId) -> Vec<ShortLink> {
+            self.inner.list_links_by_owner(owner)
+        }
+
+        fn top_links(&self, n: usize) -> Vec<Stats> {
+            self.inner.top_links(n)
+        }
+
+        fn global_stats(&self) -> GlobalStats {
+            self.inner.global_stats()
+        }
+
+        fn list_aliases(&self, slug: Slug) -> Result<Vec<Slug>, ShortenerError> {
+            self.inner.list_aliases(slug)
+        }
+    }
+
+    impl<Q: CommandHandler> CommandHandler for QueryCache<Q> {
+        fn handle_create_short_link(
+            &mut self,
+            url: Url,
+            slug: Option<Slug>,
+        ) -> Result<ShortLink, ShortenerError> {
+            self.inner.handle_create_short_link(url, slug)
+        }
+
+        fn handle_redirect(
+            &mut self,
+            slug: Slug,
+        ) -> Result<ShortLink, ShortenerError> {
+            self.inner.handle_redirect(slug)
+        }
+
+        fn handle_change_short_link(
+            &mut self,
+            slug: Slug,
+            new_url: Url
+        ) -> Result<ShortLink, ShortenerError> {
+            self.inner.handle_change_short_link(slug, new_url)
+        }
+    }
+
+    /// [`EventListener`] dropping cached results of a [`QueryCache`] of the
+    /// links events are recorded for.
+    #[derive(Clone)]
+    pub struct CacheInvalidator {
+        entries: Arc<Mutex<Entries>>,
+    }
+
+    impl EventListener for CacheInvalidator {
+        fn on_event(&mut self, envelope: &EventEnvelope) {
+            let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+            entries.invalidate(envelope.event.slug());
+            match &envelope.event {
+                super::Event::SlugRenamed { new_slug: other, .. }
+                | super::Event::AliasAdded { alias: other, .. } => entries.invalidate(other),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// API-key authentication and role-based authorization of the service.
+///
+/// Keys are issued to an [`OwnerId`] and kept in an [`ApiKeyStore`], which is
+/// event sourced like the links themselves, together with the [`Role`]s
+/// assigned to the owners. An [`AuthenticatedService`] checks the key before
+/// every command and only runs commands permitted by the role of its owner.
+pub mod auth {
+    use std::collections::HashMap;
+    use std::time::SystemTime;
+
+    use rand::distributions::Alphanumeric;
+    use rand::{thread_rng, Rng};
+    use sha2::{Digest, Sha256};
+
+    use super::commands::{
+        CampaignHandler, CommandHandler, LinkManagementHandler, OwnedLinkHandler, TagHandler,
+    };
+    use super::queries::QueryHandler;
+    use super::store::{EventStore, InMemoryEventStore};
+    use super::{
+        Campaign, Device, LinkOptions, OwnerId, RedirectPolicy, ShortLink, ShortenerError, Slug,
+        Stats, Url, UrlShortenerService,
+    };
+
+    /// Secret API key. Only its hash is stored.
+    #[derive(Clone, PartialEq, Eq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub struct ApiKey(pub String);
+
+    impl std::fmt::Debug for ApiKey {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.write_str("ApiKey(..)")
+        }
+    }
+
+    impl ApiKey {
+        //hex encoded sha-256 the key is stored as
+        fn hash(&self) -> String {
+            Sha256::digest(self.0.as_bytes())
+                .iter()
+                .map(|byte| format!("{byte:02x}"))
+                .collect()
+        }
+    }
+
+    /// Role of an owner, limiting the commands it may issue. Roles are
+    /// ordered, every role permits everything the lower ones do.
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub enum Role {
+        /// May only follow links and query them.
+        Viewer,
+        /// May also create links and modify links it owns, except for
+        /// deleting them.
+        #[default]
+        Editor,
+        /// May also delete links and modify links of other owners.
+        Admin,
+    }
+
+    /// Event of the [`ApiKeyStore`] log.
+    #[derive(Debug, Clone, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub enum AuthEvent {
+        ApiKeyIssued {
+            key_hash: String,
+            owner: OwnerId,
+            occurred_at: SystemTime,
+        },
+
+        ApiKeyRevoked {
+            key_hash: String,
+            occurred_at: SystemTime,
+        },
+
+        RoleAssigned {
+            owner: OwnerId,
+            role: Role,
+            occurred_at: SystemTime,
+        },
+    }
+
+    /// Event sourced store of issued [`ApiKey`]s and assigned [`Role`]s.
+    #[derive(Debug, Default)]
+    pub struct ApiKeyStore {
+        events: Vec<AuthEvent>,
+        owners: HashMap<String, OwnerId>,
+        roles: HashMap<OwnerId, Role>,
+    }
+
+    impl ApiKeyStore {
+        /// Length of issued keys.
+        pub const KEY_LENGTH: usize = 32;
+
+        /// Creates an empty store.
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Rebuilds the store from its previously recorded events.
+        pub fn from_events(events: Vec<AuthEvent>) -> Self {
+            let mut store = Self::default();
+            for event in events {
+                store.apply(event);
+            }
+            store
+        }
+
+        /// Returns all recorded events in the order they were recorded.
+        pub fn events(&self) -> &[AuthEvent] {
+            &self.events
+        }
+
+        /// Issues a new random [`ApiKey`] for the given owner. The key is
+        /// returned only here, so it must be handed over to the owner right
+        /// away.
+        pub fn issue_key(&mut self, owner: OwnerId) -> ApiKey {
+            let key = ApiKey(
+                thread_rng()
+                    .sample_iter(&Alphanumeric)
+                    .take(Self::KEY_LENGTH)
+                    .map(char::from)
+                    .collect(),
+            );
+            self.apply(AuthEvent::ApiKeyIssued {
+                key_hash: key.hash(),
+                owner,
+                occurred_at: SystemTime::now(),
+            });
+            key
+        }
+
+        /// Revokes the given [`ApiKey`], so it is no longer accepted.
+        ///
+        /// ## Errors
+        ///
+        /// Returns [`ShortenerError::Unauthorized`] if the key is unknown or
+        /// already revoked.
+        pub fn revoke_key(&mut self, key: &ApiKey) -> Result<(), ShortenerError> {
+            self.authenticate(key)?;
+            self.apply(AuthEvent::ApiKeyRevoked {
+                key_hash: key.hash(),
+                occurred_at: SystemTime::now(),
+            });
+            Ok(())
+        }
+
+        /// Returns the owner of the given [`ApiKey`].
+        ///
+        /// ## Errors
+        ///
+        /// Returns [`ShortenerError::Unauthorized`] if the key is unknown or
+        /// revoked.
+        pub fn authenticate(&self, key: &ApiKey) -> Result<OwnerId, ShortenerError> {
+            self.owners
+                .get(&key.hash())
+                .cloned()
+                .ok_or(ShortenerError::Unauthorized)
+        }
+
+        /// Assigns the [`Role`] to the owner, replacing its previous role.
+        pub fn assign_role(&mut self, owner: OwnerId, role: Role) {
+            self.apply(AuthEvent::RoleAssigned {
+                owner,
+                role,
+                occurred_at: SystemTime::now(),
+            });
+        }
+
+        /// Returns the [`Role`] of the owner, [`Role::Editor`] unless
+        /// assigned otherwise.
+        pub fn role(&self, owner: &OwnerId) -> Role {
+            self.roles.get(owner).copied().unwrap_or_default()
+        }
+
+        fn apply(&mut self, event: AuthEvent) {
+            match &event {
+                AuthEvent::ApiKeyIssued { key_hash, owner, .. } => {
+                    self.owners.insert(key_hash.clone(), owner.clone());
+                }
+                AuthEvent::ApiKeyRevoked { key_hash, .. } => {
+                    self.owners.remove(key_hash);
+                }
+                AuthEvent::RoleAssigned { owner, role, .. } => {
+                    self.roles.insert(owner.clone(), *role);
+                }
+            }
+            self.events.push(event);
+        }
+    }
+
+    /// [`UrlShortenerService`] accepting commands only with a valid
+    /// [`ApiKey`].
+    pub struct AuthenticatedService<S: EventStore = InMemoryEventStore> {
+        service: UrlShortenerService<S>,
+        keys: ApiKeyStore,
+    }
+
+    impl<S: EventStore> AuthenticatedService<S> {
+        /// Wraps the service, authenticating commands with the given keys.
+        pub fn new(service: UrlShortenerService<S>, keys: ApiKeyStore) -> Self {
+            Self { service, keys }
+        }
+
+        /// Returns the [`ApiKeyStore`].
+        pub fn keys(&self) -> &ApiKeyStore {
+            &self.keys
+        }
+
+        /// Returns the [`ApiKeyStore`] to issue or revoke keys.
+        pub fn keys_mut(&mut self) -> &mut ApiKeyStore {
+            &mut self.keys
+        }
+
+        /// Returns the wrapped service, e.g. for queries which don't need
+        /// authentication.
+        pub fn service(&self) -> &UrlShortenerService<S> {
+            &self.service
+        }
+
+        /// Unwraps the service and the key store.
+        pub fn into_inner(self) -> (UrlShortenerService<S>, ApiKeyStore) {
+            (self.service, self.keys)
+        }
+
+        /// Authenticates the [`ApiKey`], returning the handle to issue
+        /// commands on behalf of its owner.
+        ///
+        /// ## Errors
+        ///
+        /// Returns [`ShortenerError::Unauthorized`] if the key is unknown or
+        /// revoked.
+        pub fn authenticate(
+            &mut self,
+            key: &ApiKey,
+        ) -> Result<AuthenticatedSession<'_, S>, ShortenerError> {
+            let owner = self.keys.authenticate(key)?;
+            Ok(AuthenticatedSession {
+                service: &mut self.service,
+                role: self.keys.role(&owner),
+                owner,
+            })
+        }
+    }
+
+    /// Handle issuing commands on behalf of the owner of an authenticated
+    /// [`ApiKey`], as permitted by its [`Role`]:
+    ///
+    /// - redirects and queries need [`Role::Viewer`],
+    /// - creating links and modifying own links needs [`Role::Editor`],
+    /// - deleting links and modifying links of other owners needs
+    ///   [`Role::Admin`].
+    ///
+    /// Commands not permitted by the role fail with
+    /// [`ShortenerError::Forbidden`], commands of editors on links of other
+    /// owners with [`ShortenerError::NotOwner`]. Created links are owned by
+    /// the owner of the key.
+    pub struct AuthenticatedSession<'a, S: EventStore = InMemoryEventStore> {
+        service: &'a mut UrlShortenerService<S>,
+        owner: OwnerId,
+        role: Role,
+    }
+
+    impl<S: EventStore> AuthenticatedSession<'_, S> {
+        /// Returns the owner the commands are issued on behalf of.
+        pub fn owner(&self) -> &OwnerId {
+            &self.owner
+        }
+
+        /// Returns the [`Role`] of the owner.
+        pub fn role(&self) -> Role {
+            self.role
+        }
+
+        //fails unless the role is at least the required one and, for non admins, the link is owned
+        fn authorize(&self, required: Role, slug: Option<&Slug>) -> Result<(), ShortenerError> {
+            if self.role < required {
+                return Err(ShortenerError::Forbidden);
+            }
+            match slug {
+                Some(slug) if self.role < Role::Admin => self.service.check_owner(slug, &self.owner),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    impl<S: EventStore> CommandHandler for AuthenticatedSession<'_, S> {
+        fn handle_create_short_link(
+            &mut self,
+            url: Url,
+            slug: Option<Slug>,
+        ) -> Result<ShortLink, ShortenerError> {
+            self.authorize(Role::Editor, None)?;
+            self.service.handle_create_owned_link(self.owner.clone(), url, slug)
+        }
+
+        fn handle_redirect(
+            &mut self,
+            slug: Slug,
+        ) -> Result<ShortLink, ShortenerError> {
+            self.service.handle_redirect(slug)
+        }
+
+        fn handle_change_short_link(
+            &mut self,
+            slug: Slug,
+            new_url: Url
+        ) -> Result<ShortLink, ShortenerError> {
+            self.authorize(Role::Editor, Some(&slug))?;
+            self.service.handle_change_short_link(slug, new_url)
+        }
+    }
+
+    impl<S: EventStore> LinkManagementHandler for AuthenticatedSession<'_, S> {
+        fn handle_create_short_link_with_options(
+            &mut self,
+            url: Url,
+            slug: Option<Slug>,
+            options: LinkOptions,
+        ) -> Result<ShortLink, ShortenerError> {
+            self.authorize(Role::Editor, None)?;
+            let options = LinkOptions {
+                rate_limit_key: Some(self.owner.0.clone()),
+                owner: Some(self.owner.clone()),
+                ..options
+            };
+            self.service.handle_create_short_link_with_options(url, slug, options)
+        }
+
+        fn handle_delete_short_link(
+            &mut self,
+            slug: Slug,
+        ) -> Result<ShortLink, ShortenerError> {
+            self.authorize(Role::Admin, Some(&slug))?;
+            self.service.handle_delete_short_link(slug)
+        }
+
+        fn handle_disable_link(
+            &mut self,
+            slug: Slug,
+        ) -> Result<ShortLink, ShortenerError> {
+            self.authorize(Role::Editor, Some(&slug))?;
+            self.service.handle_disable_link(slug)
+        }
+
+        fn handle_enable_link(
+            &mut self,
+            slug: Slug,
+        ) -> Result<ShortLink, ShortenerError> {
+            self.authorize(Role::Editor, Some(&slug))?;
+            self.service.handle_enable_link(slug)
+        }
+
+        fn handle_rename_slug(
+            &mut self,
+            old: Slug,
+            new: Slug,
+        ) -> Result<ShortLink, ShortenerError> {
+            self.authorize(Role::Editor, Some(&old))?;
+            self.service.handle_rename_slug(old, new)
+        }
+
+        fn handle_add_alias(
+            &mut self,
+            slug: Slug,
+            alias: Slug,
+        ) -> Result<ShortLink, ShortenerError> {
+            self.authorize(Role::Editor, Some(&slug))?;
+            self.service.handle_add_alias(slug, alias)
+        }
+
+        fn handle_revert_url_change(
+            &mut self,
+            slug: Slug,
+        ) -> Result<ShortLink, ShortenerError> {
+            self.authorize(Role::Editor, Some(&slug))?;
+            self.service.handle_revert_url_change(slug)
+        }
+
+        fn handle_set_destinations(
+            &mut self,
+            slug: Slug,
+            destinations: Vec<(Url, u32)>,
+        ) -> Result<ShortLink, ShortenerError> {
+            self.authorize(Role::Editor, Some(&slug))?;
+            self.service.handle_set_destinations(slug, destinations)
+        }
+
+        fn handle_set_geo_rules(
+            &mut self,
+            slug: Slug,
+            rules: Vec<(String, Url)>,
+        ) -> Result<ShortLink, ShortenerError> {
+            self.authorize(Role::Editor, Some(&slug))?;
+            self.service.handle_set_geo_rules(slug, rules)
+        }
+
+        fn handle_set_device_rule(
+            &mut self,
+            slug: Slug,
+            device: Device,
+            url: Option<Url>,
+        ) -> Result<ShortLink, ShortenerError> {
+            self.authorize(Role::Editor, Some(&slug))?;
+            self.service.handle_set_device_rule(slug, device, url)
+        }
+
+        fn handle_set_redirect_policy(
+            &mut self,
+            slug: Slug,
+            policy: RedirectPolicy,
+        ) -> Result<ShortLink, ShortenerError> {
+            self.authorize(Role::Editor, Some(&slug))?;
+            self.service.handle_set_redirect_policy(slug, policy)
+        }
+    }
+
+    impl<S: EventStore> QueryHandler for AuthenticatedSession<'_, S> {
+        fn get_stats(&self, slug: Slug) -> Result<Stats, ShortenerError> {
+            self.service.get_stats(slug)
+        }
+    }
+
+    impl<S: EventStore> CampaignHandler for AuthenticatedSession<'_, S> {
+        fn handle_create_campaign(
+            &mut self,
+            campaign: Slug,
+            name: String,
+        ) -> Result<Campaign, ShortenerError> {
+            self.authorize(Role::Editor, None)?;
+            self.service.handle_create_campaign(campaign, name)
+        }
+
+        fn handle_add_to_campaign(
+            &mut self,
+            slug: Slug,
+            campaign: Slug,
+        ) -> Result<ShortLink, ShortenerError> {
+            self.authorize(Role::Editor, Some(&slug))?;
+            self.service.handle_add_to_campaign(slug, campaign)
+        }
+
+        fn handle_remove_from_campaign(
+            &mut self,
+            slug: Slug,
+        ) -> Result<ShortLink, ShortenerError> {
+            self.authorize(Role::Editor, Some(&slug))?;
+            self.service.handle_remove_from_campaign(slug)
+        }
+    }
+
+    impl<S: EventStore> TagHandler for AuthenticatedSession<'_, S> {
+        fn handle_tag_link(
+            &mut self,
+            slug: Slug,
+            tag: String,
+        ) -> Result<ShortLink, ShortenerError> {
+            self.authorize(Role::Editor, Some(&slug))?;
+            self.service.handle_tag_link(slug, tag)
+        }
+
+        fn handle_untag_link(
+            &mut self,
+            slug: Slug,
+            tag: String,
+        ) -> Result<ShortLink, ShortenerError> {
+            self.authorize(Role::Editor, Some(&slug))?;
+            self.service.handle_untag_link(slug, tag)
+        }
+    }
+}
+
+/// Fetching of [`LinkMetadata`] of destination pages with
+/// [reqwest](https://docs.rs/reqwest).
+#[cfg(feature = "metadata")]
+pub mod metadata {
+    use std::time::Duration;
+
+    use super::{LinkMetadata, ShortenerError, Url};
+
+    /// Maximum number of bytes of a page read, the metadata is expected in
+    /// its head.
+    pub const MAX_PAGE_SIZE: usize = 512 * 1024;
+
+    /// Timeout of fetching a page.
+    pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Fetches the page and extracts its [`LinkMetadata`]. Open Graph title
+    /// and description take precedence over the HTML ones.
+    ///
+    /// ## Errors
+    ///
+    /// Returns [`ShortenerError::MetadataUnavailable`] if the page can't be
+    /// fetched.
+    pub async fn fetch(url: &Url) -> Result<LinkMetadata, ShortenerError> {
+        let client = reqwest::Client::builder()
+            .timeout(TIMEOUT)
+            .build()
+            .map_err(|_| ShortenerError::MetadataUnavailable)?;
+        let mut response = client
+            .get(&url.0)
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(|_| ShortenerError::MetadataUnavailable)?;
+        //icons are relative to the page after redirects
+        let page_url = response.url().clone();
+        let mut body = Vec::new();
+        while let Some(chunk) = response
+            .chunk()
+            .await
+            .map_err(|_| ShortenerError::MetadataUnavailable)?
+        {
+            body.extend_from_slice(&chunk);
+            if body.len() >= MAX_PAGE_SIZE {
+                body.truncate(MAX_PAGE_SIZE);
+                break;
+            }
+        }
+        Ok(parse(&String::from_utf8_lossy(&body), &page_url))
+    }
+
+    //metadata from the title, meta and link tags of the html
+    fn parse(html: &str, page_url: &url::Url) -> LinkMetadata {
+        let mut title = None;
+        let mut description = None;
+        let mut og_title = None;
+        let mut og_description = None;
+        let mut favicon = None;
+        for tag in tags(html, "meta") {
+            let key = attribute(tag, "property").or_else(|| attribute(tag, "name"));
+            let (Some(key), Some(content)) = (key, attribute(tag, "content")) else {
+                continue;
+            };
+            match key.to_ascii_lowercase().as_str() {
+                "og:title" => og_title = og_title.or(Some(content)),
+                "og:description" => og_description = og_description.or(Some(content)),
+                "description" => description = description.or(Some(content)),
+                _ => {}
+            }
+        }
+        for tag in tags(html, "link") {
+            let is_icon = attribute(tag, "rel").is_some_and(|rel| {
+                rel.split_ascii_whitespace()
+                    .any(|rel| rel.eq_ignore_ascii_case("icon"))
+            });
+            if let (true, None, Some(href)) = (is_icon, &favicon, attribute(tag, "href")) {
+                favicon = page_url.join(&href).ok().map(|url| Url(url.into()));
+            }
+        }
+        let lowercase = html.to_ascii_lowercase();
+        if let Some(start) = lowercase.find("<title") {
+            let start = lowercase[start..].find('>').map(|end| start + end + 1);
+            if let Some(start) = start {
+                let end = lowercase[start..].find("</title").map_or(html.len(), |end| start + end);
+                title = Some(decode(html[start..end].trim()));
+            }
+        }
+        let non_empty = |value: Option<String>| value.filter(|value| !value.is_empty());
+        LinkMetadata {
+            title: non_empty(og_title).or(non_empty(title)),
+            description: non_empty(og_description).or(non_empty(description)),
+            favicon,
+        }
+    }
+
+    //attribute text of every tag with the given name
+    fn tags<'a>(html: &'a str, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        let lowercase = html.to_ascii_lowercase();
+        let open = format!("<{name}");
+        let mut offset = 0;
+        std::iter::from_fn(move || loop {
+            let start = offset + lowercase[offset..].find(&open)? + open.len();
+            let end = start + lowercase[start..].find('>').unwrap_or(lowercase.len() - start);
+            offset = end;
+            if lowercase[start..].starts_with(|c: char| c.is_ascii_whitespace() || c == '/') {
+                return Some(&html[start..end]);
+            }
+        })
+    }
+
+    //value of the attribute of a tag, entities decoded
+    fn attribute(tag: &str, name: &str) -> Option<String> {
+        let mut rest = tag;
+        loop {
+            rest = rest.trim_start_matches(|c: char| c.is_ascii_whitespace() || c == '/');
+            if rest.is_empty() {
+                return None;
+            }
+            let key_end = rest
+                .find(|c: char| c.is_ascii_whitespace() || c == '=' || c == '/')
+                .unwrap_or(rest.len());
+            let key = &rest[..key_end];
+            rest = rest[key_end..].trim_start();
+            let value = match rest.strip_prefix('=') {
+                Some(value) => {
+                    let value = value.trim_start();
+                    let (value, remaining) = match value.chars().next() {
+                        Some(quote @ ('"' | '\'')) => {
+                            let value = &value[1..];
+                            let end = value.find(quote).unwrap_or(value.len());
+                            (&value[..end], value.get(end + 1..).unwrap_or_default())
+                        }
+                        _ => {
+                            let end = value
+                                .find(|c: char| c.is_ascii_whitespace())
+                                .unwrap_or(value.len());
+                            (&value[..end], &value[end..])
+                        }
+                    };
+                    rest = remaining;
+                    value
+                }
+                None => "",
+            };
+            if key.eq_ignore_ascii_case(name) {
+                return Some(decode(value.trim()));
+            }
+        }
+    }
+
+    //decodes the most common html entities
+    fn decode(text: &str) -> String {
+        text.replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&quot;", "\"")
+            .replace("&#39;", "'")
+            .replace("&#x27;", "'")
+            .replace("&nbsp;", " ")
+            .replace("&amp;", "&")
+    }
+}
+
+/// Background checks of the health of link destinations with
+/// [reqwest](https://docs.rs/reqwest), finding links pointing at missing
+/// pages.
+#[cfg(feature = "health")]
+pub mod health {
+    use std::time::Duration;
+
+    use super::store::EventStore;
+    use super::{SharedUrlShortenerService, ShortenerError, Url};
+
+    /// Timeout of a single check.
+    pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Default time between two rounds of checks.
+    pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+    /// Checks the destination with a `HEAD` request, falling back to `GET`
+    /// for servers not supporting `HEAD`. Redirects are followed.
+    ///
+    /// ## Errors
+    ///
+    /// Returns the reason the destination is unhealthy: the status of the
+    /// response if it is a client or server error, or why the request
+    /// failed.
+    pub async fn check(client: &reqwest::Client, url: &Url) -> Result<(), String> {
+        let mut response = client.head(&url.0).send().await.map_err(|e| e.to_string())?;
+        if matches!(
+            response.status(),
+            reqwest::StatusCode::METHOD_NOT_ALLOWED | reqwest::StatusCode::NOT_IMPLEMENTED
+        ) {
+            response = client.get(&url.0).send().await.map_err(|e| e.to_string())?;
+        }
+        let status = response.status();
+        if status.is_client_error() || status.is_server_error() {
+            return Err(status.to_string());
+        }
+        Ok(())
+    }
+
+    /// Periodically checks the destinations of all the links of a
+    /// [`SharedUrlShortenerService`] and records the results with
+    /// [`UrlShortenerService::record_destination_health()`], so broken links
+    /// are returned by [`HealthQueryHandler::broken_links()`].
+    ///
+    /// [`UrlShortenerService::record_destination_health()`]: super::UrlShortenerService::record_destination_health
+    /// [`HealthQueryHandler::broken_links()`]: super::queries::HealthQueryHandler::broken_links
+    pub struct HealthChecker<S: EventStore> {
+        service: SharedUrlShortenerService<S>,
+        client: reqwest::Client,
+        interval: Duration,
+    }
+
+    impl<S: EventStore> HealthChecker<S> {
+        /// Creates a checker of the links of the service, checking them every
+        /// [`DEFAULT_INTERVAL`].
+        pub fn new(service: SharedUrlShortenerService<S>) -> Self {
+            Self {
+                service,
+                client: reqwest::Client::builder()
+                    .timeout(TIMEOUT)
+                    .build()
+                    .unwrap_or_default(),
+                interval: DEFAULT_INTERVAL,
+            }
+        }
+
+        /// Sets the time between two rounds of checks.
+        pub fn interval(mut self, interval: Duration) -> Self {
+            self.interval = interval;
+            self
+        }
+
+        /// Checks the destinations of all the links once, one after another,
+        /// returning the number of links whose health changed.
+        ///
+        /// ## Errors
+        ///
+        /// Returns [`ShortenerError::StorageFailure`] if a result could not
+        /// be recorded.
+        pub async fn check_all(&self) -> Result<usize, ShortenerError> {
+            let destinations = self.service.read().destinations();
+            let mut changed = 0;
+            for (slug, url) in destinations {
+                let result = check(&self.client, &url).await;
+                match self.service.write().record_destination_health(slug, url, result) {
+                    Ok(true) => changed += 1,
+                    //deleted while being checked
+                    Ok(false) | Err(ShortenerError::SlugNotFound) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(changed)
+        }
+
+        /// Checks the destinations in rounds separated by the interval,
+        /// forever. Meant to be spawned as a background task, e.g. with
+        /// `tokio::spawn(checker.run())`. Rounds failing to record their
+        /// results are retried in the next round.
+        pub async fn run(self) {
+            loop {
+                let _ = self.check_all().await;
+                tokio::time::sleep(self.interval).await;
+            }
+        }
+    }
+}
+
+/// Outgoing webhooks notifying external endpoints about link events, sent
+/// with [ureq](https://docs.rs/ureq).
+#[cfg(feature = "webhooks")]
+pub mod webhooks {
+    use std::collections::HashMap;
+    use std::sync::mpsc::{self, Receiver, Sender};
+    use std::sync::{Arc, Mutex, PoisonError};
+    use std::thread;
+    use std::time::{Duration, SystemTime};
+
+    use serde::Serialize;
+
+    use super::{Event, EventEnvelope, EventListener, Slug, Url};
+
+    /// Configuration of the [`WebhookDispatcher`].
+    #[derive(Debug, Clone)]
+    pub struct WebhookConfig {
+        /// URLs every payload is POSTed to.
+        pub endpoints: Vec<String>,
+
+        /// Redirect counts at which [`WebhookPayload::ClicksMilestone`] is
+        /// sent.
+        pub click_milestones: Vec<u64>,
+
+        /// Number of delivery attempts to a single endpoint before the payload
+        /// is moved to the [`DeadLetterQueue`].
+        pub max_attempts: u32,
+
+        /// Delay before the first retry, doubled after every failed attempt.
+        pub initial_backoff: Duration,
+
+        /// Longest delay between retries, so a failing endpoint doesn't hold
+        /// up the delivery of later payloads for long.
+        pub max_backoff: Duration,
+
+        /// Timeout of a single request.
+        pub timeout: Duration,
+    }
+
+    impl Default for WebhookConfig {
+        fn default() -> Self {
+            Self {
+                endpoints: Vec::new(),
+                click_milestones: vec![100, 1_000, 10_000],
+                max_attempts: 5,
+                initial_backoff: Duration::from_millis(500),
+                max_backoff: Duration::from_secs(30),
+                timeout: Duration::from_secs(10),
+            }
+        }
+    }
+
+    /// JSON body of a webhook request, tagged by its `type`.
+    #[derive(Debug, Clone, PartialEq, Serialize)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    pub enum WebhookPayload {
+        /// A link was created.
+        LinkCreated {
+            slug: Slug,
+            url: Url,
+            occurred_at: SystemTime,
+        },
+
+        /// The original URL of a link was changed.
+        UrlChanged {
+            slug: Slug,
+            new_url: Url,
+            occurred_at: SystemTime,
+        },
+
+        /// A link reached one of the [`WebhookConfig::click_milestones`], or
+        /// the service recorded [`Event::MilestoneReached`].
+        ClicksMilestone {
+            slug: Slug,
+            clicks: u64,
+            occurred_at: SystemTime,
+        },
+    }
+
+    /// Payload which could not be delivered to an endpoint.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct FailedDelivery {
+        /// The endpoint the payload was sent to.
+        pub endpoint: String,
+
+        /// The undelivered payload.
+        pub payload: WebhookPayload,
+
+        /// Number of delivery attempts made.
+        pub attempts: u32,
+
+        /// Error of the last attempt.
+        pub error: String,
+    }
+
+    /// Queue of [`FailedDelivery`]s shared with the [`WebhookDispatcher`], so
+    /// they can be inspected and resent.
+    #[derive(Debug, Clone, Default)]
+    pub struct DeadLetterQueue {
+        inner: Arc<Mutex<Vec<FailedDelivery>>>,
+    }
+
+    impl DeadLetterQueue {
+        /// Returns the number of failed deliveries in the queue.
+        pub fn len(&self) -> usize {
+            self.lock().len()
+        }
+
+        /// Returns whether the queue is empty.
+        pub fn is_empty(&self) -> bool {
+            self.lock().is_empty()
+        }
+
+        /// Removes and returns all the failed deliveries, oldest first.
+        pub fn drain(&self) -> Vec<FailedDelivery> {
+            std::mem::take(&mut *self.lock())
+        }
+
+        fn push(&self, delivery: FailedDelivery) {
+            self.lock().push(delivery);
+        }
+
+        fn lock(&self) -> std::sync::MutexGuard<'_, Vec<FailedDelivery>> {
+            self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+        }
+    }
+
+    /// [`EventListener`] POSTing [`WebhookPayload`]s to the configured
+    /// endpoints.
+    ///
+    /// Payloads are delivered in order by a background thread, retried with
+    /// exponential backoff, so commands are not slowed down by the endpoints.
+    /// Click milestones are counted from the redirects the dispatcher was
+    /// notified about.
+    pub struct WebhookDispatcher {
+        sender: Sender<WebhookPayload>,
+        click_milestones: Vec<u64>,
+        clicks: HashMap<Slug, u64>,
+        dead_letters: DeadLetterQueue,
+    }
+
+    impl WebhookDispatcher {
+        /// Creates the dispatcher and starts its delivery thread, which stops
+        /// once the dispatcher is dropped.
+        pub fn new(config: WebhookConfig) -> Self {
+            let (sender, receiver) = mpsc::channel();
+            let dead_letters = DeadLetterQueue::default();
+            let click_milestones = config.click_milestones.clone();
+            let queue = dead_letters.clone();
+            thread::spawn(move || deliver_all(&config, &receiver, &queue));
+            Self {
+                sender,
+                click_milestones,
+                clicks: HashMap::new(),
+                dead_letters,
+            }
+        }
+
+        /// Returns the [`DeadLetterQueue`] of payloads which could not be
+        /// delivered.
+        pub fn dead_letters(&self) -> DeadLetterQueue {
+            self.dead_letters.clone()
+        }
+
+        fn send(&self, payload: WebhookPayload) {
+            //the delivery thread only stops when the dispatcher is dropped
+            let _ = self.sender.send(payload);
+        }
+    }
+
+    impl EventListener for WebhookDispatcher {
+        fn on_event(&mut self, envelope: &EventEnvelope) {
+            let occurred_at = envelope.occurred_at;
+            match &envelope.event {
+                Event::LinkCreated { slug, url, .. } => {
+                    self.clicks.remove(slug);
+                    self.send(WebhookPayload::LinkCreated {
+                        slug: slug.clone(),
+                        url: url.clone(),
+                        occurred_at,
+                    });
+                }
+                Event::UrlChanged { slug, new_url } => {
+                    self.send(WebhookPayload::UrlChanged {
+                        slug: slug.clone(),
+                        new_url: new_url.clone(),
+                        occurred_at,
+                    });
+                }
+                Event::LinkAccessed { slug } | Event::LinkAccessedV2 { slug, .. } => {
+                    let clicks = self.clicks.entry(slug.clone()).or_default();
+                    *clicks += 1;
+                    let clicks = *clicks;
+                    if self.click_milestones.contains(&clicks) {
+                        self.send(WebhookPayload::ClicksMilestone {
+                            slug: slug.clone(),
+                            clicks,
+                            occurred_at,
+                        });
+                    }
+                }
+                Event::ClicksAggregated { slug, count, .. } => {
+                    let clicks = self.clicks.entry(slug.clone()).or_default();
+                    let before = *clicks;
+                    *clicks += count;
+                    let after = *clicks;
+                    let crossed: Vec<u64> = self
+                        .click_milestones
+                        .iter()
+                        .copied()
+                        .filter(|milestone| (before + 1..=after).contains(milestone))
+                        .collect();
+                    for clicks in crossed {
+                        self.send(WebhookPayload::ClicksMilestone {
+                            slug: slug.clone(),
+                            clicks,
+                            occurred_at,
+                        });
+                    }
+                }
+                Event::MilestoneReached { slug, clicks }
+                    if !self.click_milestones.contains(clicks) =>
+                {
+                    self.send(WebhookPayload::ClicksMilestone {
+                        slug: slug.clone(),
+                        clicks: *clicks,
+                        occurred_at,
+                    });
+                }
+                Event::SlugRenamed { slug, new_slug, .. } => {
+                    if let Some(clicks) = self.clicks.remove(slug) {
+                        self.clicks.insert(new_slug.clone(), clicks);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    //delivery thread loop
+    fn deliver_all(
+        config: &WebhookConfig,
+        receiver: &Receiver<WebhookPayload>,
+        dead_letters: &DeadLetterQueue,
+    ) {
+        let agent = ureq::AgentBuilder::new().timeout(config.timeout).build();
+        for payload in receiver {
+            let Ok(body) = serde_json::to_string(&payload) else {
+                continue;
+            };
+            for endpoint in &config.endpoints {
+                let mut backoff = config.initial_backoff;
+                let mut attempts = 0;
+                loop {
+                    attempts += 1;
+                    let result = agent
+                        .post(endpoint)
+                        .set("Content-Type", "application/json")
+                        .send_string(&body);
+                    let Err(e) = result else {
+                        break;
+                    };
+                    if attempts >= config.max_attempts {
+                        dead_letters.push(FailedDelivery {
+                            endpoint: endpoint.clone(),
+                            payload: payload.clone(),
+                            attempts,
+                            error: e.to_string(),
+                        });
+                        break;
+                    }
+                    thread::sleep(backoff);
+                    backoff = backoff.saturating_mul(2).min(config.max_backoff);
+                }
+            }
+        }
+    }
+}
+
+/// Publishing of the event log to message brokers, so other systems can
+/// consume the events of the service: [Kafka](https://kafka.apache.org)
+/// topics with the `kafka` feature and [NATS](https://nats.io) JetStream
+/// subjects with the `nats` feature. Events are published as JSON, so both
+/// need the `serde` feature too.
+#[cfg(all(feature = "serde", any(feature = "kafka", feature = "nats")))]
+pub mod publishing {
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+    use std::sync::Arc;
+    use std::task::{Context, Poll, Waker};
+    use std::thread::{self, JoinHandle};
+    use std::time::Duration;
+
+    use super::{DomainEvent, EventEnvelope, Subscription, Uuid};
+
+    /// Message published to a [`Broker`].
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct BrokerMessage {
+        /// Topic (or subject) the message is published to.
+        pub topic: String,
+
+        /// Key of the message, the [`StreamId`] of the event, so the events
+        /// of a link stay ordered on partitioned topics.
+        ///
+        /// [`StreamId`]: super::store::StreamId
+        pub key: String,
+
+        /// Identifier of the published event, for deduplicating messages
+        /// delivered more than once.
+        pub id: Uuid,
+
+        /// The [`EventEnvelope`] serialized as JSON.
+        pub payload: Vec<u8>,
+    }
+
+    /// Message broker the [`EventPublisher`] forwards events to.
+    pub trait Broker {
+        /// Publishes the message, returning once the broker acknowledged it.
+        ///
+        /// ## Errors
+        ///
+        /// Returns the description of the failure, the message is then
+        /// published again after a backoff.
+        fn publish(&mut self, message: &BrokerMessage) -> Result<(), String>;
+    }
+
+    /// Mapping of events to the topics they are published to.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub enum TopicMapping {
+        /// Every event is published to the same topic.
+        Single(String),
+
+        /// Events are published to `{prefix}.{event_type}`, e.g.
+        /// `shortener.link_created`, see [`DomainEvent::event_type()`].
+        PerEventType { prefix: String },
+
+        /// Events are published to the topic mapped to their event type, or
+        /// to the `fallback` one. Events without a topic are not published.
+        Custom {
+            topics: HashMap<String, String>,
+            fallback: Option<String>,
+        },
+    }
+
+    impl TopicMapping {
+        /// Returns the topic the event is published to, [`None`] if it is not
+        /// published at all.
+        pub fn topic(&self, envelope: &EventEnvelope) -> Option<String> {
+            let event_type = envelope.event.event_type();
+            match self {
+                TopicMapping::Single(topic) => Some(topic.clone()),
+                TopicMapping::PerEventType { prefix } => Some(format!("{prefix}.{event_type}")),
+                TopicMapping::Custom { topics, fallback } => {
+                    topics.get(event_type).or(fallback.as_ref()).cloned()
+                }
+            }
+        }
+    }
+
+    /// Configuration of the [`EventPublisher`].
+    #[derive(Debug, Clone)]
+    pub struct PublisherConfig {
+        /// Topics the events are published to.
+        pub topics: TopicMapping,
+
+        /// Delay before the first retry, doubled after every failed attempt.
+        pub initial_backoff: Duration,
+
+        /// Upper bound of the delay between retries.
+        pub max_backoff: Duration,
+
+        /// Interval between checks for new events while there are none,
+        /// bounding how long stopping the publisher takes.
+        pub poll_interval: Duration,
+    }
+
+    impl Default for PublisherConfig {
+        fn default() -> Self {
+            Self {
+                topics: TopicMapping::Single("url-shortener.events".to_string()),
+                initial_backoff: Duration::from_millis(500),
+                max_backoff: Duration::from_secs(30),
+                poll_interval: Duration::from_millis(100),
+            }
+        }
+    }
+
+    /// Background thread forwarding the events of a [`Subscription`] to a
+    /// [`Broker`], at least once and in log order.
+    ///
+    /// A message the broker failed to acknowledge is retried with exponential
+    /// backoff until it is, so later events wait for it. The
+    /// [`EventPublisher::checkpoint()`] can be persisted to resume publishing
+    /// with [`UrlShortenerService::subscribe_from()`] after a restart, events
+    /// published after the persisted checkpoint are then published again.
+    /// Dropping the publisher stops the thread once the message being
+    /// published is done.
+    ///
+    /// [`UrlShortenerService::subscribe_from()`]:
+    /// super::UrlShortenerService::subscribe_from
+    pub struct EventPublisher {
+        checkpoint: Arc<AtomicU64>,
+        stop: mpsc::Sender<()>,
+        thread: JoinHandle<()>,
+    }
+
+    impl EventPublisher {
+        /// Starts publishing the events of the subscription on a background
+        /// thread, which stops once the service of the subscription is
+        /// dropped and its remaining events are published, or once the
+        /// publisher is stopped.
+        pub fn spawn<B>(subscription: Subscription, broker: B, config: PublisherConfig) -> Self
+        where
+            B: Broker + Send + 'static,
+        {
+            let checkpoint = Arc::new(AtomicU64::new(subscription.checkpoint()));
+            let published = Arc::clone(&checkpoint);
+            let (stop, stopped) = mpsc::channel();
+            let thread = thread::spawn(move || {
+                publish_all(subscription, broker, &config, &published, &stopped)
+            });
+            Self { checkpoint, stop, thread }
+        }
+
+        /// Returns the sequence of the next event to publish, all the events
+        /// before it were acknowledged by the broker.
+        pub fn checkpoint(&self) -> u64 {
+            self.checkpoint.load(Ordering::Acquire)
+        }
+
+        /// Waits for the publishing thread to stop once the service of the
+        /// subscription is dropped and returns the final checkpoint.
+        pub fn join(self) -> u64 {
+            let _ = self.thread.join();
+            self.checkpoint.load(Ordering::Acquire)
+        }
+
+        /// Stops the thread for a graceful shutdown, waiting for the message
+        /// being published, and returns the final checkpoint. Events not
+        /// acknowledged by then are published again when resuming from it.
+        pub fn stop(self) -> u64 {
+            let _ = self.stop.send(());
+            self.thread
+                .join()
+                .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+            self.checkpoint.load(Ordering::Acquire)
+        }
+    }
+
+    //publishing thread loop
+    fn publish_all<B: Broker>(
+        mut subscription: Subscription,
+        mut broker: B,
+        config: &PublisherConfig,
+        checkpoint: &AtomicU64,
+        stopped: &Receiver<()>,
+    ) {
+        let mut context = Context::from_waker(Waker::noop());
+        loop {
+            let envelope = match subscription.poll_next(&mut context) {
+                Poll::Ready(Some(envelope)) => envelope,
+                //the service was dropped
+                Poll::Ready(None) => return,
+                Poll::Pending => {
+                    //stopped explicitly or dropped
+                    if !matches!(
+                        stopped.recv_timeout(config.poll_interval),
+                        Err(RecvTimeoutError::Timeout)
+                    ) {
+                        return;
+                    }
+                    continue;
+                }
+            };
+            let next = envelope.sequence + 1;
+            let Some(topic) = config.topics.topic(&envelope) else {
+                checkpoint.store(next, Ordering::Release);
+                continue;
+            };
+            let Ok(payload) = serde_json::to_vec(&envelope) else {
+                checkpoint.store(next, Ordering::Release);
+                continue;
+            };
+            let message = BrokerMessage {
+                topic,
+                key: envelope.event.stream_id().0 .0,
+                id: envelope.id,
+                payload,
+            };
+            let mut backoff = config.initial_backoff;
+            while broker.publish(&message).is_err() {
+                if !matches!(stopped.recv_timeout(backoff), Err(RecvTimeoutError::Timeout)) {
+                    return;
+                }
+                backoff = backoff.saturating_mul(2).min(config.max_backoff);
+            }
+            checkpoint.store(next, Ordering::Release);
+        }
+    }
+
+    /// [`Broker`] producing to Kafka topics with
+    /// [rdkafka](https://docs.rs/rdkafka).
+    #[cfg(feature = "kafka")]
+    pub struct KafkaBroker {
+        producer: rdkafka::producer::FutureProducer,
+    }
+
+    #[cfg(feature = "kafka")]
+    impl KafkaBroker {
+        /// Creates an idempotent producer connected to the comma-separated
+        /// bootstrap servers.
+        ///
+        /// ## Errors
+        ///
+        /// Returns [`KafkaError`](rdkafka::error::KafkaError) if the producer
+        /// couldn't be created.
+        pub fn new(bootstrap_servers: &str) -> Result<Self, rdkafka::error::KafkaError> {
+            let producer = rdkafka::ClientConfig::new()
+                .set("bootstrap.servers", bootstrap_servers)
+                .set("enable.idempotence", "true")
+                .create()?;
+            Ok(Self { producer })
+        }
+
+        /// Wraps an already configured producer, e.g. one authenticating to
+        /// the cluster.
+        pub fn from_producer(producer: rdkafka::producer::FutureProducer) -> Self {
+            Self { producer }
+        }
+    }
+
+    #[cfg(feature = "kafka")]
+    impl Broker for KafkaBroker {
+        fn publish(&mut self, message: &BrokerMessage) -> Result<(), String> {
+            let record = rdkafka::producer::FutureRecord::to(&message.topic)
+                .key(&message.key)
+                .payload(&message.payload);
+            let delivery = self
+                .producer
+                .send_result(record)
+                .map_err(|(e, _)| e.to_string())?;
+            match super::block_on(delivery) {
+                Ok(Ok(_)) => Ok(()),
+                Ok(Err((e, _))) => Err(e.to_string()),
+                Err(_) => Err("the delivery was canceled".to_string()),
+            }
+        }
+    }
+
+    /// [`Broker`] publishing to NATS subjects captured by
+    /// [JetStream](https://docs.nats.io/nats-concepts/jetstream) streams with
+    /// [async-nats](https://docs.rs/async-nats), on its own
+    /// [tokio](https://docs.rs/tokio) runtime.
+    ///
+    /// A message is published once the stream acknowledged storing it, so
+    /// publishing to a subject no stream captures fails. Messages carry the
+    /// `Nats-Msg-Id` header with the event id, so the streams drop the
+    /// duplicates.
+    #[cfg(feature = "nats")]
+    pub struct NatsBroker {
+        jetstream: async_nats::jetstream::Context,
+        runtime: tokio::runtime::Runtime,
+    }
+
+    #[cfg(feature = "nats")]
+    impl NatsBroker {
+        /// Connects to the NATS server at the URL, e.g.
+        /// `nats://localhost:4222`.
+        ///
+        /// ## Errors
+        ///
+        /// Returns [`std::io::Error`] if the runtime couldn't be started or
+        /// the connection failed.
+        pub fn connect(url: &str) -> std::io::Result<Self> {
+            let runtime = tokio::runtime::Builder::new_multi_thread()
+                .worker_threads(1)
+                .enable_all()
+                .build()?;
+            let client = runtime
+                .block_on(async_nats::connect(url))
+                .map_err(std::io::Error::other)?;
+            let jetstream = async_nats::jetstream::new(client);
+            Ok(Self { jetstream, runtime })
+        }
+    }
+
+    #[cfg(feature = "nats")]
+    impl Broker for NatsBroker {
+        fn publish(&mut self, message: &BrokerMessage) -> Result<(), String> {
+            let mut headers = async_nats::HeaderMap::new();
+            headers.insert("Nats-Msg-Id", message.id.to_string().as_str());
+            let jetstream = &self.jetstream;
+            self.runtime.block_on(async {
+                jetstream
+                    .publish_with_headers(
+                        message.topic.clone(),
+                        headers,
+                        message.payload.clone().into(),
+                    )
+                    .await
+                    .map_err(|e| e.to_string())?
+                    .await
+                    .map_err(|e| e.to_string())?;
+                Ok(())
+            })
+        }
+    }
+}
+
+/// Mirror of the links kept in [Redis](https://redis.io) with
+/// [redis-rs](https://docs.rs/redis), so a fleet of stateless redirect
+/// frontends can look them up without holding the event log.
+#[cfg(feature = "redis")]
+pub mod redis_projection {
+    use std::collections::HashMap;
+
+    use redis::{Client, Commands, Connection, RedisResult};
+
+    use super::{Event, EventEnvelope, Projection, ShortLink, Slug, Url};
+
+    /// Link as mirrored into Redis, see [`RedisLinkReader::lookup()`].
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct MirroredLink {
+        /// The link, with the [`Slug`] it is currently known by.
+        pub link: ShortLink,
+
+        /// Number of redirects of the link.
+        pub clicks: u64,
+
+        /// Whether the link was disabled by its owner.
+        pub disabled: bool,
+
+        /// Whether the link reached its maximum number of redirects.
+        pub exhausted: bool,
+
+        /// Whether the link is outside of its activity window.
+        pub inactive: bool,
+    }
+
+    impl MirroredLink {
+        /// Returns whether visitors of the link are redirected.
+        pub fn is_redirectable(&self) -> bool {
+            !(self.disabled || self.exhausted || self.inactive)
+        }
+    }
+
+    /// [`Projection`] mirroring the slug to URL map of the links, their
+    /// redirect counts and whether they redirect into Redis, read by
+    /// [`RedisLinkReader`]s.
+    ///
+    /// Every link is a hash under `{prefix}:link:{slug}` and every alias a
+    /// string under `{prefix}:alias:{alias}` holding the slug of its link.
+    /// The changes of an event are written in one transaction together with
+    /// the sequence of the next event under `{prefix}:checkpoint`, and
+    /// events before the checkpoint are skipped, so registering the
+    /// projection again after a restart doesn't count redirects twice. The
+    /// keys under a prefix are written by a single projection at a time.
+    ///
+    /// Events which fail to be written are kept and written again before the
+    /// next event over a new connection, the last failure is available from
+    /// [`RedisProjection::last_error()`].
+    pub struct RedisProjection {
+        client: Client,
+        //opened again after a failure
+        connection: Option<Connection>,
+        prefix: String,
+        //sequence of the next event to write, read from redis if unknown
+        checkpoint: Option<u64>,
+        //events failed to be written, oldest first
+        backlog: Vec<EventEnvelope>,
+        last_error: Option<redis::RedisError>,
+    }
+
+    impl RedisProjection {
+        /// Connects to Redis at the URL, e.g. `redis://localhost:6379`, keeping
+        /// the keys under the prefix.
+        ///
+        /// ## Errors
+        ///
+        /// Returns [`redis::RedisError`] if the URL is invalid or the
+        /// connection failed.
+        pub fn connect(url: &str, prefix: impl Into<String>) -> RedisResult<Self> {
+            let client = Client::open(url)?;
+            let connection = client.get_connection()?;
+            let mut projection = Self::from_client(client, prefix);
+            projection.connection = Some(connection);
+            Ok(projection)
+        }
+
+        /// Uses an already configured client, e.g. one authenticating to the
+        /// server, keeping the keys under the prefix. The connection is opened
+        /// with the first event.
+        pub fn from_client(client: Client, prefix: impl Into<String>) -> Self {
+            Self {
+                client,
+                connection: None,
+                prefix: prefix.into(),
+                checkpoint: None,
+                backlog: Vec::new(),
+                last_error: None,
+            }
+        }
+
+        /// Returns the number of events waiting to be written after a
+        /// failure.
+        pub fn backlog(&self) -> usize {
+            self.backlog.len()
+        }
+
+        /// Returns the error of the last failed write, if it was not followed
+        /// by a successful one.
+        pub fn last_error(&self) -> Option<&redis::RedisError> {
+            self.last_error.as_ref()
+        }
+
+        fn key(&self, kind: &str, slug: &Slug) -> String {
+            format!("{}:{kind}:{}", self.prefix, slug.0)
+        }
+
+        fn connection(&mut self) -> RedisResult<&mut Connection> {
+            let connection = match self.connection.take() {
+                Some(connection) => connection,
+                None => self.client.get_connection()?,
+            };
+            Ok(self.connection.insert(connection))
+        }
+
+        fn write(&mut self, envelope: &EventEnvelope) -> RedisResult<()> {
+            let checkpoint_key = format!("{}:checkpoint", self.prefix);
+            let checkpoint = match self.checkpoint {
+                Some(checkpoint) => checkpoint,
+                None => self.connection()?.get::<_, Option<u64>>(&checkpoint_key)?.unwrap_or(0),
+            };
+            self.checkpoint = Some(checkpoint);
+            if envelope.sequence < checkpoint {
+                return Ok(());
+            }
+            let mut pipe = redis::pipe();
+            pipe.atomic();
+            match &envelope.event {
+                Event::LinkCreated {
+                    slug,
+                    url,
+                    active_from,
+                    active_until,
+                    ..
+                } => {
+                    let at = envelope.occurred_at;
+                    let active = active_from.is_none_or(|from| from <= at)
+                        && active_until.is_none_or(|until| at < until);
+                    pipe.del(self.key("link", slug)).ignore();
+                    pipe.hset_multiple(
+                        self.key("link", slug),
+                        &[
+                            ("url", url.0.as_str()),
+                            ("clicks", "0"),
+                            ("disabled", "0"),
+                            ("exhausted", "0"),
+                            ("inactive", if active { "0" } else { "1" }),
+                        ],
+                    )
+                    .ignore();
+                }
+                Event::LinkAccessed { slug } | Event::LinkAccessedV2 { slug, .. } => {
+                    self.count(&mut pipe, slug, 1);
+                }
+                Event::ClicksAggregated { slug, count, .. } => {
+                    self.count(&mut pipe, slug, *count);
+                }
+                Event::UrlChanged { slug, new_url } => {
+                    self.set(&mut pipe, slug, "url", &new_url.0);
+                }
+                Event::LinkDeleted { slug } => {
+                    pipe.del(self.key("link", slug)).ignore();
+                }
+                Event::LinkExhausted { slug } => self.set(&mut pipe, slug, "exhausted", "1"),
+                Event::LinkDisabled { slug } => self.set(&mut pipe, slug, "disabled", "1"),
+                Event::LinkEnabled { slug } => self.set(&mut pipe, slug, "disabled", "0"),
+                Event::LinkActivated { slug } => self.set(&mut pipe, slug, "inactive", "0"),
+                Event::LinkDeactivated { slug } => self.set(&mut pipe, slug, "inactive", "1"),
+                Event::AliasAdded { slug, alias } => {
+                    pipe.set(self.key("alias", alias), &slug.0).ignore();
+                    pipe.sadd(self.key("aliases", slug), &alias.0).ignore();
+                }
+                Event::SlugRenamed {
+                    slug,
+                    new_slug,
+                    keep_alias,
+                } => {
+                    let aliases_key = self.key("aliases", slug);
+                    let aliases: Vec<String> = self.connection()?.smembers(aliases_key)?;
+                    for alias in &aliases {
+                        pipe.set(self.key("alias", &Slug(alias.clone())), &new_slug.0).ignore();
+                    }
+                    pipe.del(self.key("alias", new_slug)).ignore();
+                    pipe.srem(self.key("aliases", slug), &new_slug.0).ignore();
+                    pipe.sunionstore(
+                        self.key("aliases", new_slug),
+                        &[self.key("aliases", new_slug), self.key("aliases", slug)],
+                    )
+                    .ignore();
+                    pipe.del(self.key("aliases", slug)).ignore();
+                    pipe.cmd("EVAL")
+                        .arg(
+                            "if redis.call('EXISTS', KEYS[1]) == 1 then \
+                             redis.call('RENAME', KEYS[1], KEYS[2]) end",
+                        )
+                        .arg(2)
+                        .arg(self.key("link", slug))
+                        .arg(self.key("link", new_slug))
+                        .ignore();
+                    if *keep_alias {
+                        pipe.set(self.key("alias", slug), &new_slug.0).ignore();
+                        pipe.sadd(self.key("aliases", new_slug), &slug.0).ignore();
+                    }
+                }
+                _ => {}
+            }
+            pipe.set(&checkpoint_key, envelope.sequence + 1).ignore();
+            pipe.query::<()>(self.connection()?)?;
+            self.checkpoint = Some(envelope.sequence + 1);
+            Ok(())
+        }
+
+        //counts redirects of a link which wasn't deleted
+        fn count(&self, pipe: &mut redis::Pipeline, slug: &Slug, count: u64) {
+            let key = self.key("link", slug);
+            pipe.cmd("EVAL")
+                .arg(
+                    "if redis.call('EXISTS', KEYS[1]) == 1 then \
+                     redis.call('HINCRBY', KEYS[1], 'clicks', ARGV[1]) end",
+                )
+                .arg(1)
+                .arg(key)
+                .arg(count)
+                .ignore();
+        }
+
+        //sets a field of a link which wasn't deleted
+        fn set(&self, pipe: &mut redis::Pipeline, slug: &Slug, field: &str, value: &str) {
+            pipe.cmd("EVAL")
+                .arg(
+                    "if redis.call('EXISTS', KEYS[1]) == 1 then \
+                     redis.call('HSET', KEYS[1], ARGV[1], ARGV[2]) end",
+                )
+                .arg(1)
+                .arg(self.key("link", slug))
+                .arg(field)
+                .arg(value)
+                .ignore();
+        }
+    }
+
+    impl Projection for RedisProjection {
+        fn apply(&mut self, envelope: &EventEnvelope) {
+            self.backlog.push(envelope.clone());
+            let backlog = std::mem::take(&mut self.backlog);
+            for (written, envelope) in backlog.iter().enumerate() {
+                if let Err(e) = self.write(envelope) {
+                    //the transaction may have been applied, the checkpoint
+                    //tells
+                    self.checkpoint = None;
+                    self.connection = None;
+                    self.last_error = Some(e);
+                    self.backlog = backlog[written..].to_vec();
+                    return;
+                }
+            }
+            self.last_error = None;
+        }
+
+        //removes all the keys under the prefix
+        fn reset(&mut self) {
+            self.backlog.clear();
+            self.checkpoint = None;
+            let pattern = format!("{}:*", self.prefix);
+            let result = self.connection().and_then(|connection| {
+                let keys: Vec<String> = connection.scan_match(&pattern)?.collect();
+                if keys.is_empty() {
+                    return Ok(());
+                }
+                connection.del(keys)
+            });
+            if result.is_err() {
+                self.connection = None;
+            }
+            self.last_error = result.err();
+        }
+    }
+
+    /// Lookups of the links mirrored by a [`RedisProjection`], for redirect
+    /// frontends. A lookup failing opens a new connection for the next one.
+    pub struct RedisLinkReader {
+        client: Client,
+        connection: Option<Connection>,
+        prefix: String,
+    }
+
+    impl RedisLinkReader {
+        /// Connects to Redis at the URL, reading the keys under the prefix the
+        /// [`RedisProjection`] writes them under.
+        ///
+        /// ## Errors
+        ///
+        /// Returns [`redis::RedisError`] if the connection failed.
+        pub fn connect(url: &str, prefix: impl Into<String>) -> RedisResult<Self> {
+            let client = Client::open(url)?;
+            Ok(Self {
+                connection: Some(client.get_connection()?),
+                client,
+                prefix: prefix.into(),
+            })
+        }
+
+        /// Uses an already configured client, reading the keys under the
+        /// prefix. The connection is opened with the first lookup.
+        pub fn from_client(client: Client, prefix: impl Into<String>) -> Self {
+            Self {
+                client,
+                connection: None,
+                prefix: prefix.into(),
+            }
+        }
+
+        /// Returns the link with the [`Slug`], or the one it is an alias of,
+        /// [`None`] if there is no such link or it was deleted.
+        ///
+        /// ## Errors
+        ///
+        /// Returns [`redis::RedisError`] if reading from Redis failed.
+        pub fn lookup(&mut self, slug: &Slug) -> RedisResult<Option<MirroredLink>> {
+            let connection = match self.connection.take() {
+                Some(connection) => connection,
+                None => self.client.get_connection()?,
+            };
+            let result = Self::resolve(self.connection.insert(connection), &self.prefix, slug);
+            if result.is_err() {
+                self.connection = None;
+            }
+            result
+        }
+
+        fn resolve(
+            connection: &mut Connection,
+            prefix: &str,
+            slug: &Slug,
+        ) -> RedisResult<Option<MirroredLink>> {
+            if let Some(link) = Self::read(connection, prefix, slug)? {
+                return Ok(Some(link));
+            }
+            let alias_key = format!("{prefix}:alias:{}", slug.0);
+            match connection.get::<_, Option<String>>(alias_key)? {
+                Some(target) => Self::read(connection, prefix, &Slug(target)),
+                None => Ok(None),
+            }
+        }
+
+        fn read(
+            connection: &mut Connection,
+            prefix: &str,
+            slug: &Slug,
+        ) -> RedisResult<Option<MirroredLink>> {
+            let key = format!("{prefix}:link:{}", slug.0);
+            let fields: HashMap<String, String> = connection.hgetall(key)?;
+            let Some(url) = fields.get("url") else {
+                return Ok(None);
+            };
+            let flag = |field: &str| fields.get(field).is_some_and(|value| value == "1");
+            Ok(Some(MirroredLink {
+                link: ShortLink {
+                    slug: slug.clone(),
+                    url: Url(url.clone()),
+                },
+                clicks: fields.get("clicks").and_then(|clicks| clicks.parse().ok()).unwrap_or(0),
+                disabled: flag("disabled"),
+                exhausted: flag("exhausted"),
+                inactive: flag("inactive"),
+            }))
+        }
+    }
+}
+
+/// [Prometheus](https://docs.rs/prometheus) metrics of the service.
+#[cfg(feature = "metrics")]
+pub mod metrics {
+    use std::time::Duration;
+
+    use prometheus::{
+        exponential_buckets, Encoder, Histogram, HistogramOpts, IntCounter, IntGauge, Registry,
+        TextEncoder,
+    };
+
+    use super::{Event, EventEnvelope, EventListener, GlobalStats};
+
+    /// Metrics of the [`UrlShortenerService`], registered in a Prometheus
+    /// [`Registry`]. Cloned metrics share their values.
+    ///
+    /// | Metric                                  | Type      |
+    /// |-----------------------------------------|-----------|
+    /// | `url_shortener_links_created_total`     | counter   |
+    /// | `url_shortener_redirects_total`         | counter   |
+    /// | `url_shortener_lookup_duration_seconds` | histogram |
+    /// | `url_shortener_events`                  | gauge     |
+    ///
+    /// [`UrlShortenerService`]: super::UrlShortenerService
+    #[derive(Debug, Clone)]
+    pub struct ServiceMetrics {
+        registry: Registry,
+        links_created: IntCounter,
+        redirects: IntCounter,
+        lookup_duration: Histogram,
+        events: IntGauge,
+    }
+
+    impl ServiceMetrics {
+        /// Creates the metrics in a new [`Registry`].
+        pub fn new() -> prometheus::Result<Self> {
+            Self::with_registry(Registry::new())
+        }
+
+        /// Creates the metrics in the given [`Registry`], e.g. shared with
+        /// other metrics of the application.
+        pub fn with_registry(registry: Registry) -> prometheus::Result<Self> {
+            let links_created = IntCounter::new(
+                "url_shortener_links_created_total",
+                "Number of created short links.",
+            )?;
+            let redirects = IntCounter::new(
+                "url_shortener_redirects_total",
+                "Number of redirects served.",
+            )?;
+            let lookup_duration = Histogram::with_opts(
+                HistogramOpts::new(
+                    "url_shortener_lookup_duration_seconds",
+                    "Duration of link lookups in the read model.",
+                )
+                .buckets(exponential_buckets(1e-6, 4.0, 10)?),
+            )?;
+            let events =
+                IntGauge::new("url_shortener_events", "Number of events in the event log.")?;
+            registry.register(Box::new(links_created.clone()))?;
+            registry.register(Box::new(redirects.clone()))?;
+            registry.register(Box::new(lookup_duration.clone()))?;
+            registry.register(Box::new(events.clone()))?;
+            Ok(Self {
+                registry,
+                links_created,
+                redirects,
+                lookup_duration,
+                events,
+            })
+        }
+
+        /// Returns the [`Registry`] the metrics are registered in.
+        pub fn registry(&self) -> &Registry {
+            &self.registry
+        }
+
+        /// Returns all metrics of the [`Registry`] in the Prometheus text
+        /// exposition format.
+        pub fn gather(&self) -> String {
+            let mut buffer = Vec::new();
+            //writing into a vec can't fail
+            let _ = TextEncoder::new().encode(&self.registry.gather(), &mut buffer);
+            String::from_utf8(buffer).unwrap_or_default()
+        }
+
+        //counts the state of the service the metrics were attached to
+        pub(crate) fn init(&self, totals: &GlobalStats) {
+            self.links_created.inc_by(totals.links_created);
+            self.redirects.inc_by(totals.redirects);
+            self.events.set(totals.events as i64);
+        }
+
+        pub(crate) fn observe_lookup(&self, duration: Duration) {
+            self.lookup_duration.observe(duration.as_secs_f64());
+        }
+    }
+
+    impl EventListener for ServiceMetrics {
+        fn on_event(&mut self, envelope: &EventEnvelope) {
+            match &envelope.event {
+                Event::LinkCreated { .. } => self.links_created.inc(),
+                Event::LinkAccessed { .. } | Event::LinkAccessedV2 { .. } => self.redirects.inc(),
+                Event::ClicksAggregated { count, .. } => self.redirects.inc_by(*count),
+                _ => {}
+            }
+            self.events.set(envelope.sequence as i64 + 1);
+        }
+    }
+}
+
+/// HTTP API of the service built with [axum](https://docs.rs/axum). Its
+/// bodies are JSON, so the `http` feature enables `serde` too.
+///
+/// | Method | Path                  | Action                                  |
+/// |--------|-----------------------|-----------------------------------------|
+/// | `POST` | `/links`              | creates a short link                    |
+/// | `GET`  | `/{slug}`             | redirects (`302`) and counts the click  |
+/// | `PUT`  | `/links/{slug}`       | changes the destination of the link     |
+/// | `GET`  | `/links/{slug}/stats` | returns [`Stats`] of the link           |
+/// | `GET`  | `/metrics`            | Prometheus metrics (`metrics` feature)  |
+/// | `POST` | `/graphql`            | GraphQL API (`graphql` feature)         |
+/// | `GET`  | `/openapi.json`       | OpenAPI document ([`ApiDoc`])           |
+#[cfg(feature = "http")]
+pub mod http {
+    use axum::extract::{Path, RawQuery, State};
+    use axum::http::{header, HeaderMap, StatusCode};
+    use axum::response::{Html, IntoResponse, Response};
+    use axum::routing::{get, post, put};
+    use axum::{Json, Router};
+    use serde::Deserialize;
+    use utoipa::{OpenApi, ToSchema};
+
+    use super::commands::RedirectHandler;
+    use super::queries::QueryHandler;
+    use super::store::EventStore;
+    use super::{
+        ClickContext, SharedUrlShortenerService, ShortLink, ShortenerError, Slug, Stats, Url,
+    };
+
+    /// Body of the `POST /links` request.
+    #[derive(Debug, Deserialize, ToSchema)]
+    pub struct CreateLinkRequest {
+        /// The original URL to shorten.
+        pub url: Url,
+
+        /// Optional custom [`Slug`], generated if missing.
+        pub slug: Option<Slug>,
+    }
+
+    /// Body of the `PUT /links/{slug}` request.
+    #[derive(Debug, Deserialize, ToSchema)]
+    pub struct ChangeUrlRequest {
+        /// The new URL the link should point to.
+        pub url: Url,
+    }
+
+    impl ShortenerError {
+        /// HTTP status code corresponding to the error.
+        pub fn status_code(&self) -> StatusCode {
+            match self {
+                ShortenerError::InvalidUrl
+                | ShortenerError::InvalidSlug(_)
+                | ShortenerError::InvalidWeights
+                | ShortenerError::InvalidCountry
+                | ShortenerError::InvalidTag => StatusCode::BAD_REQUEST,
+                ShortenerError::SlugReserved
+                | ShortenerError::DestinationBlocked
+                | ShortenerError::RedirectLoop
+                | ShortenerError::Rejected(_) => StatusCode::UNPROCESSABLE_ENTITY,
+                ShortenerError::SlugAlreadyInUse
+                | ShortenerError::VersionConflict
+                | ShortenerError::NothingToRevert
+                | ShortenerError::CampaignAlreadyExists => StatusCode::CONFLICT,
+                ShortenerError::SlugNotFound | ShortenerError::CampaignNotFound => {
+                    StatusCode::NOT_FOUND
+                }
+                ShortenerError::Unauthorized => StatusCode::UNAUTHORIZED,
+                ShortenerError::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+                ShortenerError::LinkExhausted => StatusCode::GONE,
+                ShortenerError::LinkDisabled
+                | ShortenerError::LinkNotActive
+                | ShortenerError::NotOwner
+                | ShortenerError::Forbidden => StatusCode::FORBIDDEN,
+                ShortenerError::StorageFailure | ShortenerError::NoSlugInShard => {
+                    StatusCode::INTERNAL_SERVER_ERROR
+                }
+                ShortenerError::MetadataUnavailable => StatusCode::BAD_GATEWAY,
+            }
+        }
+    }
+
+    impl IntoResponse for ShortenerError {
+        fn into_response(self) -> Response {
+            (self.status_code(), Json(self)).into_response()
+        }
+    }
+
+    /// OpenAPI document of the routes of the [`router()`], served on
+    /// `/openapi.json`.
+    #[derive(OpenApi)]
+    #[openapi(
+        info(title = "URL shortener"),
+        paths(create_link, redirect, change_url, stats)
+    )]
+    pub struct ApiDoc;
+
+    /// Builds the [`Router`] serving the given service.
+    pub fn router<S>(service: SharedUrlShortenerService<S>) -> Router
+    where
+        S: EventStore + Send + Sync + 'static,
+    {
+        let router = Router::new()
+            .route("/links", post(create_link::<S>))
+            .route("/links/{slug}", put(change_url::<S>))
+            .route("/links/{slug}/stats", get(stats::<S>))
+            .route("/{slug}", get(redirect::<S>))
+            .route("/openapi.json", get(|| async { Json(ApiDoc::openapi()) }));
+        #[cfg(feature = "metrics")]
+        let router = router.route("/metrics", get(metrics::<S>));
+        #[cfg(feature = "graphql")]
+        let router = {
+            let schema = super::graphql::schema(service.clone());
+            router.route(
+                "/graphql",
+                post(|Json(request): Json<async_graphql::Request>| async move {
+                    Json(schema.execute(request).await)
+                }),
+            )
+        };
+        router.with_state(service)
+    }
+
+    #[utoipa::path(
+        post,
+        path = "/links",
+        request_body = CreateLinkRequest,
+        responses(
+            (status = CREATED, description = "The link was created", body = ShortLink),
+            (status = BAD_REQUEST, description = "Invalid URL or slug", body = ShortenerError),
+            (status = CONFLICT, description = "The slug is already in use", body = ShortenerError),
+        )
+    )]
+    async fn create_link<S: EventStore>(
+        State(service): State<SharedUrlShortenerService<S>>,
+        Json(request): Json<CreateLinkRequest>,
+    ) -> Result<impl IntoResponse, ShortenerError> {
+        let link = service.create_checked(request.url, request.slug).await?;
+        Ok((StatusCode::CREATED, Json(link)))
+    }
+
+    #[utoipa::path(
+        get,
+        path = "/{slug}",
+        params(("slug" = String, Path, description = "Slug of the link")),
+        responses(
+            (status = MOVED_PERMANENTLY, description = "Permanent redirect to the link",
+                headers(("Location" = String, description = "URL of the link"))),
+            (status = FOUND, description = "Redirect to the link",
+                headers(("Location" = String, description = "URL of the link"))),
+            (status = OK, description = "Warning page of a link flagged as unsafe",
+                content_type = "text/html", body = String),
+            (status = NOT_FOUND, description = "No such link", body = ShortenerError),
+            (status = FORBIDDEN, description = "The link is disabled or not active",
+                body = ShortenerError),
+            (status = GONE, description = "The link ran out of clicks", body = ShortenerError),
+        )
+    )]
+    async fn redirect<S: EventStore>(
+        State(service): State<SharedUrlShortenerService<S>>,
+        Path(slug): Path<String>,
+        RawQuery(query): RawQuery,
+        headers: HeaderMap,
+    ) -> Result<impl IntoResponse, ShortenerError> {
+        let header = |name| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+        let context = ClickContext {
+            referrer: header(header::REFERER),
+            user_agent: header(header::USER_AGENT),
+            ip: None,
+            country: None,
+        };
+        let decision = (&service).resolve_redirect(Slug(slug), context, query.as_deref(), None)?;
+        if let Some(reason) = &decision.warning {
+            return Ok(Html(interstitial(&decision.location, reason)).into_response());
+        }
+        let status = match decision.permanent {
+            true => StatusCode::MOVED_PERMANENTLY,
+            false => StatusCode::FOUND,
+        };
+        Ok((status, [(header::LOCATION, decision.location.0)]).into_response())
+    }
+
+    //warning page shown instead of redirecting to a flagged destination
+    fn interstitial(location: &Url, reason: &str) -> String {
+        let escape = |text: &str| {
+            text.replace('&', "&amp;")
+                .replace('<', "&lt;")
+                .replace('>', "&gt;")
+                .replace('"', "&quot;")
+        };
+        let (location, reason) = (escape(&location.0), escape(reason));
+        format!(
+            "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Unsafe link</title>\
+             </head><body><h1>This link may be unsafe</h1><p>The destination was flagged: \
+             {reason}.</p><p><a href=\"{location}\" rel=\"noopener noreferrer nofollow\">\
+             Continue to {location}</a></p></body></html>\n"
+        )
+    }
+
+    #[utoipa::path(
+        put,
+        path = "/links/{slug}",
+        params(("slug" = String, Path, description = "Slug of the link")),
+        request_body = ChangeUrlRequest,
+        responses(
+            (status = OK, description = "The URL was changed", body = ShortLink),
+            (status = BAD_REQUEST, description = "Invalid URL", body = ShortenerError),
+            (status = NOT_FOUND, description = "No such link", body = ShortenerError),
+        )
+    )]
+    async fn change_url<S: EventStore>(
+        State(service): State<SharedUrlShortenerService<S>>,
+        Path(slug): Path<String>,
+        Json(request): Json<ChangeUrlRequest>,
+    ) -> Result<impl IntoResponse, ShortenerError> {
+        let link = service.change_checked(Slug(slug), request.url).await?;
+        Ok(Json(link))
+    }
+
+    #[cfg(feature = "metrics")]
+    async fn metrics<S: EventStore>(
+        State(service): State<SharedUrlShortenerService<S>>,
+    ) -> Response {
+        match service.read().metrics() {
+            Some(metrics) => (
+                [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+                metrics.gather(),
+            )
+                .into_response(),
+            None => StatusCode::NOT_FOUND.into_response(),
+        }
+    }
+
+    #[utoipa::path(
+        get,
+        path = "/links/{slug}/stats",
+        params(("slug" = String, Path, description = "Slug of the link")),
+        responses(
+            (status = OK, description = "Statistics of the link", body = Stats),
+            (status = NOT_FOUND, description = "No such link", body = ShortenerError),
+        )
+    )]
+    async fn stats<S: EventStore>(
+        State(service): State<SharedUrlShortenerService<S>>,
+        Path(slug): Path<String>,
+    ) -> Result<impl IntoResponse, ShortenerError> {
+        Ok(Json(service.get_stats(Slug(slug))?))
+    }
+}
+
+/// gRPC API of the service built with [tonic](https://docs.rs/tonic).
+///
+/// The service is described by [`PROTO`], which clients in other languages
+/// can generate their stubs from. The messages below are the ones `prost`
+/// generates from it.
+#[cfg(feature = "grpc")]
+pub mod grpc {
+    use std::convert::Infallible;
+    use std::future::{ready, Ready};
+    use std::task::{Context, Poll};
+
+    use tonic::codegen::{http, BoxFuture, Service, StdError};
+    use tonic::server::{Grpc, NamedService, UnaryService};
+    use tonic::{Code, Status};
+
+    use super::commands::{CommandHandler, RedirectHandler};
+    use super::queries::{LinkQueryHandler, QueryHandler};
+    use super::store::EventStore;
+    use super::{ClickContext, OwnerId, SharedUrlShortenerService, ShortenerError, Slug, Url};
+
+    /// Protocol Buffers definition of the `url_shortener.UrlShortener`
+    /// service.
+    pub const PROTO: &str = r#"syntax = "proto3";
+
+package url_shortener;
+
+service UrlShortener {
+  rpc CreateLink(CreateLinkRequest) returns (Link);
+  rpc Redirect(RedirectRequest) returns (RedirectReply);
+  rpc ChangeUrl(ChangeUrlRequest) returns (Link);
+  rpc GetStats(GetStatsRequest) returns (StatsReply);
+  rpc ListLinks(ListLinksRequest) returns (ListLinksReply);
+}
+
+message Link {
+  string slug = 1;
+  string url = 2;
+}
+
+message CreateLinkRequest {
+  string url = 1;
+  optional string slug = 2;
+}
+
+message RedirectRequest {
+  string slug = 1;
+  optional string referrer = 2;
+  optional string user_agent = 3;
+  optional string query = 4;
+}
+
+message RedirectReply {
+  string location = 1;
+  bool permanent = 2;
+}
+
+message ChangeUrlRequest {
+  string slug = 1;
+  string url = 2;
+}
+
+message GetStatsRequest {
+  string slug = 1;
+}
+
+message StatsReply {
+  Link link = 1;
+  uint64 redirects = 2;
+}
+
+message ListLinksRequest {
+  optional string owner = 1;
+  uint32 limit = 2;
+}
+
+message ListLinksReply {
+  repeated Link links = 1;
+}
+"#;
+
+    /// Name of the gRPC service.
+    pub const SERVICE_NAME: &str = "url_shortener.UrlShortener";
+
+    /// A short link.
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Link {
+        /// Slug of the link.
+        #[prost(string, tag = "1")]
+        pub slug: String,
+
+        /// URL the link points to.
+        #[prost(string, tag = "2")]
+        pub url: String,
+    }
+
+    /// Request of `CreateLink`.
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct CreateLinkRequest {
+        /// The original URL to shorten.
+        #[prost(string, tag = "1")]
+        pub url: String,
+
+        /// Optional custom slug, generated if missing.
+        #[prost(string, optional, tag = "2")]
+        pub slug: Option<String>,
+    }
+
+    /// Request of `Redirect`.
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct RedirectRequest {
+        /// Slug of the link.
+        #[prost(string, tag = "1")]
+        pub slug: String,
+
+        /// `Referer` of the redirected request.
+        #[prost(string, optional, tag = "2")]
+        pub referrer: Option<String>,
+
+        /// `User-Agent` of the redirected request.
+        #[prost(string, optional, tag = "3")]
+        pub user_agent: Option<String>,
+
+        /// Query of the redirected request, without the leading `?`.
+        #[prost(string, optional, tag = "4")]
+        pub query: Option<String>,
+    }
+
+    /// Reply of `Redirect`.
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct RedirectReply {
+        /// URL to redirect to.
+        #[prost(string, tag = "1")]
+        pub location: String,
+
+        /// Whether the redirect is permanent.
+        #[prost(bool, tag = "2")]
+        pub permanent: bool,
+    }
+
+    /// Request of `ChangeUrl`.
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ChangeUrlRequest {
+        /// Slug of the link.
+        #[prost(string, tag = "1")]
+        pub slug: String,
+
+        /// The new URL the link should point to.
+        #[prost(string, tag = "2")]
+        pub url: String,
+    }
+
+    /// Request of `GetStats`.
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct GetStatsRequest {
+        /// Slug of the link.
+        #[prost(string, tag = "1")]
+        pub slug: String,
+    }
+
+    /// Reply of `GetStats`.
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct StatsReply {
+        /// The link.
+        #[prost(message, optional, tag = "1")]
+        pub link: Option<Link>,
+
+        /// Number of redirects of the link.
+        #[prost(uint64, tag = "2")]
+        pub redirects: u64,
+    }
+
+    /// Request of `ListLinks`.
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ListLinksRequest {
+        /// Owner whose links are listed, ordered by slug. The most redirected
+        /// links are listed if missing.
+        #[prost(string, optional, tag = "1")]
+        pub owner: Option<String>,
+
+        /// Maximum number of listed links, unlimited if zero.
+        #[prost(uint32, tag = "2")]
+        pub limit: u32,
+    }
+
+    /// Reply of `ListLinks`.
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ListLinksReply {
+        /// The listed links.
+        #[prost(message, repeated, tag = "1")]
+        pub links: Vec<Link>,
+    }
+
+    impl From<super::ShortLink> for Link {
+        fn from(link: super::ShortLink) -> Self {
+            Self {
+                slug: link.slug.0,
+                url: link.url.0,
+            }
+        }
+    }
+
+    impl ShortenerError {
+        /// gRPC status code corresponding to the error.
+        pub fn grpc_code(&self) -> Code {
+            match self {
+                ShortenerError::InvalidUrl
+                | ShortenerError::InvalidSlug(_)
+                | ShortenerError::InvalidWeights
+                | ShortenerError::InvalidCountry
+                | ShortenerError::InvalidTag
+                | ShortenerError::SlugReserved
+                | ShortenerError::DestinationBlocked
+                | ShortenerError::RedirectLoop => Code::InvalidArgument,
+                ShortenerError::SlugAlreadyInUse | ShortenerError::CampaignAlreadyExists => {
+                    Code::AlreadyExists
+                }
+                ShortenerError::VersionConflict => Code::Aborted,
+                ShortenerError::NothingToRevert
+                | ShortenerError::LinkExhausted
+                | ShortenerError::LinkNotActive
+                | ShortenerError::Rejected(_) => Code::FailedPrecondition,
+                ShortenerError::SlugNotFound | ShortenerError::CampaignNotFound => Code::NotFound,
+                ShortenerError::Unauthorized => Code::Unauthenticated,
+                ShortenerError::RateLimited => Code::ResourceExhausted,
+                ShortenerError::LinkDisabled
+                | ShortenerError::NotOwner
+                | ShortenerError::Forbidden => Code::PermissionDenied,
+                ShortenerError::StorageFailure | ShortenerError::NoSlugInShard => Code::Internal,
+                ShortenerError::MetadataUnavailable => Code::Unavailable,
+            }
+        }
+    }
+
+    impl From<ShortenerError> for Status {
+        fn from(error: ShortenerError) -> Self {
+            Status::new(error.grpc_code(), error.to_string())
+        }
+    }
+
+    /// The `url_shortener.UrlShortener` service, to be added to a
+    /// `tonic::transport::Server`.
+    pub struct UrlShortenerServer<S: EventStore> {
+        service: SharedUrlShortenerService<S>,
+    }
+
+    impl<S: EventStore> UrlShortenerServer<S> {
+        /// Creates the gRPC service serving the given service.
+        pub fn new(service: SharedUrlShortenerService<S>) -> Self {
+            Self { service }
+        }
+    }
+
+    impl<S: EventStore> Clone for UrlShortenerServer<S> {
+        fn clone(&self) -> Self {
+            Self {
+                service: self.service.clone(),
+            }
+        }
+    }
+
+    impl<S: EventStore> NamedService for UrlShortenerServer<S> {
+        const NAME: &'static str = SERVICE_NAME;
+    }
+
+    impl<S, B> Service<http::Request<B>> for UrlShortenerServer<S>
+    where
+        S: EventStore + Send + Sync + 'static,
+        B: http_body::Body + Send + 'static,
+        B::Error: Into<StdError> + Send + 'static,
+    {
+        type Response = http::Response<tonic::body::Body>;
+        type Error = Infallible;
+        type Future = BoxFuture<Self::Response, Self::Error>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, request: http::Request<B>) -> Self::Future {
+            let service = self.service.clone();
+            match request.uri().path() {
+                "/url_shortener.UrlShortener/CreateLink" => {
+                    unary(request, Unary(service, create_link::<S>))
+                }
+                "/url_shortener.UrlShortener/Redirect" => {
+                    unary(request, Unary(service, redirect::<S>))
+                }
+                "/url_shortener.UrlShortener/ChangeUrl" => {
+                    unary(request, Unary(service, change_url::<S>))
+                }
+                "/url_shortener.UrlShortener/GetStats" => {
+                    unary(request, Unary(service, stats::<S>))
+                }
+                "/url_shortener.UrlShortener/ListLinks" => {
+                    unary(request, Unary(service, list_links::<S>))
+                }
+                _ => Box::pin(ready(Ok(Status::unimplemented("").into_http()))),
+            }
+        }
+    }
+
+    //unary method answered by a handler of the service
+    struct Unary<S: EventStore, F>(SharedUrlShortenerService<S>, F);
+
+    impl<S, F, Q, R> UnaryService<Q> for Unary<S, F>
+    where
+        S: EventStore,
+        F: Fn(&SharedUrlShortenerService<S>, Q) -> Result<R, ShortenerError>,
+    {
+        type Response = R;
+        type Future = Ready<Result<tonic::Response<R>, Status>>;
+
+        fn call(&mut self, request: tonic::Request<Q>) -> Self::Future {
+            let reply = (self.1)(&self.0, request.into_inner());
+            ready(reply.map(tonic::Response::new).map_err(Status::from))
+        }
+    }
+
+    fn unary<B, Q, M>(
+        request: http::Request<B>,
+        method: M,
+    ) -> BoxFuture<http::Response<tonic::body::Body>, Infallible>
+    where
+        B: http_body::Body + Send + 'static,
+        B::Error: Into<StdError> + Send + 'static,
+        Q: prost::Message + Default + Send + 'static,
+        M: UnaryService<Q> + Send + 'static,
+        M::Response: prost::Message + Send + 'static,
+        M::Future: Send,
+    {
+        Box::pin(async move {
+            let mut grpc = Grpc::new(tonic::codec::ProstCodec::default());
+            Ok(grpc.unary(method, request).await)
+        })
+    }
+
+    fn create_link<S: EventStore>(
+        mut service: &SharedUrlShortenerService<S>,
+        request: CreateLinkRequest,
+    ) -> Result<Link, ShortenerError> {
+        let link = service.handle_create_short_link(Url(request.url), request.slug.map(Slug))?;
+        Ok(link.into())
+    }
+
+    fn redirect<S: EventStore>(
+        mut service: &SharedUrlShortenerService<S>,
+        request: RedirectRequest,
+    ) -> Result<RedirectReply, ShortenerError> {
+        let context = ClickContext {
+            referrer: request.referrer,
+            user_agent: request.user_agent,
+            ip: None,
+            country: None,
+        };
+        let decision = service.resolve_redirect(
+            Slug(request.slug),
+            context,
+            request.query.as_deref(),
+            None,
+        )?;
+        Ok(RedirectReply {
+            location: decision.location.0,
+            permanent: decision.permanent,
+        })
+    }
+
+    fn change_url<S: EventStore>(
+        mut service: &SharedUrlShortenerService<S>,
+        request: ChangeUrlRequest,
+    ) -> Result<Link, ShortenerError> {
+        let link = service.handle_change_short_link(Slug(request.slug), Url(request.url))?;
+        Ok(link.into())
+    }
+
+    fn stats<S: EventStore>(
+        service: &SharedUrlShortenerService<S>,
+        request: GetStatsRequest,
+    ) -> Result<StatsReply, ShortenerError> {
+        let stats = service.get_stats(Slug(request.slug))?;
+        Ok(StatsReply {
+            link: Some(stats.link.into()),
+            redirects: stats.redirects,
+        })
+    }
+
+    fn list_links<S: EventStore>(
+        service: &SharedUrlShortenerService<S>,
+        request: ListLinksRequest,
+    ) -> Result<ListLinksReply, ShortenerError> {
+        let limit = match request.limit {
+            0 => usize::MAX,
+            limit => limit as usize,
+        };
+        let service = service.read();
+        let links = match request.owner {
+            Some(owner) => service
+                .list_links_by_owner(OwnerId(owner))
+                .into_iter()
+                .take(limit)
+                .collect(),
+            None => service
+                .top_links(limit)
+                .into_iter()
+                .map(|stats| stats.link)
+                .collect::<Vec<_>>(),
+        };
+        Ok(ListLinksReply {
+            links: links.into_iter().map(Link::from).collect(),
+        })
+    }
+}
+
+/// GraphQL API of the service built with
+/// [async-graphql](https://docs.rs/async-graphql).
+///
+/// Queries map to the query handlers and mutations to the commands. Errors
+/// carry the [`ShortenerError::code()`] in the `code` extension.
+#[cfg(feature = "graphql")]
+pub mod graphql {
+    use async_graphql::{
+        EmptySubscription, Error, ErrorExtensions, Json, Object, Result, Schema, SimpleObject,
+    };
+
+    use super::commands::LinkManagementHandler;
+    use super::queries::{HistoryQueryHandler, LinkQueryHandler, QueryHandler};
+    use super::store::EventStore;
+    use super::{
+        format_timestamp, Event, SharedUrlShortenerService, ShortLink, ShortenerError, Slug,
+        Stats, Url,
+    };
+
+    /// GraphQL schema of the service.
+    pub type UrlShortenerSchema<S> = Schema<QueryRoot<S>, MutationRoot<S>, EmptySubscription>;
+
+    /// Builds the [`UrlShortenerSchema`] serving the given service.
+    pub fn schema<S>(service: SharedUrlShortenerService<S>) -> UrlShortenerSchema<S>
+    where
+        S: EventStore + Send + Sync + 'static,
+    {
+        Schema::new(
+            QueryRoot {
+                service: service.clone(),
+            },
+            MutationRoot { service },
+            EmptySubscription,
+        )
+    }
+
+    /// A short link.
+    #[derive(Debug, Clone, SimpleObject)]
+    pub struct Link {
+        /// Slug of the link.
+        pub slug: String,
+
+        /// URL the link points to.
+        pub url: String,
+    }
+
+    /// Statistics of a link.
+    #[derive(Debug, Clone, SimpleObject)]
+    pub struct LinkStats {
+        /// The link.
+        pub link: Link,
+
+        /// Number of redirects of the link.
+        pub redirects: u64,
+    }
+
+    /// An event of the history of a link.
+    #[derive(Debug, Clone, SimpleObject)]
+    pub struct HistoryEntry {
+        /// Position of the event in the event log.
+        pub sequence: u64,
+
+        /// Version of the link the event produced.
+        pub version: u64,
+
+        /// When the event was recorded, as an RFC 3339 timestamp.
+        pub occurred_at: String,
+
+        /// The event, serialized as JSON.
+        pub event: Json<Event>,
+    }
+
+    impl From<ShortLink> for Link {
+        fn from(link: ShortLink) -> Self {
+            Self {
+                slug: link.slug.0,
+                url: link.url.0,
+            }
+        }
+    }
+
+    impl From<Stats> for LinkStats {
+        fn from(stats: Stats) -> Self {
+            Self {
+                link: stats.link.into(),
+                redirects: stats.redirects,
+            }
+        }
+    }
+
+    impl ErrorExtensions for ShortenerError {
+        fn extend(&self) -> Error {
+            Error::new(self.to_string())
+                .extend_with(|_, extensions| extensions.set("code", self.code()))
+        }
+    }
+
+    /// Root of the queries.
+    pub struct QueryRoot<S: EventStore> {
+        service: SharedUrlShortenerService<S>,
+    }
+
+    #[Object]
+    impl<S: EventStore + Send + Sync + 'static> QueryRoot<S> {
+        /// The link of the slug, without counting a redirect.
+        async fn link(&self, slug: String) -> Result<Link> {
+            let link = self
+                .service
+                .read()
+                .get_link(Slug(slug))
+                .map_err(|e| e.extend())?;
+            Ok(link.into())
+        }
+
+        /// Statistics of the link of the slug.
+        async fn stats(&self, slug: String) -> Result<LinkStats> {
+            let stats = self.service.get_stats(Slug(slug)).map_err(|e| e.extend())?;
+            Ok(stats.into())
+        }
+
+        /// Events of the link the slug points to, oldest first.
+        async fn history(&self, slug: String) -> Vec<HistoryEntry> {
+            self.service
+                .read()
+                .get_history(Slug(slug))
+                .into_iter()
+                .map(|envelope| HistoryEntry {
+                    sequence: envelope.sequence,
+                    version: envelope.version,
+                    occurred_at: format_timestamp(envelope.occurred_at),
+                    event: Json(envelope.event),
+                })
+                .collect()
+        }
+
+        /// Statistics of up to `limit` most redirected links.
+        async fn top_links(&self, limit: usize) -> Vec<LinkStats> {
+            self.service
+                .read()
+                .top_links(limit)
+                .into_iter()
+                .map(LinkStats::from)
+                .collect()
+        }
+
+        /// Links pointing to the URL, normalized the same way as when
+        /// creating links.
+        async fn search(&self, url: String) -> Vec<Link> {
+            self.service
+                .read()
+                .find_by_url(Url(url))
+                .into_iter()
+                .map(Link::from)
+                .collect()
+        }
+    }
+
+    /// Root of the mutations.
+    pub struct MutationRoot<S: EventStore> {
+        service: SharedUrlShortenerService<S>,
+    }
+
+    #[Object]
+    impl<S: EventStore + Send + Sync + 'static> MutationRoot<S> {
+        /// Creates a link to the URL, with a generated slug if missing.
+        async fn create_link(&self, url: String, slug: Option<String>) -> Result<Link> {
+            let link = self
+                .service
+                .create_checked(Url(url), slug.map(Slug))
+                .await
+                .map_err(|e| e.extend())?;
+            Ok(link.into())
+        }
+
+        /// Changes the URL the link points to.
+        async fn change_url(&self, slug: String, url: String) -> Result<Link> {
+            let link = self
+                .service
+                .change_checked(Slug(slug), Url(url))
+                .await
+                .map_err(|e| e.extend())?;
+            Ok(link.into())
+        }
+
+        /// Deletes the link, keeping its history.
+        async fn delete_link(&self, slug: String) -> Result<Link> {
+            let link = self
+                .service
+                .write()
+                .handle_delete_short_link(Slug(slug))
+                .map_err(|e| e.extend())?;
+            Ok(link.into())
+        }
+
+        /// Disables the link, so it stops redirecting.
+        async fn disable_link(&self, slug: String) -> Result<Link> {
+            let link = self
+                .service
+                .write()
+                .handle_disable_link(Slug(slug))
+                .map_err(|e| e.extend())?;
+            Ok(link.into())
+        }
+
+        /// Enables the previously disabled link.
+        async fn enable_link(&self, slug: String) -> Result<Link> {
+            let link = self
+                .service
+                .write()
+                .handle_enable_link(Slug(slug))
+                .map_err(|e| e.extend())?;
+            Ok(link.into())
+        }
+
+        /// Renames the slug of the link, carrying its statistics over.
+        async fn rename_slug(&self, slug: String, new_slug: String) -> Result<Link> {
+            let link = self
+                .service
+                .write()
+                .handle_rename_slug(Slug(slug), Slug(new_slug))
+                .map_err(|e| e.extend())?;
+            Ok(link.into())
+        }
+    }
+}
+
+/// `url-shortener` command line interface built with
+/// [clap](https://docs.rs/clap), operating on a [`FileEventStore`].
+///
+/// [`FileEventStore`]: store::FileEventStore
+#[cfg(feature = "cli")]
+pub mod cli {
+    use std::path::PathBuf;
+    use std::process::ExitCode;
+
+    use clap::{Parser, Subcommand};
+
+    use super::commands::CommandHandler;
+    use super::queries::QueryHandler;
+    use super::store::FileEventStore;
+    use super::{ExportFormat, Slug, Url, UrlShortenerService};
+    #[cfg(feature = "qr")]
+    use super::{QrFormat, ServiceConfig};
+
+    /// Manages short links stored in a local event log.
+    #[derive(Debug, Parser)]
+    #[command(name = "url-shortener")]
+    pub struct Cli {
+        /// Path of the event log file.
+        #[arg(long, default_value = "events.log")]
+        pub log: PathBuf,
+
+        #[command(subcommand)]
+        pub command: Command,
+    }
+
+    /// Subcommands of the [`Cli`].
+    #[derive(Debug, Subcommand)]
+    pub enum Command {
+        /// Creates a new short link.
+        Create {
+            /// The original URL to shorten.
+            url: String,
+
+            /// Custom slug, generated if missing.
+            #[arg(long)]
+            slug: Option<String>,
+        },
+
+        /// Resolves the link and counts a redirect, printing the original URL.
+        Redirect {
+            /// Slug of the link.
+            slug: String,
+        },
+
+        /// Changes the original URL of the link.
+        ChangeUrl {
+            /// Slug of the link.
+            slug: String,
+
+            /// The new URL the link should point to.
+            url: String,
+        },
+
+        /// Prints the redirect count of the link.
+        Stats {
+            /// Slug of the link.
+            slug: String,
+        },
+
+        /// Prints the whole event log as JSON.
+        ExportEvents,
+
+        /// Prints the stats of all links.
+        ExportStats {
+            /// Output format.
+            #[arg(long, value_enum, default_value = "csv")]
+            format: ExportFormat,
+        },
+
+        /// Replays the event log into fresh projections, reporting progress
+        /// to stderr.
+        RebuildProjections,
+
+        /// Writes a QR code of the short URL of a link to stdout.
+        #[cfg(feature = "qr")]
+        Qr {
+            /// Slug of the link.
+            slug: String,
+
+            /// Public URL the short links are served under.
+            #[arg(long)]
+            base_url: Option<String>,
+
+            /// Image format.
+            #[arg(long, value_enum, default_value = "svg")]
+            format: QrFormat,
+        },
+    }
+
+    /// Parses the command line arguments and runs the requested command.
+    pub fn run() -> ExitCode {
+        let cli = Cli::parse();
+        let store = match FileEventStore::open(&cli.log) {
+            Ok(store) => store,
+            Err(e) => {
+                eprintln!("cannot open {}: {e}", cli.log.display());
+                return ExitCode::FAILURE;
+            }
+        };
+        let mut service = UrlShortenerService::with_store(store);
+
+        let result = match cli.command {
+            Command::Create { url, slug } => service
+                .handle_create_short_link(Url(url), slug.map(Slug))
+                .map(|link| format!("{} -> {}", link.slug.0, link.url.0)),
+            Command::Redirect { slug } => service
+                .handle_redirect(Slug(slug))
+                .map(|link| link.url.0),
+            Command::ChangeUrl { slug, url } => service
+                .handle_change_short_link(Slug(slug), Url(url))
+                .map(|link| format!("{} -> {}", link.slug.0, link.url.0)),
+            Command::Stats { slug } => service
+                .get_stats(Slug(slug))
+                .map(|stats| format!("{} -> {}: {} redirects", stats.link.slug.0, stats.link.url.0, stats.redirects)),
+            Command::ExportEvents => match service.export_events_json() {
+                Ok(json) => Ok(json),
+                Err(e) => {
+                    eprintln!("cannot export events: {e}");
+                    return ExitCode::FAILURE;
+                }
+            },
+            Command::ExportStats { format } => {
+                return match service.export_stats(format, std::io::stdout().lock()) {
+                    Ok(()) => ExitCode::SUCCESS,
+                    Err(e) => {
+                        eprintln!("cannot export stats: {e}");
+                        ExitCode::FAILURE
+                    }
+                };
+            }
+            Command::RebuildProjections => {
+                let events = service.rebuild_projections(|replayed, total| {
+                    eprintln!("replayed {replayed}/{total} events");
+                });
+                Ok(format!("rebuilt projections from {events} events"))
+            }
+            #[cfg(feature = "qr")]
+            Command::Qr { slug, base_url, format } => {
+                let service = service.with_config(ServiceConfig {
+                    base_url: base_url.map(Url),
+                    ..ServiceConfig::default()
+                });
+                return match service.generate_qr(Slug(slug), format) {
+                    Ok(image) => {
+                        use std::io::Write;
+                        match std::io::stdout().lock().write_all(&image) {
+                            Ok(()) => ExitCode::SUCCESS,
+                            Err(e) => {
+                                eprintln!("cannot write QR code: {e}");
+                                ExitCode::FAILURE
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        eprintln!("error: {e}");
+                        ExitCode::FAILURE
+                    }
+                };
+            }
+        };
+
+        match result {
+            Ok(output) => {
+                println!("{output}");
+                ExitCode::SUCCESS
+            }
+            Err(e) => {
+                eprintln!("error: {e}");
+                ExitCode::FAILURE
+            }
+        }
+    }
+}
+
+#[cfg(feature = "cli")]
+fn main() -> std::process::ExitCode {
+    cli::run()
+}
+
+#[cfg(not(feature = "cli"))]
+fn main() {
+    // example of usage
+    let mut service = UrlShortenerService::new();
+    let url = Url("https://example-of/verylong-link.com".to_string());
+    let new_url = Url("https://example-of/verylong-link/asasasdsad.com".to_string());
     let slug = Slug("short".to_string());
     println!("{:?}", service.handle_create_short_link(url.clone(), Some(slug.clone())));
     println!("{:?}", service.handle_create_short_link(url.clone(), None));
@@ -362,7 +14095,4977 @@ fn main() {
     //let _ = service.handle_redirect(slug.clone());
     println!("{:?}",service.handle_change_short_link(slug.clone(),new_url.clone()));
 
-    let _ = service.handle_redirect(slug.clone());
-    
-    println!("{:?}", service.get_stats(slug.clone()));
+    let _ = service.handle_redirect(slug.clone());
+    
+    println!("{:?}", service.get_stats(slug.clone()));
+}
+
+//my tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //links a, b and c redirected once, twice and three times, b with a changed url
+    fn record_traffic<S: EventStore>(service: &mut UrlShortenerService<S>) -> Vec<Slug> {
+        let slugs: Vec<Slug> = ["a", "b", "c"].iter().map(|slug| Slug(slug.to_string())).collect();
+        for (i, slug) in slugs.iter().enumerate() {
+            let url = Url(format!("https://example.com/{i}"));
+            service.handle_create_short_link(url, Some(slug.clone())).unwrap();
+            for _ in 0..=i {
+                service.handle_redirect(slug.clone()).unwrap();
+            }
+        }
+        let new_url = Url("https://example.org/".to_string());
+        service.handle_change_short_link(slugs[1].clone(), new_url).unwrap();
+        slugs
+    }
+
+    //sends the request to the router, whose handlers never wait for anything
+    #[cfg(feature = "http")]
+    fn send(
+        router: &axum::Router,
+        request: axum::http::Request<String>,
+    ) -> axum::response::Response {
+        use std::future::Future;
+        use tower::ServiceExt;
+
+        let request = request.map(axum::body::Body::from);
+        let mut response = std::pin::pin!(router.clone().oneshot(request));
+        let mut context = std::task::Context::from_waker(std::task::Waker::noop());
+        match response.as_mut().poll(&mut context) {
+            std::task::Poll::Ready(response) => response.unwrap(),
+            std::task::Poll::Pending => panic!("the router waited for I/O"),
+        }
+    }
+
+    //records fields of all spans, in the order they were set
+    #[cfg(feature = "tracing")]
+    #[derive(Clone, Default)]
+    struct SpanRecorder {
+        spans: Arc<std::sync::Mutex<Vec<SpanFields>>>,
+    }
+
+    #[cfg(feature = "tracing")]
+    #[derive(Clone, Default)]
+    struct SpanFields(Vec<(String, String)>);
+
+    #[cfg(feature = "tracing")]
+    impl SpanFields {
+        fn get(&self, name: &str) -> Option<&str> {
+            self.0.iter().find(|(field, _)| field == name).map(|(_, value)| value.as_str())
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    impl tracing::field::Visit for SpanFields {
+        fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+            self.0.push((field.name().to_string(), value.to_string()));
+        }
+
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+            self.0.push((field.name().to_string(), format!("{value:?}")));
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    impl SpanRecorder {
+        fn find(&self, operation: &str) -> SpanFields {
+            let spans = self.spans.lock().unwrap();
+            let span = spans.iter().find(|fields| fields.get("operation") == Some(operation));
+            span.cloned().unwrap_or_default()
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    impl tracing::Subscriber for SpanRecorder {
+        fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            let mut fields = SpanFields::default();
+            span.record(&mut fields);
+            let mut spans = self.spans.lock().unwrap();
+            spans.push(fields);
+            tracing::span::Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, id: &tracing::span::Id, values: &tracing::span::Record<'_>) {
+            let mut spans = self.spans.lock().unwrap();
+            values.record(&mut spans[id.into_u64() as usize - 1]);
+        }
+
+        fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+
+        fn event(&self, _: &tracing::Event<'_>) {}
+
+        fn enter(&self, _: &tracing::span::Id) {}
+
+        fn exit(&self, _: &tracing::span::Id) {}
+    }
+
+    //serves the response to every request, returning the url of the server
+    #[cfg(any(feature = "metadata", feature = "health"))]
+    fn serve(status: &'static str, body: &'static str) -> String {
+        use std::io::{BufRead, BufReader};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/page", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    line.clear();
+                }
+                let head = format!("HTTP/1.1 {status}\r\nContent-Length: {}\r\n", body.len());
+                let response = format!("{head}Content-Type: text/html\r\n\r\n{body}");
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        url
+    }
+
+    //runs the future to completion on a fresh runtime
+    #[cfg(any(feature = "metadata", feature = "health", feature = "grpc", feature = "graphql"))]
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build();
+        runtime.unwrap().block_on(future)
+    }
+
+    //calls the method of the grpc service in process, like a generated client
+    #[cfg(feature = "grpc")]
+    fn call_grpc<Q, R>(
+        server: &grpc::UrlShortenerServer<InMemoryEventStore>,
+        method: &'static str,
+        request: Q,
+    ) -> Result<R, Box<tonic::Status>>
+    where
+        Q: prost::Message + Send + Sync + 'static,
+        R: prost::Message + Default + Send + Sync + 'static,
+    {
+        use tonic::codegen::http::uri::PathAndQuery;
+
+        let mut client = tonic::client::Grpc::new(server.clone());
+        let path = PathAndQuery::from_static(method);
+        let codec = tonic::codec::ProstCodec::default();
+        block_on(async move {
+            client.ready().await.unwrap();
+            let reply = client.unary(tonic::Request::new(request), path, codec).await;
+            reply.map(tonic::Response::into_inner).map_err(Box::new)
+        })
+    }
+
+    //path in the temporary directory unique to the test
+    #[cfg(feature = "serde")]
+    fn temporary_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("{name}-{}.log", std::process::id()))
+    }
+
+    #[test]
+    fn test_create_short_link() {
+        let mut service = UrlShortenerService::new();
+        let url = Url("https://example.com/".to_string());
+        let slug = Slug("example".to_string());
+
+        let result = service.handle_create_short_link(url.clone(), Some(slug.clone()));
+        assert_eq!(result, Ok(ShortLink { slug: slug.clone(), url: url.clone() }));
+
+        let result = service.handle_create_short_link(url.clone(), Some(slug.clone()));
+        assert_eq!(result, Err(ShortenerError::SlugAlreadyInUse));
+    }
+
+    #[test]
+    fn test_redirect() {
+        let mut service = UrlShortenerService::new();
+        let url = Url("https://example.com/".to_string());
+        let slug = Slug("example".to_string());
+
+        let _ = service.handle_create_short_link(url.clone(), Some(slug.clone()));
+
+        let result = service.handle_redirect(slug.clone());
+        assert_eq!(result, Ok(ShortLink { slug: slug.clone(), url: url.clone() }));
+    }
+
+    #[test]
+    fn test_get_stats() {
+        let mut service = UrlShortenerService::new();
+        let url = Url("https://example.com/".to_string());
+        let slug = Slug("example".to_string());
+
+        let _ = service.handle_create_short_link(url.clone(), Some(slug.clone()));
+        let _ = service.handle_redirect(slug.clone());
+
+        let result = service.get_stats(slug.clone());
+        assert_eq!(result, Ok(Stats { link: ShortLink { slug: slug.clone(), url: url.clone() }, redirects: 1 }));
+    }
+
+    #[test]
+    fn test_redirect_with_multiple_redirects() {
+        let mut service = UrlShortenerService::new();
+        let url = Url("https://example.com/".to_string());
+        let slug = Slug("example".to_string());
+
+        let _ = service.handle_create_short_link(url.clone(), Some(slug.clone()));
+        let _ = service.handle_redirect(slug.clone());
+        let _ = service.handle_redirect(slug.clone());
+        let _ = service.handle_redirect(slug.clone());
+
+        let result = service.get_stats(slug.clone());
+        assert_eq!(result, Ok(Stats { link: ShortLink { slug: slug.clone(), url: url.clone() }, redirects: 3 }));
+    }
+
+    #[test]
+    fn test_read_model_matches_full_replay() {
+        let mut service = UrlShortenerService::new();
+        let slugs = record_traffic(&mut service);
+        let replayed = UrlShortenerService::<InMemoryEventStore>::replay(&service.read_envelopes());
+        for slug in slugs {
+            let expected = replayed.get(&slug).map(LinkState::stats);
+            assert_eq!(service.get_stats(slug), expected);
+        }
+    }
+
+    #[test]
+    fn test_unknown_slugs_record_no_events() {
+        let mut service = UrlShortenerService::new();
+        let slug = Slug("missing".to_string());
+        let new_url = Url("https://example.org/".to_string());
+
+        assert_eq!(service.handle_redirect(slug.clone()), Err(ShortenerError::SlugNotFound));
+        let result = service.handle_change_short_link(slug.clone(), new_url);
+        assert_eq!(result, Err(ShortenerError::SlugNotFound));
+        assert_eq!(service.get_stats(slug), Err(ShortenerError::SlugNotFound));
+        assert!(service.store().read_all().is_empty());
+    }
+
+    #[test]
+    fn test_service_is_rebuilt_from_its_store() {
+        let mut service = UrlShortenerService::new();
+        let slugs = record_traffic(&mut service);
+        let rebuilt = UrlShortenerService::with_store(service.store().clone());
+        for slug in slugs {
+            assert_eq!(rebuilt.get_stats(slug.clone()), service.get_stats(slug));
+        }
+    }
+
+    #[test]
+    fn test_read_stream_returns_only_events_of_the_slug() {
+        let mut service = UrlShortenerService::new();
+        let slugs = record_traffic(&mut service);
+        let url = Url("https://example.com/0".to_string());
+        let stream: Vec<Event> = service
+            .store()
+            .read_stream(&store::StreamId(slugs[0].clone()))
+            .into_iter()
+            .map(|envelope| envelope.event)
+            .collect();
+        assert_eq!(stream, vec![
+            Event::LinkCreated {
+                slug: slugs[0].clone(),
+                url: url.clone(),
+                raw_url: Some(url),
+                max_clicks: None,
+                owner: None,
+                active_from: None,
+                active_until: None,
+                fallback_url: None,
+            },
+            Event::LinkAccessed { slug: slugs[0].clone() },
+        ]);
+        let missing = store::StreamId(Slug("missing".to_string()));
+        assert!(service.store().read_stream(&missing).is_empty());
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let mut service = UrlShortenerService::new();
+        let slugs = record_traffic(&mut service);
+        let snapshot = service.snapshot();
+        let taken_at = service.store().read_all().len();
+        service.handle_redirect(slugs[0].clone()).unwrap();
+        let remaining = service.read_envelopes().split_off(taken_at);
+        let restored = UrlShortenerService::from_snapshot(snapshot, remaining);
+        for slug in slugs {
+            assert_eq!(service.get_stats(slug.clone()), restored.get_stats(slug));
+        }
+        assert_eq!(restored.snapshot(), service.snapshot());
+    }
+
+    #[test]
+    fn test_restored_service_rejects_slugs_taken_before_the_snapshot() {
+        let empty = UrlShortenerService::new().snapshot();
+        assert!(empty.links.is_empty() && empty.aliases.is_empty());
+        assert_eq!(empty.last_event_index, None);
+
+        let mut service = UrlShortenerService::new();
+        let slugs = record_traffic(&mut service);
+        let mut restored = UrlShortenerService::from_snapshot(service.snapshot(), Vec::new());
+        let url = Url("https://example.com/".to_string());
+        let result = restored.handle_create_short_link(url, Some(slugs[0].clone()));
+        assert_eq!(result, Err(ShortenerError::SlugAlreadyInUse));
+        let missing = Slug("missing".to_string());
+        assert_eq!(restored.handle_redirect(missing), Err(ShortenerError::SlugNotFound));
+        assert!(restored.store().read_all().is_empty());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_events_json_round_trip() {
+        let mut service = UrlShortenerService::new();
+        let slugs = record_traffic(&mut service);
+        let json = service.export_events_json().unwrap();
+        let imported = UrlShortenerService::import_events_json(&json).unwrap();
+        assert_eq!(imported.store().read_all(), service.store().read_all());
+        for slug in slugs {
+            assert_eq!(imported.get_stats(slug.clone()), service.get_stats(slug));
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_malformed_events_json_is_rejected() {
+        assert!(UrlShortenerService::import_events_json("[{\"LinkAccessed\": {}}]").is_err());
+        assert!(UrlShortenerService::import_events_json("not json").is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_file_store_survives_restart() {
+        let path = temporary_path("restart");
+        let store = store::FileEventStore::open(&path).unwrap();
+        let mut service = UrlShortenerService::with_store(store);
+        let slugs = record_traffic(&mut service);
+        drop(service);
+
+        let reopened = UrlShortenerService::with_store(store::FileEventStore::open(&path).unwrap());
+        assert_eq!(reopened.get_stats(slugs[2].clone()).map(|stats| stats.redirects), Ok(3));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_file_store_cuts_off_torn_multi_byte_line() {
+        use std::io::Write;
+
+        let path = temporary_path("torn");
+        let store = store::FileEventStore::open(&path).unwrap();
+        let mut service = UrlShortenerService::with_store(store);
+        let slugs = record_traffic(&mut service);
+        let events = service.store().read_all().len();
+        drop(service);
+        //a write torn inside the two bytes of 'ż'
+        let mut file = std::fs::OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"sequence\":99,\"slug\":\"\xc5").unwrap();
+        drop(file);
+
+        let store = store::FileEventStore::open(&path).unwrap();
+        let mut service = UrlShortenerService::with_store(store);
+        assert_eq!(service.read_envelopes().len(), events);
+        service.handle_redirect(slugs[0].clone()).unwrap();
+        let reopened = UrlShortenerService::with_store(store::FileEventStore::open(&path).unwrap());
+        assert_eq!(reopened.get_stats(slugs[0].clone()).map(|stats| stats.redirects), Ok(2));
+        assert_eq!(reopened.read_envelopes(), service.read_envelopes());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_file_store_rejects_malformed_line_before_the_end() {
+        let path = temporary_path("malformed");
+        std::fs::write(&path, "not json\n{\"LinkAccessed\":{\"slug\":\"a\"}}\n").unwrap();
+        let error = store::FileEventStore::open(&path).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_envelopes_are_numbered_in_recording_order() {
+        let mut service = UrlShortenerService::new();
+        record_traffic(&mut service);
+        let envelopes = service.read_envelopes();
+        let sequences: Vec<u64> = envelopes.iter().map(|envelope| envelope.sequence).collect();
+        assert_eq!(sequences, (0..envelopes.len() as u64).collect::<Vec<_>>());
+        assert!(envelopes.windows(2).all(|pair| pair[0].occurred_at <= pair[1].occurred_at));
+        let ids: Vec<Uuid> = envelopes.iter().map(|envelope| envelope.id).collect();
+        assert!(ids.iter().enumerate().all(|(i, id)| !ids[..i].contains(id)));
+    }
+
+    #[test]
+    fn test_rejected_commands_record_no_envelope() {
+        let mut service = UrlShortenerService::new();
+        let slugs = record_traffic(&mut service);
+        let recorded = service.read_envelopes().len();
+        let url = Url("https://example.com/".to_string());
+        let result = service.handle_create_short_link(url, Some(slugs[0].clone()));
+        assert_eq!(result, Err(ShortenerError::SlugAlreadyInUse));
+        let missing = Slug("missing".to_string());
+        assert_eq!(service.handle_redirect(missing), Err(ShortenerError::SlugNotFound));
+        assert_eq!(service.read_envelopes().len(), recorded);
+    }
+
+    #[test]
+    fn test_streams_are_versioned_per_link() {
+        let mut service = UrlShortenerService::new();
+        let slugs = record_traffic(&mut service);
+        //b was created, redirected twice and changed
+        let stream = store::StreamId(slugs[1].clone());
+        let versions: Vec<u64> = service
+            .store()
+            .read_stream(&stream)
+            .iter()
+            .map(|envelope| envelope.version)
+            .collect();
+        assert_eq!(versions, vec![1, 2, 3, 4]);
+        assert_eq!(service.store().stream_version(&stream), 4);
+        assert_eq!(service.store().stream_version(&store::StreamId(slugs[0].clone())), 2);
+    }
+
+    #[test]
+    fn test_unknown_stream_has_no_version() {
+        let mut service = UrlShortenerService::new();
+        record_traffic(&mut service);
+        let missing = Slug("missing".to_string());
+        assert_eq!(service.handle_redirect(missing.clone()), Err(ShortenerError::SlugNotFound));
+        assert_eq!(service.store().stream_version(&store::StreamId(missing)), 0);
+    }
+
+    #[test]
+    fn test_stale_expected_version_is_rejected() {
+        use commands::CommandHandlerV2;
+
+        let mut service = UrlShortenerService::new();
+        let slug = Slug("example".to_string());
+        let url = Url("https://example.com/".to_string());
+        let created = service.handle_create_short_link_versioned(url, Some(slug.clone()), Some(0));
+        assert!(created.is_ok());
+        let read = service.link_version(&slug).unwrap();
+
+        let new_url = Url("https://example.org/".to_string());
+        let changed = service.handle_change_short_link_versioned(slug.clone(), new_url, Some(read));
+        assert!(changed.is_ok());
+        let stale_url = Url("https://example.net/".to_string());
+        let stale = service.handle_change_short_link_versioned(slug.clone(), stale_url, Some(read));
+        assert_eq!(stale, Err(ShortenerError::VersionConflict));
+        let taken = Url("https://example.net/".to_string());
+        let taken = service.handle_create_short_link_versioned(taken, Some(slug.clone()), Some(0));
+        assert_eq!(taken, Err(ShortenerError::VersionConflict));
+        let link = service.get_stats(slug).unwrap().link;
+        assert_eq!(link.url, Url("https://example.org/".to_string()));
+    }
+
+    #[test]
+    fn test_current_expected_version_advances_the_link() {
+        use commands::CommandHandlerV2;
+
+        let mut service = UrlShortenerService::new();
+        let slugs = record_traffic(&mut service);
+        let version = service.link_version(&slugs[0]).unwrap();
+        assert!(service.handle_redirect_versioned(slugs[0].clone(), Some(version)).is_ok());
+        assert!(service.handle_redirect_versioned(slugs[0].clone(), None).is_ok());
+        assert_eq!(service.link_version(&slugs[0]), Ok(version + 2));
+        let missing = Slug("missing".to_string());
+        assert_eq!(service.link_version(&missing), Err(ShortenerError::SlugNotFound));
+    }
+
+    #[test]
+    fn test_shared_service_counts_redirects_from_all_threads() {
+        let shared = SharedUrlShortenerService::new(UrlShortenerService::new());
+        let slug = Slug("example".to_string());
+        let url = Url("https://example.com/".to_string());
+        (&shared).handle_create_short_link(url, Some(slug.clone())).unwrap();
+
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                let mut shared = shared.clone();
+                let slug = slug.clone();
+                scope.spawn(move || {
+                    for _ in 0..25 {
+                        shared.handle_redirect(slug.clone()).unwrap();
+                    }
+                });
+            }
+        });
+        assert_eq!(shared.get_stats(slug).map(|stats| stats.redirects), Ok(100));
+    }
+
+    #[test]
+    fn test_shared_service_survives_a_poisoned_lock() {
+        let shared = SharedUrlShortenerService::new(UrlShortenerService::new());
+        let slug = Slug("example".to_string());
+        let poisoner = shared.clone();
+        let panicked = std::thread::spawn(move || {
+            let _guard = poisoner.write();
+            panic!("poisoning the lock");
+        })
+        .join();
+        assert!(panicked.is_err());
+
+        let missing = (&shared).handle_redirect(slug.clone());
+        assert_eq!(missing, Err(ShortenerError::SlugNotFound));
+        let url = Url("https://example.com/".to_string());
+        assert!((&shared).handle_create_short_link(url, Some(slug.clone())).is_ok());
+        assert_eq!(shared.get_stats(slug).map(|stats| stats.redirects), Ok(0));
+    }
+
+    #[cfg(feature = "http")]
+    #[test]
+    fn test_http_redirects_and_counts_the_click() {
+        use axum::http::{header, Request, StatusCode};
+
+        let shared = SharedUrlShortenerService::new(UrlShortenerService::new());
+        let router = http::router(shared.clone());
+        let body = r#"{"url": "https://example.com/", "slug": "example"}"#.to_string();
+        let create = Request::post("/links").header(header::CONTENT_TYPE, "application/json");
+        let response = send(&router, create.body(body).unwrap());
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        let response = send(&router, Request::get("/example").body(String::new()).unwrap());
+        assert_eq!(response.status(), StatusCode::FOUND);
+        assert_eq!(response.headers()[header::LOCATION], "https://example.com/");
+        let stats = shared.get_stats(Slug("example".to_string()));
+        assert_eq!(stats.map(|stats| stats.redirects), Ok(1));
+    }
+
+    #[cfg(feature = "http")]
+    #[test]
+    fn test_http_maps_errors_to_status_codes() {
+        use axum::http::{header, Request, StatusCode};
+
+        let shared = SharedUrlShortenerService::new(UrlShortenerService::new());
+        let router = http::router(shared);
+        let response = send(&router, Request::get("/missing").body(String::new()).unwrap());
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        for (url, status) in [
+            ("https://example.com/", StatusCode::CREATED),
+            ("https://example.org/", StatusCode::CONFLICT),
+            ("ftp://example.com/", StatusCode::BAD_REQUEST),
+        ] {
+            let body = format!(r#"{{"url": "{url}", "slug": "example"}}"#);
+            let create = Request::post("/links").header(header::CONTENT_TYPE, "application/json");
+            assert_eq!(send(&router, create.body(body).unwrap()).status(), status);
+        }
+    }
+
+    #[cfg(feature = "cli")]
+    #[test]
+    fn test_cli_parses_subcommands() {
+        use clap::Parser;
+
+        let args = ["url-shortener", "--log", "links.log", "create", "https://example.com/"];
+        let cli = cli::Cli::try_parse_from(args.into_iter().chain(["--slug", "example"])).unwrap();
+        assert_eq!(cli.log, std::path::PathBuf::from("links.log"));
+        assert!(matches!(
+            cli.command,
+            cli::Command::Create { url, slug: Some(slug) }
+                if url == "https://example.com/" && slug == "example"
+        ));
+        let cli = cli::Cli::try_parse_from(["url-shortener", "export-events"]).unwrap();
+        assert_eq!(cli.log, std::path::PathBuf::from("events.log"));
+        assert!(matches!(cli.command, cli::Command::ExportEvents));
+    }
+
+    #[cfg(feature = "cli")]
+    #[test]
+    fn test_cli_rejects_incomplete_commands() {
+        use clap::Parser;
+
+        assert!(cli::Cli::try_parse_from(["url-shortener", "stats"]).is_err());
+        assert!(cli::Cli::try_parse_from(["url-shortener", "change-url", "example"]).is_err());
+        assert!(cli::Cli::try_parse_from(["url-shortener", "frobnicate"]).is_err());
+    }
+
+    #[test]
+    fn test_deleted_link_is_gone_but_keeps_its_history() {
+        use commands::LinkManagementHandler;
+
+        let mut service = UrlShortenerService::new();
+        let slugs = record_traffic(&mut service);
+        let deleted = service.handle_delete_short_link(slugs[0].clone()).unwrap();
+        assert_eq!(deleted.slug, slugs[0]);
+        assert_eq!(service.handle_redirect(slugs[0].clone()), Err(ShortenerError::SlugNotFound));
+        assert_eq!(service.get_stats(slugs[0].clone()), Err(ShortenerError::SlugNotFound));
+        let history = service.store().read_stream(&store::StreamId(slugs[0].clone()));
+        assert_eq!(history.len(), 3);
+        assert_eq!(history[2].event, Event::LinkDeleted { slug: slugs[0].clone() });
+        let again = service.handle_delete_short_link(slugs[0].clone());
+        assert_eq!(again, Err(ShortenerError::SlugNotFound));
+    }
+
+    #[test]
+    fn test_deleted_slug_is_reused_only_when_allowed() {
+        use commands::LinkManagementHandler;
+
+        let url = Url("https://example.org/".to_string());
+        for allow_slug_reuse in [false, true] {
+            let config = ServiceConfig { allow_slug_reuse, ..ServiceConfig::default() };
+            let mut service = UrlShortenerService::new().with_config(config);
+            let slugs = record_traffic(&mut service);
+            service.handle_delete_short_link(slugs[0].clone()).unwrap();
+            let created = service.handle_create_short_link(url.clone(), Some(slugs[0].clone()));
+            if allow_slug_reuse {
+                assert_eq!(created.map(|link| link.url), Ok(url.clone()));
+                let stats = service.get_stats(slugs[0].clone());
+                assert_eq!(stats.map(|stats| stats.redirects), Ok(0));
+            } else {
+                assert_eq!(created, Err(ShortenerError::SlugAlreadyInUse));
+            }
+        }
+    }
+
+    #[test]
+    fn test_link_redirects_up_to_max_clicks() {
+        use commands::LinkManagementHandler;
+
+        let mut service = UrlShortenerService::new();
+        let slug = Slug("invite".to_string());
+        let url = Url("https://example.com/".to_string());
+        let options = LinkOptions { max_clicks: Some(2), ..LinkOptions::default() };
+        service.handle_create_short_link_with_options(url, Some(slug.clone()), options).unwrap();
+        assert!(service.handle_redirect(slug.clone()).is_ok());
+        assert!(service.handle_redirect(slug.clone()).is_ok());
+        let events: Vec<Event> = service.read_envelopes().into_iter().map(|e| e.event).collect();
+        assert_eq!(events.last(), Some(&Event::LinkExhausted { slug: slug.clone() }));
+    }
+
+    #[test]
+    fn test_exhausted_link_is_rejected_without_recording_a_click() {
+        use commands::LinkManagementHandler;
+
+        let mut service = UrlShortenerService::new();
+        let slug = Slug("invite".to_string());
+        let url = Url("https://example.com/".to_string());
+        let options = LinkOptions { max_clicks: Some(1), ..LinkOptions::default() };
+        service.handle_create_short_link_with_options(url, Some(slug.clone()), options).unwrap();
+        service.handle_redirect(slug.clone()).unwrap();
+        let recorded = service.read_envelopes().len();
+        assert_eq!(service.handle_redirect(slug.clone()), Err(ShortenerError::LinkExhausted));
+        assert_eq!(service.read_envelopes().len(), recorded);
+        assert_eq!(service.get_stats(slug).map(|stats| stats.redirects), Ok(1));
+    }
+
+    #[test]
+    fn test_disabled_link_keeps_stats_until_enabled() {
+        use commands::LinkManagementHandler;
+
+        let mut service = UrlShortenerService::new();
+        let slugs = record_traffic(&mut service);
+        service.handle_disable_link(slugs[2].clone()).unwrap();
+        assert_eq!(service.handle_redirect(slugs[2].clone()), Err(ShortenerError::LinkDisabled));
+        assert_eq!(service.get_stats(slugs[2].clone()).map(|stats| stats.redirects), Ok(3));
+        service.handle_enable_link(slugs[2].clone()).unwrap();
+        assert!(service.handle_redirect(slugs[2].clone()).is_ok());
+        assert_eq!(service.get_stats(slugs[2].clone()).map(|stats| stats.redirects), Ok(4));
+    }
+
+    #[test]
+    fn test_toggling_a_link_twice_records_one_event() {
+        use commands::LinkManagementHandler;
+
+        let mut service = UrlShortenerService::new();
+        let slugs = record_traffic(&mut service);
+        let recorded = service.read_envelopes().len();
+        service.handle_enable_link(slugs[0].clone()).unwrap();
+        service.handle_disable_link(slugs[0].clone()).unwrap();
+        service.handle_disable_link(slugs[0].clone()).unwrap();
+        assert_eq!(service.read_envelopes().len(), recorded + 1);
+        let missing = Slug("missing".to_string());
+        assert_eq!(service.handle_disable_link(missing), Err(ShortenerError::SlugNotFound));
+    }
+
+    #[test]
+    fn test_renamed_link_carries_its_clicks_over() {
+        use commands::LinkManagementHandler;
+
+        let new = Slug("renamed".to_string());
+        for keep_renamed_slugs in [false, true] {
+            let config = ServiceConfig { keep_renamed_slugs, ..ServiceConfig::default() };
+            let mut service = UrlShortenerService::new().with_config(config);
+            let slugs = record_traffic(&mut service);
+            let renamed = service.handle_rename_slug(slugs[2].clone(), new.clone()).unwrap();
+            assert_eq!(renamed.slug, new);
+            service.handle_redirect(new.clone()).unwrap();
+            assert_eq!(service.get_stats(new.clone()).map(|stats| stats.redirects), Ok(4));
+            let old = service.handle_redirect(slugs[2].clone()).map(|link| link.slug);
+            if keep_renamed_slugs {
+                assert_eq!(old, Ok(new.clone()));
+            } else {
+                assert_eq!(old, Err(ShortenerError::SlugNotFound));
+            }
+        }
+    }
+
+    #[test]
+    fn test_rename_to_a_taken_slug_is_rejected() {
+        use commands::LinkManagementHandler;
+
+        let mut service = UrlShortenerService::new();
+        let slugs = record_traffic(&mut service);
+        let result = service.handle_rename_slug(slugs[0].clone(), slugs[1].clone());
+        assert_eq!(result, Err(ShortenerError::SlugAlreadyInUse));
+        let missing = Slug("missing".to_string());
+        let result = service.handle_rename_slug(missing, Slug("renamed".to_string()));
+        assert_eq!(result, Err(ShortenerError::SlugNotFound));
+        assert_eq!(service.get_stats(slugs[0].clone()).map(|stats| stats.redirects), Ok(1));
+    }
+
+    #[test]
+    fn test_default_validator_accepts_absolute_web_urls() {
+        let validator = validation::DefaultUrlValidator::default();
+        for url in ["https://example.com/", "http://example.com:8080/path?query#fragment"] {
+            assert_eq!(validator.validate(&Url(url.to_string())), Ok(()), "{url}");
+        }
+
+        //validators are pluggable, e.g. to allow other schemes
+        let ftp = validation::DefaultUrlValidator {
+            allowed_schemes: vec!["ftp".to_string()],
+            ..validation::DefaultUrlValidator::default()
+        };
+        let mut service = UrlShortenerService::new().with_url_validator(ftp);
+        let url = Url("ftp://example.com/file".to_string());
+        assert!(service.handle_create_short_link(url, None).is_ok());
+    }
+
+    #[test]
+    fn test_default_validator_rejects_malformed_urls() {
+        let validator = validation::DefaultUrlValidator::default();
+        let too_long = format!("https://example.com/{}", "a".repeat(2048));
+        for url in ["httpgarbage", "https://exa mple.com/", "ftp://example.com/", "/relative", ""]
+            .into_iter()
+            .chain([too_long.as_str()])
+        {
+            let result = validator.validate(&Url(url.to_string()));
+            assert_eq!(result, Err(ShortenerError::InvalidUrl), "{url}");
+        }
+
+        let mut service = UrlShortenerService::new();
+        let slugs = record_traffic(&mut service);
+        let garbage = Url("httpgarbage".to_string());
+        let result = service.handle_change_short_link(slugs[0].clone(), garbage);
+        assert_eq!(result, Err(ShortenerError::InvalidUrl));
+    }
+
+    #[test]
+    fn test_equivalent_urls_are_stored_normalized() {
+        let mut service = UrlShortenerService::new();
+        let raw = Url("HTTPS://Example.COM:443/%7Euser/a%2fb?q=%e2%82%ac".to_string());
+        let link = service.handle_create_short_link(raw.clone(), None).unwrap();
+        assert_eq!(link.url, Url("https://example.com/~user/a%2Fb?q=%E2%82%AC".to_string()));
+        let created = &service.read_envelopes()[0].event;
+        assert!(matches!(created, Event::LinkCreated { raw_url: Some(url), .. } if *url == raw));
+
+        let normalizer = normalization::DefaultUrlNormalizer {
+            strip_tracking_params: true,
+            ..normalization::DefaultUrlNormalizer::default()
+        };
+        let mut service = UrlShortenerService::new().with_url_normalizer(normalizer);
+        let url = Url("https://example.com/?id=1&utm_source=mail&gclid=x".to_string());
+        let link = service.handle_create_short_link(url, None).unwrap();
+        assert_eq!(link.url, Url("https://example.com/?id=1".to_string()));
+    }
+
+    #[test]
+    fn test_trailing_slash_is_stripped_only_from_the_path() {
+        use normalization::{DefaultUrlNormalizer, UrlNormalizer};
+
+        let normalizer = DefaultUrlNormalizer {
+            strip_trailing_slash: true,
+            ..DefaultUrlNormalizer::default()
+        };
+        let normalize = |url: &str| normalizer.normalize(&Url(url.to_string())).0;
+        assert_eq!(normalize("https://a.com/p/"), "https://a.com/p");
+        assert_eq!(normalize("https://a.com/"), "https://a.com");
+        assert_eq!(normalize("https://a.com/p?next=/"), "https://a.com/p?next=/");
+        assert_eq!(normalize("https://a.com/p/?next=/"), "https://a.com/p?next=/");
+        assert_eq!(normalize("https://a.com/p#/"), "https://a.com/p#/");
+        assert_eq!(normalize("https://a.com/#/"), "https://a.com/#/");
+    }
+
+    #[test]
+    fn test_reuse_existing_returns_the_link_of_the_same_url() {
+        let config = ServiceConfig {
+            create_policy: CreatePolicy::ReuseExisting,
+            ..ServiceConfig::default()
+        };
+        let mut service = UrlShortenerService::new().with_config(config);
+        let first = service.handle_create_short_link(Url("https://example.com/".to_string()), None);
+        //the same url once normalized
+        let again = service.handle_create_short_link(Url("HTTPS://EXAMPLE.com".to_string()), None);
+        assert_eq!(first, again);
+        assert_eq!(service.read_envelopes().len(), 1);
+
+        let mut always = UrlShortenerService::new();
+        let url = Url("https://example.com/".to_string());
+        let first = always.handle_create_short_link(url.clone(), None).unwrap();
+        let again = always.handle_create_short_link(url, None).unwrap();
+        assert_ne!(first.slug, again.slug);
+    }
+
+    #[test]
+    fn test_reuse_existing_skips_links_which_do_not_redirect() {
+        use commands::LinkManagementHandler;
+
+        let config = ServiceConfig {
+            create_policy: CreatePolicy::ReuseExisting,
+            ..ServiceConfig::default()
+        };
+        let mut service = UrlShortenerService::new().with_config(config);
+        let url = Url("https://example.com/".to_string());
+        let disabled = service.handle_create_short_link(url.clone(), None).unwrap();
+        service.handle_disable_link(disabled.slug.clone()).unwrap();
+        let created = service.handle_create_short_link(url.clone(), None).unwrap();
+        assert_ne!(created.slug, disabled.slug);
+        service.handle_delete_short_link(created.slug.clone()).unwrap();
+        let recreated = service.handle_create_short_link(url, None).unwrap();
+        assert_ne!(recreated.slug, created.slug);
+        assert_ne!(recreated.slug, disabled.slug);
+    }
+
+    #[test]
+    fn test_base62_counter_generates_the_shortest_slugs() {
+        let mut service = UrlShortenerService::new()
+            .with_generator(generation::Base62Counter { next: 61 });
+        let url = Url("https://example.com/".to_string());
+        let first = service.handle_create_short_link(url.clone(), None).unwrap();
+        let second = service.handle_create_short_link(url, None).unwrap();
+        assert_eq!(first.slug, Slug("z".to_string()));
+        assert_eq!(second.slug, Slug("10".to_string()));
+    }
+
+    #[test]
+    fn test_generated_slug_which_is_taken_is_rejected() {
+        let mut service = UrlShortenerService::new()
+            .with_generator(generation::Base62Counter::default())
+            .with_slug_retry_policy(SlugRetryPolicy { max_attempts: 1 });
+        let url = Url("https://example.com/".to_string());
+        service.handle_create_short_link(url.clone(), Some(Slug("0".to_string()))).unwrap();
+        let result = service.handle_create_short_link(url, None);
+        assert_eq!(result, Err(ShortenerError::SlugAlreadyInUse));
+    }
+
+    #[test]
+    fn test_reserved_slugs_are_rejected() {
+        let config = ServiceConfig::default()
+            .reserve_slugs(ServiceConfig::DEFAULT_RESERVED_SLUGS.iter().copied());
+        let mut service = UrlShortenerService::new().with_config(config);
+        let url = Url("https://example.com/".to_string());
+        let result = service.handle_create_short_link(url.clone(), Some(Slug("api".to_string())));
+        assert_eq!(result, Err(ShortenerError::SlugReserved));
+        assert!(service.read_envelopes().is_empty());
+        let link = service.handle_create_short_link(url, Some(Slug("apis".to_string())));
+        assert_eq!(link.unwrap().slug, Slug("apis".to_string()));
+    }
+
+    #[test]
+    fn test_link_cannot_be_renamed_to_a_reserved_slug() {
+        use commands::LinkManagementHandler;
+
+        let config = ServiceConfig::default().reserve_slugs(["admin"]);
+        let mut service = UrlShortenerService::new().with_config(config);
+        let slug = Slug("docs".to_string());
+        let url = Url("https://example.com/".to_string());
+        service.handle_create_short_link(url, Some(slug.clone())).unwrap();
+        let result = service.handle_rename_slug(slug.clone(), Slug("admin".to_string()));
+        assert_eq!(result, Err(ShortenerError::SlugReserved));
+        assert!(service.get_stats(slug).is_ok());
+    }
+
+    #[test]
+    fn test_slug_policy_accepts_valid_slugs() {
+        use validation::{SlugCase, SlugPolicy};
+
+        let policy = SlugPolicy::default();
+        assert_eq!(policy.check(&Slug("summer-sale_2024".to_string())), Ok(()));
+        let lowercase = SlugPolicy { case: SlugCase::Lowercase, ..SlugPolicy::default() };
+        assert_eq!(lowercase.check(&Slug("docs".to_string())), Ok(()));
+        let mut service = UrlShortenerService::new();
+        let url = Url("https://example.com/".to_string());
+        let link = service.handle_create_short_link(url, Some(Slug("my-link".to_string())));
+        assert_eq!(link.unwrap().slug, Slug("my-link".to_string()));
+    }
+
+    #[test]
+    fn test_custom_slugs_breaking_the_policy_are_rejected() {
+        use validation::{SlugCase, SlugPolicy, SlugViolation};
+
+        let policy = SlugPolicy {
+            min_length: 3,
+            max_length: 5,
+            case: SlugCase::Lowercase,
+            ..SlugPolicy::default()
+        };
+        let check = |slug: &str| policy.check(&Slug(slug.to_string()));
+        assert_eq!(check("ab"), Err(SlugViolation::TooShort { min: 3 }));
+        assert_eq!(check("abcdef"), Err(SlugViolation::TooLong { max: 5 }));
+        assert_eq!(check("a b"), Err(SlugViolation::InvalidCharacter(' ')));
+        assert_eq!(check("-abc"), Err(SlugViolation::LeadingOrTrailingDash));
+        assert_eq!(check("aBc"), Err(SlugViolation::WrongCase(SlugCase::Lowercase)));
+
+        let mut service = UrlShortenerService::new();
+        let url = Url("https://example.com/".to_string());
+        let result = service.handle_create_short_link(url, Some(Slug("a/b".to_string())));
+        assert_eq!(
+            result,
+            Err(ShortenerError::InvalidSlug(SlugViolation::InvalidCharacter('/')))
+        );
+        assert!(service.read_envelopes().is_empty());
+    }
+
+    #[test]
+    fn test_case_insensitive_slugs_resolve_to_the_same_link() {
+        let config = ServiceConfig { case_insensitive_slugs: true, ..ServiceConfig::default() };
+        let mut service = UrlShortenerService::new().with_config(config);
+        let url = Url("https://example.com/".to_string());
+        let link = service.handle_create_short_link(url.clone(), Some(Slug("Promo".to_string())));
+        assert_eq!(link.unwrap().slug, Slug("Promo".to_string()));
+        assert_eq!(service.handle_redirect(Slug("promo".to_string())), Ok(ShortLink {
+            slug: Slug("Promo".to_string()),
+            url,
+        }));
+        assert_eq!(service.get_stats(Slug("PROMO".to_string())).map(|s| s.redirects), Ok(1));
+
+        let mut sensitive = UrlShortenerService::new();
+        let url = Url("https://example.com/".to_string());
+        sensitive.handle_create_short_link(url, Some(Slug("Promo".to_string()))).unwrap();
+        let result = sensitive.handle_redirect(Slug("promo".to_string()));
+        assert_eq!(result, Err(ShortenerError::SlugNotFound));
+    }
+
+    #[test]
+    fn test_case_insensitive_slugs_differing_in_case_are_taken() {
+        let config = ServiceConfig { case_insensitive_slugs: true, ..ServiceConfig::default() };
+        let mut service = UrlShortenerService::new().with_config(config);
+        let url = Url("https://example.com/".to_string());
+        service.handle_create_short_link(url.clone(), Some(Slug("Promo".to_string()))).unwrap();
+        let result = service.handle_create_short_link(url, Some(Slug("PROMO".to_string())));
+        assert_eq!(result, Err(ShortenerError::SlugAlreadyInUse));
+    }
+
+    #[test]
+    fn test_generated_slugs_which_are_taken_are_retried() {
+        let config = ServiceConfig::default().reserve_slugs(["1"]);
+        let mut service = UrlShortenerService::new()
+            .with_config(config)
+            .with_generator(generation::Base62Counter::default());
+        let url = Url("https://example.com/".to_string());
+        service.handle_create_short_link(url.clone(), Some(Slug("0".to_string()))).unwrap();
+        let link = service.handle_create_short_link(url.clone(), None).unwrap();
+        assert_eq!(link.slug, Slug("2".to_string()));
+
+        //every attempt collides
+        let mut exhausted = UrlShortenerService::new()
+            .with_generator(generation::Base62Counter::default())
+            .with_slug_retry_policy(SlugRetryPolicy { max_attempts: 2 });
+        for slug in ["0", "1"] {
+            exhausted.handle_create_short_link(url.clone(), Some(Slug(slug.to_string()))).unwrap();
+        }
+        let result = exhausted.handle_create_short_link(url, None);
+        assert_eq!(result, Err(ShortenerError::SlugAlreadyInUse));
+    }
+
+    #[test]
+    fn test_hash_of_url_gives_the_same_slug_to_the_same_url() {
+        let url = Url("https://example.com/".to_string());
+        let mut first = UrlShortenerService::new().with_generator(generation::HashOfUrl::default());
+        let mut second =
+            UrlShortenerService::new().with_generator(generation::HashOfUrl::default());
+        let link = first.handle_create_short_link(url.clone(), None).unwrap();
+        assert_eq!(link.slug.0.len(), 7);
+        assert_eq!(second.handle_create_short_link(url, None).as_ref(), Ok(&link));
+        let other = Url("https://example.org/".to_string());
+        let other = first.handle_create_short_link(other, None).unwrap();
+        assert_ne!(other.slug.0[..7], link.slug.0[..7]);
+    }
+
+    #[test]
+    fn test_hash_of_url_extends_colliding_slugs() {
+        let url = Url("https://example.com/".to_string());
+        let mut service =
+            UrlShortenerService::new().with_generator(generation::HashOfUrl::default());
+        let first = service.handle_create_short_link(url.clone(), None).unwrap();
+        let second = service.handle_create_short_link(url.clone(), None).unwrap();
+        assert_eq!(second.slug.0.len(), 8);
+        assert!(second.slug.0.starts_with(&first.slug.0));
+
+        let mut service = UrlShortenerService::new()
+            .with_generator(generation::HashOfUrl::default())
+            .with_slug_retry_policy(SlugRetryPolicy { max_attempts: 1 });
+        service.handle_create_short_link(url.clone(), None).unwrap();
+        let result = service.handle_create_short_link(url, None);
+        assert_eq!(result, Err(ShortenerError::SlugAlreadyInUse));
+    }
+
+    #[test]
+    fn test_get_link_does_not_count_a_redirect() {
+        use commands::LinkManagementHandler;
+        use queries::LinkQueryHandler;
+
+        let mut service = UrlShortenerService::new();
+        let url = Url("https://example.com/".to_string());
+        let link = service.handle_create_short_link(url, None).unwrap();
+        service.handle_disable_link(link.slug.clone()).unwrap();
+        assert_eq!(service.get_link(link.slug.clone()).as_ref(), Ok(&link));
+        assert_eq!(service.get_stats(link.slug).map(|stats| stats.redirects), Ok(0));
+    }
+
+    #[test]
+    fn test_get_link_of_unknown_or_deleted_slug_fails() {
+        use commands::LinkManagementHandler;
+        use queries::LinkQueryHandler;
+
+        let mut service = UrlShortenerService::new();
+        let unknown = service.get_link(Slug("missing".to_string()));
+        assert_eq!(unknown, Err(ShortenerError::SlugNotFound));
+        let url = Url("https://example.com/".to_string());
+        let link = service.handle_create_short_link(url, None).unwrap();
+        service.handle_delete_short_link(link.slug.clone()).unwrap();
+        assert_eq!(service.get_link(link.slug), Err(ShortenerError::SlugNotFound));
+    }
+
+    #[test]
+    fn test_find_by_url_returns_links_in_creation_order() {
+        use queries::LinkQueryHandler;
+
+        let mut service = UrlShortenerService::new();
+        let url = Url("https://example.com/".to_string());
+        let first = service.handle_create_short_link(url.clone(), None).unwrap();
+        let second = service.handle_create_short_link(url, None).unwrap();
+        let other = Url("https://example.org/".to_string());
+        service.handle_create_short_link(other, None).unwrap();
+        let found = service.find_by_url(Url("HTTPS://example.com".to_string()));
+        assert_eq!(found, vec![first, second]);
+    }
+
+    #[test]
+    fn test_find_by_url_skips_deleted_links() {
+        use commands::LinkManagementHandler;
+        use queries::LinkQueryHandler;
+
+        let mut service = UrlShortenerService::new();
+        let url = Url("https://example.com/".to_string());
+        assert!(service.find_by_url(url.clone()).is_empty());
+        let link = service.handle_create_short_link(url.clone(), None).unwrap();
+        service.handle_delete_short_link(link.slug).unwrap();
+        assert!(service.find_by_url(url).is_empty());
+    }
+
+    #[test]
+    fn test_top_links_are_ordered_by_redirects() {
+        use queries::LinkQueryHandler;
+
+        let mut service = UrlShortenerService::new();
+        let slugs = record_traffic(&mut service);
+        let top = |service: &UrlShortenerService, n| -> Vec<(Slug, u64)> {
+            let stats = service.top_links(n);
+            stats.into_iter().map(|stats| (stats.link.slug, stats.redirects)).collect()
+        };
+        assert_eq!(top(&service, 2), vec![(slugs[2].clone(), 3), (slugs[1].clone(), 2)]);
+        for _ in 0..3 {
+            service.handle_redirect(slugs[0].clone()).unwrap();
+        }
+        //ties are ordered by slug
+        assert_eq!(top(&service, 2), vec![(slugs[0].clone(), 4), (slugs[2].clone(), 3)]);
+        assert_eq!(top(&service, 10).len(), 3);
+    }
+
+    #[test]
+    fn test_top_links_skip_deleted_links() {
+        use commands::LinkManagementHandler;
+        use queries::LinkQueryHandler;
+
+        let mut service = UrlShortenerService::new();
+        assert!(service.top_links(3).is_empty());
+        let slugs = record_traffic(&mut service);
+        service.handle_delete_short_link(slugs[2].clone()).unwrap();
+        let top: Vec<Slug> = service.top_links(3).into_iter().map(|s| s.link.slug).collect();
+        assert_eq!(top, vec![slugs[1].clone(), slugs[0].clone()]);
+        let restored = UrlShortenerService::from_snapshot(service.snapshot(), Vec::new());
+        assert_eq!(restored.top_links(3).len(), 2);
+    }
+
+    #[test]
+    fn test_global_stats_count_the_whole_log() {
+        use queries::LinkQueryHandler;
+
+        let mut service = UrlShortenerService::new();
+        assert_eq!(service.global_stats(), GlobalStats::default());
+        record_traffic(&mut service);
+        let expected = GlobalStats { links_created: 3, redirects: 6, url_changes: 1, events: 10 };
+        assert_eq!(service.global_stats(), expected);
+        let restored = UrlShortenerService::from_snapshot(service.snapshot(), Vec::new());
+        assert_eq!(restored.global_stats(), expected);
+    }
+
+    #[test]
+    fn test_global_stats_ignore_rejected_commands() {
+        use queries::LinkQueryHandler;
+
+        let mut service = UrlShortenerService::new();
+        let slugs = record_traffic(&mut service);
+        let before = service.global_stats();
+        let url = Url("https://example.com/".to_string());
+        let taken = service.handle_create_short_link(url, Some(slugs[0].clone()));
+        assert_eq!(taken, Err(ShortenerError::SlugAlreadyInUse));
+        let missing = service.handle_redirect(Slug("missing".to_string()));
+        assert_eq!(missing, Err(ShortenerError::SlugNotFound));
+        assert_eq!(service.global_stats(), before);
+    }
+
+    #[test]
+    fn test_stats_over_time_group_redirects_into_buckets() {
+        use queries::StatsQueryHandler;
+
+        //monday, 2024-01-01 00:00 UTC
+        let monday = SystemTime::UNIX_EPOCH + Duration::from_secs(1_704_067_200);
+        let slug = Slug("a".to_string());
+        let url = Url("https://example.com/".to_string());
+        let mut store = InMemoryEventStore::default();
+        let created = Event::LinkCreated {
+            slug: slug.clone(),
+            url: url.clone(),
+            raw_url: Some(url),
+            max_clicks: None,
+            owner: None,
+            active_from: None,
+            active_until: None,
+            fallback_url: None,
+        };
+        let envelope = EventEnvelope { occurred_at: monday, ..EventEnvelope::new(0, 1, created) };
+        store.append(envelope).unwrap();
+        for (i, minutes) in [10, 30, 25 * 60, 8 * 24 * 60 + 60].into_iter().enumerate() {
+            let accessed = Event::LinkAccessed { slug: slug.clone() };
+            let envelope = EventEnvelope {
+                occurred_at: monday + Duration::from_secs(minutes * 60),
+                ..EventEnvelope::new(i as u64 + 1, i as u64 + 2, accessed)
+            };
+            store.append(envelope).unwrap();
+        }
+        let service = UrlShortenerService::with_store(store);
+        let buckets = |interval| -> Vec<(u64, u64)> {
+            let buckets = service.get_stats_over_time(slug.clone(), interval).unwrap();
+            buckets
+                .into_iter()
+                .map(|bucket| {
+                    let hours = bucket.start.duration_since(monday).unwrap().as_secs() / 3600;
+                    (hours, bucket.redirects)
+                })
+                .collect()
+        };
+        assert_eq!(buckets(Interval::Hour), vec![(0, 2), (25, 1), (193, 1)]);
+        assert_eq!(buckets(Interval::Day), vec![(0, 2), (24, 1), (192, 1)]);
+        assert_eq!(buckets(Interval::Week), vec![(0, 3), (168, 1)]);
+    }
+
+    #[test]
+    fn test_stats_over_time_of_unknown_slug_fail() {
+        use queries::StatsQueryHandler;
+
+        let mut service = UrlShortenerService::new();
+        let link = service.handle_create_short_link(Url("https://example.com/".to_string()), None);
+        let buckets = service.get_stats_over_time(link.unwrap().slug, Interval::Day);
+        assert_eq!(buckets, Ok(Vec::new()));
+        let missing = service.get_stats_over_time(Slug("missing".to_string()), Interval::Hour);
+        assert_eq!(missing, Err(ShortenerError::SlugNotFound));
+    }
+
+    #[test]
+    fn test_click_context_is_broken_down_by_referrer_and_user_agent() {
+        use commands::RedirectHandler;
+        use queries::StatsQueryHandler;
+
+        let mut service = UrlShortenerService::new();
+        let slugs = record_traffic(&mut service);
+        let context = |referrer: &str, user_agent: Option<&str>| ClickContext {
+            referrer: Some(referrer.to_string()),
+            user_agent: user_agent.map(str::to_string),
+            ip: Some(IpAddr::from([127, 0, 0, 1])),
+            country: None,
+        };
+        for (referrer, user_agent) in [("x.com", Some("curl")), ("y.com", None), ("y.com", None)] {
+            let context = context(referrer, user_agent);
+            service.handle_redirect_with_context(slugs[0].clone(), context).unwrap();
+        }
+        let referrers = service.referrer_breakdown(slugs[0].clone());
+        assert_eq!(referrers, Ok(vec![("y.com".to_string(), 2), ("x.com".to_string(), 1)]));
+        let user_agents = service.user_agent_breakdown(slugs[0].clone());
+        assert_eq!(user_agents, Ok(vec![("curl".to_string(), 1)]));
+        //plain redirects are counted, but have no context
+        assert_eq!(service.get_stats(slugs[0].clone()).map(|stats| stats.redirects), Ok(4));
+        assert_eq!(service.referrer_breakdown(slugs[1].clone()), Ok(Vec::new()));
+        let restored = UrlShortenerService::from_snapshot(service.snapshot(), Vec::new());
+        assert_eq!(restored.referrer_breakdown(slugs[0].clone()), referrers);
+    }
+
+    #[test]
+    fn test_redirect_with_context_of_unknown_slug_records_nothing() {
+        use commands::RedirectHandler;
+        use queries::StatsQueryHandler;
+
+        let mut service = UrlShortenerService::new();
+        let missing = Slug("missing".to_string());
+        let result = service.handle_redirect_with_context(missing.clone(), ClickContext::default());
+        assert_eq!(result, Err(ShortenerError::SlugNotFound));
+        assert!(service.read_envelopes().is_empty());
+        assert_eq!(service.referrer_breakdown(missing), Err(ShortenerError::SlugNotFound));
+    }
+
+    #[cfg(feature = "http")]
+    #[test]
+    fn test_http_redirect_records_the_referrer() {
+        use axum::http::{header, Request};
+        use queries::StatsQueryHandler;
+
+        let mut service = UrlShortenerService::new();
+        let url = Url("https://example.com/".to_string());
+        service.handle_create_short_link(url, Some(Slug("example".to_string()))).unwrap();
+        let shared = SharedUrlShortenerService::new(service);
+        let router = http::router(shared.clone());
+        let request = Request::get("/example").header(header::REFERER, "https://x.com/");
+        send(&router, request.body(String::new()).unwrap());
+        let referrers = shared.read().referrer_breakdown(Slug("example".to_string()));
+        assert_eq!(referrers, Ok(vec![("https://x.com/".to_string(), 1)]));
+    }
+
+    #[test]
+    fn test_unique_visitors_are_counted_once() {
+        use commands::RedirectHandler;
+        use queries::StatsQueryHandler;
+
+        let mut service = UrlShortenerService::new();
+        let slugs = record_traffic(&mut service);
+        let visitor = |ip: [u8; 4]| ClickContext {
+            ip: Some(IpAddr::from(ip)),
+            user_agent: Some("curl".to_string()),
+            ..ClickContext::default()
+        };
+        for ip in [[10, 0, 0, 1], [10, 0, 0, 2], [10, 0, 0, 1]] {
+            service.handle_redirect_with_context(slugs[0].clone(), visitor(ip)).unwrap();
+        }
+        //anonymous clicks are not visitors
+        service.handle_redirect_with_context(slugs[0].clone(), ClickContext::default()).unwrap();
+        let detailed = service.get_detailed_stats(slugs[0].clone()).unwrap();
+        assert_eq!(detailed.stats.redirects, 5);
+        assert_eq!(detailed.unique_visitors, 2);
+        let restored = UrlShortenerService::from_snapshot(service.snapshot(), Vec::new());
+        assert_eq!(restored.get_detailed_stats(slugs[0].clone()), Ok(detailed));
+        let missing = service.get_detailed_stats(Slug("missing".to_string()));
+        assert_eq!(missing, Err(ShortenerError::SlugNotFound));
+    }
+
+    #[test]
+    fn test_many_unique_visitors_are_estimated_closely() {
+        use commands::RedirectHandler;
+        use queries::StatsQueryHandler;
+
+        let mut service = UrlShortenerService::new();
+        let slugs = record_traffic(&mut service);
+        for i in 0..5000u32 {
+            let context = ClickContext {
+                ip: Some(IpAddr::from(i.to_be_bytes())),
+                ..ClickContext::default()
+            };
+            service.handle_redirect_with_context(slugs[0].clone(), context).unwrap();
+        }
+        let visitors = service.get_detailed_stats(slugs[0].clone()).unwrap().unique_visitors;
+        assert!((4500..=5500).contains(&visitors), "{visitors}");
+    }
+
+    #[test]
+    fn test_stats_are_exported_as_csv_and_json() {
+        //2024-01-01 00:00 UTC
+        let created_at = SystemTime::UNIX_EPOCH + Duration::from_secs(1_704_067_200);
+        let mut store = InMemoryEventStore::default();
+        for (sequence, slug, url) in [(0, "b", "https://example.com/?q=a,b"), (1, "a", "x\"y")] {
+            let created = Event::LinkCreated {
+                slug: Slug(slug.to_string()),
+                url: Url(url.to_string()),
+                raw_url: None,
+                max_clicks: None,
+                owner: None,
+                active_from: None,
+                active_until: None,
+                fallback_url: None,
+            };
+            let envelope = EventEnvelope {
+                occurred_at: created_at,
+                ..EventEnvelope::new(sequence, 1, created)
+            };
+            store.append(envelope).unwrap();
+        }
+        let accessed = Event::LinkAccessed { slug: Slug("b".to_string()) };
+        let envelope = EventEnvelope {
+            occurred_at: created_at + Duration::from_secs(90_061),
+            ..EventEnvelope::new(2, 2, accessed)
+        };
+        store.append(envelope).unwrap();
+        let service = UrlShortenerService::with_store(store);
+
+        let mut csv = Vec::new();
+        service.export_stats(ExportFormat::Csv, &mut csv).unwrap();
+        assert_eq!(String::from_utf8(csv).unwrap(), concat!(
+            "slug,url,redirects,created_at,last_accessed\n",
+            "a,\"x\"\"y\",0,2024-01-01T00:00:00Z,\n",
+            "b,\"https://example.com/?q=a,b\",1,2024-01-01T00:00:00Z,2024-01-02T01:01:01Z\n",
+        ));
+        let mut json = Vec::new();
+        service.export_stats(ExportFormat::Json, &mut json).unwrap();
+        assert_eq!(String::from_utf8(json).unwrap(), concat!(
+            r#"[{"slug":"a","url":"x\"y","redirects":0,"#,
+            r#""created_at":"2024-01-01T00:00:00Z","last_accessed":null},"#,
+            r#"{"slug":"b","url":"https://example.com/?q=a,b","redirects":1,"#,
+            r#""created_at":"2024-01-01T00:00:00Z","last_accessed":"2024-01-02T01:01:01Z"}]"#,
+            "\n",
+        ));
+    }
+
+    #[test]
+    fn test_export_stats_reports_write_errors() {
+        struct FailingWriter;
+
+        impl Write for FailingWriter {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(io::Error::other("disk full"))
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut service = UrlShortenerService::new();
+        record_traffic(&mut service);
+        for format in [ExportFormat::Csv, ExportFormat::Json] {
+            let error = service.export_stats(format, FailingWriter).unwrap_err();
+            assert_eq!(error.to_string(), "disk full");
+        }
+    }
+
+    #[test]
+    fn test_listeners_are_notified_in_subscription_order() {
+        let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut service = UrlShortenerService::new();
+        for name in ["first", "second"] {
+            let seen = Arc::clone(&seen);
+            service.subscribe(Box::new(move |envelope: &EventEnvelope| {
+                seen.lock().unwrap().push((name, envelope.sequence));
+            }));
+        }
+        let link = service.handle_create_short_link(Url("https://example.com/".to_string()), None);
+        service.handle_redirect(link.unwrap().slug).unwrap();
+        let seen = seen.lock().unwrap().clone();
+        assert_eq!(seen, vec![("first", 0), ("second", 0), ("first", 1), ("second", 1)]);
+    }
+
+    #[test]
+    fn test_listeners_are_not_notified_about_rejected_commands() {
+        let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut service = UrlShortenerService::new();
+        let slugs = record_traffic(&mut service);
+        let recorded = Arc::clone(&seen);
+        service.subscribe(Box::new(move |envelope: &EventEnvelope| {
+            recorded.lock().unwrap().push(envelope.event.clone());
+        }));
+        let url = Url("https://example.com/".to_string());
+        assert!(service.handle_create_short_link(url, Some(slugs[0].clone())).is_err());
+        assert!(service.handle_redirect(Slug("missing".to_string())).is_err());
+        assert!(seen.lock().unwrap().is_empty());
+    }
+
+    #[cfg(feature = "webhooks")]
+    #[test]
+    fn test_webhooks_are_posted_for_created_links_and_milestones() {
+        use std::io::{BufRead, BufReader, Read};
+        use webhooks::{WebhookConfig, WebhookDispatcher};
+
+        //answers every request with 200, passing its body on
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}/", listener.local_addr().unwrap());
+        let (sender, bodies) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            length = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").unwrap();
+                sender.send(String::from_utf8(body).unwrap()).unwrap();
+            }
+        });
+        let dispatcher = WebhookDispatcher::new(WebhookConfig {
+            endpoints: vec![endpoint],
+            click_milestones: vec![2],
+            ..WebhookConfig::default()
+        });
+        let dead_letters = dispatcher.dead_letters();
+        let mut service = UrlShortenerService::new();
+        service.subscribe(Box::new(dispatcher));
+        let url = Url("https://example.com/".to_string());
+        service.handle_create_short_link(url, Some(Slug("a".to_string()))).unwrap();
+        for _ in 0..3 {
+            service.handle_redirect(Slug("a".to_string())).unwrap();
+        }
+        let timeout = Duration::from_secs(5);
+        let created: serde_json::Value =
+            serde_json::from_str(&bodies.recv_timeout(timeout).unwrap()).unwrap();
+        assert_eq!(created["type"], "link_created");
+        assert_eq!(created["slug"], "a");
+        assert_eq!(created["url"], "https://example.com/");
+        let milestone: serde_json::Value =
+            serde_json::from_str(&bodies.recv_timeout(timeout).unwrap()).unwrap();
+        assert_eq!(milestone["type"], "clicks_milestone");
+        assert_eq!(milestone["clicks"], 2);
+        assert!(bodies.recv_timeout(Duration::from_millis(100)).is_err());
+        assert!(dead_letters.is_empty());
+    }
+
+    #[cfg(feature = "webhooks")]
+    #[test]
+    fn test_webhook_retries_are_capped_by_max_backoff() {
+        use webhooks::{WebhookConfig, WebhookDispatcher};
+
+        //nothing listens on the port, unbounded doubling would take over 20s
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}/", listener.local_addr().unwrap());
+        drop(listener);
+        let dispatcher = WebhookDispatcher::new(WebhookConfig {
+            endpoints: vec![endpoint],
+            max_attempts: 12,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(20),
+            ..WebhookConfig::default()
+        });
+        let dead_letters = dispatcher.dead_letters();
+        let mut service = UrlShortenerService::new();
+        service.subscribe(Box::new(dispatcher));
+        service.handle_create_short_link(Url("https://example.com/".to_string()), None).unwrap();
+
+        let started = std::time::Instant::now();
+        while dead_letters.is_empty() && started.elapsed() < Duration::from_secs(5) {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let failed = dead_letters.drain();
+        assert_eq!(failed.iter().map(|failed| failed.attempts).collect::<Vec<_>>(), [12]);
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn test_metrics_count_links_redirects_and_events() {
+        let mut service = UrlShortenerService::new();
+        record_traffic(&mut service);
+        let metrics = metrics::ServiceMetrics::new().unwrap();
+        let mut service = service.with_metrics(metrics.clone());
+        let link = service.handle_create_short_link(Url("https://example.com/".to_string()), None);
+        service.handle_redirect(link.unwrap().slug).unwrap();
+        assert!(service.get_stats(Slug("missing".to_string())).is_err());
+        let gathered = metrics.gather();
+        assert!(gathered.contains("url_shortener_links_created_total 4\n"), "{gathered}");
+        assert!(gathered.contains("url_shortener_redirects_total 7\n"), "{gathered}");
+        assert!(gathered.contains("url_shortener_events 12\n"), "{gathered}");
+        //one lookup of the redirect, one of the stats
+        assert!(gathered.contains("url_shortener_lookup_duration_seconds_count 2\n"), "{gathered}");
+    }
+
+    #[cfg(all(feature = "metrics", feature = "http"))]
+    #[test]
+    fn test_http_serves_metrics_only_if_enabled() {
+        use axum::http::{Request, StatusCode};
+
+        let request = || Request::get("/metrics").body(String::new()).unwrap();
+        let shared = SharedUrlShortenerService::new(UrlShortenerService::new());
+        let response = send(&http::router(shared), request());
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let metrics = metrics::ServiceMetrics::new().unwrap();
+        let service = UrlShortenerService::new().with_metrics(metrics);
+        let response = send(&http::router(SharedUrlShortenerService::new(service)), request());
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn test_metrics_cannot_be_registered_twice() {
+        let registry = prometheus::Registry::new();
+        metrics::ServiceMetrics::with_registry(registry.clone()).unwrap();
+        assert!(metrics::ServiceMetrics::with_registry(registry).is_err());
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_commands_are_traced_with_their_slug_and_outcome() {
+        let recorder = SpanRecorder::default();
+        tracing::subscriber::with_default(recorder.clone(), || {
+            let mut service = UrlShortenerService::new();
+            let url = Url("https://example.com/".to_string());
+            service.handle_create_short_link(url, Some(Slug("a".to_string()))).unwrap();
+        });
+        let fields = recorder.find("create_short_link");
+        assert_eq!(fields.get("slug"), Some("a"));
+        assert_eq!(fields.get("outcome"), Some("ok"));
+        assert!(fields.get("duration_us").is_some());
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_failed_queries_are_traced_with_the_error() {
+        let recorder = SpanRecorder::default();
+        tracing::subscriber::with_default(recorder.clone(), || {
+            let service = UrlShortenerService::new();
+            let result = service.get_stats(Slug("missing".to_string()));
+            assert_eq!(result, Err(ShortenerError::SlugNotFound));
+        });
+        let fields = recorder.find("get_stats");
+        assert_eq!(fields.get("slug"), Some("missing"));
+        assert_eq!(fields.get("outcome"), Some("SlugNotFound"));
+    }
+
+    #[test]
+    fn test_events_are_timestamped_by_the_clock() {
+        let clock = clock::MockClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(1000));
+        let mut service = UrlShortenerService::new().with_clock(clock.clone());
+        let link = service.handle_create_short_link(Url("https://example.com/".to_string()), None);
+        clock.advance(Duration::from_secs(60));
+        service.handle_redirect(link.unwrap().slug).unwrap();
+        let times: Vec<u64> = service
+            .read_envelopes()
+            .iter()
+            .map(|envelope| {
+                envelope.occurred_at.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs()
+            })
+            .collect();
+        assert_eq!(times, vec![1000, 1060]);
+    }
+
+    #[test]
+    fn test_mock_clock_drives_time_bucketed_stats() {
+        use queries::StatsQueryHandler;
+
+        let clock = clock::MockClock::default();
+        let mut service = UrlShortenerService::new().with_clock(clock.clone());
+        let slugs = record_traffic(&mut service);
+        clock.set(SystemTime::UNIX_EPOCH + Duration::from_secs(86_400));
+        service.handle_redirect(slugs[0].clone()).unwrap();
+        let buckets = service.get_stats_over_time(slugs[0].clone(), Interval::Day).unwrap();
+        let redirects: Vec<u64> = buckets.iter().map(|bucket| bucket.redirects).collect();
+        assert_eq!(redirects, vec![1, 1]);
+        //a failed redirect is not timestamped at all
+        clock.advance(Duration::from_secs(86_400));
+        assert!(service.handle_redirect(Slug("missing".to_string())).is_err());
+        assert_eq!(service.get_stats_over_time(slugs[0].clone(), Interval::Day), Ok(buckets));
+    }
+
+    #[test]
+    fn test_seeded_rng_reproduces_slugs() {
+        use rand::rngs::StdRng;
+        use rand::SeedableRng;
+
+        let create = |seed| {
+            let mut service = UrlShortenerService::new().with_rng(StdRng::seed_from_u64(seed));
+            (0..3)
+                .map(|i| {
+                    let url = Url(format!("https://example.com/{i}"));
+                    service.handle_create_short_link(url, None).unwrap().slug
+                })
+                .collect::<Vec<Slug>>()
+        };
+        let slugs = create(7);
+        assert_eq!(create(7), slugs);
+        assert_ne!(create(8), slugs);
+        assert!(slugs.iter().all(|slug| slug.0.len() == 6));
+    }
+
+    #[test]
+    fn test_rng_repeating_slugs_runs_out_of_attempts() {
+        let rng = rand::rngs::mock::StepRng::new(0, 0);
+        let mut service = UrlShortenerService::new().with_rng(rng);
+        let url = Url("https://example.com/".to_string());
+        service.handle_create_short_link(url.clone(), None).unwrap();
+        let result = service.handle_create_short_link(url, None);
+        assert_eq!(result, Err(ShortenerError::SlugAlreadyInUse));
+    }
+
+    #[test]
+    fn test_stats_at_a_point_in_time_replay_the_log_up_to_it() {
+        use queries::HistoryQueryHandler;
+
+        let clock = clock::MockClock::default();
+        let mut service = UrlShortenerService::new().with_clock(clock.clone());
+        let slugs = record_traffic(&mut service);
+        let monday = clock.now();
+        clock.advance(Duration::from_secs(3600));
+        service.handle_redirect(slugs[0].clone()).unwrap();
+        let redirects = |at| service.stats_at(slugs[0].clone(), at).map(|stats| stats.redirects);
+        assert_eq!(redirects(PointInTime::Time(monday)), Ok(1));
+        assert_eq!(redirects(PointInTime::Time(clock.now())), Ok(2));
+        //the link was created by the first event and accessed by the second
+        assert_eq!(redirects(PointInTime::Sequence(0)), Ok(0));
+        assert_eq!(redirects(PointInTime::Sequence(1)), Ok(1));
+        let state = service.state_at(PointInTime::Sequence(1));
+        assert_eq!(state.links.len(), 1);
+        assert_eq!(state.last_event_index, Some(1));
+    }
+
+    #[test]
+    fn test_stats_at_a_point_before_the_link_existed_fail() {
+        use queries::HistoryQueryHandler;
+
+        let clock = clock::MockClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(60));
+        let mut service = UrlShortenerService::new().with_clock(clock);
+        let slugs = record_traffic(&mut service);
+        let before = PointInTime::Time(SystemTime::UNIX_EPOCH);
+        assert_eq!(service.stats_at(slugs[0].clone(), before), Err(ShortenerError::SlugNotFound));
+        assert!(service.state_at(before).links.is_empty());
+        let missing = service.stats_at(Slug("missing".to_string()), PointInTime::Sequence(9));
+        assert_eq!(missing, Err(ShortenerError::SlugNotFound));
+    }
+
+    #[test]
+    fn test_history_follows_renames_of_the_link() {
+        use commands::LinkManagementHandler;
+        use queries::HistoryQueryHandler;
+
+        let mut service = UrlShortenerService::new();
+        let slugs = record_traffic(&mut service);
+        let new = Slug("renamed".to_string());
+        service.handle_rename_slug(slugs[1].clone(), new.clone()).unwrap();
+        service.handle_redirect(new.clone()).unwrap();
+        let history: Vec<Event> =
+            service.get_history(new).into_iter().map(|envelope| envelope.event).collect();
+        assert_eq!(history.len(), 6);
+        assert!(matches!(&history[0], Event::LinkCreated { slug, .. } if *slug == slugs[1]));
+        assert!(matches!(&history[3], Event::UrlChanged { slug, .. } if *slug == slugs[1]));
+        assert!(matches!(&history[4], Event::SlugRenamed { .. }));
+        assert!(matches!(&history[5], Event::LinkAccessed { .. }));
+    }
+
+    #[test]
+    fn test_history_skips_deleted_links_of_the_same_slug() {
+        use commands::LinkManagementHandler;
+        use queries::HistoryQueryHandler;
+
+        let config = ServiceConfig { allow_slug_reuse: true, ..ServiceConfig::default() };
+        let mut service = UrlShortenerService::new().with_config(config);
+        assert!(service.get_history(Slug("missing".to_string())).is_empty());
+        let slugs = record_traffic(&mut service);
+        service.handle_delete_short_link(slugs[2].clone()).unwrap();
+        let url = Url("https://example.com/".to_string());
+        service.handle_create_short_link(url, Some(slugs[2].clone())).unwrap();
+        let history = service.get_history(slugs[2].clone());
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].sequence, 11);
+    }
+
+    #[test]
+    fn test_reverting_a_url_change_restores_the_previous_url() {
+        use commands::LinkManagementHandler;
+
+        let mut service = UrlShortenerService::new();
+        let slugs = record_traffic(&mut service);
+        let reverted = service.handle_revert_url_change(slugs[1].clone()).unwrap();
+        assert_eq!(reverted.url, Url("https://example.com/1".to_string()));
+        assert_eq!(service.handle_redirect(slugs[1].clone()), Ok(reverted));
+        //the revert is a change itself
+        let again = service.handle_revert_url_change(slugs[1].clone()).unwrap();
+        assert_eq!(again.url, Url("https://example.org/".to_string()));
+    }
+
+    #[test]
+    fn test_reverting_an_unchanged_url_fails() {
+        use commands::LinkManagementHandler;
+
+        let mut service = UrlShortenerService::new();
+        let slugs = record_traffic(&mut service);
+        let events = service.read_envelopes().len();
+        let result = service.handle_revert_url_change(slugs[0].clone());
+        assert_eq!(result, Err(ShortenerError::NothingToRevert));
+        let missing = service.handle_revert_url_change(Slug("missing".to_string()));
+        assert_eq!(missing, Err(ShortenerError::SlugNotFound));
+        assert_eq!(service.read_envelopes().len(), events);
+    }
+
+    #[test]
+    fn test_rate_limit_refills_tokens_over_time() {
+        use commands::LinkManagementHandler;
+
+        let clock = clock::MockClock::default();
+        let policy = RateLimitPolicy { capacity: 2, refill_interval: Duration::from_secs(10) };
+        let mut service =
+            UrlShortenerService::new().with_clock(clock.clone()).with_rate_limit_policy(policy);
+        let url = Url("https://example.com/".to_string());
+        let mut create = |key: &str| {
+            let options = LinkOptions {
+                rate_limit_key: Some(key.to_string()),
+                ..LinkOptions::default()
+            };
+            service.handle_create_short_link_with_options(url.clone(), None, options)
+        };
+        assert!(create("alice").is_ok());
+        assert!(create("alice").is_ok());
+        assert_eq!(create("alice"), Err(ShortenerError::RateLimited));
+        //other callers have their own tokens
+        assert!(create("bob").is_ok());
+        clock.advance(Duration::from_secs(10));
+        assert!(create("alice").is_ok());
+        assert_eq!(create("alice"), Err(ShortenerError::RateLimited));
+    }
+
+    #[test]
+    fn test_rate_limited_creation_records_nothing() {
+        let policy = RateLimitPolicy { capacity: 1, refill_interval: Duration::from_secs(60) };
+        let mut service = UrlShortenerService::new().with_rate_limit_policy(policy);
+        let url = Url("https://example.com/".to_string());
+        service.handle_create_short_link(url.clone(), None).unwrap();
+        //callers without a key share the limit
+        let result = service.handle_create_short_link(url, Some(Slug("a".to_string())));
+        assert_eq!(result, Err(ShortenerError::RateLimited));
+        assert_eq!(service.read_envelopes().len(), 1);
+        assert!(service.get_stats(Slug("a".to_string())).is_err());
+    }
+
+    #[test]
+    fn test_owners_manage_and_list_their_links() {
+        use commands::OwnedLinkHandler;
+        use queries::LinkQueryHandler;
+
+        let mut service = UrlShortenerService::new();
+        let alice = OwnerId("alice".to_string());
+        let url = Url("https://example.com/".to_string());
+        let a = Slug("a".to_string());
+        service.handle_create_owned_link(alice.clone(), url.clone(), Some(a.clone())).unwrap();
+        service.handle_create_owned_link(alice.clone(), url.clone(), None).unwrap();
+        service.handle_create_short_link(url, None).unwrap();
+        assert_eq!(service.list_links_by_owner(alice.clone()).len(), 2);
+        let new_url = Url("https://example.org/".to_string());
+        let link = service.handle_change_owned_link(alice.clone(), a.clone(), new_url.clone());
+        assert_eq!(link.unwrap().url, new_url);
+        service.handle_delete_owned_link(alice.clone(), a).unwrap();
+        assert_eq!(service.list_links_by_owner(alice).len(), 1);
+        assert!(service.list_links_by_owner(OwnerId("bob".to_string())).is_empty());
+    }
+
+    #[test]
+    fn test_links_of_other_owners_cannot_be_changed() {
+        use commands::OwnedLinkHandler;
+
+        let mut service = UrlShortenerService::new();
+        let url = Url("https://example.com/".to_string());
+        let a = Slug("a".to_string());
+        let b = Slug("b".to_string());
+        let alice = OwnerId("alice".to_string());
+        let bob = OwnerId("bob".to_string());
+        service.handle_create_owned_link(alice, url.clone(), Some(a.clone())).unwrap();
+        service.handle_create_short_link(url.clone(), Some(b.clone())).unwrap();
+        let events = service.read_envelopes().len();
+        for slug in [a.clone(), b] {
+            let result = service.handle_change_owned_link(bob.clone(), slug.clone(), url.clone());
+            assert_eq!(result, Err(ShortenerError::NotOwner));
+            let result = service.handle_delete_owned_link(bob.clone(), slug);
+            assert_eq!(result, Err(ShortenerError::NotOwner));
+        }
+        assert_eq!(service.read_envelopes().len(), events);
+        assert!(service.get_stats(a).is_ok());
+    }
+
+    #[test]
+    fn test_authenticated_commands_create_links_owned_by_the_key_owner() {
+        use queries::LinkQueryHandler;
+
+        let mut service = auth::AuthenticatedService::new(
+            UrlShortenerService::new(),
+            auth::ApiKeyStore::new(),
+        );
+        let alice = OwnerId("alice".to_string());
+        let key = service.keys_mut().issue_key(alice.clone());
+        assert_eq!(key.0.len(), auth::ApiKeyStore::KEY_LENGTH);
+        let url = Url("https://example.com/".to_string());
+        let a = Slug("a".to_string());
+        let mut session = service.authenticate(&key).unwrap();
+        assert_eq!(session.owner(), &alice);
+        session.handle_create_short_link(url.clone(), Some(a.clone())).unwrap();
+        let new_url = Url("https://example.org/".to_string());
+        let link = session.handle_change_short_link(a.clone(), new_url.clone()).unwrap();
+        assert_eq!(link.url, new_url);
+        assert_eq!(service.service().list_links_by_owner(alice).len(), 1);
+        //the keys survive a replay of their events
+        let keys = auth::ApiKeyStore::from_events(service.keys().events().to_vec());
+        assert_eq!(keys.authenticate(&key), Ok(OwnerId("alice".to_string())));
+    }
+
+    #[test]
+    fn test_unknown_and_revoked_api_keys_are_rejected() {
+        let mut service = auth::AuthenticatedService::new(
+            UrlShortenerService::new(),
+            auth::ApiKeyStore::new(),
+        );
+        let unknown = auth::ApiKey("unknown".to_string());
+        assert!(matches!(service.authenticate(&unknown), Err(ShortenerError::Unauthorized)));
+        let alice = service.keys_mut().issue_key(OwnerId("alice".to_string()));
+        let bob = service.keys_mut().issue_key(OwnerId("bob".to_string()));
+        let url = Url("https://example.com/".to_string());
+        let a = Slug("a".to_string());
+        let mut session = service.authenticate(&alice).unwrap();
+        session.handle_create_short_link(url.clone(), Some(a.clone())).unwrap();
+        let mut session = service.authenticate(&bob).unwrap();
+        let result = session.handle_change_short_link(a.clone(), url);
+        assert_eq!(result, Err(ShortenerError::NotOwner));
+        service.keys_mut().revoke_key(&alice).unwrap();
+        assert!(matches!(service.authenticate(&alice), Err(ShortenerError::Unauthorized)));
+        assert_eq!(service.keys_mut().revoke_key(&alice), Err(ShortenerError::Unauthorized));
+        assert_eq!(service.keys().events().len(), 3);
+    }
+
+    #[test]
+    fn test_roles_permit_commands() {
+        use auth::{ApiKeyStore, AuthenticatedService, Role};
+        use commands::{LinkManagementHandler, OwnedLinkHandler};
+        use ShortenerError::{Forbidden, NotOwner};
+
+        let url = || Url("https://example.com/".to_string());
+        let own = Slug("own".to_string());
+        let other = Slug("other".to_string());
+        //outcomes of the commands for viewers, editors and admins
+        let expected = [
+            ("create", [Err(Forbidden), Ok(()), Ok(())]),
+            ("redirect", [Ok(()), Ok(()), Ok(())]),
+            ("change own", [Err(Forbidden), Ok(()), Ok(())]),
+            ("change other", [Err(Forbidden), Err(NotOwner), Ok(())]),
+            ("delete own", [Err(Forbidden), Err(Forbidden), Ok(())]),
+            ("delete other", [Err(Forbidden), Err(Forbidden), Ok(())]),
+        ];
+        let roles = [Role::Viewer, Role::Editor, Role::Admin];
+        for (command, outcomes) in expected {
+            for (role, outcome) in roles.into_iter().zip(outcomes) {
+                let owner = OwnerId("owner".to_string());
+                let mut service = UrlShortenerService::new();
+                service.handle_create_owned_link(owner.clone(), url(), Some(own.clone())).unwrap();
+                let stranger = OwnerId("stranger".to_string());
+                service.handle_create_owned_link(stranger, url(), Some(other.clone())).unwrap();
+                let mut keys = ApiKeyStore::new();
+                let key = keys.issue_key(owner.clone());
+                keys.assign_role(owner, role);
+                let mut service = AuthenticatedService::new(service, keys);
+                let mut session = service.authenticate(&key).unwrap();
+
+                let result = match command {
+                    "create" => session.handle_create_short_link(url(), None),
+                    "redirect" => session.handle_redirect(other.clone()),
+                    "change own" => session.handle_change_short_link(own.clone(), url()),
+                    "change other" => session.handle_change_short_link(other.clone(), url()),
+                    "delete own" => session.handle_delete_short_link(own.clone()),
+                    _ => session.handle_delete_short_link(other.clone()),
+                };
+                assert_eq!(result.map(drop), outcome, "{command} as {role:?}");
+            }
+        }
+    }
+
+    #[cfg(feature = "qr")]
+    #[test]
+    fn test_qr_codes_are_rendered_as_svg_and_png() {
+        let mut service = UrlShortenerService::new().with_config(ServiceConfig {
+            base_url: Some(Url("https://sho.rt/".to_string())),
+            ..ServiceConfig::default()
+        });
+        let slug = Slug("a".to_string());
+        let url = Url("https://example.com/".to_string());
+        service.handle_create_short_link(url, Some(slug.clone())).unwrap();
+        let svg = service.generate_qr(slug.clone(), QrFormat::Svg).unwrap();
+        assert!(String::from_utf8(svg).unwrap().contains("<svg"));
+        let png = service.generate_qr(slug, QrFormat::Png).unwrap();
+        assert!(png.starts_with(b"\x89PNG"));
+    }
+
+    #[cfg(feature = "qr")]
+    #[test]
+    fn test_qr_codes_of_missing_or_too_long_links_are_rejected() {
+        let mut service = UrlShortenerService::new().with_config(ServiceConfig {
+            base_url: Some(Url(format!("https://{}.com", "a".repeat(4000)))),
+            ..ServiceConfig::default()
+        });
+        let slug = Slug("a".to_string());
+        let missing = service.generate_qr(slug.clone(), QrFormat::Svg);
+        assert_eq!(missing, Err(ShortenerError::SlugNotFound));
+        let url = Url("https://example.com/".to_string());
+        service.handle_create_short_link(url, Some(slug.clone())).unwrap();
+        assert_eq!(service.generate_qr(slug, QrFormat::Png), Err(ShortenerError::InvalidUrl));
+    }
+
+    #[cfg(feature = "metadata")]
+    #[test]
+    fn test_enriched_links_return_the_metadata_of_their_page() {
+        use queries::LinkQueryHandler;
+
+        let page = r#"<html><head><title>Example &amp; co</title>
+            <meta name="description" content="Plain description">
+            <meta property="og:title" content="Example">
+            <link rel="shortcut icon" href="/favicon.ico"></head></html>"#;
+        let url = serve("200 OK", page);
+        let mut service = UrlShortenerService::new();
+        let slug = Slug("a".to_string());
+        service.handle_create_short_link(Url(url.clone()), Some(slug.clone())).unwrap();
+        assert_eq!(service.get_link_details(slug.clone()).unwrap().metadata, None);
+        let metadata = block_on(service.enrich_link(slug.clone())).unwrap();
+        let favicon = url.replace("/page", "/favicon.ico");
+        let expected = LinkMetadata {
+            title: Some("Example".to_string()),
+            description: Some("Plain description".to_string()),
+            favicon: Some(Url(favicon)),
+        };
+        assert_eq!(metadata, expected);
+        let details = service.get_link_details(slug.clone()).unwrap();
+        assert_eq!(details.metadata, Some(expected));
+        //the metadata belongs to the previous destination
+        let new_url = Url("https://example.org/".to_string());
+        service.handle_change_short_link(slug.clone(), new_url).unwrap();
+        assert_eq!(service.get_link_details(slug).unwrap().metadata, None);
+    }
+
+    #[cfg(feature = "metadata")]
+    #[test]
+    fn test_unavailable_metadata_is_not_recorded() {
+        let url = serve("500 Internal Server Error", "");
+        let mut service = UrlShortenerService::new();
+        let slug = Slug("a".to_string());
+        let missing = block_on(service.enrich_link(slug.clone()));
+        assert_eq!(missing, Err(ShortenerError::SlugNotFound));
+        service.handle_create_short_link(Url(url), Some(slug.clone())).unwrap();
+        let result = block_on(service.enrich_link(slug));
+        assert_eq!(result, Err(ShortenerError::MetadataUnavailable));
+        assert_eq!(service.read_envelopes().len(), 1);
+    }
+
+    #[test]
+    fn test_redirects_are_split_between_weighted_destinations() {
+        use commands::LinkManagementHandler;
+        use queries::StatsQueryHandler;
+
+        let mut service = UrlShortenerService::new();
+        let slug = Slug("a".to_string());
+        let url = Url("https://example.com/".to_string());
+        let a = Url("https://example.org/a".to_string());
+        let b = Url("https://example.org/b".to_string());
+        service.handle_create_short_link(url.clone(), Some(slug.clone())).unwrap();
+        let destinations = vec![(a.clone(), 1), (b.clone(), 0)];
+        service.handle_set_destinations(slug.clone(), destinations).unwrap();
+        for _ in 0..3 {
+            assert_eq!(service.handle_redirect(slug.clone()).unwrap().url, a);
+        }
+        let expected = vec![
+            VariantStats { url: a, weight: 1, redirects: 3 },
+            VariantStats { url: b, weight: 0, redirects: 0 },
+        ];
+        assert_eq!(service.get_variant_stats(slug.clone()).unwrap(), expected);
+        //no destinations restore the url of the link
+        service.handle_set_destinations(slug.clone(), Vec::new()).unwrap();
+        assert_eq!(service.handle_redirect(slug.clone()).unwrap().url, url);
+        assert!(service.get_variant_stats(slug).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_invalid_destinations_are_rejected() {
+        use commands::LinkManagementHandler;
+
+        let mut service = UrlShortenerService::new();
+        let slug = Slug("a".to_string());
+        let url = Url("https://example.com/".to_string());
+        let unknown = service.handle_set_destinations(slug.clone(), vec![(url.clone(), 1)]);
+        assert_eq!(unknown, Err(ShortenerError::SlugNotFound));
+        service.handle_create_short_link(url.clone(), Some(slug.clone())).unwrap();
+        let zero = service.handle_set_destinations(slug.clone(), vec![(url, 0)]);
+        assert_eq!(zero, Err(ShortenerError::InvalidWeights));
+        let invalid = vec![(Url("not a url".to_string()), 1)];
+        let invalid = service.handle_set_destinations(slug, invalid);
+        assert_eq!(invalid, Err(ShortenerError::InvalidUrl));
+        assert_eq!(service.read_envelopes().len(), 1);
+    }
+
+    #[test]
+    fn test_visitors_are_redirected_by_their_country() {
+        use commands::{LinkManagementHandler, RedirectHandler};
+        use queries::StatsQueryHandler;
+
+        let mut service = UrlShortenerService::new();
+        let slug = Slug("a".to_string());
+        let url = Url("https://example.com/".to_string());
+        let german = Url("https://example.de/".to_string());
+        service.handle_create_short_link(url.clone(), Some(slug.clone())).unwrap();
+        let rules = vec![("de".to_string(), german.clone())];
+        service.handle_set_geo_rules(slug.clone(), rules).unwrap();
+        let mut redirect = |country: Option<&str>| {
+            let context = ClickContext {
+                country: country.map(str::to_string),
+                ..ClickContext::default()
+            };
+            service.handle_redirect_with_context(slug.clone(), context).unwrap().url
+        };
+        assert_eq!(redirect(Some("DE")), german);
+        assert_eq!(redirect(Some("de")), german);
+        assert_eq!(redirect(Some("FR")), url);
+        assert_eq!(redirect(None), url);
+        let expected = vec![("DE".to_string(), 2), ("FR".to_string(), 1)];
+        assert_eq!(service.country_breakdown(slug.clone()).unwrap(), expected);
+        //no rules redirect everyone to the url of the link
+        service.handle_set_geo_rules(slug.clone(), Vec::new()).unwrap();
+        let context = ClickContext { country: Some("DE".to_string()), ..ClickContext::default() };
+        assert_eq!(service.handle_redirect_with_context(slug, context).unwrap().url, url);
+    }
+
+    #[test]
+    fn test_invalid_geo_rules_are_rejected() {
+        use commands::LinkManagementHandler;
+        use queries::StatsQueryHandler;
+
+        let mut service = UrlShortenerService::new();
+        let slug = Slug("a".to_string());
+        let url = Url("https://example.com/".to_string());
+        let rules = |country: &str, url: &str| vec![(country.to_string(), Url(url.to_string()))];
+        let unknown = service.handle_set_geo_rules(slug.clone(), rules("DE", &url.0));
+        assert_eq!(unknown, Err(ShortenerError::SlugNotFound));
+        assert_eq!(service.country_breakdown(slug.clone()), Err(ShortenerError::SlugNotFound));
+        service.handle_create_short_link(url.clone(), Some(slug.clone())).unwrap();
+        for country in ["DEU", "D", "1A"] {
+            let result = service.handle_set_geo_rules(slug.clone(), rules(country, &url.0));
+            assert_eq!(result, Err(ShortenerError::InvalidCountry));
+        }
+        let invalid = service.handle_set_geo_rules(slug, rules("DE", "not a url"));
+        assert_eq!(invalid, Err(ShortenerError::InvalidUrl));
+        assert_eq!(service.read_envelopes().len(), 1);
+    }
+
+    #[test]
+    fn test_visitors_are_redirected_by_their_device() {
+        use commands::{LinkManagementHandler, RedirectHandler};
+
+        let mut service = UrlShortenerService::new();
+        let slug = Slug("a".to_string());
+        let url = Url("https://example.com/".to_string());
+        let app = Url("https://app.example.com/open".to_string());
+        let german = Url("https://example.de/".to_string());
+        service.handle_create_short_link(url.clone(), Some(slug.clone())).unwrap();
+        service.handle_set_device_rule(slug.clone(), Device::Mobile, Some(app.clone())).unwrap();
+        let rules = vec![("DE".to_string(), german.clone())];
+        service.handle_set_geo_rules(slug.clone(), rules).unwrap();
+        let iphone = "Mozilla/5.0 (iPhone; CPU iPhone OS 17_0 like Mac OS X) Mobile/15E148";
+        let mut redirect = |user_agent: Option<&str>, country: Option<&str>| {
+            let context = ClickContext {
+                user_agent: user_agent.map(str::to_string),
+                country: country.map(str::to_string),
+                ..ClickContext::default()
+            };
+            service.handle_redirect_with_context(slug.clone(), context).unwrap().url
+        };
+        assert_eq!(redirect(Some(iphone), None), app);
+        assert_eq!(redirect(Some("Mozilla/5.0 (X11; Linux x86_64)"), None), url);
+        assert_eq!(redirect(None, None), url);
+        //country rules take precedence
+        assert_eq!(redirect(Some(iphone), Some("DE")), german);
+        service.handle_set_device_rule(slug.clone(), Device::Mobile, None).unwrap();
+        let context = ClickContext {
+            user_agent: Some(iphone.to_string()),
+            ..ClickContext::default()
+        };
+        assert_eq!(service.handle_redirect_with_context(slug, context).unwrap().url, url);
+    }
+
+    #[test]
+    fn test_invalid_device_rules_are_rejected() {
+        use commands::LinkManagementHandler;
+
+        let mut service = UrlShortenerService::new();
+        let slug = Slug("a".to_string());
+        let url = Url("https://example.com/".to_string());
+        let unknown = service.handle_set_device_rule(slug.clone(), Device::Mobile, None);
+        assert_eq!(unknown, Err(ShortenerError::SlugNotFound));
+        service.handle_create_short_link(url, Some(slug.clone())).unwrap();
+        let invalid = Some(Url("not a url".to_string()));
+        let result = service.handle_set_device_rule(slug, Device::Desktop, invalid);
+        assert_eq!(result, Err(ShortenerError::InvalidUrl));
+        assert_eq!(service.read_envelopes().len(), 1);
+    }
+
+    #[test]
+    fn test_redirects_are_resolved_with_the_policy_of_the_link() {
+        use commands::{LinkManagementHandler, RedirectHandler};
+
+        let mut service = UrlShortenerService::new();
+        let slug = Slug("a".to_string());
+        let url = Url("https://example.com/page?ref=short#top".to_string());
+        service.handle_create_short_link(url.clone(), Some(slug.clone())).unwrap();
+        let mut resolve = |query, fragment| {
+            let context = ClickContext::default();
+            service.resolve_redirect(slug.clone(), context, query, fragment).unwrap()
+        };
+        let decision = resolve(Some("utm=1"), Some("end"));
+        assert_eq!(decision.status_code(), 302);
+        assert_eq!(decision.location, url);
+        let policy =
+            RedirectPolicy { permanent: true, preserve_query: true, preserve_fragment: true };
+        service.handle_set_redirect_policy(slug.clone(), policy).unwrap();
+        let context = ClickContext::default();
+        let decision = service.resolve_redirect(slug, context, Some("utm=1"), Some("end")).unwrap();
+        let location = Url("https://example.com/page?ref=short&utm=1#end".to_string());
+        assert_eq!(decision.status_code(), 301);
+        assert_eq!(decision.location, location);
+        assert_eq!(service.get_stats(Slug("a".to_string())).unwrap().redirects, 2);
+    }
+
+    #[test]
+    fn test_redirect_policies_of_missing_links_are_rejected() {
+        use commands::{LinkManagementHandler, RedirectHandler};
+
+        let mut service = UrlShortenerService::new();
+        let slug = Slug("a".to_string());
+        let policy = RedirectPolicy { permanent: true, ..RedirectPolicy::default() };
+        let result = service.handle_set_redirect_policy(slug.clone(), policy);
+        assert_eq!(result, Err(ShortenerError::SlugNotFound));
+        let result = service.resolve_redirect(slug, ClickContext::default(), None, None);
+        assert_eq!(result, Err(ShortenerError::SlugNotFound));
+        assert!(service.read_envelopes().is_empty());
+    }
+
+    #[cfg(feature = "http")]
+    #[test]
+    fn test_http_redirects_follow_the_policy_of_the_link() {
+        use axum::http::{header, Request, StatusCode};
+        use commands::LinkManagementHandler;
+
+        let mut service = UrlShortenerService::new();
+        let slug = Slug("a".to_string());
+        let url = Url("https://example.com/".to_string());
+        service.handle_create_short_link(url, Some(slug.clone())).unwrap();
+        let policy =
+            RedirectPolicy { permanent: true, preserve_query: true, ..RedirectPolicy::default() };
+        service.handle_set_redirect_policy(slug, policy).unwrap();
+        let router = http::router(SharedUrlShortenerService::new(service));
+        let request = Request::get("/a?utm=1").body(String::new()).unwrap();
+        let response = send(&router, request);
+        assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(response.headers()[header::LOCATION], "https://example.com/?utm=1");
+        let request = Request::get("/missing").body(String::new()).unwrap();
+        assert_eq!(send(&router, request).status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_short_urls_are_rendered_under_the_base_urls() {
+        use queries::LinkQueryHandler;
+
+        let slug = Slug("a".to_string());
+        let service = UrlShortenerService::new();
+        assert_eq!(service.short_url(slug.clone()), Url("http://localhost/a".to_string()));
+        let mut service = UrlShortenerService::new().with_config(ServiceConfig {
+            base_url: Some(Url("https://sho.rt/".to_string())),
+            alternate_base_urls: vec![Url("https://go.example.com/links".to_string())],
+            ..ServiceConfig::default()
+        });
+        let url = Url("https://example.com/".to_string());
+        service.handle_create_short_link(url, Some(slug.clone())).unwrap();
+        let short_url = Url("https://sho.rt/a".to_string());
+        let alternate = Url("https://go.example.com/links/a".to_string());
+        let expected = vec![short_url.clone(), alternate.clone()];
+        assert_eq!(service.short_urls(slug.clone()), expected);
+        let details = service.get_link_details(slug).unwrap();
+        assert_eq!(details.short_url, short_url);
+        assert_eq!(details.alternate_short_urls, vec![alternate]);
+    }
+
+    #[test]
+    fn test_details_of_missing_links_are_not_found() {
+        use queries::LinkQueryHandler;
+
+        let service = UrlShortenerService::new();
+        let result = service.get_link_details(Slug("missing".to_string()));
+        assert_eq!(result, Err(ShortenerError::SlugNotFound));
+    }
+
+    #[test]
+    fn test_builder_configures_the_service() {
+        use queries::LinkQueryHandler;
+        use rand::rngs::StdRng;
+        use rand::SeedableRng;
+
+        let mut store = InMemoryEventStore::new();
+        let mut original = UrlShortenerService::new();
+        record_traffic(&mut original);
+        for envelope in original.read_envelopes() {
+            store.append(envelope).unwrap();
+        }
+        let created_at = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let build = || {
+            UrlShortenerService::builder()
+                .store(store.clone())
+                .clock(clock::MockClock::new(created_at))
+                .rng(StdRng::seed_from_u64(7))
+                .base_url(Url("https://sho.rt".to_string()))
+                .build()
+        };
+        let mut service = build();
+        assert_eq!(service.global_stats().links_created, 3);
+        let url = Url("https://example.com/new".to_string());
+        let link = service.handle_create_short_link(url.clone(), None).unwrap();
+        assert_eq!(build().handle_create_short_link(url, None).unwrap().slug, link.slug);
+        assert_eq!(service.read_envelopes().last().unwrap().occurred_at, created_at);
+        let short_url = Url(format!("https://sho.rt/{}", link.slug.0));
+        assert_eq!(service.short_url(link.slug), short_url);
+    }
+
+    #[test]
+    fn test_builder_limits_apply_to_the_service() {
+        use validation::{SlugPolicy, SlugViolation};
+
+        let policy = SlugPolicy { min_length: 3, ..SlugPolicy::default() };
+        let rate_limit = RateLimitPolicy { capacity: 2, refill_interval: Duration::from_secs(60) };
+        let mut service = UrlShortenerService::builder()
+            .slug_policy(policy)
+            .rate_limit_policy(rate_limit)
+            .build();
+        let url = Url("https://example.com/".to_string());
+        let result = service.handle_create_short_link(url.clone(), Some(Slug("ab".to_string())));
+        assert_eq!(result, Err(ShortenerError::InvalidSlug(SlugViolation::TooShort { min: 3 })));
+        //rejected attempts count against the limit too
+        service.handle_create_short_link(url.clone(), None).unwrap();
+        let result = service.handle_create_short_link(url, None);
+        assert_eq!(result, Err(ShortenerError::RateLimited));
+        assert_eq!(service.read_envelopes().len(), 1);
+    }
+
+    #[test]
+    fn test_errors_are_displayed_with_their_context() {
+        use validation::SlugViolation;
+
+        let error = ShortenerError::InvalidSlug(SlugViolation::TooShort { min: 3 });
+        assert_eq!(error.to_string(), "invalid slug: shorter than 3 characters");
+        assert_eq!(error.code(), "invalid_slug");
+        let mut service = UrlShortenerService::new();
+        let slug = Slug("missing".to_string());
+        let error = service.handle_redirect(slug.clone()).with_slug(&slug).unwrap_err();
+        assert_eq!(error.kind(), &ShortenerError::SlugNotFound);
+        assert_eq!(error.slug(), Some(&slug));
+        assert_eq!(error.to_string(), "slug not found (slug `missing`)");
+        let url = Url("not a url".to_string());
+        let result = service.handle_create_short_link(url.clone(), Some(slug.clone()));
+        let error = result.with_url(&url).with_slug(&slug).unwrap_err();
+        assert_eq!(error.code(), "invalid_url");
+        assert_eq!(error.to_string(), "invalid URL (slug `missing`) (URL `not a url`)");
+    }
+
+    #[test]
+    fn test_errors_work_with_the_question_mark_operator() {
+        fn create(service: &mut UrlShortenerService) -> Result<Slug, Box<dyn std::error::Error>> {
+            let url = Url("https://example.com/".to_string());
+            let slug = Slug("a".to_string());
+            service.handle_create_short_link(url.clone(), Some(slug.clone()))?;
+            Ok(service.handle_create_short_link(url, Some(slug.clone())).with_slug(&slug)?.slug)
+        }
+
+        let mut service = UrlShortenerService::new();
+        let error = create(&mut service).unwrap_err();
+        assert_eq!(error.to_string(), "slug is already in use (slug `a`)");
+        let error = error.downcast::<Error>().unwrap();
+        assert_eq!(error.into_kind(), ShortenerError::SlugAlreadyInUse);
+        assert!(Error::from(ShortenerError::RateLimited).slug().is_none());
+    }
+
+    #[test]
+    fn test_query_cache_is_invalidated_by_events() {
+        use cache::QueryCache;
+
+        let mut cache = QueryCache::new(UrlShortenerService::new(), Duration::from_secs(60));
+        let invalidator = cache.invalidator();
+        cache.inner_mut().subscribe(Box::new(invalidator));
+        let slugs = record_traffic(cache.inner_mut());
+        let redirects = |cache: &QueryCache<UrlShortenerService>, slug: &Slug| {
+            cache.get_stats(slug.clone()).map(|stats| stats.redirects)
+        };
+        assert_eq!(redirects(&cache, &slugs[0]), Ok(1));
+        assert_eq!(redirects(&cache, &slugs[1]), Ok(2));
+        cache.handle_redirect(slugs[0].clone()).unwrap();
+        assert_eq!(redirects(&cache, &slugs[0]), Ok(2));
+        assert_eq!(redirects(&cache, &slugs[1]), Ok(2));
+    }
+
+    #[test]
+    fn test_query_cache_skips_results_invalidated_while_looked_up() {
+        use cache::{CacheInvalidator, QueryCache};
+        use std::sync::Mutex;
+
+        //handler whose lookups race with an event of the link
+        #[derive(Default)]
+        struct Racing {
+            invalidator: Mutex<Option<CacheInvalidator>>,
+            lookups: Mutex<u64>,
+        }
+        impl QueryHandler for Racing {
+            fn get_stats(&self, slug: Slug) -> Result<Stats, ShortenerError> {
+                let mut lookups = self.lookups.lock().unwrap();
+                *lookups += 1;
+                let event = Event::LinkAccessed { slug: slug.clone() };
+                if let Some(invalidator) = &mut *self.invalidator.lock().unwrap() {
+                    invalidator.on_event(&EventEnvelope::new(*lookups, *lookups, event));
+                }
+                let link = ShortLink { slug, url: Url("https://example.com/".to_string()) };
+                Ok(Stats { link, redirects: *lookups })
+            }
+        }
+
+        let cache = QueryCache::new(Racing::default(), Duration::from_secs(60));
+        *cache.inner().invalidator.lock().unwrap() = Some(cache.invalidator());
+        let slug = Slug("example".to_string());
+        assert_eq!(cache.get_stats(slug.clone()).map(|stats| stats.redirects), Ok(1));
+        assert_eq!(cache.get_stats(slug.clone()).map(|stats| stats.redirects), Ok(2));
+        cache.inner().invalidator.lock().unwrap().take();
+        assert_eq!(cache.get_stats(slug.clone()).map(|stats| stats.redirects), Ok(3));
+        assert_eq!(cache.get_stats(slug).map(|stats| stats.redirects), Ok(3));
+    }
+
+    #[test]
+    fn test_query_cache_does_not_cache_failed_lookups() {
+        use cache::QueryCache;
+
+        //without the invalidator results are only dropped by their age
+        let mut cache = QueryCache::new(UrlShortenerService::new(), Duration::from_secs(60));
+        let slug = Slug("a".to_string());
+        assert_eq!(cache.get_stats(slug.clone()), Err(ShortenerError::SlugNotFound));
+        let url = Url("https://example.com/".to_string());
+        cache.handle_create_short_link(url, Some(slug.clone())).unwrap();
+        assert_eq!(cache.get_stats(slug.clone()).map(|stats| stats.redirects), Ok(0));
+        cache.handle_redirect(slug.clone()).unwrap();
+        assert_eq!(cache.get_stats(slug.clone()).map(|stats| stats.redirects), Ok(0));
+        cache.clear();
+        assert_eq!(cache.get_stats(slug).map(|stats| stats.redirects), Ok(1));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_compaction_survives_restart() {
+        let path = temporary_path("compaction");
+        let store = store::FileEventStore::open(&path).unwrap();
+        let mut service = UrlShortenerService::with_store(store);
+        let slugs = record_traffic(&mut service);
+        let stats: Vec<_> = slugs.iter().map(|slug| service.get_stats(slug.clone())).collect();
+        let last = service.store().read_envelopes().last().unwrap().sequence;
+        let removed = service.compact(last).unwrap();
+        assert_eq!(removed, 3);
+        drop(service);
+
+        let store = store::FileEventStore::open(&path).unwrap();
+        let mut service = UrlShortenerService::with_store(store);
+        for (slug, stats) in slugs.iter().zip(stats) {
+            assert_eq!(service.get_stats(slug.clone()), stats);
+        }
+        assert_eq!(service.store().read_envelopes().last().unwrap().sequence, last);
+        service.handle_redirect(slugs[2].clone()).unwrap();
+        assert_eq!(service.store().read_envelopes().last().unwrap().sequence, last + 1);
+        assert_eq!(service.get_stats(slugs[2].clone()).map(|stats| stats.redirects), Ok(4));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_compaction_folds_runs_of_clicks_within_an_hour() {
+        use queries::LinkQueryHandler;
+
+        let clock = clock::MockClock::default();
+        let mut service = UrlShortenerService::new().with_clock(clock.clone());
+        let slugs = record_traffic(&mut service);
+        clock.advance(Duration::from_secs(3600));
+        service.handle_redirect(slugs[2].clone()).unwrap();
+        let stats: Vec<_> = slugs.iter().map(|slug| service.get_stats(slug.clone())).collect();
+        let global = service.global_stats();
+        //the clicks of b and c fold into one event each, the next hour stays apart
+        assert_eq!(service.compact(u64::MAX), Ok(3));
+        let envelopes = service.read_envelopes();
+        assert_eq!(envelopes.len(), 8);
+        let aggregated = Event::ClicksAggregated {
+            slug: slugs[2].clone(),
+            count: 3,
+            up_to_sequence: 8,
+            folded: 2,
+        };
+        assert!(envelopes.iter().any(|envelope| envelope.event == aggregated));
+        assert_eq!(service.compact(u64::MAX), Ok(0));
+        let replayed = UrlShortenerService::with_store(service.store().clone());
+        for (slug, stats) in slugs.iter().zip(stats) {
+            assert_eq!(replayed.get_stats(slug.clone()), stats);
+        }
+        assert_eq!(replayed.global_stats(), global);
+    }
+
+    #[test]
+    fn test_compaction_keeps_events_after_the_sequence() {
+        let mut service = UrlShortenerService::new().with_clock(clock::MockClock::default());
+        let slugs = record_traffic(&mut service);
+        let snapshot = service.snapshot();
+        service.handle_redirect(slugs[0].clone()).unwrap();
+        service.handle_redirect(slugs[0].clone()).unwrap();
+        let last = snapshot.last_event_index.unwrap() as u64;
+        assert_eq!(service.compact(last), Ok(3));
+        let remaining: Vec<EventEnvelope> = service
+            .read_envelopes()
+            .into_iter()
+            .filter(|envelope| envelope.sequence > last)
+            .collect();
+        assert_eq!(remaining.len(), 2);
+        let restored = UrlShortenerService::from_snapshot(snapshot, remaining);
+        assert_eq!(restored.get_stats(slugs[0].clone()).map(|stats| stats.redirects), Ok(3));
+    }
+
+    #[cfg(feature = "sled")]
+    #[test]
+    fn test_sled_store_survives_restart() {
+        let path = temporary_path("sled");
+        let store = store::SledEventStore::open(&path).unwrap();
+        let clock = clock::MockClock::default();
+        let mut service = UrlShortenerService::with_store(store).with_clock(clock);
+        let slugs = record_traffic(&mut service);
+        let stats: Vec<_> = slugs.iter().map(|slug| service.get_stats(slug.clone())).collect();
+        let last = service.store().read_envelopes().last().unwrap().sequence;
+        assert_eq!(service.compact(last), Ok(3));
+        drop(service);
+
+        let store = store::SledEventStore::open(&path).unwrap();
+        let stream = store::StreamId(slugs[2].clone());
+        assert_eq!(store.read_stream(&stream).len(), 2);
+        assert_eq!(store.stream_version(&stream), 4);
+        let mut service = UrlShortenerService::with_store(store);
+        for (slug, stats) in slugs.iter().zip(stats) {
+            assert_eq!(service.get_stats(slug.clone()), stats);
+        }
+        service.handle_redirect(slugs[2].clone()).unwrap();
+        assert_eq!(service.store().read_envelopes().last().unwrap().sequence, last + 1);
+        drop(service);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[cfg(feature = "sled")]
+    #[test]
+    fn test_sled_store_rejects_malformed_events() {
+        let path = temporary_path("sled-malformed");
+        let db = sled::open(&path).unwrap();
+        db.open_tree("events").unwrap().insert(0u64.to_be_bytes(), "{not json").unwrap();
+        let error = store::SledEventStore::from_db(db).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[cfg(feature = "postgres")]
+    #[test]
+    #[ignore = "needs an empty PostgreSQL database in DATABASE_URL"]
+    fn test_postgres_store_survives_restart() {
+        use store::PostgresEventStore;
+
+        let url = std::env::var("DATABASE_URL").unwrap();
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let store = runtime.block_on(PostgresEventStore::connect(&url)).unwrap();
+        let mut other = runtime.block_on(PostgresEventStore::connect(&url)).unwrap();
+        let mut service = UrlShortenerService::with_store(store);
+        let slugs = record_traffic(&mut service);
+        let stats: Vec<_> = slugs.iter().map(|slug| service.get_stats(slug.clone())).collect();
+        //events of other writers are picked up on demand
+        let appended = runtime.block_on(other.catch_up()).unwrap();
+        assert_eq!(appended, service.read_envelopes());
+        drop(service);
+
+        let store = runtime.block_on(PostgresEventStore::connect(&url)).unwrap();
+        let service = UrlShortenerService::with_store(store);
+        for (slug, stats) in slugs.iter().zip(stats) {
+            assert_eq!(service.get_stats(slug.clone()), stats);
+        }
+    }
+
+    #[cfg(feature = "postgres")]
+    #[test]
+    fn test_postgres_store_reports_unreachable_databases() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let _runtime = runtime.enter();
+        let pool = sqlx::postgres::PgPoolOptions::new()
+            .acquire_timeout(Duration::from_millis(100))
+            .connect_lazy("postgres://shortener@127.0.0.1:1/shortener")
+            .unwrap();
+        let result = runtime.block_on(store::PostgresEventStore::from_pool(pool));
+        assert!(matches!(result, Err(sqlx::Error::PoolTimedOut | sqlx::Error::Io(_))));
+    }
+
+    #[cfg(feature = "grpc")]
+    #[test]
+    fn test_grpc_service_creates_redirects_and_lists_links() {
+        use grpc::{
+            ChangeUrlRequest, CreateLinkRequest, GetStatsRequest, Link, ListLinksReply,
+            ListLinksRequest, RedirectReply, RedirectRequest, StatsReply,
+        };
+
+        let server = grpc::UrlShortenerServer::new(SharedUrlShortenerService::new(
+            UrlShortenerService::new(),
+        ));
+        let create = CreateLinkRequest {
+            url: "https://example.com/".to_string(),
+            slug: Some("a".to_string()),
+        };
+        let link: Link =
+            call_grpc(&server, "/url_shortener.UrlShortener/CreateLink", create).unwrap();
+        assert_eq!(link.slug, "a");
+        let change = ChangeUrlRequest {
+            slug: "a".to_string(),
+            url: "https://example.org/".to_string(),
+        };
+        let link: Link =
+            call_grpc(&server, "/url_shortener.UrlShortener/ChangeUrl", change).unwrap();
+        assert_eq!(link.url, "https://example.org/");
+        let redirect = RedirectRequest {
+            slug: "a".to_string(),
+            ..RedirectRequest::default()
+        };
+        let reply: RedirectReply =
+            call_grpc(&server, "/url_shortener.UrlShortener/Redirect", redirect).unwrap();
+        assert_eq!(reply.location, "https://example.org/");
+        let request = GetStatsRequest { slug: "a".to_string() };
+        let stats: StatsReply =
+            call_grpc(&server, "/url_shortener.UrlShortener/GetStats", request).unwrap();
+        assert_eq!(stats.redirects, 1);
+        let request = ListLinksRequest { owner: None, limit: 0 };
+        let reply: ListLinksReply =
+            call_grpc(&server, "/url_shortener.UrlShortener/ListLinks", request).unwrap();
+        assert_eq!(reply.links, vec![link]);
+    }
+
+    #[cfg(feature = "grpc")]
+    #[test]
+    fn test_grpc_errors_are_mapped_to_status_codes() {
+        use grpc::{CreateLinkRequest, GetStatsRequest, Link, StatsReply};
+
+        let server = grpc::UrlShortenerServer::new(SharedUrlShortenerService::new(
+            UrlShortenerService::new(),
+        ));
+        let request = GetStatsRequest { slug: "missing".to_string() };
+        let result: Result<StatsReply, _> =
+            call_grpc(&server, "/url_shortener.UrlShortener/GetStats", request);
+        let status = result.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+        assert_eq!(status.message(), "slug not found");
+        let create = CreateLinkRequest { url: "not a url".to_string(), slug: None };
+        let result: Result<Link, _> =
+            call_grpc(&server, "/url_shortener.UrlShortener/CreateLink", create);
+        assert_eq!(result.unwrap_err().code(), tonic::Code::InvalidArgument);
+        let request = GetStatsRequest::default();
+        let result: Result<StatsReply, _> =
+            call_grpc(&server, "/url_shortener.UrlShortener/Missing", request);
+        assert_eq!(result.unwrap_err().code(), tonic::Code::Unimplemented);
+    }
+
+    #[cfg(feature = "graphql")]
+    #[test]
+    fn test_graphql_mutations_and_queries() {
+        let schema = graphql::schema(SharedUrlShortenerService::new(UrlShortenerService::new()));
+        let execute = |query: &str| {
+            let response = block_on(schema.execute(query));
+            assert!(response.errors.is_empty(), "{:?}", response.errors);
+            response.data.into_json().unwrap()
+        };
+        let created =
+            execute(r#"mutation { createLink(url: "https://example.com/", slug: "a") { slug } }"#);
+        assert_eq!(created, serde_json::json!({ "createLink": { "slug": "a" } }));
+        execute(r#"mutation { changeUrl(slug: "a", url: "https://example.org/") { url } }"#);
+        let data = execute(
+            r#"{
+                stats(slug: "a") { link { url } redirects }
+                history(slug: "a") { sequence event }
+                search(url: "https://example.org/") { slug }
+            }"#,
+        );
+        assert_eq!(data["stats"]["link"]["url"], "https://example.org/");
+        assert_eq!(data["stats"]["redirects"], 0);
+        assert_eq!(data["history"].as_array().unwrap().len(), 2);
+        assert_eq!(data["search"], serde_json::json!([{ "slug": "a" }]));
+    }
+
+    #[cfg(feature = "graphql")]
+    #[test]
+    fn test_graphql_errors_carry_the_error_code() {
+        let schema = graphql::schema(SharedUrlShortenerService::new(UrlShortenerService::new()));
+        let response = block_on(schema.execute(r#"{ link(slug: "missing") { url } }"#));
+        let error = serde_json::to_value(&response.errors[0]).unwrap();
+        assert_eq!(error["message"], "slug not found");
+        assert_eq!(error["extensions"]["code"], "slug_not_found");
+        let mutation = r#"mutation { createLink(url: "not a url") { slug } }"#;
+        let response = block_on(schema.execute(mutation));
+        let error = serde_json::to_value(&response.errors[0]).unwrap();
+        assert_eq!(error["extensions"]["code"], "invalid_url");
+    }
+
+    #[cfg(feature = "http")]
+    #[test]
+    fn test_http_serves_the_openapi_document() {
+        use axum::body::to_bytes;
+        use axum::http::{Request, StatusCode};
+        use std::future::Future;
+
+        let router = http::router(SharedUrlShortenerService::new(UrlShortenerService::new()));
+        let request = Request::get("/openapi.json").body(String::new()).unwrap();
+        let response = send(&router, request);
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = std::pin::pin!(to_bytes(response.into_body(), usize::MAX));
+        let mut context = std::task::Context::from_waker(std::task::Waker::noop());
+        let std::task::Poll::Ready(body) = body.poll(&mut context) else {
+            panic!("the body was not ready");
+        };
+        let document: serde_json::Value = serde_json::from_slice(&body.unwrap()).unwrap();
+        assert_eq!(document["info"]["title"], "URL shortener");
+        let paths = document["paths"].as_object().unwrap();
+        let mut paths: Vec<&str> = paths.keys().map(String::as_str).collect();
+        paths.sort_unstable();
+        assert_eq!(paths, ["/links", "/links/{slug}", "/links/{slug}/stats", "/{slug}"]);
+        assert!(document["components"]["schemas"]["ShortenerError"].is_object());
+        let request = Request::post("/openapi.json").body(String::new()).unwrap();
+        assert_eq!(send(&router, request).status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[test]
+    fn test_milestones_are_recorded_when_links_reach_them() {
+        let mut service = UrlShortenerService::builder().click_milestones([2, 3]).build();
+        let url = Url("https://example.com/".to_string());
+        let slug = service.handle_create_short_link(url, None).unwrap().slug;
+        for _ in 0..4 {
+            service.handle_redirect(slug.clone()).unwrap();
+        }
+        let milestones: Vec<_> = service
+            .read_envelopes()
+            .into_iter()
+            .filter_map(|envelope| match envelope.event {
+                Event::MilestoneReached { slug, clicks } => Some((slug, clicks)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(milestones, vec![(slug.clone(), 2), (slug.clone(), 3)]);
+        assert_eq!(service.get_stats(slug).map(|stats| stats.redirects), Ok(4));
+    }
+
+    #[test]
+    fn test_milestones_are_not_recorded_for_rejected_redirects() {
+        use commands::LinkManagementHandler;
+
+        let mut service = UrlShortenerService::builder().click_milestones([2]).build();
+        let slug = Slug("invite".to_string());
+        let url = Url("https://example.com/".to_string());
+        let options = LinkOptions { max_clicks: Some(1), ..LinkOptions::default() };
+        service.handle_create_short_link_with_options(url, Some(slug.clone()), options).unwrap();
+        service.handle_redirect(slug.clone()).unwrap();
+        assert_eq!(service.handle_redirect(slug), Err(ShortenerError::LinkExhausted));
+        let missing = Slug("missing".to_string());
+        assert_eq!(service.handle_redirect(missing), Err(ShortenerError::SlugNotFound));
+        let reached = service
+            .read_envelopes()
+            .into_iter()
+            .any(|envelope| matches!(envelope.event, Event::MilestoneReached { .. }));
+        assert!(!reached);
+    }
+
+    #[test]
+    fn test_links_redirect_within_their_activation_window() {
+        use commands::LinkManagementHandler;
+
+        let at = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        let clock = clock::MockClock::new(at(1000));
+        let mut service = UrlShortenerService::new().with_clock(clock.clone());
+        let slug = Slug("launch".to_string());
+        let url = Url("https://example.com/".to_string());
+        let options = LinkOptions {
+            active_from: Some(at(2000)),
+            active_until: Some(at(3000)),
+            ..LinkOptions::default()
+        };
+        service.handle_create_short_link_with_options(url, Some(slug.clone()), options).unwrap();
+        assert_eq!(service.handle_redirect(slug.clone()), Err(ShortenerError::LinkNotActive));
+        clock.set(at(2000));
+        assert!(service.handle_redirect(slug.clone()).is_ok());
+        clock.set(at(3000));
+        assert_eq!(service.handle_redirect(slug.clone()), Err(ShortenerError::LinkNotActive));
+        assert_eq!(service.handle_redirect(slug.clone()), Err(ShortenerError::LinkNotActive));
+        let boundaries: Vec<_> = service
+            .read_envelopes()
+            .into_iter()
+            .map(|envelope| envelope.event)
+            .filter(|event| {
+                matches!(event, Event::LinkActivated { .. } | Event::LinkDeactivated { .. })
+            })
+            .collect();
+        assert_eq!(boundaries, vec![
+            Event::LinkActivated { slug: slug.clone() },
+            Event::LinkDeactivated { slug: slug.clone() },
+        ]);
+        assert_eq!(service.get_stats(slug).map(|stats| stats.redirects), Ok(1));
+    }
+
+    #[test]
+    fn test_inactive_links_redirect_to_their_fallback_url() {
+        use commands::LinkManagementHandler;
+
+        let mut service = UrlShortenerService::new();
+        let url = Url("https://example.com/".to_string());
+        let fallback = Url("https://example.com/soon".to_string());
+        let options = LinkOptions {
+            active_from: Some(SystemTime::now() + Duration::from_secs(3600)),
+            fallback_url: Some(fallback.clone()),
+            ..LinkOptions::default()
+        };
+        let link = service.handle_create_short_link_with_options(url.clone(), None, options);
+        let slug = link.unwrap().slug;
+        assert_eq!(service.handle_redirect(slug.clone()).map(|link| link.url), Ok(fallback));
+        assert_eq!(service.get_stats(slug).map(|stats| stats.redirects), Ok(0));
+        let options = LinkOptions {
+            fallback_url: Some(Url("not a url".to_string())),
+            ..LinkOptions::default()
+        };
+        let created = service.handle_create_short_link_with_options(url, None, options);
+        assert_eq!(created, Err(ShortenerError::InvalidUrl));
+    }
+
+    #[test]
+    fn test_campaigns_group_links_and_aggregate_their_stats() {
+        use commands::{CampaignHandler, LinkManagementHandler};
+        use queries::CampaignQueryHandler;
+
+        let mut service = UrlShortenerService::new();
+        let slugs = record_traffic(&mut service);
+        let spring = Slug("spring".to_string());
+        let campaign = service.handle_create_campaign(spring.clone(), "Spring".to_string());
+        assert_eq!(campaign.map(|campaign| campaign.links), Ok(vec![]));
+        service.handle_add_to_campaign(slugs[0].clone(), spring.clone()).unwrap();
+        service.handle_add_to_campaign(slugs[2].clone(), spring.clone()).unwrap();
+        let stats = service.get_campaign_stats(spring.clone()).unwrap();
+        assert_eq!(stats.name, "Spring");
+        assert_eq!(stats.redirects, 4);
+        let ranked: Vec<Slug> = stats.links.into_iter().map(|stats| stats.link.slug).collect();
+        assert_eq!(ranked, vec![slugs[2].clone(), slugs[0].clone()]);
+        service.handle_remove_from_campaign(slugs[0].clone()).unwrap();
+        service.handle_add_to_campaign(slugs[1].clone(), spring.clone()).unwrap();
+        service.handle_delete_short_link(slugs[1].clone()).unwrap();
+        let campaign = service.get_campaign(spring.clone()).unwrap();
+        assert_eq!(campaign.links, vec![slugs[2].clone()]);
+        assert_eq!(service.list_campaigns(), vec![campaign]);
+    }
+
+    #[test]
+    fn test_missing_and_duplicate_campaigns_are_rejected() {
+        use commands::CampaignHandler;
+        use queries::CampaignQueryHandler;
+
+        let mut service = UrlShortenerService::new();
+        let slugs = record_traffic(&mut service);
+        let spring = Slug("spring".to_string());
+        service.handle_create_campaign(spring.clone(), "Spring".to_string()).unwrap();
+        let duplicate = service.handle_create_campaign(spring.clone(), "Other".to_string());
+        assert_eq!(duplicate, Err(ShortenerError::CampaignAlreadyExists));
+        let summer = Slug("summer".to_string());
+        let added = service.handle_add_to_campaign(slugs[0].clone(), summer.clone());
+        assert_eq!(added, Err(ShortenerError::CampaignNotFound));
+        let added = service.handle_add_to_campaign(Slug("missing".to_string()), spring);
+        assert_eq!(added, Err(ShortenerError::SlugNotFound));
+        assert_eq!(service.get_campaign_stats(summer), Err(ShortenerError::CampaignNotFound));
+    }
+
+    #[test]
+    fn test_links_are_listed_and_counted_by_tag() {
+        use commands::{LinkManagementHandler, TagHandler};
+        use queries::TagQueryHandler;
+
+        let mut service = UrlShortenerService::new();
+        let slugs = record_traffic(&mut service);
+        service.handle_tag_link(slugs[0].clone(), " spring ".to_string()).unwrap();
+        service.handle_tag_link(slugs[0].clone(), "spring".to_string()).unwrap();
+        service.handle_tag_link(slugs[1].clone(), "spring".to_string()).unwrap();
+        service.handle_tag_link(slugs[1].clone(), "ads".to_string()).unwrap();
+        service.handle_tag_link(slugs[2].clone(), "ads".to_string()).unwrap();
+        service.handle_tag_link(slugs[2].clone(), "social".to_string()).unwrap();
+        let tagged: Vec<Slug> =
+            service.list_links_by_tag("spring").into_iter().map(|link| link.slug).collect();
+        assert_eq!(tagged, vec![slugs[0].clone(), slugs[1].clone()]);
+        service.handle_untag_link(slugs[0].clone(), "spring".to_string()).unwrap();
+        service.handle_delete_short_link(slugs[2].clone()).unwrap();
+        let counts = service.tag_counts();
+        assert_eq!(counts, vec![("ads".to_string(), 1), ("spring".to_string(), 1)]);
+        assert!(service.list_links_by_tag("social").is_empty());
+    }
+
+    #[test]
+    fn test_invalid_tags_and_missing_links_are_rejected() {
+        use commands::TagHandler;
+        use queries::TagQueryHandler;
+
+        let mut service = UrlShortenerService::new();
+        let slugs = record_traffic(&mut service);
+        let tagged = service.handle_tag_link(slugs[0].clone(), "  ".to_string());
+        assert_eq!(tagged, Err(ShortenerError::InvalidTag));
+        let tagged = service.handle_tag_link(slugs[0].clone(), "x".repeat(65));
+        assert_eq!(tagged, Err(ShortenerError::InvalidTag));
+        let missing = Slug("missing".to_string());
+        let tagged = service.handle_tag_link(missing.clone(), "ads".to_string());
+        assert_eq!(tagged, Err(ShortenerError::SlugNotFound));
+        let untagged = service.handle_untag_link(missing, "ads".to_string());
+        assert_eq!(untagged, Err(ShortenerError::SlugNotFound));
+        assert!(service.tag_counts().is_empty());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_exported_state_is_imported_with_or_without_a_snapshot() {
+        let mut service = UrlShortenerService::new();
+        let slugs = record_traffic(&mut service);
+        for include_snapshot in [false, true] {
+            let mut exported = Vec::new();
+            service.export_state(&mut exported, include_snapshot).unwrap();
+            let store = InMemoryEventStore::new();
+            let imported = UrlShortenerService::import_state(exported.as_slice(), store).unwrap();
+            assert_eq!(imported.read_envelopes(), service.read_envelopes());
+            for slug in &slugs {
+                assert_eq!(imported.get_stats(slug.clone()), service.get_stats(slug.clone()));
+            }
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_unsupported_exports_and_non_empty_stores_are_rejected() {
+        let mut service = UrlShortenerService::new();
+        record_traffic(&mut service);
+        let mut exported = Vec::new();
+        service.export_state(&mut exported, false).unwrap();
+        let json = String::from_utf8(exported).unwrap();
+        let future = json.replace("\"format_version\":1", "\"format_version\":2");
+        let store = InMemoryEventStore::new();
+        let imported = UrlShortenerService::import_state(future.as_bytes(), store);
+        assert_eq!(imported.err().map(|error| error.kind()), Some(io::ErrorKind::InvalidData));
+        let store = InMemoryEventStore::from_envelopes(service.read_envelopes());
+        let imported = UrlShortenerService::import_state(json.as_bytes(), store);
+        assert_eq!(imported.err().map(|error| error.kind()), Some(io::ErrorKind::InvalidInput));
+        let store = InMemoryEventStore::new();
+        let imported = UrlShortenerService::import_state(&b"not json"[..], store);
+        assert!(imported.is_err());
+    }
+
+    #[test]
+    fn test_rebuild_projections_replays_the_event_log_with_progress() {
+        let mut service = UrlShortenerService::new();
+        let url = Url("https://example.com/".to_string());
+        let slug = service.handle_create_short_link(url, None).unwrap().slug;
+        for _ in 0..1200 {
+            service.handle_redirect(slug.clone()).unwrap();
+        }
+        let stats = service.get_stats(slug.clone());
+        let mut reports = Vec::new();
+        let replayed = service.rebuild_projections(|replayed, total| {
+            reports.push((replayed, total));
+        });
+        assert_eq!(replayed, 1201);
+        assert_eq!(reports, vec![(1000, 1201), (1201, 1201)]);
+        assert_eq!(service.get_stats(slug), stats);
+        #[cfg(feature = "cli")]
+        {
+            use clap::Parser;
+
+            let cli = cli::Cli::try_parse_from(["url-shortener", "rebuild-projections"]).unwrap();
+            assert!(matches!(cli.command, cli::Command::RebuildProjections));
+        }
+    }
+
+    #[test]
+    fn test_rebuild_projections_of_an_empty_log_reports_completion() {
+        let mut service = UrlShortenerService::new();
+        let mut reports = Vec::new();
+        let replayed = service.rebuild_projections(|replayed, total| {
+            reports.push((replayed, total));
+        });
+        assert_eq!(replayed, 0);
+        assert_eq!(reports, vec![(0, 0)]);
+        let missing = service.get_stats(Slug("missing".to_string()));
+        assert_eq!(missing, Err(ShortenerError::SlugNotFound));
+    }
+
+    #[test]
+    fn test_recorded_event_log_is_consistent() {
+        use commands::CampaignHandler;
+
+        let mut service = UrlShortenerService::new();
+        let slugs = record_traffic(&mut service);
+        let spring = Slug("spring".to_string());
+        service.handle_create_campaign(spring.clone(), "Spring".to_string()).unwrap();
+        service.handle_add_to_campaign(slugs[0].clone(), spring).unwrap();
+        let report = service.verify_consistency();
+        assert_eq!(report.events, 12);
+        assert!(report.is_consistent(), "{:?}", report.violations);
+    }
+
+    #[test]
+    fn test_hand_edited_event_log_violations_are_reported() {
+        let mut service = UrlShortenerService::new();
+        let slugs = record_traffic(&mut service);
+        let mut envelopes = service.read_envelopes();
+        let created = EventEnvelope { sequence: 10, ..envelopes[0].clone() };
+        let ghost = Slug("ghost".to_string());
+        let accessed = EventEnvelope::new(9, 1, Event::LinkAccessed { slug: ghost.clone() });
+        envelopes.extend([created, accessed]);
+        let edited = UrlShortenerService::with_store(InMemoryEventStore::from_envelopes(envelopes));
+        let report = edited.verify_consistency();
+        assert_eq!(report.events, 12);
+        assert_eq!(report.violations, vec![
+            ConsistencyViolation::VersionOutOfOrder { sequence: 10, version: 1, previous: 2 },
+            ConsistencyViolation::DuplicateSlug { sequence: 10, slug: slugs[0].clone() },
+            ConsistencyViolation::SequenceOutOfOrder { sequence: 9, previous: 10 },
+            ConsistencyViolation::UnknownSlug { sequence: 9, slug: ghost },
+        ]);
+        let message = report.violations[1].to_string();
+        assert_eq!(message, "event 10 reuses slug a of an existing link");
+    }
+
+    #[test]
+    fn test_bot_redirects_are_counted_apart_from_clicks() {
+        use commands::RedirectHandler;
+        use queries::StatsQueryHandler;
+
+        let mut service = UrlShortenerService::new();
+        let slugs = record_traffic(&mut service);
+        let context = |user_agent: &str| ClickContext {
+            user_agent: Some(user_agent.to_string()),
+            ..ClickContext::default()
+        };
+        let crawler = context("Mozilla/5.0 (compatible; Googlebot/2.1)");
+        let link = service.handle_redirect_with_context(slugs[0].clone(), crawler).unwrap();
+        assert_eq!(link.url, Url("https://example.com/0".to_string()));
+        let browser = context("Mozilla/5.0 (X11; Linux x86_64) Firefox/130.0");
+        service.handle_redirect_with_context(slugs[0].clone(), browser).unwrap();
+        let stats = service.get_detailed_stats(slugs[0].clone()).unwrap();
+        assert_eq!((stats.stats.redirects, stats.bot_redirects), (2, 1));
+        let last = service.read_envelopes().into_iter().rev().nth(1).unwrap();
+        assert!(matches!(last.event, Event::BotAccess { .. }));
+    }
+
+    #[test]
+    fn test_click_filters_can_be_replaced() {
+        use commands::RedirectHandler;
+        use filtering::NoFilter;
+        use queries::StatsQueryHandler;
+
+        let mut service = UrlShortenerService::new().with_click_filter(NoFilter);
+        let slugs = record_traffic(&mut service);
+        let crawler = ClickContext {
+            user_agent: Some("curl/8.5.0".to_string()),
+            ..ClickContext::default()
+        };
+        service.handle_redirect_with_context(slugs[0].clone(), crawler.clone()).unwrap();
+        let stats = service.get_detailed_stats(slugs[0].clone()).unwrap();
+        assert_eq!((stats.stats.redirects, stats.bot_redirects), (2, 0));
+        let missing = service.handle_redirect_with_context(Slug("missing".to_string()), crawler);
+        assert_eq!(missing, Err(ShortenerError::SlugNotFound));
+    }
+
+    #[test]
+    fn test_daily_clicks_are_counted_per_utc_day() {
+        use queries::StatsQueryHandler;
+
+        let clock = clock::MockClock::default();
+        let mut service = UrlShortenerService::new().with_clock(clock.clone());
+        let slugs = record_traffic(&mut service);
+        clock.set(SystemTime::UNIX_EPOCH + Duration::from_secs(2 * 86_400 + 3600));
+        service.handle_redirect(slugs[2].clone()).unwrap();
+        let date = |day| Date { year: 1970, month: 1, day };
+        let clicks = service.daily_clicks(slugs[2].clone(), date(1), date(31));
+        assert_eq!(clicks, Ok(vec![(date(1), 3), (date(3), 1)]));
+        let clicks = service.daily_clicks(slugs[2].clone(), date(2), date(3));
+        assert_eq!(clicks, Ok(vec![(date(3), 1)]));
+        let leap_day = Date::of(SystemTime::UNIX_EPOCH + Duration::from_secs(951_782_400));
+        assert_eq!(leap_day.to_string(), "2000-02-29");
+    }
+
+    #[test]
+    fn test_daily_clicks_of_missing_links_and_reversed_ranges() {
+        use queries::StatsQueryHandler;
+
+        let mut service = UrlShortenerService::new();
+        let slugs = record_traffic(&mut service);
+        let from = Date { year: 2024, month: 1, day: 1 };
+        let to = Date { year: 2024, month: 12, day: 31 };
+        let missing = service.daily_clicks(Slug("missing".to_string()), from, to);
+        assert_eq!(missing, Err(ShortenerError::SlugNotFound));
+        assert_eq!(service.daily_clicks(slugs[0].clone(), to, from), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_top_referrers_are_counted_per_domain() {
+        use commands::RedirectHandler;
+        use queries::StatsQueryHandler;
+
+        let mut service = UrlShortenerService::new();
+        let slugs = record_traffic(&mut service);
+        let referrers = [
+            "https://www.Example.org/post/1",
+            "https://example.org/post/2",
+            "https://news.ycombinator.com/item?id=1",
+            "https://t.co/abc",
+            "https://t.co/def",
+            "https://t.co/ghi",
+            "android-app://com.slack",
+            "not a url",
+        ];
+        for referrer in referrers {
+            let context = ClickContext {
+                referrer: Some(referrer.to_string()),
+                ..ClickContext::default()
+            };
+            service.handle_redirect_with_context(slugs[0].clone(), context).unwrap();
+        }
+        let top = service.top_referrers(slugs[0].clone(), 2).unwrap();
+        assert_eq!(top, vec![("t.co".to_string(), 3), ("example.org".to_string(), 2)]);
+        let all = service.top_referrers(slugs[0].clone(), 10).unwrap();
+        let domains: Vec<&str> = all.iter().map(|(domain, _)| domain.as_str()).collect();
+        assert_eq!(domains, ["t.co", "example.org", "com.slack", "news.ycombinator.com"]);
+    }
+
+    #[test]
+    fn test_top_referrers_of_missing_links_are_not_found() {
+        use queries::StatsQueryHandler;
+
+        let mut service = UrlShortenerService::new();
+        let slugs = record_traffic(&mut service);
+        assert_eq!(service.top_referrers(slugs[0].clone(), 5), Ok(vec![]));
+        let missing = service.top_referrers(Slug("missing".to_string()), 5);
+        assert_eq!(missing, Err(ShortenerError::SlugNotFound));
+    }
+
+    #[test]
+    fn test_detailed_stats_report_creation_last_access_and_url_changes() {
+        use commands::LinkManagementHandler;
+        use queries::StatsQueryHandler;
+
+        let at = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        let clock = clock::MockClock::new(at(1000));
+        let mut service = UrlShortenerService::new().with_clock(clock.clone());
+        let url = Url("https://example.com/".to_string());
+        let slug = service.handle_create_short_link(url, None).unwrap().slug;
+        let stats = service.get_detailed_stats(slug.clone()).unwrap();
+        assert_eq!((stats.created_at, stats.last_accessed_at), (Some(at(1000)), None));
+        clock.set(at(2000));
+        service.handle_redirect(slug.clone()).unwrap();
+        let new_url = Url("https://example.org/".to_string());
+        service.handle_change_short_link(slug.clone(), new_url).unwrap();
+        service.handle_revert_url_change(slug.clone()).unwrap();
+        clock.set(at(3000));
+        let stats = service.get_detailed_stats(slug).unwrap();
+        assert_eq!(stats.created_at, Some(at(1000)));
+        assert_eq!(stats.last_accessed_at, Some(at(2000)));
+        assert_eq!(stats.url_change_count, 2);
+    }
+
+    #[test]
+    fn test_failed_url_changes_are_not_counted() {
+        use commands::LinkManagementHandler;
+        use queries::StatsQueryHandler;
+
+        let mut service = UrlShortenerService::new();
+        let slugs = record_traffic(&mut service);
+        let reverted = service.handle_revert_url_change(slugs[0].clone());
+        assert_eq!(reverted, Err(ShortenerError::NothingToRevert));
+        let invalid = Url("not a url".to_string());
+        let changed = service.handle_change_short_link(slugs[0].clone(), invalid);
+        assert_eq!(changed, Err(ShortenerError::InvalidUrl));
+        let stats = service.get_detailed_stats(slugs[0].clone()).unwrap();
+        assert_eq!(stats.url_change_count, 0);
+        let missing = service.get_detailed_stats(Slug("missing".to_string()));
+        assert_eq!(missing, Err(ShortenerError::SlugNotFound));
+    }
+
+    #[test]
+    fn test_unhealthy_destinations_are_listed_as_broken_links() {
+        use queries::HealthQueryHandler;
+
+        let mut service = UrlShortenerService::new();
+        let slugs = record_traffic(&mut service);
+        let url = |i| Url(format!("https://example.com/{i}"));
+        let failed = Err("404 Not Found".to_string());
+        let recorded = service.record_destination_health(slugs[0].clone(), url(0), failed.clone());
+        assert_eq!(recorded, Ok(true));
+        let recorded = service.record_destination_health(slugs[0].clone(), url(0), failed.clone());
+        assert_eq!(recorded, Ok(false));
+        let recorded = service.record_destination_health(slugs[2].clone(), url(2), failed.clone());
+        assert_eq!(recorded, Ok(true));
+        let broken: Vec<(Slug, String)> = service
+            .broken_links()
+            .into_iter()
+            .map(|broken| (broken.link.slug, broken.reason))
+            .collect();
+        assert_eq!(broken, vec![
+            (slugs[0].clone(), "404 Not Found".to_string()),
+            (slugs[2].clone(), "404 Not Found".to_string()),
+        ]);
+        assert_eq!(service.record_destination_health(slugs[0].clone(), url(0), Ok(())), Ok(true));
+        service.handle_change_short_link(slugs[2].clone(), url(3)).unwrap();
+        assert!(service.broken_links().is_empty());
+        //results of checks of a previous destination are ignored
+        assert_eq!(service.record_destination_health(slugs[2].clone(), url(2), failed), Ok(false));
+        assert!(service.broken_links().is_empty());
+    }
+
+    #[test]
+    fn test_health_of_missing_links_is_not_recorded() {
+        let mut service = UrlShortenerService::new();
+        let url = Url("https://example.com/".to_string());
+        let recorded = service.record_destination_health(Slug("missing".to_string()), url, Ok(()));
+        assert_eq!(recorded, Err(ShortenerError::SlugNotFound));
+        assert!(service.read_envelopes().is_empty());
+    }
+
+    #[cfg(feature = "health")]
+    #[test]
+    fn test_health_checker_records_broken_destinations() {
+        use health::HealthChecker;
+        use queries::HealthQueryHandler;
+
+        let mut service = UrlShortenerService::new();
+        let unreachable = "http://127.0.0.1:1/".to_string();
+        let urls = [serve("200 OK", ""), serve("404 Not Found", ""), unreachable];
+        for (slug, url) in ["ok", "missing", "down"].into_iter().zip(urls) {
+            service.handle_create_short_link(Url(url), Some(Slug(slug.to_string()))).unwrap();
+        }
+        let shared = SharedUrlShortenerService::new(service);
+        let checker = HealthChecker::new(shared.clone());
+        assert_eq!(block_on(checker.check_all()), Ok(2));
+        let broken = shared.read().broken_links();
+        let slugs: Vec<&str> = broken.iter().map(|broken| broken.link.slug.0.as_str()).collect();
+        assert_eq!(slugs, ["down", "missing"]);
+        assert_eq!(broken[1].reason, "404 Not Found");
+        assert_eq!(block_on(checker.check_all()), Ok(0));
+    }
+
+    #[test]
+    fn test_commands_and_queries_are_executed_as_values() {
+        use commands::{Command, CommandOutput};
+        use queries::{Query, QueryOutput};
+
+        let mut service = UrlShortenerService::new();
+        let slug = Slug("promo".to_string());
+        let url = Url("https://example.com/".to_string());
+        let commands = vec![
+            Command::CreateShortLink { url: url.clone(), slug: Some(slug.clone()) },
+            Command::Redirect { slug: slug.clone() },
+            Command::TagLink { slug: slug.clone(), tag: "ads".to_string() },
+        ];
+        #[cfg(feature = "serde")]
+        let commands: Vec<Command> =
+            serde_json::from_str(&serde_json::to_string(&commands).unwrap()).unwrap();
+        let link = ShortLink { slug: slug.clone(), url };
+        for command in commands {
+            assert_eq!(command.execute(&mut service), Ok(CommandOutput::Link(link.clone())));
+        }
+        let stats = Query::GetStats { slug }.execute(&service);
+        assert_eq!(stats, Ok(QueryOutput::Stats(Stats { link: link.clone(), redirects: 1 })));
+        let tagged = Query::ListLinksByTag { tag: "ads".to_string() }.execute(&service);
+        assert_eq!(tagged, Ok(QueryOutput::Links(vec![link])));
+    }
+
+    #[test]
+    fn test_failed_commands_and_queries_return_the_error() {
+        use commands::Command;
+        use queries::Query;
+
+        let mut service = UrlShortenerService::new();
+        let missing = Slug("missing".to_string());
+        let redirect = Command::Redirect { slug: missing.clone() }.execute(&mut service);
+        assert_eq!(redirect, Err(ShortenerError::SlugNotFound));
+        let campaign = Command::AddToCampaign { slug: missing.clone(), campaign: missing.clone() };
+        assert_eq!(campaign.execute(&mut service), Err(ShortenerError::SlugNotFound));
+        let stats = Query::GetDetailedStats { slug: missing }.execute(&service);
+        assert_eq!(stats, Err(ShortenerError::SlugNotFound));
+        assert!(service.read_envelopes().is_empty());
+    }
+
+    #[test]
+    fn test_process_runner_issues_commands_and_resumes_at_its_position() {
+        use process::{DisableUnhealthyLinks, MilestoneNotifier, ProcessRunner};
+
+        let mut service = UrlShortenerService::builder().click_milestones([2]).build();
+        let slugs = record_traffic(&mut service);
+        let url = Url("https://example.com/0".to_string());
+        let failed = Err("404 Not Found".to_string());
+        service.record_destination_health(slugs[0].clone(), url, failed).unwrap();
+        let mut runner = ProcessRunner::new(DisableUnhealthyLinks);
+        assert_eq!(runner.run(&mut service), Ok(14));
+        assert_eq!(runner.position(), 14);
+        assert_eq!(service.handle_redirect(slugs[0].clone()), Err(ShortenerError::LinkDisabled));
+        assert_eq!(runner.run(&mut service), Ok(0));
+
+        let mut reached = Vec::new();
+        let notifier = MilestoneNotifier::new(|slug: &Slug, clicks| {
+            reached.push((slug.clone(), clicks));
+        });
+        let mut runner = ProcessRunner::starting_at(notifier, 6);
+        assert_eq!(runner.run(&mut service), Ok(8));
+        assert_eq!(reached, vec![(slugs[2].clone(), 2)]);
+    }
+
+    #[test]
+    fn test_process_managers_are_told_about_failed_commands() {
+        use commands::Command;
+        use process::{ProcessManager, ProcessRunner};
+
+        #[derive(Default)]
+        struct DisableMissing {
+            failures: Vec<(Command, ShortenerError)>,
+        }
+
+        impl ProcessManager for DisableMissing {
+            fn handle(&mut self, envelope: &EventEnvelope) -> Vec<Command> {
+                match &envelope.event {
+                    Event::LinkCreated { .. } => {
+                        vec![Command::DisableLink { slug: Slug("missing".to_string()) }]
+                    }
+                    _ => Vec::new(),
+                }
+            }
+
+            fn command_failed(&mut self, command: Command, error: ShortenerError) {
+                self.failures.push((command, error));
+            }
+        }
+
+        let mut service = UrlShortenerService::new();
+        record_traffic(&mut service);
+        let mut runner = ProcessRunner::new(DisableMissing::default());
+        assert_eq!(runner.run(&mut service), Ok(10));
+        let failures = &runner.manager().failures;
+        assert_eq!(failures.len(), 3);
+        assert_eq!(failures[0].1, ShortenerError::SlugNotFound);
+        assert_eq!(service.read_envelopes().len(), 10);
+    }
+
+    #[test]
+    fn test_events_of_a_workflow_share_its_correlation_id() {
+        use commands::{Command, CommandEnvelope};
+        use process::{DisableUnhealthyLinks, ProcessRunner};
+        use queries::HistoryQueryHandler;
+
+        let mut service = UrlShortenerService::new();
+        let slug = Slug("promo".to_string());
+        let url = Url("https://example.com/".to_string());
+        let create = CommandEnvelope::new(Command::CreateShortLink {
+            url: url.clone(),
+            slug: Some(slug.clone()),
+        });
+        service.execute(create.clone()).unwrap();
+        let created = service.read_envelopes().pop().unwrap();
+        assert_eq!(created.correlation_id, Some(create.id));
+        assert_eq!(created.causation_id, Some(create.id));
+        let command = Command::Redirect { slug: slug.clone() };
+        let redirect = CommandEnvelope::caused_by(command, &created);
+        service.execute(redirect.clone()).unwrap();
+        service.record_destination_health(slug, url, Err("410 Gone".to_string())).unwrap();
+        let unhealthy = service.read_envelopes().pop().unwrap();
+        assert_eq!(unhealthy.correlation_id, None);
+        ProcessRunner::new(DisableUnhealthyLinks).run(&mut service).unwrap();
+
+        let workflow = service.get_correlated_events(create.id);
+        let causes: Vec<Option<Uuid>> =
+            workflow.iter().map(|envelope| envelope.causation_id).collect();
+        assert_eq!(causes, vec![Some(create.id), Some(redirect.id)]);
+        let disabled = service.get_correlated_events(unhealthy.id);
+        assert_eq!(disabled.len(), 2);
+        assert_eq!(disabled[1].causation_id, Some(unhealthy.id));
+    }
+
+    #[test]
+    fn test_failed_commands_record_no_correlated_events() {
+        use commands::{Command, CommandEnvelope};
+        use queries::HistoryQueryHandler;
+
+        let mut service = UrlShortenerService::new();
+        let command = Command::Redirect { slug: Slug("missing".to_string()) };
+        let redirect = CommandEnvelope::new(command);
+        assert_eq!(service.execute(redirect.clone()), Err(ShortenerError::SlugNotFound));
+        assert!(service.get_correlated_events(redirect.id).is_empty());
+        //the ids of the command do not leak into later events
+        let url = Url("https://example.com/".to_string());
+        service.handle_create_short_link(url, None).unwrap();
+        let created = service.read_envelopes().pop().unwrap();
+        assert_eq!((created.correlation_id, created.causation_id), (None, None));
+    }
+
+    #[test]
+    fn test_executed_commands_are_audited_with_their_outcome() {
+        use commands::{Command, CommandEnvelope};
+
+        let at = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        let clock = clock::MockClock::new(at(1000));
+        let mut service = UrlShortenerService::new().with_clock(clock.clone());
+        let slug = Slug("promo".to_string());
+        let url = Url("https://example.com/".to_string());
+        let create = Command::CreateShortLink { url, slug: Some(slug.clone()) };
+        service.execute(CommandEnvelope::new(create.clone())).unwrap();
+        clock.set(at(2000));
+        let missing = Command::Redirect { slug: Slug("missing".to_string()) };
+        let redirect = CommandEnvelope::new(missing.clone());
+        assert!(service.execute(redirect).is_err());
+        service.execute(CommandEnvelope::new(Command::Redirect { slug: slug.clone() })).unwrap();
+
+        let entries = service.query_audit(&AuditFilter::new());
+        assert_eq!(entries.len(), 3);
+        let envelopes = service.read_envelopes();
+        let events: Vec<Uuid> = envelopes.iter().map(|envelope| envelope.id).collect();
+        assert_eq!(entries[0].command, create);
+        assert_eq!(entries[0].outcome, AuditOutcome::Accepted { events: vec![events[0]] });
+        let rejected = service.query_audit(&AuditFilter::new().rejected(true));
+        assert_eq!(rejected.len(), 1);
+        assert_eq!(rejected[0].command, missing);
+        assert_eq!(rejected[0].outcome, AuditOutcome::Rejected {
+            error: ShortenerError::SlugNotFound,
+        });
+        let of_link = service.query_audit(&AuditFilter::new().slug(slug).from(at(1500)));
+        assert_eq!(of_link.len(), 1);
+        assert_eq!(of_link[0].outcome, AuditOutcome::Accepted { events: vec![events[1]] });
+        assert!(service.query_audit(&AuditFilter::new().until(at(1000))).is_empty());
+    }
+
+    #[test]
+    fn test_failing_audit_log_rejects_commands_after_recording_them() {
+        use audit::AuditLog;
+        use commands::{Command, CommandEnvelope};
+
+        struct FailingAuditLog;
+
+        impl AuditLog for FailingAuditLog {
+            fn append(&mut self, _: AuditEntry) -> io::Result<()> {
+                Err(io::Error::other("audit log is unavailable"))
+            }
+
+            fn entries(&self) -> Vec<AuditEntry> {
+                Vec::new()
+            }
+        }
+
+        let mut service = UrlShortenerService::new().with_audit_log(FailingAuditLog);
+        let url = Url("https://example.com/".to_string());
+        let create = CommandEnvelope::new(Command::CreateShortLink { url, slug: None });
+        assert_eq!(service.execute(create), Err(ShortenerError::StorageFailure));
+        assert_eq!(service.read_envelopes().len(), 1);
+    }
+
+    #[test]
+    fn test_shared_buffered_redirects_run_under_the_read_lock() {
+        let service = UrlShortenerService::builder()
+            .buffer_clicks(true)
+            .click_milestones([5])
+            .build();
+        let shared = SharedUrlShortenerService::new(service);
+        let slug = Slug("example".to_string());
+        let url = Url("https://example.com/".to_string());
+        (&shared).handle_create_short_link(url, Some(slug.clone())).unwrap();
+
+        //redirects needing the write lock would wait for the reader forever
+        let reader = shared.read();
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| (&shared).handle_redirect(slug.clone()).unwrap());
+            }
+        });
+        drop(reader);
+        assert_eq!(shared.get_stats(slug.clone()).map(|stats| stats.redirects), Ok(0));
+        //the milestone is recorded under the write lock, flushing the clicks
+        (&shared).handle_redirect(slug.clone()).unwrap();
+        assert_eq!(shared.get_stats(slug).map(|stats| stats.redirects), Ok(5));
+        let milestones = shared.read().store().read_envelopes().into_iter().filter(|envelope| {
+            matches!(envelope.event, Event::MilestoneReached { clicks: 5, .. })
+        });
+        assert_eq!(milestones.count(), 1);
+    }
+
+    #[test]
+    fn test_shared_plain_redirects_take_the_read_lock() {
+        use commands::RedirectHandler;
+
+        let service = UrlShortenerService::builder().buffer_clicks(true).build();
+        let shared = SharedUrlShortenerService::new(service);
+        let slug = Slug("a".to_string());
+        let url = Url("https://example.com/".to_string());
+        (&shared).handle_create_short_link(url.clone(), Some(slug.clone())).unwrap();
+
+        let reading = shared.read();
+        let (done, finished) = mpsc::channel();
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                let context = ClickContext::default();
+                let decision = (&shared).resolve_redirect(slug.clone(), context, None, None);
+                done.send(decision.map(|decision| decision.location)).unwrap();
+            });
+            let redirected = finished.recv_timeout(Duration::from_secs(5));
+            //released before asserting, not to block the redirect forever
+            drop(reading);
+            assert_eq!(redirected, Ok(Ok(url.clone())));
+        });
+        let context = ClickContext {
+            user_agent: Some("Mozilla/5.0".to_string()),
+            ..ClickContext::default()
+        };
+        let decision = (&shared).resolve_redirect(slug.clone(), context, None, None).unwrap();
+        assert_eq!(decision.location, url);
+        assert_eq!(shared.get_stats(slug.clone()).map(|stats| stats.redirects), Ok(1));
+        shared.write().flush().unwrap();
+        assert_eq!(shared.get_stats(slug).map(|stats| stats.redirects), Ok(2));
+    }
+
+    #[test]
+    fn test_buffered_clicks_are_recorded_when_flushed() {
+        let service = UrlShortenerService::builder().buffer_clicks(true).build();
+        let shared = SharedUrlShortenerService::new(service);
+        let slugs = record_traffic(&mut shared.write());
+        assert_eq!(shared.get_stats(slugs[2].clone()).map(|stats| stats.redirects), Ok(0));
+        //the clicks of b were flushed before its url changed
+        assert_eq!(shared.write().flush(), Ok(2));
+        assert_eq!(shared.write().flush(), Ok(0));
+        for (slug, redirects) in slugs.iter().zip([1, 2, 3]) {
+            assert_eq!(shared.get_stats(slug.clone()).map(|stats| stats.redirects), Ok(redirects));
+        }
+        let aggregated = shared.read().store().read_envelopes().into_iter().filter(|envelope| {
+            matches!(envelope.event, Event::ClicksAggregated { .. })
+        });
+        assert_eq!(aggregated.count(), 3);
+
+        //the flusher flushes once more when stopped
+        let flusher = ClickFlusher::spawn(shared.clone(), Duration::from_secs(3600));
+        (&shared).handle_redirect(slugs[0].clone()).unwrap();
+        assert_eq!(flusher.stop(), Ok(1));
+        assert_eq!(shared.get_stats(slugs[0].clone()).map(|stats| stats.redirects), Ok(2));
+    }
+
+    #[test]
+    fn test_missing_links_buffer_no_clicks_and_deleted_links_flush_theirs() {
+        use commands::LinkManagementHandler;
+
+        let mut service = UrlShortenerService::builder().buffer_clicks(true).build();
+        let slugs = record_traffic(&mut service);
+        let missing = service.handle_redirect(Slug("missing".to_string()));
+        assert_eq!(missing, Err(ShortenerError::SlugNotFound));
+        service.handle_delete_short_link(slugs[0].clone()).unwrap();
+        let envelopes = service.read_envelopes();
+        let [.., aggregated, deleted] = &envelopes[..] else {
+            panic!("no events recorded");
+        };
+        assert!(matches!(
+            &aggregated.event,
+            Event::ClicksAggregated { slug, count: 1, .. } if *slug == slugs[0]
+        ));
+        assert_eq!(deleted.event, Event::LinkDeleted { slug: slugs[0].clone() });
+        //the clicks of c are still buffered
+        assert_eq!(service.flush(), Ok(1));
+    }
+
+    //time it takes `threads` threads to redirect `redirects` times each, every
+    //thread to its own link, checking all the redirects were counted
+    fn time_redirects<H: Sync>(service: &H, threads: usize, redirects: usize) -> Duration
+    where
+        for<'a> &'a H: CommandHandler + QueryHandler,
+    {
+        let mut handler = service;
+        let slugs: Vec<Slug> = (0..threads)
+            .map(|thread| {
+                let url = Url(format!("https://example.com/{thread}"));
+                handler.handle_create_short_link(url, None).unwrap().slug
+            })
+            .collect();
+        let started = Instant::now();
+        thread::scope(|scope| {
+            for slug in &slugs {
+                scope.spawn(move || {
+                    let mut handler = service;
+                    for _ in 0..redirects {
+                        handler.handle_redirect(slug.clone()).unwrap();
+                    }
+                });
+            }
+        });
+        let elapsed = started.elapsed();
+        for slug in slugs {
+            let counted = handler.get_stats(slug).map(|stats| stats.redirects);
+            assert_eq!(counted, Ok(redirects as u64));
+        }
+        elapsed
+    }
+
+    #[test]
+    fn test_sharded_redirects_are_counted() {
+        let service = sharding::ShardedUrlShortenerService::new(4);
+        time_redirects(&service, 8, 100);
+    }
+
+    //creates links without slugs in a sharded service of the generator
+    fn create_sharded(
+        generator: impl generation::SlugGenerator + Clone + Send + Sync + 'static,
+        links: usize,
+    ) -> Vec<Result<ShortLink, ShortenerError>> {
+        let service = sharding::ShardedUrlShortenerService::builder()
+            .shard_with(move |_| {
+                UrlShortenerService::builder()
+                    .generator(generator.clone())
+                    .build()
+            })
+            .build();
+        (0..links)
+            .map(|link| {
+                let url = Url(format!("https://example.com/{link}"));
+                let created = (&service).handle_create_short_link(url.clone(), None)?;
+                assert_eq!((&service).handle_redirect(created.slug.clone())?.url, url);
+                Ok(created)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_sharded_links_without_slugs_are_generated_in_their_shard() {
+        let hashed = create_sharded(generation::HashOfUrl::default(), 100);
+        assert!(hashed.iter().all(Result::is_ok), "{hashed:?}");
+        let counted = create_sharded(generation::Base62Counter::default(), 100);
+        assert!(counted.iter().all(Result::is_ok), "{counted:?}");
+    }
+
+    #[test]
+    fn test_sharded_link_fails_without_a_slug_of_its_shard() {
+        #[derive(Clone)]
+        struct Fixed;
+
+        impl generation::SlugGenerator for Fixed {
+            fn generate(&mut self, _: &Url, _: u32) -> Slug {
+                Slug("fixed".to_string())
+            }
+        }
+
+        let created = create_sharded(Fixed, 16);
+        assert!(created.contains(&Err(ShortenerError::NoSlugInShard)));
+        assert_eq!(created.iter().filter(|created| created.is_ok()).count(), 1);
+    }
+
+    #[test]
+    #[ignore = "benchmark, run with --ignored --nocapture"]
+    fn bench_sharded_redirects() {
+        let (threads, redirects) = (8, 20_000);
+        let service = SharedUrlShortenerService::new(UrlShortenerService::new());
+        let unsharded = time_redirects(&service, threads, redirects);
+        let shards = sharding::DEFAULT_SHARDS;
+        let service = sharding::ShardedUrlShortenerService::new(shards);
+        let sharded = time_redirects(&service, threads, redirects);
+        println!("{threads} threads x {redirects} redirects: unsharded {unsharded:?}");
+        println!("{threads} threads x {redirects} redirects: {shards} shards {sharded:?}");
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn test_parallel_rebuild_matches_the_sequential_replay() {
+        use commands::CampaignHandler;
+        use queries::{CampaignQueryHandler, LinkQueryHandler};
+
+        let mut service = UrlShortenerService::new();
+        let slugs: Vec<Slug> = (0..50)
+            .map(|i| {
+                let url = Url(format!("https://example.com/{i}"));
+                service.handle_create_short_link(url, None).unwrap().slug
+            })
+            .collect();
+        for (i, slug) in slugs.iter().enumerate() {
+            for _ in 0..200 + i {
+                service.handle_redirect(slug.clone()).unwrap();
+            }
+        }
+        let campaign = Slug("spring".to_string());
+        service.handle_create_campaign(campaign.clone(), "Spring".to_string()).unwrap();
+        for slug in &slugs[..10] {
+            service.handle_add_to_campaign(slug.clone(), campaign.clone()).unwrap();
+        }
+        let stats: Vec<_> = slugs.iter().map(|slug| service.get_stats(slug.clone())).collect();
+        let campaign_stats = service.get_campaign_stats(campaign.clone());
+        let global = service.global_stats();
+
+        let mut reported = Vec::new();
+        let total = service.rebuild_projections(|replayed, total| reported.push((replayed, total)));
+        assert!(total >= PARALLEL_REPLAY_THRESHOLD);
+        assert!(reported.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        assert_eq!(reported.last(), Some(&(total, total)));
+        for (slug, stats) in slugs.iter().zip(stats) {
+            assert_eq!(service.get_stats(slug.clone()), stats);
+        }
+        assert_eq!(service.get_campaign_stats(campaign), campaign_stats);
+        assert_eq!(service.global_stats(), global);
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn test_parallel_replay_keeps_renamed_links_in_one_partition() {
+        use commands::LinkManagementHandler;
+
+        let mut service = UrlShortenerService::new();
+        let slugs = record_traffic(&mut service);
+        let renamed = Slug("renamed".to_string());
+        service.handle_rename_slug(slugs[0].clone(), renamed.clone()).unwrap();
+        service.handle_redirect(renamed.clone()).unwrap();
+        let envelopes = service.read_envelopes();
+        //more partitions than streams, so unrelated streams never share one
+        let partitions = partition(&envelopes, 64);
+        let of_a = partitions.iter().filter(|partition| {
+            partition.iter().any(|envelope| envelope.event.slug() == &slugs[0])
+        });
+        let of_a: Vec<_> = of_a.collect();
+        assert_eq!(of_a.len(), 1);
+        assert!(of_a[0].iter().any(|envelope| envelope.event.slug() == &renamed));
+        let replayed = ReadModel::replay_parallel(&envelopes, false, |_| {});
+        let redirects = replayed.get(&renamed).map(|state| state.redirects);
+        assert_eq!(redirects, Ok(2));
+        //the old slug was released by the rename
+        let released = replayed.get(&slugs[0]).map(|state| state.redirects);
+        assert_eq!(released, Err(ShortenerError::SlugNotFound));
+    }
+
+    #[test]
+    fn test_store_stats_report_the_log_since_the_last_snapshot() {
+        let clock = clock::MockClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(60));
+        let mut service = UrlShortenerService::new().with_clock(clock.clone());
+        let slugs = record_traffic(&mut service);
+        let stats = service.store_stats();
+        assert_eq!(stats.events, 10);
+        assert_eq!(stats.slugs, 3);
+        assert!(stats.approximate_bytes >= 10 * std::mem::size_of::<EventEnvelope>());
+        assert_eq!((stats.snapshot_age, stats.events_since_snapshot), (None, 10));
+        //the clicks of b and c fold into one event each
+        assert_eq!(stats.compaction_candidates, 3);
+
+        service.snapshot();
+        clock.advance(Duration::from_secs(30));
+        service.handle_redirect(slugs[0].clone()).unwrap();
+        let stats = service.store_stats();
+        assert_eq!(stats.events, 11);
+        assert_eq!(stats.snapshot_age, Some(Duration::from_secs(30)));
+        assert_eq!(stats.events_since_snapshot, 1);
+    }
+
+    #[test]
+    fn test_store_stats_of_an_empty_log_and_a_clock_behind_the_snapshot() {
+        let clock = clock::MockClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(60));
+        let service = UrlShortenerService::new().with_clock(clock.clone());
+        assert_eq!(service.store_stats(), StoreStats {
+            events: 0,
+            approximate_bytes: 0,
+            slugs: 0,
+            snapshot_age: None,
+            events_since_snapshot: 0,
+            compaction_candidates: 0,
+        });
+        service.snapshot();
+        clock.set(SystemTime::UNIX_EPOCH);
+        assert_eq!(service.store_stats().snapshot_age, Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn test_interned_clicks_are_read_back_as_recorded() {
+        let mut service = UrlShortenerService::new();
+        let slugs = record_traffic(&mut service);
+        let recorded = service.read_envelopes();
+        let mut store = InMemoryEventStore::new();
+        for envelope in recorded.clone() {
+            store.append(envelope).unwrap();
+        }
+        assert_eq!(store.read_envelopes(), recorded);
+        let stream = store::StreamId(slugs[2].clone());
+        let of_c: Vec<_> = recorded
+            .iter()
+            .filter(|envelope| envelope.event.slug() == &slugs[2])
+            .cloned()
+            .collect();
+        assert_eq!(store.read_stream(&stream), of_c);
+        assert_eq!(store.stream_version(&stream), 4);
+    }
+
+    #[test]
+    fn test_interned_slugs_differing_in_case_stay_apart() {
+        let mut store = InMemoryEventStore::new();
+        for (sequence, slug) in ["a", "A", "a"].into_iter().enumerate() {
+            let event = Event::LinkAccessed { slug: Slug(slug.to_string()) };
+            store.append(EventEnvelope::new(sequence as u64, 1, event)).unwrap();
+        }
+        let missing = store::StreamId(Slug("b".to_string()));
+        assert!(store.read_stream(&missing).is_empty());
+        assert_eq!(store.compact(u64::MAX).unwrap(), 1);
+        let counts: Vec<_> = store
+            .read_envelopes()
+            .into_iter()
+            .map(|envelope| match envelope.event {
+                Event::ClicksAggregated { slug, count, .. } => (slug.0, count),
+                Event::LinkAccessed { slug } => (slug.0, 1),
+                event => panic!("unexpected event {event:?}"),
+            })
+            .collect();
+        assert_eq!(counts, vec![("A".to_string(), 1), ("a".to_string(), 2)]);
+    }
+
+    #[test]
+    fn test_opted_in_schemes_are_accepted_besides_http() {
+        use validation::{DefaultUrlValidator, UrlValidator};
+
+        let validator = DefaultUrlValidator::default().allow_scheme("FTP:");
+        assert_eq!(validator.allowed_schemes, vec!["http", "https", "ftp"]);
+        let url = |url: &str| Url(url.to_string());
+        assert_eq!(validator.validate(&url("ftp://files.example.com/a.txt")), Ok(()));
+        assert_eq!(validator.validate(&url("https://example.com/")), Ok(()));
+        let mut service = UrlShortenerService::builder().url_validator(validator).build();
+        let link = service.handle_create_short_link(url("ftp://files.example.com/a.txt"), None);
+        assert!(link.is_ok());
+    }
+
+    #[test]
+    fn test_scripting_and_local_schemes_are_rejected_even_if_allowed() {
+        use validation::{DefaultUrlValidator, UrlValidator};
+
+        let mut service = UrlShortenerService::new();
+        for url in [
+            "javascript:alert(1)",
+            "data:text/html,<script>alert(1)</script>",
+            "file:///etc/passwd",
+            "ftp://files.example.com/a.txt",
+        ] {
+            let link = service.handle_create_short_link(Url(url.to_string()), None);
+            assert_eq!(link, Err(ShortenerError::InvalidUrl), "{url}");
+        }
+        let validator = DefaultUrlValidator::default()
+            .allow_scheme("javascript")
+            .allow_scheme("blob");
+        let blob = Url("blob:https://example.com/1b2c".to_string());
+        assert_eq!(validator.validate(&blob), Err(ShortenerError::InvalidUrl));
+        let script = Url("javascript://example.com/%0Aalert(1)".to_string());
+        assert_eq!(validator.validate(&script), Err(ShortenerError::InvalidUrl));
+    }
+
+    #[test]
+    fn test_destinations_on_blocked_domains_are_rejected_and_counted() {
+        use queries::HealthQueryHandler;
+        use validation::DomainPolicy;
+
+        let policy = DomainPolicy::default().block("Bad.Example.com.");
+        let mut service = UrlShortenerService::builder().domain_policy(policy).build();
+        let url = |url: &str| Url(url.to_string());
+        let slug = Slug("a".to_string());
+        let created = service.handle_create_short_link(url("https://example.com/"), None);
+        assert!(created.is_ok());
+        for blocked in ["https://bad.example.com/", "https://www.bad.example.com/x"] {
+            let created = service.handle_create_short_link(url(blocked), Some(slug.clone()));
+            assert_eq!(created, Err(ShortenerError::DestinationBlocked));
+        }
+        //a domain merely ending in the blocked one is unrelated
+        let created = service.handle_create_short_link(url("https://notbad.example.com/"), None);
+        assert!(created.is_ok());
+        assert_eq!(service.blocked_domains(), vec![
+            ("bad.example.com".to_string(), 1),
+            ("www.bad.example.com".to_string(), 1),
+        ]);
+        assert_eq!(service.get_stats(slug), Err(ShortenerError::SlugNotFound));
+    }
+
+    #[test]
+    fn test_url_changes_outside_the_allowed_domains_are_rejected() {
+        use queries::HealthQueryHandler;
+        use validation::DomainPolicy;
+
+        let policy = DomainPolicy::default().allow_only("example.com");
+        let mut service = UrlShortenerService::builder().domain_policy(policy).build();
+        let url = Url("https://www.example.com/".to_string());
+        let link = service.handle_create_short_link(url.clone(), None).unwrap();
+        let other = Url("https://example.org/".to_string());
+        let changed = service.handle_change_short_link(link.slug.clone(), other);
+        assert_eq!(changed, Err(ShortenerError::DestinationBlocked));
+        assert_eq!(service.get_stats(link.slug).map(|stats| stats.link.url), Ok(url));
+        assert_eq!(service.blocked_domains(), vec![("example.org".to_string(), 1)]);
+    }
+
+    #[test]
+    fn test_shared_safety_check_runs_before_the_write_lock() {
+        use queries::LinkQueryHandler;
+
+        let (checking, started) = mpsc::channel();
+        let (release, released) = mpsc::channel::<()>();
+        let released = Mutex::new(released);
+        let checker = move |url: &Url| {
+            if !url.0.ends_with("/phishing") {
+                return SafetyVerdict::Safe;
+            }
+            checking.send(()).unwrap();
+            released.lock().unwrap().recv().unwrap();
+            SafetyVerdict::Flagged("phishing".to_string())
+        };
+        let service = UrlShortenerService::builder().safety_checker(checker).build();
+        let shared = SharedUrlShortenerService::new(service);
+        let safe = Slug("safe".to_string());
+        let url = Url("https://example.com/".to_string());
+        (&shared).handle_create_short_link(url, Some(safe.clone())).unwrap();
+
+        let flagged = Slug("flagged".to_string());
+        thread::scope(|scope| {
+            let url = Url("https://example.com/phishing".to_string());
+            let slug = Some(flagged.clone());
+            let creating = scope.spawn(|| (&shared).handle_create_short_link(url, slug));
+            started.recv().unwrap();
+            //released before asserting, not to wait for the check forever
+            let unlocked = shared.inner.try_write().is_ok();
+            release.send(()).unwrap();
+            creating.join().unwrap().unwrap();
+            assert!(unlocked);
+        });
+        let details = shared.read().get_link_details(flagged).unwrap();
+        assert_eq!(details.flagged.as_deref(), Some("phishing"));
+    }
+
+    #[test]
+    fn test_flagged_destinations_redirect_through_a_warning() {
+        use commands::RedirectHandler;
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let listed = Arc::new(AtomicBool::new(false));
+        let feed = listed.clone();
+        let checker = move |url: &Url| {
+            if url.0.ends_with("/phishing") || feed.load(Ordering::SeqCst) {
+                SafetyVerdict::Flagged("phishing".to_string())
+            } else {
+                SafetyVerdict::Safe
+            }
+        };
+        let service = UrlShortenerService::builder().safety_checker(checker).build();
+        let shared = SharedUrlShortenerService::new(service);
+        let (safe, flagged) = (Slug("safe".to_string()), Slug("flagged".to_string()));
+        let url = |url: &str| Url(url.to_string());
+        let home = url("https://example.com/");
+        (&shared).handle_create_short_link(home, Some(safe.clone())).unwrap();
+        let phishing = url("https://example.com/phishing");
+        (&shared).handle_create_short_link(phishing, Some(flagged.clone())).unwrap();
+        let warning = |slug: &Slug| {
+            let context = ClickContext::default();
+            (&shared).resolve_redirect(slug.clone(), context, None, None).unwrap().warning
+        };
+        assert_eq!(warning(&safe), None);
+        assert_eq!(warning(&flagged), Some("phishing".to_string()));
+        let changed = url("https://example.com/fixed");
+        (&shared).handle_change_short_link(flagged.clone(), changed).unwrap();
+        assert_eq!(warning(&flagged), None);
+
+        //destinations listed after the links were created are found by scans
+        listed.store(true, Ordering::SeqCst);
+        assert_eq!(safety::scan(&shared), Ok(2));
+        assert_eq!(safety::scan(&shared), Ok(0));
+        assert_eq!(warning(&safe), Some("phishing".to_string()));
+    }
+
+    #[test]
+    fn test_stale_and_missing_safety_verdicts_are_not_recorded() {
+        let mut service = UrlShortenerService::new();
+        record_traffic(&mut service);
+        let flagged = SafetyVerdict::Flagged("malware".to_string());
+        let mut record = |slug: &str, url: &str, verdict: &SafetyVerdict| {
+            let (slug, url) = (Slug(slug.to_string()), Url(url.to_string()));
+            service.record_safety_verdict(slug, url, verdict.clone())
+        };
+        let recorded = record("missing", "https://example.com/", &flagged);
+        assert_eq!(recorded, Err(ShortenerError::SlugNotFound));
+        //b was checked before its url changed
+        assert_eq!(record("b", "https://example.com/1", &flagged), Ok(false));
+        assert_eq!(record("a", "https://example.com/0", &SafetyVerdict::Safe), Ok(false));
+        assert_eq!(service.read_envelopes().len(), 10);
+        //nothing is scanned without a checker
+        assert_eq!(safety::scan(&SharedUrlShortenerService::new(service)), Ok(0));
+    }
+
+    #[test]
+    fn test_destinations_through_few_other_shorteners_are_accepted() {
+        use validation::LoopPolicy;
+
+        let base_url = Url("https://sho.rt/go".to_string());
+        let mut service = UrlShortenerService::builder().base_url(base_url.clone()).build();
+        let url = |url: &str| Url(url.to_string());
+        for accepted in [
+            "https://example.com/",
+            "https://bit.ly/abc",
+            "https://t.co/x?u=https://bit.ly/y",
+            //the same host outside the path the service is served under
+            "https://sho.rt/blog/post",
+        ] {
+            let created = service.handle_create_short_link(url(accepted), None);
+            assert!(created.is_ok(), "{accepted}");
+        }
+        let policy = LoopPolicy { max_chain_depth: None, ..LoopPolicy::default() };
+        let chained = url("https://t.co/a?u=https://bit.ly/b?u=https://ow.ly/c");
+        assert_eq!(policy.check(&chained, &[base_url]), Ok(()));
+    }
+
+    #[test]
+    fn test_destinations_looping_back_or_chaining_deeply_are_rejected() {
+        use validation::LoopPolicy;
+
+        let base_url = Url("https://sho.rt/go".to_string());
+        let mut service = UrlShortenerService::builder().base_url(base_url.clone()).build();
+        let url = |url: &str| Url(url.to_string());
+        for rejected in [
+            "https://sho.rt/go/abc",
+            "https://SHO.RT./go",
+            "https://tinyurl.com/x?next=https://sho.rt/go/abc",
+            "https://t.co/a?u=https://bit.ly/b?u=https://ow.ly/c",
+        ] {
+            let created = service.handle_create_short_link(url(rejected), None);
+            assert_eq!(created, Err(ShortenerError::RedirectLoop), "{rejected}");
+        }
+        let link = service.handle_create_short_link(url("https://example.com/"), None).unwrap();
+        let changed = service.handle_change_short_link(link.slug, url("https://sho.rt/go/x"));
+        assert_eq!(changed, Err(ShortenerError::RedirectLoop));
+        let policy = LoopPolicy { max_chain_depth: Some(0), ..LoopPolicy::default() };
+        assert_eq!(policy.check(&url("https://bit.ly/a"), &[]), Err(ShortenerError::RedirectLoop));
+    }
+
+    #[test]
+    fn test_taken_slugs_come_with_available_suggestions() {
+        let config = ServiceConfig::default().reserve_slugs(["sale2"]);
+        let mut service = UrlShortenerService::new().with_config(config);
+        let url = Url("https://example.com/".to_string());
+        let slug = |slug: &str| Slug(slug.to_string());
+        for taken in ["sale", "sale-2"] {
+            service.handle_create_short_link(url.clone(), Some(slug(taken))).unwrap();
+        }
+        let error = service.create_with_suggestions(url.clone(), slug("sale"), 3).unwrap_err();
+        assert_eq!(error.kind(), &ShortenerError::SlugAlreadyInUse);
+        assert_eq!(error.suggestions(), [slug("sale3"), slug("sale-3"), slug("sale4")]);
+        assert_eq!(
+            error.to_string(),
+            "slug is already in use (slug `sale`) (URL `https://example.com/`) \
+             (available: `sale3`, `sale-3`, `sale4`)"
+        );
+        //a number the slug ends with is counted up
+        assert_eq!(service.suggest_slugs(&slug("sale-2"), 2), [slug("sale3"), slug("sale-3")]);
+        let created = service.create_with_suggestions(url, slug("sale3"), 3);
+        assert_eq!(created.map(|link| link.slug), Ok(slug("sale3")));
+    }
+
+    #[test]
+    fn test_other_creation_errors_come_without_suggestions() {
+        let mut service = UrlShortenerService::new();
+        let slug = Slug("sale".to_string());
+        let invalid = Url("not a url".to_string());
+        let error = service.create_with_suggestions(invalid.clone(), slug.clone(), 3).unwrap_err();
+        assert_eq!(error.kind(), &ShortenerError::InvalidUrl);
+        assert_eq!((error.slug(), error.url()), (Some(&slug), Some(&invalid)));
+        assert!(error.suggestions().is_empty());
+        assert!(service.suggest_slugs(&slug, 0).is_empty());
+    }
+
+    #[test]
+    fn test_aliases_redirect_to_their_link_and_share_its_stats() {
+        use commands::LinkManagementHandler;
+        use queries::LinkQueryHandler;
+
+        let mut service = UrlShortenerService::new();
+        let slugs = record_traffic(&mut service);
+        let slug = |slug: &str| Slug(slug.to_string());
+        for alias in ["brand", "promo", "brand"] {
+            let link = service.handle_add_alias(slugs[0].clone(), slug(alias)).unwrap();
+            assert_eq!(link.slug, slugs[0]);
+        }
+        service.handle_redirect(slug("brand")).unwrap();
+        service.handle_redirect(slug("promo")).unwrap();
+        assert_eq!(service.get_stats(slugs[0].clone()).map(|stats| stats.redirects), Ok(3));
+        assert_eq!(service.get_stats(slug("promo")).map(|stats| stats.redirects), Ok(3));
+        assert_eq!(service.list_aliases(slug("brand")), Ok(vec![slug("brand"), slug("promo")]));
+        assert_eq!(service.list_aliases(slugs[1].clone()), Ok(Vec::new()));
+        let replayed = UrlShortenerService::with_store(service.store().clone());
+        assert_eq!(replayed.list_aliases(slugs[0].clone()), Ok(vec![slug("brand"), slug("promo")]));
+    }
+
+    #[test]
+    fn test_taken_and_invalid_aliases_are_rejected() {
+        use commands::LinkManagementHandler;
+        use queries::LinkQueryHandler;
+
+        let mut service = UrlShortenerService::new();
+        let slugs = record_traffic(&mut service);
+        let slug = |slug: &str| Slug(slug.to_string());
+        let added = service.handle_add_alias(slug("missing"), slug("alias"));
+        assert_eq!(added, Err(ShortenerError::SlugNotFound));
+        let added = service.handle_add_alias(slugs[0].clone(), slugs[1].clone());
+        assert_eq!(added, Err(ShortenerError::SlugAlreadyInUse));
+        let added = service.handle_add_alias(slugs[0].clone(), slug("not valid"));
+        assert!(matches!(added, Err(ShortenerError::InvalidSlug(_))));
+        assert_eq!(service.list_aliases(slug("missing")), Err(ShortenerError::SlugNotFound));
+        assert_eq!(service.read_envelopes().len(), 10);
+    }
+
+    //projection counting the redirects of every link
+    #[derive(Default)]
+    struct RedirectCounts(BTreeMap<Slug, u64>);
+
+    impl Projection for RedirectCounts {
+        fn apply(&mut self, envelope: &EventEnvelope) {
+            if let Event::LinkAccessed { slug } = &envelope.event {
+                *self.0.entry(slug.clone()).or_default() += 1;
+            }
+        }
+
+        fn reset(&mut self) {
+            self.0.clear();
+        }
+    }
+
+    #[test]
+    fn test_registered_projections_catch_up_and_follow_the_log() {
+        let mut service = UrlShortenerService::new();
+        let slugs = record_traffic(&mut service);
+        let counts = service.register_projection(RedirectCounts::default());
+        let expected = |counts: [u64; 3]| slugs.iter().cloned().zip(counts).collect();
+        assert_eq!(counts.lock().unwrap().0, expected([1, 2, 3]));
+        service.handle_redirect(slugs[0].clone()).unwrap();
+        assert_eq!(counts.lock().unwrap().0, expected([2, 2, 3]));
+        let mut reported = Vec::new();
+        service.rebuild_projections(|replayed, total| reported.push((replayed, total)));
+        assert_eq!(counts.lock().unwrap().0, expected([2, 2, 3]));
+        assert_eq!(reported, vec![(11, 11)]);
+    }
+
+    #[test]
+    fn test_projections_see_neither_failed_commands_nor_snapshotted_events() {
+        let mut service = UrlShortenerService::new();
+        let slugs = record_traffic(&mut service);
+        let counts = service.register_projection(RedirectCounts::default());
+        let missing = service.handle_redirect(Slug("missing".to_string()));
+        assert_eq!(missing, Err(ShortenerError::SlugNotFound));
+        assert_eq!(counts.lock().unwrap().0.values().sum::<u64>(), 6);
+
+        let snapshot = service.snapshot();
+        let mut restored = UrlShortenerService::from_snapshot(snapshot, Vec::new());
+        let counts = restored.register_projection(RedirectCounts::default());
+        assert!(counts.lock().unwrap().0.is_empty());
+        restored.handle_redirect(slugs[2].clone()).unwrap();
+        assert_eq!(counts.lock().unwrap().0, BTreeMap::from([(slugs[2].clone(), 1)]));
+    }
+
+    #[test]
+    fn test_subscriptions_catch_up_from_their_checkpoint_and_follow_the_log() {
+        let mut service = UrlShortenerService::new();
+        let slugs = record_traffic(&mut service);
+        let mut subscription = service.subscribe_from(7);
+        let caught_up = (&mut subscription).take(3).map(|envelope| envelope.sequence);
+        let sequences: Vec<u64> = caught_up.collect();
+        assert_eq!(sequences, vec![7, 8, 9]);
+        assert_eq!(subscription.checkpoint(), 10);
+        assert!(subscription.try_next().is_none());
+
+        let recorded = thread::scope(|scope| {
+            let next = scope.spawn(|| block_on(subscription.next_async()));
+            service.handle_redirect(slugs[0].clone()).unwrap();
+            next.join().unwrap()
+        });
+        assert_eq!(recorded.map(|envelope| envelope.sequence), Some(10));
+        //resuming from the checkpoint misses nothing
+        let mut resumed = service.subscribe_from(subscription.checkpoint());
+        service.handle_redirect(slugs[1].clone()).unwrap();
+        assert_eq!(resumed.try_next().map(|envelope| envelope.sequence), Some(11));
+    }
+
+    #[test]
+    fn test_subscriptions_end_when_the_service_is_dropped() {
+        let mut service = UrlShortenerService::new();
+        record_traffic(&mut service);
+        let mut subscription = service.subscribe_from(u64::MAX);
+        let mut context = Context::from_waker(Waker::noop());
+        assert!(subscription.poll_next(&mut context).is_pending());
+        drop(service);
+        assert!(subscription.poll_next(&mut context).is_ready());
+        assert_eq!(subscription.next(), None);
+        assert_eq!(subscription.checkpoint(), u64::MAX);
+    }
+
+
+    #[test]
+    fn test_aggregate_commands_are_checked_like_service_commands() {
+        use aggregate::{LinkAggregate, LinkCommand};
+
+        let config = ServiceConfig::default().reserve_slugs(["admin"]);
+        let mut service = UrlShortenerService::builder().config(config).build();
+        let create = |slug: &str, url: &str| LinkCommand::Create {
+            slug: Slug(slug.to_string()),
+            url: Url(url.to_string()),
+        };
+        let stream = |slug: &str| store::StreamId(Slug(slug.to_string()));
+        let reserved = create("admin", "https://example.com/");
+        let result = service.execute_aggregate::<LinkAggregate>(&stream("admin"), reserved);
+        assert_eq!(result, Err(ShortenerError::SlugReserved));
+        let unsafe_url = create("script", "javascript:alert(1)");
+        let result = service.execute_aggregate::<LinkAggregate>(&stream("script"), unsafe_url);
+        assert_eq!(result, Err(ShortenerError::InvalidUrl));
+        assert!(service.store().read_envelopes().is_empty());
+
+        let created = create("docs", "HTTPS://Example.com/docs");
+        service.execute_aggregate::<LinkAggregate>(&stream("docs"), created).unwrap();
+        let changed = LinkCommand::ChangeUrl { url: Url("data:text/html,hi".to_string()) };
+        let result = service.execute_aggregate::<LinkAggregate>(&stream("docs"), changed);
+        assert_eq!(result, Err(ShortenerError::InvalidUrl));
+        let link = service.handle_redirect(Slug("docs".to_string())).unwrap();
+        assert_eq!(link.url, Url("https://example.com/docs".to_string()));
+    }
+
+    #[test]
+    fn test_link_aggregates_are_loaded_from_and_saved_to_their_stream() {
+        use aggregate::{AggregateRepository, LinkAggregate, LinkCommand};
+
+        let mut repository = AggregateRepository::new(InMemoryEventStore::new());
+        let slug = Slug("docs".to_string());
+        let stream = store::StreamId(slug.clone());
+        let url = Url("https://example.com/docs".to_string());
+        let create = LinkCommand::Create { slug: slug.clone(), url: url.clone() };
+        for command in [create, LinkCommand::Access, LinkCommand::Access, LinkCommand::Disable] {
+            repository.execute::<LinkAggregate>(&stream, command).unwrap();
+        }
+        let disabled = repository.execute::<LinkAggregate>(&stream, LinkCommand::Disable);
+        assert_eq!(disabled, Ok(Vec::new()));
+        let (link, version) = repository.load::<LinkAggregate>(&stream);
+        assert_eq!(link.link(), Some(&ShortLink { slug: slug.clone(), url }));
+        assert_eq!((link.redirects(), link.is_disabled(), version), (2, true, 4));
+        let service = UrlShortenerService::with_store(repository.into_inner());
+        assert_eq!(service.get_stats(slug).map(|stats| stats.redirects), Ok(2));
+    }
+
+    #[test]
+    fn test_link_aggregate_commands_and_stale_saves_are_rejected() {
+        use aggregate::{AggregateRepository, LinkAggregate, LinkCommand};
+
+        let mut repository = AggregateRepository::new(InMemoryEventStore::new());
+        let slug = Slug("docs".to_string());
+        let stream = store::StreamId(slug.clone());
+        let accessed = repository.execute::<LinkAggregate>(&stream, LinkCommand::Access);
+        assert_eq!(accessed, Err(ShortenerError::SlugNotFound));
+        let url = Url("https://example.com/docs".to_string());
+        let create = LinkCommand::Create { slug: slug.clone(), url };
+        repository.execute::<LinkAggregate>(&stream, create.clone()).unwrap();
+        let created = repository.execute::<LinkAggregate>(&stream, create);
+        assert_eq!(created, Err(ShortenerError::SlugAlreadyInUse));
+        repository.execute::<LinkAggregate>(&stream, LinkCommand::Disable).unwrap();
+        let accessed = repository.execute::<LinkAggregate>(&stream, LinkCommand::Access);
+        assert_eq!(accessed, Err(ShortenerError::LinkDisabled));
+        //saved against the version the stream had before it was disabled
+        let events = vec![Event::LinkAccessed { slug }];
+        assert_eq!(repository.save(&stream, 1, events), Err(ShortenerError::VersionConflict));
+        assert_eq!(repository.store().read_envelopes().len(), 2);
+    }
+
+    #[test]
+    fn test_recorded_events_have_stable_types_and_versions() {
+        let mut service = UrlShortenerService::new();
+        record_traffic(&mut service);
+        let envelopes = service.read_envelopes();
+        let kinds = envelopes.iter().fold(BTreeMap::new(), |mut kinds, envelope| {
+            *kinds.entry(envelope.event.event_type()).or_default() += 1;
+            kinds
+        });
+        assert_eq!(
+            kinds,
+            BTreeMap::from([("link_accessed", 6), ("link_created", 3), ("url_changed", 1)])
+        );
+        assert!(envelopes.iter().all(|envelope| envelope.event.event_version() == 1));
+    }
+
+    #[test]
+    fn test_upgraded_payloads_keep_their_type_and_bump_their_version() {
+        let slug = Slug("a".to_string());
+        let old = Event::LinkAccessed { slug: slug.clone() };
+        let new = Event::LinkAccessedV2 { slug: slug.clone(), context: ClickContext::default() };
+        assert_eq!(old.event_type(), new.event_type());
+        assert_eq!((old.event_version(), new.event_version()), (1, 2));
+        //related events never share a type
+        let deleted = Event::LinkDeleted { slug: slug.clone() };
+        let exhausted = Event::LinkExhausted { slug };
+        assert_ne!(deleted.event_type(), exhausted.event_type());
+        assert_ne!(deleted.event_type(), old.event_type());
+    }
+
+    #[test]
+    fn test_command_validators_run_in_order_before_commands() {
+        use commands::{Command, CommandEnvelope};
+        use validation::{OwnerQuota, Rejection};
+
+        let no_promos = |command: &Command, _: &dyn queries::LinkQueryHandler| match command {
+            Command::CreateOwnedLink { slug: Some(slug), .. } if slug.0.starts_with("promo") => {
+                Err(Rejection::new("no_promos", "promotions are paused"))
+            }
+            _ => Ok(()),
+        };
+        let mut service = UrlShortenerService::builder()
+            .command_validator(OwnerQuota { max_links: 2 })
+            .command_validator(no_promos)
+            .build();
+        let create = |slug: &str| {
+            CommandEnvelope::new(Command::CreateOwnedLink {
+                owner: OwnerId("alice".to_string()),
+                url: Url("https://example.com/".to_string()),
+                slug: Some(Slug(slug.to_string())),
+            })
+        };
+        let rejected = service.execute(create("promo"));
+        let rejection = Rejection::new("no_promos", "promotions are paused");
+        assert_eq!(rejected, Err(ShortenerError::Rejected(rejection)));
+        service.execute(create("a")).unwrap();
+        service.execute(create("b")).unwrap();
+        //the quota runs first
+        let Err(ShortenerError::Rejected(rejection)) = service.execute(create("promo")) else {
+            panic!("the quota was not enforced");
+        };
+        assert_eq!(rejection.to_string(), "owner_quota: owner already has 2 links");
+        assert_eq!(service.read_envelopes().len(), 2);
+    }
+
+    #[test]
+    fn test_built_in_policies_reject_commands_they_break() {
+        use commands::{Command, CommandEnvelope};
+        use validation::{
+            DefaultUrlValidator, DomainPolicy, SlugPolicy, UrlPolicy, Validator, ValidatorChain,
+        };
+
+        let service = UrlShortenerService::new();
+        let chain = ValidatorChain::new()
+            .with(UrlPolicy(DefaultUrlValidator::default()))
+            .with(SlugPolicy::default())
+            .with(DomainPolicy::default().block("evil.example"));
+        assert_eq!(chain.len(), 3);
+        let create = |url: &str, slug: &str| Command::CreateShortLink {
+            url: Url(url.to_string()),
+            slug: Some(Slug(slug.to_string())),
+        };
+        assert_eq!(chain.validate(&create("https://example.com/", "ok"), &service), Ok(()));
+        let rules: Vec<String> = [
+            create("javascript:alert(1)", "ok"),
+            create("https://example.com/", "not ok"),
+            create("https://evil.example/", "ok"),
+        ]
+        .iter()
+        .map(|command| chain.validate(command, &service).unwrap_err().rule)
+        .collect();
+        assert_eq!(rules, ["url_policy", "slug_policy", "domain_policy"]);
+
+        let mut service = UrlShortenerService::new().with_command_validator(chain);
+        let rejected = service.execute(CommandEnvelope::new(create("https://evil.example/", "a")));
+        assert_eq!(rejected.map_err(|error| error.code()), Err("rejected"));
+        assert!(service.read_envelopes().is_empty());
+    }
+
+    #[test]
+    fn test_commit_hooks_see_the_events_of_a_command_as_one_batch() {
+        use commands::{Command, CommandEnvelope};
+
+        let mut service = UrlShortenerService::builder().buffer_clicks(true).build();
+        let checked = Arc::new(Mutex::new(Vec::new()));
+        let committed = Arc::new(Mutex::new(Vec::new()));
+        let before = Arc::clone(&checked);
+        service.on_before_commit(move |envelopes| {
+            before.lock().unwrap().push(envelopes.len());
+            Ok(())
+        });
+        let after = Arc::clone(&committed);
+        service.on_after_commit(move |envelopes| {
+            let types = envelopes.iter().map(|envelope| envelope.event.event_type());
+            after.lock().unwrap().push(types.collect::<Vec<_>>());
+        });
+        let slugs = record_traffic(&mut service);
+        let deleted = service.execute(CommandEnvelope::new(Command::DeleteShortLink {
+            slug: slugs[0].clone(),
+        }));
+        assert!(deleted.is_ok());
+        //every event recorded by the handlers is a batch of its own
+        assert_eq!(*checked.lock().unwrap(), [1, 1, 1, 1, 1, 2]);
+        let committed = committed.lock().unwrap();
+        assert_eq!(committed.last().unwrap(), &["clicks_aggregated", "link_deleted"]);
+        assert_eq!(committed.len(), 6);
+    }
+
+    #[test]
+    fn test_vetoed_batches_are_neither_stored_nor_applied() {
+        use commands::{Command, CommandEnvelope, LinkManagementHandler};
+        use validation::Rejection;
+
+        let mut service = UrlShortenerService::new();
+        let slugs = record_traffic(&mut service);
+        service.on_before_commit(|envelopes| {
+            match envelopes.iter().any(|envelope| envelope.event.event_type() == "link_deleted") {
+                true => Err(Rejection::new("retention", "links are never deleted")),
+                false => Ok(()),
+            }
+        });
+        let committed = Arc::new(Mutex::new(0));
+        let after = Arc::clone(&committed);
+        service.on_after_commit(move |_| *after.lock().unwrap() += 1);
+        let deleted = service.execute(CommandEnvelope::new(Command::DeleteShortLink {
+            slug: slugs[0].clone(),
+        }));
+        let rejection = Rejection::new("retention", "links are never deleted");
+        assert_eq!(deleted, Err(ShortenerError::Rejected(rejection.clone())));
+        let deleted = service.handle_delete_short_link(slugs[0].clone());
+        assert_eq!(deleted, Err(ShortenerError::Rejected(rejection)));
+        assert_eq!(service.read_envelopes().len(), 10);
+        assert_eq!(*committed.lock().unwrap(), 0);
+        assert!(service.handle_redirect(slugs[0].clone()).is_ok());
+        assert_eq!(*committed.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_unit_of_work_is_committed_as_one_batch() {
+        use commands::{Command, CommandOutput, UnitOfWork};
+
+        let mut service = UrlShortenerService::new();
+        let batches = Arc::new(Mutex::new(Vec::new()));
+        let after = Arc::clone(&batches);
+        service.on_after_commit(move |envelopes| after.lock().unwrap().push(envelopes.to_vec()));
+        let campaign = Slug("launch".to_string());
+        let unit = (0..2).fold(
+            UnitOfWork::new().with(Command::CreateCampaign {
+                campaign: campaign.clone(),
+                name: "Launch".to_string(),
+            }),
+            |unit, i| {
+                let slug = Slug(format!("launch-{i}"));
+                let url = Url(format!("https://example.com/{i}"));
+                unit.with(Command::CreateShortLink { url, slug: Some(slug.clone()) })
+                    .with(Command::AddToCampaign { slug, campaign: campaign.clone() })
+            },
+        );
+        let id = unit.id();
+
+        let outputs = service.commit_unit(unit).unwrap();
+        assert!(matches!(outputs[..], [CommandOutput::Campaign(_), _, _, _, _]));
+        let batches = batches.lock().unwrap();
+        let [batch] = &batches[..] else {
+            panic!("the unit was not committed as one batch");
+        };
+        assert_eq!(batch.len(), 5);
+        assert!(batch.iter().all(|envelope| envelope.correlation_id == Some(id)));
+        assert_eq!(service.read_envelopes(), *batch);
+    }
+
+    #[test]
+    fn test_failed_unit_of_work_leaves_nothing_behind() {
+        use commands::{Command, UnitOfWork};
+
+        let mut service = UrlShortenerService::new();
+        let slug = Slug("aaa".to_string());
+        let unit = UnitOfWork::new()
+            .with(Command::CreateShortLink {
+                url: Url("https://example.com/".to_string()),
+                slug: Some(slug.clone()),
+            })
+            .with(Command::Redirect { slug: slug.clone() })
+            .with(Command::AddToCampaign { slug: slug.clone(), campaign: Slug("x".to_string()) });
+
+        let failed = service.commit_unit(unit);
+        assert_eq!(failed.map_err(|error| error.code()), Err("campaign_not_found"));
+        assert!(service.read_envelopes().is_empty());
+        assert_eq!(service.get_stats(slug), Err(ShortenerError::SlugNotFound));
+        let audit = service.query_audit(&audit::AuditFilter::default());
+        assert_eq!(audit.len(), 3);
+        assert!(audit.iter().all(|entry| matches!(entry.outcome, AuditOutcome::Rejected { .. })));
+    }
+
+    //in-memory store failing every append once `appends` succeeded
+    struct FailingStore {
+        inner: InMemoryEventStore,
+        appends: usize,
+    }
+
+    impl EventStore for FailingStore {
+        fn append(&mut self, envelope: EventEnvelope) -> io::Result<()> {
+            if self.appends == 0 {
+                return Err(io::Error::other("disk full"));
+            }
+            self.appends -= 1;
+            self.inner.append(envelope)
+        }
+
+        fn read_envelopes(&self) -> Vec<EventEnvelope> {
+            self.inner.read_envelopes()
+        }
+
+        fn read_stream(&self, stream: &store::StreamId) -> Vec<EventEnvelope> {
+            self.inner.read_stream(stream)
+        }
+
+        fn stream_version(&self, stream: &store::StreamId) -> u64 {
+            self.inner.stream_version(stream)
+        }
+
+        fn truncate(&mut self, len: usize) -> io::Result<()> {
+            self.inner.truncate(len)
+        }
+    }
+
+    #[test]
+    fn test_unit_of_work_failing_to_be_stored_commits_nothing() {
+        let store = FailingStore { inner: InMemoryEventStore::new(), appends: 1 };
+        let mut service = UrlShortenerService::with_store(store);
+        let create = |slug: &str| commands::Command::CreateShortLink {
+            url: Url(format!("https://example.com/{slug}")),
+            slug: Some(Slug(slug.to_string())),
+        };
+        let unit = commands::UnitOfWork::new().with(create("aaa")).with(create("bbb"));
+
+        assert_eq!(service.commit_unit(unit), Err(ShortenerError::StorageFailure));
+        assert!(service.store().read_envelopes().is_empty());
+        assert_eq!(service.get_stats(Slug("aaa".to_string())), Err(ShortenerError::SlugNotFound));
+        let audit = service.query_audit(&audit::AuditFilter::default());
+        assert_eq!(audit.len(), 2);
+        assert!(audit.iter().all(|entry| matches!(entry.outcome, AuditOutcome::Rejected { .. })));
+        //the failed unit left no gap in the sequences
+        service.store.appends = 1;
+        service.handle_create_short_link(Url("https://example.com/".to_string()), None).unwrap();
+        assert_eq!(service.store().read_envelopes()[0].sequence, 0);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_file_store_batch_survives_restart() {
+        let path = temporary_path("batch");
+        let store = store::FileEventStore::open(&path).unwrap();
+        let mut service = UrlShortenerService::with_store(store);
+        let unit = (0..3).fold(commands::UnitOfWork::new(), |unit, i| {
+            let url = Url(format!("https://example.com/{i}"));
+            unit.with(commands::Command::CreateShortLink { url, slug: None })
+        });
+        service.commit_unit(unit).unwrap();
+        let lines = std::fs::read_to_string(&path).unwrap().lines().count();
+        assert_eq!(lines, 1);
+
+        let reopened = store::FileEventStore::open(&path).unwrap();
+        assert_eq!(reopened.read_envelopes(), service.store().read_envelopes());
+        assert_eq!(reopened.read_envelopes().len(), 3);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(all(feature = "serde", any(feature = "kafka", feature = "nats")))]
+    #[test]
+    fn test_event_publisher_stops_while_retrying_and_idle() {
+        use publishing::{BrokerMessage, EventPublisher, PublisherConfig};
+
+        //broker acknowledging only the messages of the link
+        struct Broker(Slug, mpsc::Sender<Uuid>);
+
+        impl publishing::Broker for Broker {
+            fn publish(&mut self, message: &BrokerMessage) -> Result<(), String> {
+                if message.key != self.0 .0 {
+                    return Err("unavailable".to_string());
+                }
+                self.1.send(message.id).map_err(|e| e.to_string())
+            }
+        }
+
+        let config = PublisherConfig {
+            initial_backoff: Duration::from_millis(10),
+            poll_interval: Duration::from_millis(10),
+            ..PublisherConfig::default()
+        };
+        let mut service = UrlShortenerService::new();
+        let slugs = record_traffic(&mut service);
+        let (published, acknowledged) = mpsc::channel();
+        let broker = Broker(slugs[0].clone(), published);
+        let publisher = EventPublisher::spawn(service.subscribe_from(0), broker, config.clone());
+        //the events of a are acknowledged before the first one of b is retried
+        assert!(acknowledged.recv_timeout(Duration::from_secs(5)).is_ok());
+        assert!(acknowledged.recv_timeout(Duration::from_secs(5)).is_ok());
+        assert_eq!(publisher.stop(), 2);
+
+        //no events are left to publish
+        let end = service.store().read_envelopes().len() as u64;
+        let (published, _acknowledged) = mpsc::channel();
+        let broker = Broker(slugs[0].clone(), published);
+        let publisher = EventPublisher::spawn(service.subscribe_from(end), broker, config);
+        assert_eq!(publisher.stop(), end);
+    }
+
+    #[cfg(all(feature = "serde", any(feature = "kafka", feature = "nats")))]
+    #[test]
+    fn test_event_publisher_retries_until_every_event_is_acknowledged() {
+        use publishing::{BrokerMessage, EventPublisher, PublisherConfig, TopicMapping};
+
+        //broker failing every other attempt
+        struct Broker(bool, mpsc::Sender<BrokerMessage>);
+
+        impl publishing::Broker for Broker {
+            fn publish(&mut self, message: &BrokerMessage) -> Result<(), String> {
+                self.0 = !self.0;
+                if self.0 {
+                    return Err("timed out".to_string());
+                }
+                self.1.send(message.clone()).map_err(|e| e.to_string())
+            }
+        }
+
+        let config = PublisherConfig {
+            topics: TopicMapping::PerEventType { prefix: "shortener".to_string() },
+            initial_backoff: Duration::from_millis(1),
+            poll_interval: Duration::from_millis(10),
+            ..PublisherConfig::default()
+        };
+        let mut service = UrlShortenerService::new();
+        let slugs = record_traffic(&mut service);
+        let (published, acknowledged) = mpsc::channel();
+        let broker = Broker(false, published);
+        let publisher = EventPublisher::spawn(service.subscribe_from(10), broker, config);
+        for slug in slugs {
+            service.handle_redirect(slug).unwrap();
+        }
+        let envelopes = service.read_envelopes();
+        drop(service);
+
+        assert_eq!(publisher.join(), 13);
+        let messages: Vec<BrokerMessage> = acknowledged.into_iter().collect();
+        assert_eq!(messages.len(), 3);
+        for (message, envelope) in messages.iter().zip(&envelopes[10..]) {
+            assert_eq!(message.id, envelope.id);
+            assert_eq!(message.topic, format!("shortener.{}", envelope.event.event_type()));
+            assert_eq!(message.key, envelope.event.stream_id().0 .0);
+            let payload: EventEnvelope = serde_json::from_slice(&message.payload).unwrap();
+            assert_eq!(payload, *envelope);
+        }
+    }
+
+    #[cfg(all(feature = "serde", any(feature = "kafka", feature = "nats")))]
+    #[test]
+    fn test_events_without_a_topic_are_skipped_by_the_publisher() {
+        use publishing::{BrokerMessage, EventPublisher, PublisherConfig, TopicMapping};
+
+        struct Broker(mpsc::Sender<String>);
+
+        impl publishing::Broker for Broker {
+            fn publish(&mut self, message: &BrokerMessage) -> Result<(), String> {
+                self.0.send(message.topic.clone()).map_err(|e| e.to_string())
+            }
+        }
+
+        let topics = HashMap::from([("url_changed".to_string(), "changes".to_string())]);
+        let config = PublisherConfig {
+            topics: TopicMapping::Custom { topics, fallback: None },
+            poll_interval: Duration::from_millis(10),
+            ..PublisherConfig::default()
+        };
+        let mut service = UrlShortenerService::new();
+        record_traffic(&mut service);
+        let (published, acknowledged) = mpsc::channel();
+        let publisher = EventPublisher::spawn(service.subscribe_from(0), Broker(published), config);
+        drop(service);
+
+        //the skipped events are behind the checkpoint too
+        assert_eq!(publisher.join(), 10);
+        assert_eq!(acknowledged.into_iter().collect::<Vec<_>>(), ["changes"]);
+    }
+
+    //outbox message announcing every created link
+    fn announce_links(envelope: &EventEnvelope) -> Vec<outbox::OutboxMessage> {
+        match &envelope.event {
+            Event::LinkCreated { slug, .. } => {
+                vec![outbox::OutboxMessage::new(envelope, "links", slug.0.clone())]
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_outbox_messages_are_delivered_until_accepted() {
+        use outbox::{OutboxConfig, OutboxDispatcher, OutboxMessage};
+
+        let mut service = UrlShortenerService::new().with_outbox(announce_links);
+        record_traffic(&mut service);
+        let pending = service.pending_outbox();
+        let payloads: Vec<&str> = pending.iter().map(|message| message.payload.as_str()).collect();
+        assert_eq!(payloads, ["a", "b", "c"]);
+        let envelopes = service.read_envelopes();
+        assert!(pending.iter().all(|message| {
+            envelopes[message.sequence as usize].id == message.event_id && message.topic == "links"
+        }));
+
+        let shared = SharedUrlShortenerService::new(service);
+        let (delivered, received) = mpsc::channel();
+        let mut attempts = 0;
+        //every message is refused once
+        let sink = move |message: &OutboxMessage| {
+            attempts += 1;
+            if attempts % 2 == 1 {
+                return Err("unavailable".to_string());
+            }
+            delivered.send(message.payload.clone()).map_err(|e| e.to_string())
+        };
+        let config = OutboxConfig {
+            poll_interval: Duration::from_millis(10),
+            initial_backoff: Duration::from_millis(1),
+            ..OutboxConfig::default()
+        };
+        let dispatcher = OutboxDispatcher::spawn(shared.clone(), sink, config);
+        let received: Vec<String> = (0..3)
+            .map(|_| received.recv_timeout(Duration::from_secs(5)).unwrap())
+            .collect();
+        dispatcher.stop();
+        assert_eq!(received, ["a", "b", "c"]);
+        assert!(shared.read().pending_outbox().is_empty());
+    }
+
+    #[test]
+    fn test_events_are_not_recorded_without_their_outbox_messages() {
+        let store = FailingStore { inner: InMemoryEventStore::new(), appends: usize::MAX };
+        let mut service = UrlShortenerService::with_store(store).with_outbox(announce_links);
+        let url = Url("https://example.com/".to_string());
+        let created = service.handle_create_short_link(url, Some(Slug("a".to_string())));
+        //the store has no outbox
+        assert_eq!(created, Err(ShortenerError::StorageFailure));
+        assert!(service.read_envelopes().is_empty());
+        assert_eq!(service.acknowledge_outbox(Uuid::nil()), Ok(()));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_file_store_keeps_only_unacknowledged_messages_across_restarts() {
+        let path = temporary_path("outbox");
+        let store = store::FileEventStore::open(&path).unwrap();
+        let mut service = UrlShortenerService::with_store(store).with_outbox(announce_links);
+        record_traffic(&mut service);
+        let pending = service.pending_outbox();
+        service.acknowledge_outbox(pending[0].id).unwrap();
+        drop(service);
+
+        let reopened = store::FileEventStore::open(&path).unwrap();
+        assert_eq!(reopened.read_outbox(), pending[1..]);
+        std::fs::remove_file(path.with_extension("outbox")).unwrap();
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(feature = "redis")]
+    #[test]
+    #[ignore = "needs an empty Redis database in REDIS_URL"]
+    fn test_redis_projection_mirrors_links_across_restarts() {
+        use commands::LinkManagementHandler;
+        use redis_projection::{RedisLinkReader, RedisProjection};
+
+        let url = std::env::var("REDIS_URL").unwrap();
+        let mut service = UrlShortenerService::new();
+        let projection = RedisProjection::connect(&url, "test").unwrap();
+        let projection = service.register_projection(projection);
+        let slugs = record_traffic(&mut service);
+        let alias = Slug("bee".to_string());
+        service.handle_add_alias(slugs[1].clone(), alias.clone()).unwrap();
+        service.handle_delete_short_link(slugs[2].clone()).unwrap();
+        assert!(projection.lock().unwrap().last_error().is_none());
+
+        let mut reader = RedisLinkReader::connect(&url, "test").unwrap();
+        let mirrored = reader.lookup(&alias).unwrap().unwrap();
+        assert_eq!(mirrored.link.slug, slugs[1]);
+        assert_eq!(mirrored.link.url, Url("https://example.org/".to_string()));
+        assert_eq!(mirrored.clicks, 2);
+        assert!(mirrored.is_redirectable());
+        assert_eq!(reader.lookup(&slugs[2]), Ok(None));
+
+        //replaying the log after a restart counts no redirects twice
+        let mut restarted = UrlShortenerService::with_store(service.store().clone());
+        restarted.register_projection(RedisProjection::connect(&url, "test").unwrap());
+        assert_eq!(reader.lookup(&slugs[0]).unwrap().unwrap().clicks, 1);
+    }
+
+    #[cfg(feature = "redis")]
+    #[test]
+    fn test_redis_projection_keeps_events_while_unreachable() {
+        use redis_projection::{RedisLinkReader, RedisProjection};
+
+        let client = redis::Client::open("redis://127.0.0.1:1/").unwrap();
+        let mut service = UrlShortenerService::new();
+        let projection = service.register_projection(RedisProjection::from_client(client, "test"));
+        record_traffic(&mut service);
+        let projection = projection.lock().unwrap();
+        assert_eq!(projection.backlog(), 10);
+        assert!(projection.last_error().is_some());
+
+        let client = redis::Client::open("redis://127.0.0.1:1/").unwrap();
+        let mut reader = RedisLinkReader::from_client(client, "test");
+        assert!(reader.lookup(&Slug("a".to_string())).is_err());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_sqlite_store_survives_restart_and_reads_ranges() {
+        let path = temporary_path("sqlite");
+        let store = store::SqliteEventStore::open(&path).unwrap();
+        let mut service = UrlShortenerService::with_store(store);
+        let slugs = record_traffic(&mut service);
+        let envelopes = service.read_envelopes();
+        drop(service);
+
+        let store = store::SqliteEventStore::open(&path).unwrap();
+        assert_eq!((store.len(), store.last_sequence()), (10, Some(9)));
+        assert_eq!(store.read_range(3..5), envelopes[3..5]);
+        assert!(store.read_range(10..20).is_empty());
+        let service = UrlShortenerService::with_store(store);
+        assert_eq!(service.read_envelopes(), envelopes);
+        assert_eq!(service.get_stats(slugs[2].clone()).unwrap().redirects, 3);
+        drop(service);
+        for extension in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{extension}", path.display()));
+        }
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_sqlite_store_rejects_a_slug_taken_by_another_writer() {
+        let path = temporary_path("sqlite-writers");
+        let open = || store::SqliteEventStore::open(&path).map(UrlShortenerService::with_store);
+        let (mut first, mut second) = (open().unwrap(), open().unwrap());
+        let slug = Slug("a".to_string());
+        let url = Url("https://example.com/".to_string());
+        first.handle_create_short_link(url.clone(), Some(slug.clone())).unwrap();
+        let created = second.handle_create_short_link(url, Some(slug));
+        assert_eq!(created, Err(ShortenerError::StorageFailure));
+        assert_eq!(second.store().len(), 1);
+
+        //the stream already has a first version
+        let mut envelope = first.read_envelopes().remove(0);
+        envelope.sequence = 1;
+        let appended = second.store.append(envelope);
+        assert_eq!(appended.unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        drop((first, second));
+        for extension in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{extension}", path.display()));
+        }
+    }
+
+    //log of `links` links redirected `redirects` times each with the details
+    //of the requests
+    #[cfg(any(feature = "serde", feature = "rkyv"))]
+    fn redirect_log(links: usize, redirects: usize) -> Vec<EventEnvelope> {
+        let mut envelopes = Vec::with_capacity(links * (redirects + 1));
+        for link in 0..links {
+            let slug = Slug(format!("link{link}"));
+            let created = Event::LinkCreated {
+                slug: slug.clone(),
+                url: Url(format!("https://example.com/articles/{link}?utm_source=newsletter")),
+                raw_url: None,
+                max_clicks: None,
+                owner: None,
+                active_from: None,
+                active_until: None,
+                fallback_url: None,
+            };
+            envelopes.push(EventEnvelope::new(envelopes.len() as u64, 1, created));
+            for redirect in 0..redirects {
+                let accessed = Event::LinkAccessedV2 {
+                    slug: slug.clone(),
+                    context: ClickContext {
+                        referrer: Some("https://news.example.org/".to_string()),
+                        user_agent: Some("Mozilla/5.0 (X11; Linux x86_64)".to_string()),
+                        ip: Some(IpAddr::from([192, 0, 2, redirect as u8])),
+                        country: Some("DE".to_string()),
+                    },
+                };
+                let occurred_at = SystemTime::UNIX_EPOCH
+                    + Duration::from_secs(1_700_000_000 + redirect as u64);
+                let sequence = envelopes.len() as u64;
+                let version = redirect as u64 + 2;
+                envelopes.push(EventEnvelope::new_at(sequence, version, accessed, occurred_at));
+            }
+        }
+        envelopes
+    }
+
+    //checks the codec decodes what it encoded, single envelopes and whole
+    //logs, and rejects cut off and malformed logs
+    #[cfg(any(feature = "serde", feature = "rkyv"))]
+    fn assert_round_trip(codec: &impl encoding::EventCodec) {
+        let mut service = UrlShortenerService::new();
+        let slugs = record_traffic(&mut service);
+        let envelopes = service.store().read_envelopes();
+        for envelope in envelopes.iter().chain(&redirect_log(2, 3)) {
+            assert_eq!(codec.decode(&codec.encode(envelope).unwrap()).unwrap(), *envelope);
+        }
+
+        let mut log = Vec::new();
+        service.export_events(codec, &mut log).unwrap();
+        let imported = UrlShortenerService::import_events(log.as_slice(), codec).unwrap();
+        assert_eq!(imported.store().read_envelopes(), envelopes);
+        assert_eq!(imported.get_stats(slugs[2].clone()).map(|stats| stats.redirects), Ok(3));
+
+        let cut = UrlShortenerService::import_events(&log[..log.len() - 1], codec);
+        assert_eq!(cut.err().map(|e| e.kind()), Some(io::ErrorKind::UnexpectedEof));
+        let garbage = codec.decode(&[0xff; 8]);
+        assert_eq!(garbage.err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+
+        let mut truncated = encoding::MAX_FRAME_LEN.to_le_bytes().to_vec();
+        truncated.extend_from_slice(&log[4..]);
+        let truncated = codec.read_envelopes(&mut truncated.as_slice());
+        assert_eq!(truncated.err().map(|e| e.kind()), Some(io::ErrorKind::UnexpectedEof));
+        let oversized = (encoding::MAX_FRAME_LEN + 1).to_le_bytes();
+        let oversized = codec.read_envelopes(&mut oversized.as_slice());
+        assert_eq!(oversized.err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_json_codec_round_trip() {
+        assert_round_trip(&encoding::JsonCodec);
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn test_bincode_codec_round_trip() {
+        assert_round_trip(&encoding::BincodeCodec);
+    }
+
+    #[cfg(feature = "rkyv")]
+    #[test]
+    fn test_rkyv_codec_round_trip() {
+        assert_round_trip(&encoding::RkyvCodec);
+    }
+
+    //size of the encoded log and the time it took to encode and decode it
+    #[cfg(any(feature = "serde", feature = "rkyv"))]
+    fn time_codec(
+        codec: &impl encoding::EventCodec,
+        envelopes: &[EventEnvelope],
+    ) -> (usize, Duration, Duration) {
+        let started = Instant::now();
+        let mut encoded = Vec::new();
+        codec.write_envelopes(&mut encoded, envelopes).unwrap();
+        let encode = started.elapsed();
+        let started = Instant::now();
+        let decoded = codec.read_envelopes(&mut encoded.as_slice()).unwrap();
+        let decode = started.elapsed();
+        assert!(decoded == envelopes);
+        (encoded.len(), encode, decode)
+    }
+
+    #[cfg(any(feature = "serde", feature = "rkyv"))]
+    #[test]
+    #[ignore = "benchmark, run with --ignored --nocapture"]
+    fn bench_codecs() {
+        let envelopes = redirect_log(1000, 20);
+        let mut timings = Vec::new();
+        #[cfg(feature = "serde")]
+        timings.push(("json", time_codec(&encoding::JsonCodec, &envelopes)));
+        #[cfg(feature = "bincode")]
+        timings.push(("bincode", time_codec(&encoding::BincodeCodec, &envelopes)));
+        #[cfg(feature = "rkyv")]
+        timings.push(("rkyv", time_codec(&encoding::RkyvCodec, &envelopes)));
+        for (codec, (size, encode, decode)) in timings {
+            println!("{codec}: {size} bytes, encoded in {encode:?}, decoded in {decode:?}");
+        }
+    }
+
+    #[test]
+    fn test_flushed_clicks_count_as_single_events() {
+        use queries::LinkQueryHandler;
+
+        let mut service = UrlShortenerService::builder().buffer_clicks(true).build();
+        let slug = Slug("example".to_string());
+        let url = Url("https://example.com/".to_string());
+        service.handle_create_short_link(url, Some(slug.clone())).unwrap();
+        for _ in 0..5 {
+            service.handle_redirect(slug.clone()).unwrap();
+        }
+        assert_eq!(service.flush(), Ok(1));
+        let stats = service.global_stats();
+        assert_eq!(stats.events, service.store().read_envelopes().len() as u64);
+        assert_eq!(stats.redirects, 5);
+
+        //compaction folds the flushed clicks with later ones, which still count
+        let store = InMemoryEventStore::from_envelopes(service.store().read_envelopes());
+        let mut service = UrlShortenerService::with_store(store);
+        service.handle_redirect(slug.clone()).unwrap();
+        service.handle_redirect(slug).unwrap();
+        let recorded = service.global_stats().events;
+        assert_eq!(recorded, 4);
+        assert_eq!(service.compact(u64::MAX), Ok(2));
+        let store = InMemoryEventStore::from_envelopes(service.store().read_envelopes());
+        let restored = UrlShortenerService::with_store(store).global_stats();
+        assert_eq!((restored.events, restored.redirects), (recorded, 7));
+    }
+
+    #[test]
+    fn test_deleted_links_leave_their_campaign() {
+        use commands::{CampaignHandler, LinkManagementHandler};
+        use queries::CampaignQueryHandler;
+
+        let config = ServiceConfig {
+            allow_slug_reuse: true,
+            ..ServiceConfig::default()
+        };
+        let mut service = UrlShortenerService::builder().config(config).build();
+        let slugs = record_traffic(&mut service);
+        let campaign = Slug("launch".to_string());
+        service.handle_create_campaign(campaign.clone(), "Launch".to_string()).unwrap();
+        for slug in &slugs[..2] {
+            service.handle_add_to_campaign(slug.clone(), campaign.clone()).unwrap();
+        }
+        service.handle_delete_short_link(slugs[0].clone()).unwrap();
+        let listed = service.get_campaign(campaign.clone()).unwrap().links;
+        assert_eq!(listed, vec![slugs[1].clone()]);
+        assert_eq!(service.get_campaign_stats(campaign.clone()).unwrap().redirects, 2);
+
+        let url = Url("https://example.net/".to_string());
+        service.handle_create_short_link(url, Some(slugs[0].clone())).unwrap();
+        service.handle_redirect(slugs[0].clone()).unwrap();
+        let stats = service.get_campaign_stats(campaign).unwrap();
+        assert_eq!((stats.links.len(), stats.redirects), (1, 2));
+    }
+
+    //length and last sequence of the store agree with its envelopes, also
+    //after compaction removed some of them
+    fn assert_store_position<S: EventStore>(store: S) {
+        let mut service = UrlShortenerService::with_store(store);
+        assert!(service.store().is_empty());
+        assert_eq!(service.store().last_sequence(), None);
+        record_traffic(&mut service);
+        let last = service.store().last_sequence().unwrap();
+        assert_eq!(service.compact(last), Ok(3));
+        let envelopes = service.store().read_envelopes();
+        assert_eq!(service.store().len(), envelopes.len());
+        assert_eq!(envelopes.last().map(|envelope| envelope.sequence), Some(last));
+        assert_eq!(service.store().last_sequence(), Some(last));
+    }
+
+    #[test]
+    fn test_store_length_and_last_sequence() {
+        assert_store_position(InMemoryEventStore::default());
+        #[cfg(feature = "serde")]
+        {
+            let path = temporary_path("position");
+            assert_store_position(store::FileEventStore::open(&path).unwrap());
+            std::fs::remove_file(path).unwrap();
+        }
+        #[cfg(feature = "sqlite")]
+        assert_store_position(store::SqliteEventStore::open_in_memory().unwrap());
+        #[cfg(feature = "sled")]
+        {
+            let db = sled::Config::new().temporary(true).open().unwrap();
+            assert_store_position(store::SledEventStore::from_db(db).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_aggregates_continue_the_compacted_log() {
+        use aggregate::{AggregateRepository, LinkAggregate, LinkCommand};
+        use store::StreamId;
+
+        let mut service = UrlShortenerService::new();
+        record_traffic(&mut service);
+        let last = service.store().last_sequence().unwrap();
+        service.compact(last).unwrap();
+        let store = InMemoryEventStore::from_envelopes(service.store().read_envelopes());
+        let mut repository = AggregateRepository::new(store);
+        let slug = Slug("d".to_string());
+        let url = Url("https://example.com/d".to_string());
+        let create = LinkCommand::Create { slug: slug.clone(), url };
+        let saved = repository.execute::<LinkAggregate>(&StreamId(slug), create).unwrap();
+        assert_eq!(saved[0].sequence, last + 1);
+        let stale = repository.save(&StreamId(Slug("a".to_string())), 0, Vec::new());
+        assert_eq!(stale, Err(ShortenerError::VersionConflict));
+    }
 }